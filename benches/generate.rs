@@ -0,0 +1,82 @@
+//! Benchmark for Nix generation over a synthetic unit graph.
+//!
+//! Run with `cargo bench`. Builds a 1000-unit graph where each unit depends
+//! on a few earlier ones (so transitive closures are non-trivial), then
+//! measures parsing and generation with criterion.
+
+use std::fmt::Write as _;
+use std::hint::black_box;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use nix_cargo_unit::nix_gen::{NixGenConfig, NixGenerator};
+use nix_cargo_unit::unit_graph::UnitGraph;
+
+const UNITS: usize = 1000;
+
+/// Builds unit graph JSON with `n` library units and one binary root.
+fn synthetic_graph(n: usize) -> String {
+    let mut units = Vec::with_capacity(n);
+    for i in 0..n {
+        let mut deps = String::new();
+        let mut dep_indices = vec![i.wrapping_sub(1), i / 2, i / 3];
+        dep_indices.retain(|&d| d < i);
+        dep_indices.dedup();
+        for (j, d) in dep_indices.iter().enumerate() {
+            if j > 0 {
+                deps.push(',');
+            }
+            let _ = write!(
+                deps,
+                r#"{{"index": {d}, "extern_crate_name": "crate_{d}"}}"#
+            );
+        }
+
+        let (kind, name) = if i + 1 == n {
+            ("bin", "app".to_string())
+        } else {
+            ("lib", format!("crate_{i}"))
+        };
+        units.push(format!(
+            r#"{{
+                "pkg_id": "registry+https://github.com/rust-lang/crates.io-index#{name}@1.0.{i}",
+                "target": {{
+                    "kind": ["{kind}"],
+                    "crate_types": ["{kind}"],
+                    "name": "{name}",
+                    "src_path": "/home/user/.cargo/registry/src/index.crates.io-6f17d22bba15001f/{name}-1.0.{i}/src/lib.rs",
+                    "edition": "2021"
+                }},
+                "profile": {{"name": "release", "opt_level": "3"}},
+                "features": ["default", "std"],
+                "mode": "build",
+                "dependencies": [{deps}]
+            }}"#
+        ));
+    }
+
+    format!(
+        r#"{{"version": 1, "units": [{}], "roots": [{}]}}"#,
+        units.join(","),
+        n - 1
+    )
+}
+
+fn bench_generate(c: &mut Criterion) {
+    let json = synthetic_graph(UNITS);
+
+    c.bench_function("parse", |b| {
+        b.iter(|| serde_json::from_str::<UnitGraph>(black_box(&json)).unwrap());
+    });
+
+    let graph: UnitGraph = serde_json::from_str(&json).unwrap();
+    let generator = NixGenerator::new(NixGenConfig {
+        workspace_root: "/workspace".to_string(),
+        ..Default::default()
+    });
+    c.bench_function("generate", |b| {
+        b.iter(|| generator.generate(black_box(&graph)));
+    });
+}
+
+criterion_group!(benches, bench_generate);
+criterion_main!(benches);
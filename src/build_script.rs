@@ -31,7 +31,8 @@ pub struct BuildScriptOutput {
     pub rustc_link_libs: Vec<String>,
 
     /// Library search paths from `cargo:rustc-link-search=...`.
-    /// Format: `[KIND=]PATH` where KIND is `native`, `framework`, `all` (default).
+    /// Format: `[KIND=]PATH` where KIND is `native`, `framework`, `dependency`,
+    /// `crate`, or `all` (default); see [`Self::link_search_arg`].
     pub rustc_link_searches: Vec<String>,
 
     /// Environment variables from `cargo:rustc-env=...`.
@@ -79,6 +80,22 @@ impl BuildScriptOutput {
         Self::parse_lines(contents)
     }
 
+    /// Search path kinds rustc accepts as `-L <kind>=<path>`.
+    const LINK_SEARCH_KINDS: [&'static str; 5] =
+        ["native", "framework", "dependency", "crate", "all"];
+
+    /// Returns the `-L` value for a `rustc-link-search` entry: `kind=path` or a
+    /// bare path as given. A bare path containing `=` gets an explicit `all=`
+    /// (the default kind), so rustc doesn't read its prefix as a kind.
+    pub fn link_search_arg(search: &str) -> String {
+        match search.split_once('=') {
+            Some((kind, _)) if !Self::LINK_SEARCH_KINDS.contains(&kind) => {
+                format!("all={search}")
+            }
+            _ => search.to_string(),
+        }
+    }
+
     /// Parses the `rustc-env` file contents.
     ///
     /// Each line is `KEY=VALUE`. Lines without `=` are skipped.
@@ -149,7 +166,7 @@ impl BuildScriptOutput {
         // Add -L flags for link search paths
         for search in &self.rustc_link_searches {
             args.push("-L".to_string());
-            args.push(search.clone());
+            args.push(Self::link_search_arg(search));
         }
 
         // Add cdylib link args with -C link-arg
@@ -197,7 +214,18 @@ impl BuildScriptOutput {
 
         Self::append_flag_reader_snippet(&mut script, var, "rustc-cfg", "--cfg $line");
         Self::append_flag_reader_snippet(&mut script, var, "rustc-link-lib", "-l $line");
-        Self::append_flag_reader_snippet(&mut script, var, "rustc-link-search", "-L $line");
+        // Pass entries through like `link_search_arg`, marking bare paths with `=` as `all=`
+        script.push_str("if [ -f ");
+        script.push_str(var);
+        script.push_str("/rustc-link-search ]; then\n  while IFS= read -r line; do\n    case \"$line\" in\n      \"\") ;;\n      ");
+        script.push_str(
+            &Self::LINK_SEARCH_KINDS
+                .map(|kind| format!("{kind}=*"))
+                .join("|"),
+        );
+        script.push_str(") BUILD_SCRIPT_FLAGS=\"$BUILD_SCRIPT_FLAGS -L $line\" ;;\n      *=*) BUILD_SCRIPT_FLAGS=\"$BUILD_SCRIPT_FLAGS -L all=$line\" ;;\n      *) BUILD_SCRIPT_FLAGS=\"$BUILD_SCRIPT_FLAGS -L $line\" ;;\n    esac\n  done < ");
+        script.push_str(var);
+        script.push_str("/rustc-link-search\nfi\n");
         Self::append_flag_reader_snippet(
             &mut script,
             var,
@@ -205,6 +233,13 @@ impl BuildScriptOutput {
             "-C link-arg=$line",
         );
 
+        // Export `cargo:rustc-env` variables for env!() in the crate
+        script.push_str("# Export build script rustc-env variables\nif [ -f ");
+        script.push_str(var);
+        script.push_str("/rustc-env ]; then\n  while IFS= read -r line; do\n    [ -n \"$line\" ] && export \"$line\"\n  done < ");
+        script.push_str(var);
+        script.push_str("/rustc-env\nfi\n");
+
         // Export OUT_DIR for generated files
         script.push_str("# Set OUT_DIR for generated code\nexport OUT_DIR=");
         script.push_str(var);
@@ -264,16 +299,54 @@ pub struct BuildScriptInfo {
 
     /// Whether to use content-addressed derivations.
     pub content_addressed: bool,
+
+    /// `outputHashAlgo` of the content-addressed derivations.
+    pub ca_hash_algo: String,
+
+    /// Extra Nix expressions appended to `nativeBuildInputs` (e.g. `pkgs.protobuf`).
+    pub extra_native_build_inputs: Vec<String>,
+
+    /// Command used to invoke rustc (e.g. `sccache rustc`).
+    pub rustc_command: String,
+
+    /// Nix expression for the stdenv whose C compiler the script may invoke.
+    pub stdenv_expr: String,
+
+    /// Nixpkgs hardening flags to disable (e.g. `fortify`), for C code built by the script.
+    pub hardening_disable: Vec<String>,
+
+    /// Variables from the cargo config `[env]` table, exported when compiling and running.
+    pub cargo_env: Vec<crate::cargo_config::CargoEnvVar>,
+
+    /// Extra variables set to the output directory, like `OUT_DIR`.
+    pub out_dir_env: Vec<String>,
+
+    /// Whether the run derivation escapes the sandbox (`__noChroot`), for build
+    /// scripts that need network access.
+    pub no_chroot: bool,
+
+    /// Whether the build script runs impurely: outside the sandbox and with
+    /// `NIX_ENFORCE_PURITY=0`, for build scripts that probe the host system.
+    pub impure: bool,
+
+    /// Triple exported as `TARGET` when cross-compiling. `None` derives it
+    /// (like `HOST`) from the builder's `$system`.
+    pub target_triple: Option<String>,
+
+    /// Whether to render with [`crate::nix_gen::NixAttrSet::render_compact`].
+    pub compact: bool,
 }
 
 impl BuildScriptInfo {
     /// Extracts build script information from a unit.
     ///
-    /// Returns `None` if the unit is not a build script.
+    /// Returns `None` if the unit is not a build script. Paths are remapped
+    /// like [`crate::nix_gen::UnitDerivation::from_unit`]'s.
     #[must_use]
     pub fn from_unit(
         unit: &crate::unit_graph::Unit,
         workspace_root: &str,
+        extra_sources: &[(String, String)],
         content_addressed: bool,
     ) -> Option<Self> {
         if !unit.is_build_script() {
@@ -285,12 +358,21 @@ impl BuildScriptInfo {
         let target_name = unit.target.name.clone();
 
         // Remap source path
-        let src_path =
-            crate::source_filter::remap_source_path(&unit.target.src_path, workspace_root, "src");
+        let src_path = crate::source_filter::remap_source_path(
+            &unit.target.src_path,
+            workspace_root,
+            "src",
+            extra_sources,
+        );
 
         // Remap manifest directory (CARGO_MANIFEST_DIR)
-        let manifest_dir =
-            crate::source_filter::remap_manifest_dir(unit, workspace_root, "src", "vendorDir");
+        let manifest_dir = crate::source_filter::remap_manifest_dir(
+            unit,
+            workspace_root,
+            "src",
+            "vendorDir",
+            extra_sources,
+        );
 
         // Generate unique derivation names
         let base_hash = unit.identity_hash();
@@ -298,6 +380,8 @@ impl BuildScriptInfo {
         let run_drv_name = format!("{package_name}-build-script-run-{version}-{base_hash}");
 
         let rustc_flags = crate::rustc_flags::RustcFlags::from_unit(unit);
+        let mut features = unit.features.clone();
+        features.sort_unstable();
 
         Some(Self {
             package_name,
@@ -308,11 +392,32 @@ impl BuildScriptInfo {
             compile_drv_name,
             run_drv_name,
             rustc_flags,
-            features: unit.features.clone(),
+            features,
             content_addressed,
+            ca_hash_algo: "sha256".to_string(),
+            extra_native_build_inputs: Vec::new(),
+            rustc_command: "rustc".to_string(),
+            stdenv_expr: "pkgs.stdenv".to_string(),
+            hardening_disable: Vec::new(),
+            cargo_env: Vec::new(),
+            out_dir_env: Vec::new(),
+            no_chroot: false,
+            impure: false,
+            target_triple: None,
+            compact: false,
         })
     }
 
+    /// Renames the compile and run derivations to use the given identity hash.
+    pub fn set_identity_hash(&mut self, hash: &str) {
+        self.compile_drv_name =
+            format!("{}-build-script-{}-{hash}", self.package_name, self.version);
+        self.run_drv_name = format!(
+            "{}-build-script-run-{}-{hash}",
+            self.package_name, self.version
+        );
+    }
+
     /// Generates the Nix derivation for compiling the build script.
     ///
     /// This produces a binary that can be executed.
@@ -328,7 +433,7 @@ impl BuildScriptInfo {
         );
 
         if self.content_addressed {
-            attrs.add_ca_attrs();
+            attrs.add_ca_attrs(&self.ca_hash_algo);
         }
 
         let build_phase = self.generate_compile_phase();
@@ -343,7 +448,11 @@ impl BuildScriptInfo {
 }"#,
         );
 
-        attrs.render(2)
+        if self.compact {
+            attrs.render_compact()
+        } else {
+            attrs.render(2)
+        }
     }
 
     /// Generates the build phase for compiling the build script.
@@ -358,9 +467,12 @@ impl BuildScriptInfo {
             &self.package_name,
             &self.version,
             &self.features,
+            &self.cargo_env,
         ));
 
-        script.push_str("\nrustc \\\n");
+        script.push('\n');
+        script.push_str(&self.rustc_command);
+        script.push_str(" \\\n");
 
         for arg in self.rustc_flags.args() {
             script.push_str("  ");
@@ -411,15 +523,33 @@ impl BuildScriptInfo {
         build_inputs.extend(dep_build_script_outputs.iter().cloned());
         attrs.expr("buildInputs", &format!("[ {} ]", build_inputs.join(" ")));
 
-        // Include rustToolchain for build scripts that query rustc (e.g., rustversion)
+        // Include rustToolchain for build scripts that query rustc (e.g., rustversion),
+        // the stdenv C compiler for cc-based -sys crates,
         // and extraNativeBuildInputs for tools like protoc that run during build script execution
+        let mut native_inputs = vec![
+            "rustToolchain".to_string(),
+            format!("{}.cc", self.stdenv_expr),
+        ];
+        native_inputs.extend(self.extra_native_build_inputs.iter().cloned());
         attrs.expr(
             "nativeBuildInputs",
-            "[ rustToolchain ] ++ extraNativeBuildInputs",
+            &format!("[ {} ] ++ extraNativeBuildInputs", native_inputs.join(" ")),
         );
 
+        if !self.hardening_disable.is_empty() {
+            attrs.string_list("hardeningDisable", &self.hardening_disable);
+        }
+
+        // Only honored by Nix with `sandbox = relaxed`; strict sandboxes refuse to build it
+        if self.no_chroot || self.impure {
+            attrs.bool("__noChroot", true);
+        }
+        if self.impure {
+            attrs.string("NIX_ENFORCE_PURITY", "0");
+        }
+
         if self.content_addressed {
-            attrs.add_ca_attrs();
+            attrs.add_ca_attrs(&self.ca_hash_algo);
         }
 
         // Wrap compile_drv_var in ${...} for shell interpolation
@@ -429,7 +559,11 @@ impl BuildScriptInfo {
         attrs.multiline_interpolated("buildPhase", &build_phase);
         attrs.multiline("installPhase", "[ -d \"$out\" ] || mkdir -p $out");
 
-        attrs.render(2)
+        if self.compact {
+            attrs.render_compact()
+        } else {
+            attrs.render(2)
+        }
     }
 
     /// Generates the build phase for running the build script.
@@ -440,6 +574,13 @@ impl BuildScriptInfo {
     ) -> String {
         let mut script = String::new();
 
+        if self.impure {
+            script.push_str(
+                "# WARNING: impure build script (--impure-build-script): runs outside the\n\
+                # sandbox with NIX_ENFORCE_PURITY=0, so its output may depend on the builder.\n\n",
+            );
+        }
+
         // CA derivation check MUST be first - before any writes to $out
         // If $out already exists and is read-only, it means we're reusing a
         // previous build's output (verified by content hash). Exit early.
@@ -456,6 +597,9 @@ impl BuildScriptInfo {
 
         // Set up environment variables that build scripts expect
         script.push_str("export OUT_DIR=$out/out-dir\n");
+        for var in &self.out_dir_env {
+            script.push_str(&format!("export {var}=$out/out-dir\n"));
+        }
 
         // CARGO_MANIFEST_DIR is the directory containing Cargo.toml for this crate
         // This is pre-computed with proper remapping for workspace vs vendored crates
@@ -509,11 +653,20 @@ fi
             &self.package_name,
             &self.version,
             &self.features,
+            &self.cargo_env,
         ));
 
         // Rust compiler and target info
         // Map Nix system names to Rust target triples
         script.push_str("export RUSTC=\"$(type -p rustc)\"\n");
+
+        // C toolchain for cc-based build scripts, taken from the stdenv cc-wrapper
+        // (which also applies NIX_CFLAGS_COMPILE when the compiler is invoked)
+        script.push_str(
+            "export CC=\"''${CC:-cc}\"\n\
+            export AR=\"''${AR:-ar}\"\n\
+            export CFLAGS=\"''${CFLAGS:-}\"\n",
+        );
         script.push_str(
             r#"case "$system" in
   aarch64-darwin)
@@ -578,6 +731,11 @@ export CARGO_CFG_TARGET_POINTER_WIDTH CARGO_CFG_TARGET_ENDIAN
 export CARGO_CFG_UNIX
 "#,
         );
+        if let Some(ref target) = self.target_triple {
+            script.push_str("export TARGET=\"");
+            script.push_str(target);
+            script.push_str("\"\n");
+        }
         script.push_str("export PROFILE=\"release\"\n");
         // Add DEBUG and OPT_LEVEL for build scripts that check optimization settings
         script.push_str("export DEBUG=\"false\"\n");
@@ -585,7 +743,10 @@ export CARGO_CFG_UNIX
 
         // Run the build script and capture output
         // The binary name matches the target name (typically "build-script-build")
-        // Use a temporary file to avoid pipefail issues with failing build scripts
+        // stdout+stderr are tee'd into $out/build-script.log (kept for debugging)
+        // and a temp file the directive parser reads. errexit is off around the
+        // pipeline and the script's own status is taken from PIPESTATUS[0], since
+        // the pipeline's status is tee's
         // NOTE: We cd to CARGO_MANIFEST_DIR because some build scripts read Cargo.toml
         // from the current directory rather than from CARGO_MANIFEST_DIR env var
         script.push_str(&format!(
@@ -593,8 +754,8 @@ export CARGO_CFG_UNIX
             cd \"$CARGO_MANIFEST_DIR\"\n\
             BUILD_SCRIPT_OUTPUT=$(mktemp)\n\
             set +e\n\
-            {}/bin/{} > \"$BUILD_SCRIPT_OUTPUT\" 2>&1\n\
-            BUILD_SCRIPT_EXIT=$?\n\
+            {}/bin/{} 2>&1 | tee \"$out/build-script.log\" > \"$BUILD_SCRIPT_OUTPUT\"\n\
+            BUILD_SCRIPT_EXIT=''${{PIPESTATUS[0]}}\n\
             set -e\n\n\
             # Parse cargo directives from output\n\
             while IFS= read -r line; do\n",
@@ -722,7 +883,7 @@ mod tests {
         assert!(is_build_script_run(unit));
         assert!(is_build_script_compile(unit));
 
-        let info = BuildScriptInfo::from_unit(unit, "/workspace", false);
+        let info = BuildScriptInfo::from_unit(unit, "/workspace", &[], false);
         assert!(info.is_some());
 
         let info = info.unwrap();
@@ -760,7 +921,7 @@ mod tests {
         let unit = &graph.units[0];
 
         assert!(!is_build_script_unit(unit));
-        let info = BuildScriptInfo::from_unit(unit, "/workspace", false);
+        let info = BuildScriptInfo::from_unit(unit, "/workspace", &[], false);
         assert!(info.is_none());
     }
 
@@ -789,7 +950,7 @@ mod tests {
 
         let graph = parse_test_unit_graph(json);
         let unit = &graph.units[0];
-        let info = BuildScriptInfo::from_unit(unit, "/workspace", false).unwrap();
+        let mut info = BuildScriptInfo::from_unit(unit, "/workspace", &[], false).unwrap();
 
         let nix = info.compile_derivation();
 
@@ -800,6 +961,10 @@ mod tests {
         assert!(nix.contains("-o build/build-script"));
         // Install phase copies to $out
         assert!(nix.contains("cp build/build-script $out/bin/"));
+
+        // A configured wrapper replaces the bare rustc invocation
+        info.rustc_command = "sccache rustc".to_string();
+        assert!(info.compile_derivation().contains("sccache rustc \\\n"));
     }
 
     #[test]
@@ -827,7 +992,7 @@ mod tests {
 
         let graph = parse_test_unit_graph(json);
         let unit = &graph.units[0];
-        let info = BuildScriptInfo::from_unit(unit, "/workspace", false).unwrap();
+        let info = BuildScriptInfo::from_unit(unit, "/workspace", &[], false).unwrap();
 
         let nix = info.run_derivation("buildScript", &[]);
 
@@ -837,6 +1002,86 @@ mod tests {
         assert!(nix.contains("CARGO_FEATURE_SERDE"));
         assert!(nix.contains("cargo:rustc-cfg"));
         assert!(nix.contains("cargo:rustc-link-lib"));
+        // C compiler available for cc-based build scripts
+        assert!(nix.contains("nativeBuildInputs = [ rustToolchain pkgs.stdenv.cc ]"));
+        assert!(nix.contains("export CC="));
+        assert!(nix.contains("export AR="));
+        // Raw output is kept for debugging, and the exit code is the script's
+        assert!(nix.contains("2>&1 | tee \"$out/build-script.log\""));
+        assert!(nix.contains("BUILD_SCRIPT_EXIT=''${PIPESTATUS[0]}"));
+    }
+
+    #[test]
+    fn test_run_derivation_extra_native_build_inputs() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "prost-build-user 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let mut info =
+            BuildScriptInfo::from_unit(&graph.units[0], "/workspace", &[], false).unwrap();
+        info.extra_native_build_inputs = vec!["pkgs.protobuf".to_string()];
+
+        let nix = info.run_derivation("buildScript", &[]);
+        assert!(nix.contains(
+            "nativeBuildInputs = [ rustToolchain pkgs.stdenv.cc pkgs.protobuf ] ++ extraNativeBuildInputs"
+        ));
+    }
+
+    #[test]
+    fn test_run_derivation_cross_target() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "cc-user 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let mut info =
+            BuildScriptInfo::from_unit(&graph.units[0], "/workspace", &[], false).unwrap();
+        let native = info.run_derivation("buildScript", &[]);
+        assert!(!native.contains("export TARGET=\""));
+
+        // HOST stays the builder's system, TARGET is overridden afterwards
+        info.target_triple = Some("aarch64-unknown-linux-gnu".to_string());
+        let nix = info.run_derivation("buildScript", &[]);
+        let host = nix.find("export TARGET HOST=\"$TARGET\"").unwrap();
+        let target = nix
+            .find("export TARGET=\"aarch64-unknown-linux-gnu\"")
+            .unwrap();
+        assert!(host < target);
     }
 
     #[test]
@@ -864,7 +1109,7 @@ mod tests {
 
         let graph = parse_test_unit_graph(json);
         let unit = &graph.units[0];
-        let info = BuildScriptInfo::from_unit(unit, "/workspace", true).unwrap();
+        let info = BuildScriptInfo::from_unit(unit, "/workspace", &[], true).unwrap();
 
         let compile_nix = info.compile_derivation();
         assert!(compile_nix.contains("__contentAddressed = true"));
@@ -1016,6 +1261,66 @@ mod tests {
         assert!(script.contains("BUILD_SCRIPT_FLAGS"));
     }
 
+    #[test]
+    fn test_nix_flag_reader_exports_rustc_env() {
+        let dir = std::env::temp_dir().join(format!("nix-cargo-unit-env-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("rustc-env"), "GIT_HASH=abc\nBUILT_WITH=a=b\n").unwrap();
+
+        let script = BuildScriptOutput::generate_nix_flag_reader(dir.to_str().unwrap());
+        let output = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(format!("{script}\necho \"$GIT_HASH $BUILT_WITH\""))
+            .output()
+            .expect("failed to run bash");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "abc a=b");
+    }
+
+    #[test]
+    fn test_link_search_kinds() {
+        assert_eq!(BuildScriptOutput::link_search_arg("/usr/lib"), "/usr/lib");
+        assert_eq!(
+            BuildScriptOutput::link_search_arg("framework=/foo"),
+            "framework=/foo"
+        );
+        assert_eq!(
+            BuildScriptOutput::link_search_arg("dependency=/deps"),
+            "dependency=/deps"
+        );
+        // Not a kind rustc knows, so the whole entry is the path
+        assert_eq!(
+            BuildScriptOutput::link_search_arg("/opt/a=b"),
+            "all=/opt/a=b"
+        );
+
+        // The consuming crate's build phase passes the kind through to rustc
+        let dir =
+            std::env::temp_dir().join(format!("nix-cargo-unit-search-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("rustc-link-search"),
+            "framework=/foo\n/usr/lib\n/opt/a=b\n\n",
+        )
+        .unwrap();
+
+        let script = BuildScriptOutput::generate_nix_flag_reader(dir.to_str().unwrap());
+        let output = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(format!("{script}\necho \"$BUILD_SCRIPT_FLAGS\""))
+            .output()
+            .expect("failed to run bash");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            "-L framework=/foo -L /usr/lib -L all=/opt/a=b"
+        );
+    }
+
     #[test]
     fn test_generate_nix_expr_reader() {
         let expr = BuildScriptOutput::generate_nix_expr_reader("buildScriptOutput");
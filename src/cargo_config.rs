@@ -0,0 +1,112 @@
+//! Parsing of cargo configuration (`.cargo/config.toml`) settings that affect builds.
+
+/// A variable from the `[env]` table of a cargo config file.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct CargoEnvVar {
+    /// Variable name.
+    pub name: String,
+
+    /// Value to export. For `relative` entries this is an absolute path.
+    pub value: String,
+
+    /// Whether the value overrides a variable already set in the environment.
+    #[serde(default)]
+    pub force: bool,
+
+    /// Whether the value is a path, remapped into the Nix source tree.
+    #[serde(default)]
+    pub relative: bool,
+}
+
+/// Extracts the `[env]` table from the contents of a cargo config file.
+///
+/// Entries are either plain strings (`KEY = "value"`) or tables with `value`,
+/// `force`, and `relative` keys. Relative values are resolved against
+/// `config_root`, the directory containing `.cargo/`, as cargo does. Entries
+/// are returned sorted by name.
+pub fn parse_env(
+    contents: &str,
+    config_root: &std::path::Path,
+) -> Result<Vec<CargoEnvVar>, String> {
+    let config: toml::Table = toml::from_str(contents).map_err(|e| e.to_string())?;
+    let Some(env) = config.get("env") else {
+        return Ok(Vec::new());
+    };
+    let env = env
+        .as_table()
+        .ok_or_else(|| "`env` must be a table".to_string())?;
+
+    let mut vars = Vec::with_capacity(env.len());
+    for (name, entry) in env {
+        let mut var = match entry {
+            toml::Value::String(value) => CargoEnvVar {
+                name: name.clone(),
+                value: value.clone(),
+                force: false,
+                relative: false,
+            },
+            toml::Value::Table(table) => {
+                let value = table
+                    .get("value")
+                    .and_then(toml::Value::as_str)
+                    .ok_or_else(|| format!("env.{name}: missing string `value`"))?;
+                let flag = |key: &str| -> Result<bool, String> {
+                    match table.get(key) {
+                        None => Ok(false),
+                        Some(v) => v
+                            .as_bool()
+                            .ok_or_else(|| format!("env.{name}.{key}: expected a boolean")),
+                    }
+                };
+                CargoEnvVar {
+                    name: name.clone(),
+                    value: value.to_string(),
+                    force: flag("force")?,
+                    relative: flag("relative")?,
+                }
+            }
+            _ => return Err(format!("env.{name}: expected a string or table")),
+        };
+        if var.relative {
+            var.value = config_root.join(&var.value).to_string_lossy().into_owned();
+        }
+        vars.push(var);
+    }
+    vars.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_env() {
+        let contents = r#"
+            [build]
+            jobs = 4
+
+            [env]
+            MY_VAR = "x"
+            ASSETS = { value = "assets", relative = true }
+            OPENSSL_STATIC = { value = "1", force = true }
+        "#;
+        let vars = parse_env(contents, std::path::Path::new("/ws")).unwrap();
+        let names: Vec<&str> = vars.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["ASSETS", "MY_VAR", "OPENSSL_STATIC"]);
+
+        assert_eq!(vars[0].value, "/ws/assets");
+        assert!(vars[0].relative && !vars[0].force);
+        assert_eq!(vars[1].value, "x");
+        assert!(!vars[1].relative && !vars[1].force);
+        assert!(vars[2].force);
+    }
+
+    #[test]
+    fn test_parse_env_errors() {
+        let root = std::path::Path::new("/ws");
+        assert!(parse_env("[build]\njobs = 1\n", root).unwrap().is_empty());
+        assert!(parse_env("[env]\nX = 1\n", root).is_err());
+        assert!(parse_env("[env]\nX = { force = true }\n", root).is_err());
+    }
+}
@@ -0,0 +1,140 @@
+//! Feature unification report.
+//!
+//! Cargo resolves features per unit, and a package that ends up with
+//! different feature sets in one build produces one derivation per set.
+//! This module summarizes which features each unit resolved and flags
+//! packages that appear with more than one feature set, which is the usual
+//! reason for unexpected cache misses.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::unit_graph::UnitGraph;
+
+/// Features resolved by each unit, plus packages built with multiple feature sets.
+///
+/// Uses ordered maps so the JSON output is deterministic.
+#[derive(Debug, Default, serde::Serialize)]
+pub struct FeatureReport {
+    /// Derivation name → sorted features.
+    pub units: BTreeMap<String, Vec<String>>,
+
+    /// Package ID → every distinct sorted feature set it was built with.
+    /// Only packages with more than one feature set are listed.
+    pub conflicts: BTreeMap<String, Vec<Vec<String>>>,
+}
+
+impl FeatureReport {
+    /// Builds a report from a unit graph.
+    ///
+    /// `derivation_names` holds each unit's derivation name in graph order, as
+    /// returned by `NixGenerator::derivation_names`.
+    #[must_use]
+    pub fn from_graph(graph: &UnitGraph, derivation_names: &[String]) -> Self {
+        let mut report = Self::default();
+        let mut feature_sets: BTreeMap<&str, BTreeSet<Vec<String>>> = BTreeMap::new();
+
+        for (unit, name) in graph.units.iter().zip(derivation_names) {
+            let mut features = unit.features.clone();
+            features.sort();
+
+            feature_sets
+                .entry(&unit.pkg_id)
+                .or_default()
+                .insert(features.clone());
+            report.units.insert(name.clone(), features);
+        }
+
+        for (pkg_id, sets) in feature_sets {
+            if sets.len() > 1 {
+                report
+                    .conflicts
+                    .insert(pkg_id.to_string(), sets.into_iter().collect());
+            }
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nix_gen::{NixGenConfig, NixGenerator};
+    use crate::unit_graph::parse_test_unit_graph;
+
+    #[test]
+    fn test_same_crate_with_different_features() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "serde 1.0.219 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "serde",
+                        "src_path": "/registry/serde-1.0.219/src/lib.rs",
+                        "edition": "2018"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": ["std", "derive"],
+                    "mode": "build",
+                    "dependencies": [],
+                    "platform": "x86_64-unknown-linux-gnu"
+                },
+                {
+                    "pkg_id": "serde 1.0.219 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "serde",
+                        "src_path": "/registry/serde-1.0.219/src/lib.rs",
+                        "edition": "2018"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": ["std"],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-app 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "my_app",
+                        "src_path": "/workspace/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "serde", "public": false}
+                    ]
+                }
+            ],
+            "roots": [2]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let names = NixGenerator::new(NixGenConfig::default()).derivation_names(&graph);
+        let report = FeatureReport::from_graph(&graph, &names);
+
+        // Units are keyed by the names the generated expression uses
+        assert_eq!(report.units.len(), 3);
+        assert_ne!(names[0], names[1]);
+        assert_eq!(report.units[&names[0]], vec!["derive", "std"]);
+        assert_eq!(report.units[&names[1]], vec!["std"]);
+        assert!(report.units[&names[2]].is_empty());
+
+        // Only serde is flagged
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(
+            report.conflicts[&graph.units[0].pkg_id],
+            vec![
+                vec!["derive".to_string(), "std".to_string()],
+                vec!["std".to_string()]
+            ]
+        );
+    }
+}
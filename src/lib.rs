@@ -4,7 +4,11 @@
 //! Nix derivations for each compilation unit, enabling fine-grained caching.
 
 pub mod build_script;
+pub mod cargo_config;
+pub mod feature_report;
+pub mod ninja_gen;
 pub mod nix_gen;
+pub mod plan;
 pub mod proc_macro;
 pub mod rustc_flags;
 pub mod shell;
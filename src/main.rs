@@ -1,24 +1,40 @@
-use std::io::Read as _;
+use std::io::{Read as _, Write as _};
 
-use nix_cargo_unit::nix_gen::{NixGenConfig, NixGenerator};
+use nix_cargo_unit::cargo_config;
+use nix_cargo_unit::feature_report::FeatureReport;
+use nix_cargo_unit::ninja_gen;
+use nix_cargo_unit::nix_gen::{self, NixGenConfig, NixGenerator};
+use nix_cargo_unit::plan;
+use nix_cargo_unit::source_filter::{self, SourceLocation};
 use nix_cargo_unit::unit_graph;
 
 #[derive(clap::Parser)]
 #[command(name = "nix-cargo-unit")]
 #[command(about = "Convert cargo unit-graph to Nix derivations")]
 struct Cli {
-    /// Output format: nix or json
-    #[arg(short, long, default_value = "nix")]
-    format: String,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    /// Workspace root path for source remapping
-    #[arg(short, long, default_value = ".")]
-    workspace_root: String,
+    /// TOML config file with generator options (CLI flags take precedence)
+    #[arg(long, value_name = "PATH", global = true)]
+    config: Option<std::path::PathBuf>,
+
+    /// Output format: nix, ninja, json, json-plan, or features [default: nix]
+    #[arg(short, long)]
+    format: Option<String>,
+
+    /// Workspace root path for source remapping [default: .]
+    #[arg(short, long, global = true)]
+    workspace_root: Option<String>,
 
     /// Enable content-addressed derivations (CA-derivations)
     #[arg(long)]
     content_addressed: bool,
 
+    /// outputHashAlgo of content-addressed derivations [default: sha256]
+    #[arg(long, value_name = "ALGO", value_parser = ["sha1", "sha256", "sha512"])]
+    ca_hash_algo: Option<String>,
+
     /// Enable cross-compilation mode (use hostRustToolchain for proc-macros)
     #[arg(long)]
     cross_compile: bool,
@@ -34,6 +50,272 @@ struct Cli {
     /// Toolchain hash to include in identity computation (prevents stale CA outputs when rustc changes)
     #[arg(long)]
     toolchain_hash: Option<String>,
+
+    /// Export RUSTC_BOOTSTRAP=1 for all units (enables nightly features on stable)
+    #[arg(long)]
+    rustc_bootstrap: bool,
+
+    /// Export RUSTC_BOOTSTRAP=1 only for the named package (repeatable)
+    #[arg(long = "rustc-bootstrap-package", value_name = "PACKAGE")]
+    rustc_bootstrap_packages: Vec<String>,
+
+    /// Additional source root for path dependencies outside the workspace,
+    /// as NIX_VAR=PATH (repeatable; NIX_VAR becomes an argument of the output)
+    #[arg(long = "extra-source", value_name = "NIX_VAR=PATH", value_parser = parse_key_value, global = true)]
+    extra_sources: Vec<(String, String)>,
+
+    /// Extra nativeBuildInputs Nix expression for one package, as PACKAGE=EXPR
+    /// (repeatable, e.g. `--native-build-input my-proto=pkgs.protobuf`)
+    #[arg(long = "native-build-input", value_name = "PACKAGE=EXPR", value_parser = parse_key_value)]
+    native_build_inputs: Vec<(String, String)>,
+
+    /// Extra variable pointing at one package's build script output directory
+    /// (like OUT_DIR), as PACKAGE=VAR (repeatable)
+    #[arg(long = "build-script-out-dir-env", value_name = "PACKAGE=VAR", value_parser = parse_key_value)]
+    build_script_out_dir_env: Vec<(String, String)>,
+
+    /// Package whose build script needs network access, which the sandbox
+    /// blocks; generation warns about it (repeatable)
+    #[arg(long = "network-build-script", value_name = "PACKAGE")]
+    network_build_scripts: Vec<String>,
+
+    /// Run --network-build-script packages' build scripts outside the sandbox
+    /// (`__noChroot`; needs `sandbox = relaxed`)
+    #[arg(long)]
+    allow_network_build_scripts: bool,
+
+    /// Package whose build script must run impurely: outside the sandbox and
+    /// with NIX_ENFORCE_PURITY=0. Last resort for build scripts that probe the
+    /// host; breaks reproducibility and needs `sandbox = relaxed` (repeatable)
+    #[arg(long = "impure-build-script", value_name = "PACKAGE")]
+    impure_build_scripts: Vec<String>,
+
+    /// Builder feature required by every compile derivation, e.g. `big-parallel`
+    /// (repeatable; per-package lists go in the config file)
+    #[arg(long = "required-system-feature", value_name = "FEATURE")]
+    required_system_features: Vec<String>,
+
+    /// Nixpkgs hardening flag to disable on every derivation, e.g. `fortify`
+    /// (repeatable; per-package lists go in the config file)
+    #[arg(long = "hardening-disable", value_name = "FLAG")]
+    hardening_disable: Vec<String>,
+
+    /// Cargo config file (e.g. `.cargo/config.toml`) whose `[env]` variables are
+    /// exported when compiling units and running build scripts
+    #[arg(long, value_name = "PATH")]
+    cargo_config: Option<std::path::PathBuf>,
+
+    /// Define `vendorDir` from fetchers for registry and git crates instead of
+    /// taking it as an argument (hashes are placeholders unless --lockfile is given)
+    #[arg(long)]
+    emit_fetchers: bool,
+
+    /// Cargo.lock to check the graph's registry and git packages against (failing
+    /// on stale graphs); also supplies registry checksums for --emit-fetchers
+    #[arg(long, value_name = "PATH")]
+    lockfile: Option<std::path::PathBuf>,
+
+    /// Command used to invoke rustc, e.g. `sccache rustc` [default: rustc]
+    #[arg(long)]
+    rustc_command: Option<String>,
+
+    /// Nix expression for the stdenv to build with, e.g. `pkgs.pkgsMusl.stdenv`
+    /// [default: pkgs.stdenv]
+    #[arg(long, value_name = "EXPR")]
+    stdenv: Option<String>,
+
+    /// Override `-C embed-bitcode` for every unit: `yes` or `no` (`no` is ignored
+    /// for units built under LTO) [default: yes under LTO, rustc's default otherwise]
+    #[arg(long, value_name = "yes|no", value_parser = ["yes", "no"])]
+    embed_bitcode: Option<String>,
+
+    /// Layout of the generated Nix: `pretty`, or `compact` for fewer lines and
+    /// smaller files [default: pretty]
+    #[arg(long, value_name = "LAYOUT", value_parser = ["pretty", "compact"])]
+    output_format: Option<String>,
+
+    /// Scratch directory rustc writes outputs to inside each derivation [default: build]
+    #[arg(long, value_name = "NAME")]
+    build_dir: Option<String>,
+
+    /// Pass a dependency to rustc under another extern crate name, as FROM=TO (repeatable)
+    #[arg(long = "extern-rename", value_name = "FROM=TO", value_parser = parse_key_value)]
+    extern_renames: Vec<(String, String)>,
+
+    /// Extra `--cfg` for all non-host units, e.g. `tokio_unstable` (repeatable)
+    #[arg(long = "cfg", value_name = "CFG")]
+    extra_cfgs: Vec<String>,
+
+    /// Extra codegen option for all non-host units, as KEY=VALUE, e.g.
+    /// `relro-level=full` (passed as `-C KEY=VALUE`; repeatable)
+    #[arg(long = "codegen", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    extra_codegen: Vec<(String, String)>,
+
+    /// Deny a lint (e.g. `warnings`) in local crates; dependencies are unaffected (repeatable)
+    #[arg(long = "deny", value_name = "LINT")]
+    deny_lints: Vec<String>,
+
+    /// Warn on a lint in local crates (repeatable; overrides --deny)
+    #[arg(long = "warn", value_name = "LINT")]
+    warn_lints: Vec<String>,
+
+    /// Allow a lint in local crates (repeatable; overrides --deny and --warn)
+    #[arg(long = "allow", value_name = "LINT")]
+    allow_lints: Vec<String>,
+
+    /// Do not allow lints that only newer toolchains know (for older pinned toolchains)
+    #[arg(long)]
+    no_compat_lint_allows: bool,
+
+    /// Linker for binaries, tests, and cdylibs (passed as `-C linker=`)
+    #[arg(long)]
+    linker: Option<String>,
+
+    /// Linker flavor for binaries, tests, and cdylibs (passed as `-C linker-flavor=`)
+    #[arg(long)]
+    linker_flavor: Option<String>,
+
+    /// Trace rustc invocations in build logs with `set -x`
+    #[arg(long)]
+    trace: bool,
+
+    /// Log proc-macro lookup diagnostics in build logs
+    #[arg(long)]
+    debug_procmacro: bool,
+
+    /// Instrument units for source-based coverage (`-C instrument-coverage`)
+    #[arg(long)]
+    coverage: bool,
+
+    /// Take `-C codegen-units` from `$NIX_BUILD_CORES` at build time; faster
+    /// builds, but outputs then depend on the builder and are not reproducible
+    #[arg(long)]
+    codegen_units_from_cores: bool,
+
+    /// Link binaries against the toolchain's std dylib (`-C prefer-dynamic`);
+    /// they then need libstd at runtime
+    #[arg(long)]
+    prefer_dynamic: bool,
+
+    /// Move debug symbols of binaries built with debuginfo into a separate
+    /// `debug` output (`separateDebugInfo`)
+    #[arg(long)]
+    separate_debug_info: bool,
+
+    /// Also provide a hash-free `lib<name>.rlib` symlink in library outputs
+    #[arg(long)]
+    emit_stable_symlinks: bool,
+
+    /// Build target units metadata-only (like `cargo check`)
+    #[arg(long)]
+    check_only: bool,
+
+    /// Bytes of SHA-256 kept in derivation name hashes (4-32; changing it invalidates all caches) [default: 8]
+    #[arg(long)]
+    hash_len_bytes: Option<usize>,
+
+    /// Fail generation when a source path cannot be remapped into the Nix sandbox
+    #[arg(long)]
+    strict_sandbox: bool,
+
+    /// Skip units that cannot be generated, and their dependents, instead of
+    /// failing; skipped units are listed in a trailing comment
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Omit the `# Generated by nix-cargo-unit` header comment
+    #[arg(long)]
+    no_default_header: bool,
+
+    /// Argument pattern for the generated function, e.g. `{ pkgs, rustToolchain, src, ... }`;
+    /// an empty string emits only the `let ... in { ... }` body
+    #[arg(long, value_name = "PATTERN")]
+    function_args: Option<String>,
+
+    /// Also write a JSON manifest of each derivation's identity hash inputs to PATH
+    #[arg(long, value_name = "PATH")]
+    emit_manifest: Option<std::path::PathBuf>,
+
+    /// Print each input of the named derivation's identity hash, and the hash,
+    /// instead of generating Nix
+    #[arg(long, value_name = "DERIVATION")]
+    print_identity: Option<String>,
+
+    /// Compare the derivation names of a previously generated output at PATH
+    /// (a file, or a --emit-per-unit-drv-files directory) with this run's,
+    /// listing added (`+`) and removed (`-`) derivations, instead of generating Nix
+    #[arg(long, value_name = "PATH")]
+    diff: Option<std::path::PathBuf>,
+
+    /// Add a `tests` attrset exposing every test harness unit, not just roots
+    #[arg(long)]
+    emit_tests_attrset: bool,
+
+    /// Add a `devShell` (mkShell) exporting the rustc flags of CRATE (package or
+    /// target name) as $RUSTC_ARGS and its dependency flags as $RUSTC_DEP_ARGS
+    #[arg(long, value_name = "CRATE")]
+    emit_devshell: Option<String>,
+
+    /// Write each derivation to DIR/<name>.nix, with a DIR/default.nix importing
+    /// them, instead of printing one expression
+    #[arg(long, value_name = "DIR")]
+    emit_per_unit_drv_files: Option<std::path::PathBuf>,
+
+    /// Print how long parsing and generation took to stderr
+    #[arg(long)]
+    timing: bool,
+
+    /// Warn when units in a root's closure disagree on debug-assertions, overflow-checks, or panic
+    #[arg(long)]
+    check_profile_consistency: bool,
+
+    /// Print a table of units (derivation, kind, source, dependency count) and
+    /// summary counts instead of generating output
+    #[arg(long)]
+    list_units: bool,
+
+    /// Validate the unit graph without emitting output (exits nonzero on problems)
+    #[arg(long)]
+    check: bool,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Print how each unit's pkg_id and source path are parsed and remapped
+    Inspect,
+}
+
+/// Contents of a `--config` file: generator options plus CLI-only settings.
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+struct FileConfig {
+    /// Output format (same values as `--format`).
+    format: Option<String>,
+
+    #[serde(flatten)]
+    generator: NixGenConfig,
+}
+
+impl FileConfig {
+    /// Loads a config file, or returns defaults when no path is given.
+    fn load(path: Option<&std::path::Path>) -> color_eyre::Result<Self> {
+        let mut config: Self = match path {
+            Some(path) => toml::from_str(&std::fs::read_to_string(path)?)?,
+            None => Self::default(),
+        };
+        if config.generator.workspace_root.is_empty() {
+            config.generator.workspace_root = ".".to_string();
+        }
+        Ok(config)
+    }
+}
+
+/// Parses a `KEY=VALUE` argument, splitting on the first `=`.
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{s}`"))?;
+    Ok((key.to_string(), value.to_string()))
 }
 
 fn main() -> color_eyre::Result<()> {
@@ -45,35 +327,421 @@ fn main() -> color_eyre::Result<()> {
     let mut input = String::new();
     std::io::stdin().read_to_string(&mut input)?;
 
+    let parse_start = std::time::Instant::now();
     let graph: unit_graph::UnitGraph = serde_json::from_str(&input)?;
+    if cli.timing {
+        report_timing("parse", parse_start.elapsed());
+    }
 
-    match cli.format.as_str() {
-        "nix" => {
-            let mut config = NixGenConfig {
-                workspace_root: cli.workspace_root,
-                content_addressed: cli.content_addressed,
-                toolchain_hash: cli.toolchain_hash,
-                ..Default::default()
-            };
+    if cli.check_profile_consistency {
+        for warning in graph.profile_consistency_warnings() {
+            eprintln!("warning: {warning}");
+        }
+    }
+
+    if cli.check {
+        return check_graph(&graph);
+    }
+
+    let file_config = FileConfig::load(cli.config.as_deref())?;
+
+    if let Some(Command::Inspect) = cli.command {
+        let mut config = file_config.generator;
+        if let Some(workspace_root) = cli.workspace_root {
+            config.workspace_root = workspace_root;
+        }
+        config.extra_sources.extend(cli.extra_sources);
+        inspect_graph(&graph, &config);
+        return Ok(());
+    }
+
+    let format = cli
+        .format
+        .or(file_config.format)
+        .unwrap_or_else(|| "nix".to_string());
+
+    let generate_start = std::time::Instant::now();
+    match format.as_str() {
+        "nix" | "json-plan" => {
+            // Start from the config file, then apply CLI overrides
+            let mut config = file_config.generator;
+            if let Some(workspace_root) = cli.workspace_root {
+                config.workspace_root = workspace_root;
+            }
+            config.content_addressed |= cli.content_addressed;
+            if let Some(algo) = cli.ca_hash_algo {
+                config.ca_hash_algo = algo;
+            }
+            config.rustc_bootstrap |= cli.rustc_bootstrap;
+            config.trace |= cli.trace;
+            config.debug_procmacro |= cli.debug_procmacro;
+            config.coverage |= cli.coverage;
+            config.codegen_units_from_cores |= cli.codegen_units_from_cores;
+            config.prefer_dynamic |= cli.prefer_dynamic;
+            config.separate_debug_info |= cli.separate_debug_info;
+            config.emit_stable_symlinks |= cli.emit_stable_symlinks;
+            config.emit_tests_attrset |= cli.emit_tests_attrset;
+            if cli.emit_devshell.is_some() {
+                config.devshell = cli.emit_devshell;
+            }
+            config.check_only |= cli.check_only;
+            config.strict_sandbox |= cli.strict_sandbox;
+            config.keep_going |= cli.keep_going;
+            if cli.no_default_header {
+                config.emit_header = false;
+            }
+            if cli.function_args.is_some() {
+                config.function_args = cli.function_args;
+            }
+            if cli.toolchain_hash.is_some() {
+                config.toolchain_hash = cli.toolchain_hash;
+            }
+            if cli.rustc_command.is_some() {
+                config.rustc_command = cli.rustc_command;
+            }
+            if cli.stdenv.is_some() {
+                config.stdenv_expr = cli.stdenv;
+            }
+            if let Some(build_dir) = cli.build_dir {
+                config.build_dir = build_dir;
+            }
+            if let Some(ref embed) = cli.embed_bitcode {
+                config.embed_bitcode = Some(embed == "yes");
+            }
+            if let Some(ref layout) = cli.output_format {
+                config.compact = layout == "compact";
+            }
+            if cli.linker.is_some() {
+                config.linker = cli.linker;
+            }
+            if cli.linker_flavor.is_some() {
+                config.linker_flavor = cli.linker_flavor;
+            }
+            if let Some(hash_len_bytes) = cli.hash_len_bytes {
+                config.hash_len_bytes = hash_len_bytes;
+            }
+            config.extra_sources.extend(cli.extra_sources);
+            config.extern_renames.extend(cli.extern_renames);
+            config.extra_cfgs.extend(cli.extra_cfgs);
+            if cli.no_compat_lint_allows {
+                config.allow_compat_lints = false;
+            }
+            // Later levels win, so specific allows can carve out of a blanket deny
+            for (flag, lints) in [
+                ("-D", cli.deny_lints),
+                ("-W", cli.warn_lints),
+                ("-A", cli.allow_lints),
+            ] {
+                for lint in lints {
+                    config.lint_levels.extend([flag.to_string(), lint]);
+                }
+            }
+            config.extra_codegen.extend(cli.extra_codegen);
+            config
+                .required_system_features
+                .extend(cli.required_system_features);
+            config.hardening_disable.extend(cli.hardening_disable);
+            config.emit_fetchers |= cli.emit_fetchers;
+            if let Some(ref path) = cli.lockfile {
+                let contents = std::fs::read_to_string(path)?;
+                let locked = source_filter::parse_lock_packages(&contents)
+                    .map_err(|e| color_eyre::eyre::eyre!("{}: {e}", path.display()))?;
+                let mismatches = source_filter::lockfile_mismatches(&graph, &locked);
+                for mismatch in &mismatches {
+                    eprintln!("error: {mismatch}");
+                }
+                if !mismatches.is_empty() {
+                    color_eyre::eyre::bail!(
+                        "unit graph does not match {} ({} package(s) missing); regenerate the unit graph",
+                        path.display(),
+                        mismatches.len()
+                    );
+                }
+                let checksums = source_filter::parse_lock_checksums(&contents)
+                    .map_err(|e| color_eyre::eyre::eyre!("{}: {e}", path.display()))?;
+                config.crate_checksums.extend(checksums);
+            }
+            if let Some(ref path) = cli.cargo_config {
+                // Relative values resolve against the directory containing `.cargo/`
+                let absolute = std::path::absolute(path)?;
+                let config_root = absolute
+                    .parent()
+                    .and_then(std::path::Path::parent)
+                    .unwrap_or(std::path::Path::new("/"));
+                let env = cargo_config::parse_env(&std::fs::read_to_string(path)?, config_root)
+                    .map_err(|e| color_eyre::eyre::eyre!("{}: {e}", path.display()))?;
+                config.cargo_env.extend(env);
+            }
+            for package in cli.rustc_bootstrap_packages {
+                config.rustc_bootstrap_packages.insert(package, true);
+            }
+            for (package, var) in cli.build_script_out_dir_env {
+                config
+                    .build_script_out_dir_env
+                    .entry(package)
+                    .or_default()
+                    .push(var);
+            }
+            config
+                .network_build_scripts
+                .extend(cli.network_build_scripts);
+            config.allow_network_build_scripts |= cli.allow_network_build_scripts;
+            config.impure_build_scripts.extend(cli.impure_build_scripts);
+            for (package, expr) in cli.native_build_inputs {
+                config
+                    .extra_native_build_inputs_per_package
+                    .entry(package)
+                    .or_default()
+                    .push(expr);
+            }
 
             // Configure cross-compilation if enabled
             if cli.cross_compile {
                 config.cross_compiling = true;
-                config.host_platform = cli.host_platform;
-                config.target_platform = cli.target_platform;
+            }
+            if config.cross_compiling {
+                if cli.host_platform.is_some() {
+                    config.host_platform = cli.host_platform;
+                }
+                if cli.target_platform.is_some() {
+                    config.target_platform = cli.target_platform;
+                }
+            }
+
+            config.validate().map_err(|e| color_eyre::eyre::eyre!(e))?;
+            for warning in config.network_build_script_warnings(&graph) {
+                eprintln!("warning: {warning}");
+            }
+            for warning in config.impure_build_script_warnings(&graph) {
+                eprintln!("warning: {warning}");
+            }
+            if let Some(ref name) = config.devshell
+                && NixGenerator::new(config.clone())
+                    .devshell_unit(&graph)
+                    .is_none()
+            {
+                color_eyre::eyre::bail!("no crate named {name} for --emit-devshell");
             }
 
-            let generator = NixGenerator::new(config);
-            let nix = generator.generate(&graph);
-            println!("{nix}");
+            if cli.list_units {
+                list_units(&graph, &NixGenerator::new(config));
+            } else if let Some(ref name) = cli.print_identity {
+                let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+                if !NixGenerator::new(config).write_identity(&graph, name, &mut stdout)? {
+                    color_eyre::eyre::bail!("no derivation named {name}");
+                }
+                stdout.flush()?;
+            } else if let Some(ref path) = cli.diff {
+                // A directory from --emit-per-unit-drv-files is read via its index
+                let old = if path.is_dir() {
+                    std::fs::read_to_string(path.join("default.nix"))?
+                } else {
+                    std::fs::read_to_string(path)?
+                };
+                if nix_gen::derivation_names_in(&old).is_empty() {
+                    color_eyre::eyre::bail!(
+                        "no derivations found in {}; is it nix-cargo-unit output?",
+                        path.display()
+                    );
+                }
+                let new = NixGenerator::new(config).generate(&graph);
+                let diff = nix_gen::diff_derivations(&old, &new);
+                for name in &diff.removed {
+                    println!("- {name}");
+                }
+                for name in &diff.added {
+                    println!("+ {name}");
+                }
+                println!(
+                    "{} added, {} removed, {} unchanged",
+                    diff.added.len(),
+                    diff.removed.len(),
+                    diff.unchanged.len()
+                );
+            } else if format == "json-plan" {
+                let plan = plan::to_plan(&graph, &config);
+                println!("{}", serde_json::to_string_pretty(&plan)?);
+            } else {
+                let generator = NixGenerator::new(config);
+                if let Some(ref path) = cli.emit_manifest {
+                    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+                    generator.write_manifest(&graph, &mut file)?;
+                    file.flush()?;
+                }
+                if let Some(ref dir) = cli.emit_per_unit_drv_files {
+                    generator.write_dir(&graph, dir)?;
+                } else {
+                    let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+                    generator.write_to(&graph, &mut stdout)?;
+                    stdout.flush()?;
+                }
+            }
+        }
+        "ninja" => {
+            print!("{}", ninja_gen::generate_ninja(&graph));
         }
         "json" => {
             println!("{}", serde_json::to_string_pretty(&graph)?);
         }
+        "features" => {
+            let mut config = file_config.generator;
+            if let Some(workspace_root) = cli.workspace_root {
+                config.workspace_root = workspace_root;
+            }
+            let names = NixGenerator::new(config).derivation_names(&graph);
+            let report = FeatureReport::from_graph(&graph, &names);
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
         other => {
             color_eyre::eyre::bail!("unknown format: {other}");
         }
     }
+    if cli.timing {
+        report_timing("generate", generate_start.elapsed());
+    }
+
+    Ok(())
+}
+
+/// Prints each unit's parsed source location and remapped paths.
+fn inspect_graph(graph: &unit_graph::UnitGraph, config: &NixGenConfig) {
+    for (i, unit) in graph.units.iter().enumerate() {
+        println!("unit {i}: {}", unit.pkg_id);
+        match SourceLocation::from_unit(unit) {
+            Some(loc) => {
+                println!("  name: {}", loc.name);
+                println!("  version: {}", loc.version);
+                println!("  source: {}", loc.source);
+                println!("  crate_root: {}", loc.crate_root);
+                println!("  entry_point: {}", loc.entry_point);
+            }
+            None => println!("  source: unrecognized pkg_id or source type"),
+        }
+        let (src_path, manifest_dir) = config.remap_unit_paths(unit);
+        println!("  src_path: {} -> {src_path}", unit.target.src_path);
+        println!("  manifest_dir: {manifest_dir}");
+    }
+}
+
+/// Prints one row per unit and summary counts, for orienting in a graph.
+fn list_units(graph: &unit_graph::UnitGraph, generator: &NixGenerator) {
+    let names = generator.derivation_names(graph);
+    let rows: Vec<[String; 4]> = graph
+        .units
+        .iter()
+        .zip(&names)
+        .map(|(unit, name)| {
+            let kind = if unit.is_proc_macro() {
+                "proc-macro"
+            } else if unit.mode == "run-custom-build" {
+                "build-script-run"
+            } else if unit.is_build_script() {
+                "build-script"
+            } else if unit.is_doctest() {
+                "doctest"
+            } else if unit.is_test() {
+                "test"
+            } else if unit.is_bin() {
+                "bin"
+            } else if unit.is_lib() {
+                "lib"
+            } else {
+                "other"
+            };
+            let source = if unit.is_std {
+                "std"
+            } else {
+                match SourceLocation::from_unit(unit).map(|loc| loc.source) {
+                    Some(source_filter::SourceType::Path { .. }) => "path",
+                    Some(source_filter::SourceType::Registry { .. }) => "registry",
+                    Some(source_filter::SourceType::Git { .. }) => "git",
+                    None => "unknown",
+                }
+            };
+            [
+                name.clone(),
+                kind.to_string(),
+                source.to_string(),
+                unit.dependencies.len().to_string(),
+            ]
+        })
+        .collect();
+
+    let header = ["DERIVATION", "KIND", "SOURCE", "DEPS"];
+    let widths: Vec<usize> = (0..3)
+        .map(|col| {
+            rows.iter()
+                .map(|row| row[col].len())
+                .chain([header[col].len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    for row in std::iter::once(header.map(String::from)).chain(rows) {
+        println!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        );
+    }
+
+    let stats = graph.stats();
+    println!();
+    println!("units: {}", stats.units);
+    println!("roots: {}", stats.roots);
+    println!("packages: {}", stats.packages);
+    println!("proc-macros: {}", stats.proc_macros);
+    println!("build scripts: {}", stats.build_scripts);
+    println!("build script runs: {}", stats.build_script_runs);
+    println!("external: {}", stats.external);
+    println!("local: {}", stats.local);
+    println!("std: {}", stats.std);
+    println!("max depth: {}", stats.max_depth);
+}
+
+/// Prints the duration of one phase to stderr, in milliseconds.
+fn report_timing(phase: &str, elapsed: std::time::Duration) {
+    eprintln!("timing: {phase} {:.3}ms", elapsed.as_secs_f64() * 1000.0);
+}
+
+/// Runs all graph checks, printing each problem and a summary.
+fn check_graph(graph: &unit_graph::UnitGraph) -> color_eyre::Result<()> {
+    let mut problems = graph.validate();
+
+    for (i, unit) in graph.units.iter().enumerate() {
+        if SourceLocation::from_unit(unit).is_none() {
+            problems.push(format!(
+                "unit {i} ({}): unrecognized pkg_id or source type",
+                unit.pkg_id
+            ));
+        }
+    }
+
+    if let Some(cycle) = graph.find_cycle() {
+        let path: Vec<String> = cycle.iter().map(|i| i.to_string()).collect();
+        problems.push(format!("dependency cycle: {}", path.join(" -> ")));
+    }
+
+    for problem in &problems {
+        eprintln!("error: {problem}");
+    }
+    for warning in graph.panic_strategy_warnings() {
+        eprintln!("warning: {warning}");
+    }
+
+    if !problems.is_empty() {
+        color_eyre::eyre::bail!("unit graph check failed with {} problem(s)", problems.len());
+    }
 
+    println!(
+        "ok: {} units, {} roots",
+        graph.units.len(),
+        graph.roots.len()
+    );
     Ok(())
 }
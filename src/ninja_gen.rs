@@ -0,0 +1,492 @@
+//! Ninja build file export.
+//!
+//! Emits the same rustc invocations as the Nix backend as a `build.ninja`,
+//! for building outside Nix. Each unit gets its own output directory under
+//! `$builddir`, named like its derivation, and its outputs are the Ninja
+//! targets that dependents list as inputs.
+//!
+//! Build scripts are compiled and run, and dependents see their `OUT_DIR`,
+//! but `cargo:` directives in the script output (cfgs, link flags) are not
+//! applied.
+
+use std::fmt::Write as _;
+
+use rustc_hash::FxHashSet;
+
+use crate::nix_gen::{NixGenConfig, NixGenerator, canonical_indices};
+use crate::rustc_flags::RustcFlags;
+use crate::unit_graph::{Unit, UnitGraph};
+
+/// Default directory for all unit outputs, relative to the build file.
+const BUILD_DIR: &str = "target/ninja";
+
+/// Rule definitions shared by every unit.
+const RULES: &str = r#"rule rustc
+  command = env $env $rustc $flags
+  depfile = $depfile
+  deps = gcc
+  description = RUSTC $crate
+
+rule build_script
+  command = root=$$PWD && mkdir -p $out_dir && cd $manifest_dir && env $env OUT_DIR=$$root/$out_dir $$root/$script > $$root/$out
+  description = RUN $crate build script
+"#;
+
+/// Generates a Ninja build file for the unit graph.
+///
+/// Doctest units are skipped, since they run rustdoc rather than rustc.
+#[must_use]
+pub fn generate_ninja(graph: &UnitGraph) -> String {
+    let canonical_index = canonical_indices(graph);
+    let hashes = identity_hashes(graph, &canonical_index);
+    let mut out = String::with_capacity(graph.units.len() * 512);
+
+    out.push_str("# Generated by nix-cargo-unit\n\n");
+    let _ = writeln!(out, "builddir = {BUILD_DIR}");
+    out.push_str("rustc = rustc\n\n");
+    out.push_str(RULES);
+
+    // Units with equal identity build the same outputs; emit them once, from the
+    // canonical unit (the one with the feature superset)
+    let mut emitted = FxHashSet::default();
+    for (i, unit) in graph.units.iter().enumerate() {
+        if unit.is_doctest() || canonical_index[i] != i || !emitted.insert(&hashes[i]) {
+            continue;
+        }
+        out.push('\n');
+        if unit.mode == "run-custom-build" {
+            write_build_script_run(&mut out, graph, &hashes, i);
+        } else {
+            write_rustc_build(&mut out, graph, &hashes, i);
+        }
+    }
+
+    let defaults: Vec<String> = graph
+        .roots
+        .iter()
+        .filter(|&&i| graph.units.get(i).is_some_and(|u| !u.is_doctest()))
+        .flat_map(|&i| unit_outputs(graph, &hashes, i))
+        .map(|p| escape_path(&p))
+        .collect();
+    if !defaults.is_empty() {
+        let _ = writeln!(out, "\ndefault {}", defaults.join(" "));
+    }
+
+    out
+}
+
+/// Computes unit identity hashes the way the Nix backend names derivations,
+/// so a unit's output directory matches its default derivation name.
+fn identity_hashes(graph: &UnitGraph, canonical_index: &[usize]) -> Vec<String> {
+    NixGenerator::new(NixGenConfig::default()).identity_hashes(graph, canonical_index)
+}
+
+/// Writes the `build` statement compiling unit `i` with rustc.
+fn write_rustc_build(out: &mut String, graph: &UnitGraph, hashes: &[String], i: usize) {
+    let unit = &graph.units[i];
+    let hash = &hashes[i];
+    let dir = unit_dir(unit, hash);
+    let crate_name = unit.crate_name();
+
+    let mut flags = RustcFlags::from_unit(unit);
+    // Same as the Nix backend: rustc rejects proc-macros with a forced metadata hash
+    if !unit.is_proc_macro() {
+        flags.add_metadata(hash);
+    }
+    if unit.is_external_dependency() || unit.is_std {
+        flags.cap_lints_for_dependency();
+    }
+    if unit.is_proc_macro() {
+        flags.push_arg("--extern");
+        flags.push_arg("proc_macro");
+    }
+    flags.add_out_dir(&dir);
+    flags.push_arg(if is_metadata_only(unit) {
+        "--emit=dep-info,metadata"
+    } else if is_linked_or_macro(unit) {
+        "--emit=dep-info,link"
+    } else {
+        "--emit=dep-info,metadata,link"
+    });
+
+    let mut inputs = vec![unit.target.src_path.clone()];
+    let mut env = vec![
+        format!("CARGO_MANIFEST_DIR={}", manifest_dir(unit)),
+        format!("CARGO_PKG_NAME={}", unit.package_name()),
+        format!(
+            "CARGO_PKG_VERSION={}",
+            unit.package_version().unwrap_or("0.0.0")
+        ),
+        format!("CARGO_CRATE_NAME={crate_name}"),
+    ];
+
+    for dep in &unit.dependencies {
+        let Some(dep_unit) = graph.units.get(dep.index) else {
+            continue;
+        };
+        let dep_outputs = unit_outputs(graph, hashes, dep.index);
+        if dep.is_build_dependency(graph) {
+            env.push(format!(
+                "OUT_DIR={}/out",
+                unit_dir(dep_unit, &hashes[dep.index])
+            ));
+        } else if let Some(extern_path) = dep_outputs.first() {
+            flags.add_extern(&dep.extern_crate_name, extern_path);
+        }
+        inputs.extend(dep_outputs);
+    }
+    for dep in transitive_deps(graph, i) {
+        let dep_unit = &graph.units[dep];
+        if dep_unit.mode != "run-custom-build" && !dep_unit.is_build_script() {
+            flags.add_lib_path(&unit_dir(dep_unit, &hashes[dep]));
+        }
+    }
+
+    let outputs = unit_outputs(graph, hashes, i);
+    let depfile = if unit.is_proc_macro() {
+        format!("{dir}/{crate_name}.d")
+    } else {
+        format!("{dir}/{crate_name}-{hash}.d")
+    };
+
+    write_build_line(out, &outputs, "rustc", &inputs);
+    let _ = writeln!(out, "  crate = {}", unit.target.name);
+    let _ = writeln!(out, "  depfile = {}", escape_value(&depfile));
+    let _ = writeln!(out, "  env = {}", escape_shell_words(&env));
+    let _ = writeln!(out, "  flags = {}", escape_value(&flags.to_shell_string()));
+}
+
+/// Writes the `build` statement running the build script of unit `i`.
+fn write_build_script_run(out: &mut String, graph: &UnitGraph, hashes: &[String], i: usize) {
+    let unit = &graph.units[i];
+    let dir = unit_dir(unit, &hashes[i]);
+
+    // The compiled script is the run unit's build-script dependency
+    let script = unit
+        .dependencies
+        .iter()
+        .find(|dep| {
+            graph
+                .units
+                .get(dep.index)
+                .is_some_and(Unit::is_build_script)
+        })
+        .and_then(|dep| unit_outputs(graph, hashes, dep.index).into_iter().next());
+    let Some(script) = script else {
+        let _ = writeln!(out, "# {}: no compiled build script found", unit.pkg_id);
+        return;
+    };
+
+    // Other dependencies (e.g. `links` scripts of dependencies) must run first
+    let inputs: Vec<String> = unit
+        .dependencies
+        .iter()
+        .flat_map(|dep| unit_outputs(graph, hashes, dep.index))
+        .collect();
+
+    let mut env = vec![
+        format!("CARGO_MANIFEST_DIR={}", manifest_dir(unit)),
+        format!("CARGO_PKG_NAME={}", unit.package_name()),
+        format!(
+            "CARGO_PKG_VERSION={}",
+            unit.package_version().unwrap_or("0.0.0")
+        ),
+    ];
+    for feature in &unit.features {
+        env.push(format!(
+            "CARGO_FEATURE_{}=1",
+            feature.to_uppercase().replace('-', "_")
+        ));
+    }
+
+    write_build_line(out, &[format!("{dir}/output")], "build_script", &inputs);
+    let _ = writeln!(out, "  crate = {}", unit.package_name());
+    let _ = writeln!(
+        out,
+        "  manifest_dir = {}",
+        escape_shell_words(&[manifest_dir(unit)])
+    );
+    let _ = writeln!(
+        out,
+        "  out_dir = {}",
+        escape_shell_words(&[format!("{dir}/out")])
+    );
+    let _ = writeln!(out, "  script = {}", escape_shell_words(&[script]));
+    let _ = writeln!(out, "  env = {}", escape_shell_words(&env));
+}
+
+/// Writes `build <outputs>: <rule> <inputs>`.
+fn write_build_line(out: &mut String, outputs: &[String], rule: &str, inputs: &[String]) {
+    out.push_str("build");
+    for output in outputs {
+        out.push(' ');
+        out.push_str(&escape_path(output));
+    }
+    out.push_str(": ");
+    out.push_str(rule);
+    for input in inputs {
+        out.push(' ');
+        out.push_str(&escape_path(input));
+    }
+    out.push('\n');
+}
+
+/// Returns the output directory of a unit.
+fn unit_dir(unit: &Unit, hash: &str) -> String {
+    let version = unit.package_version().unwrap_or("0.0.0");
+    format!("{BUILD_DIR}/{}-{version}-{hash}", unit.target.name)
+}
+
+/// Returns the files a unit produces; the first is what dependents link against.
+fn unit_outputs(graph: &UnitGraph, hashes: &[String], i: usize) -> Vec<String> {
+    let unit = &graph.units[i];
+    let hash = &hashes[i];
+    let dir = unit_dir(unit, hash);
+    let name = unit.crate_name();
+    let (prefix, suffix) = (std::env::consts::DLL_PREFIX, std::env::consts::DLL_SUFFIX);
+
+    if unit.mode == "run-custom-build" {
+        return vec![format!("{dir}/output")];
+    }
+    if unit.is_proc_macro() {
+        return vec![format!("{dir}/{prefix}{name}{suffix}")];
+    }
+    if is_metadata_only(unit) {
+        return vec![format!("{dir}/lib{name}-{hash}.rmeta")];
+    }
+    if unit.is_test() {
+        return vec![format!(
+            "{dir}/{name}-{hash}{}",
+            std::env::consts::EXE_SUFFIX
+        )];
+    }
+
+    unit.target
+        .crate_types
+        .iter()
+        .map(|crate_type| match crate_type.as_str() {
+            "bin" => format!("{dir}/{name}-{hash}{}", std::env::consts::EXE_SUFFIX),
+            "dylib" | "cdylib" => format!("{dir}/{prefix}{name}-{hash}{suffix}"),
+            "staticlib" => format!("{dir}/lib{name}-{hash}.a"),
+            _ => format!("{dir}/lib{name}-{hash}.rlib"),
+        })
+        .collect()
+}
+
+/// Returns whether a unit only emits metadata (`cargo check`).
+fn is_metadata_only(unit: &Unit) -> bool {
+    unit.mode == "check" && !unit.is_proc_macro()
+}
+
+/// Returns whether a unit's outputs need no `.rmeta` (linked artifacts and proc-macros).
+fn is_linked_or_macro(unit: &Unit) -> bool {
+    unit.is_proc_macro() || unit.is_test() || unit.target.crate_types.iter().all(|t| t == "bin")
+}
+
+/// Returns the directory containing the unit's `Cargo.toml`.
+fn manifest_dir(unit: &Unit) -> String {
+    crate::source_filter::SourceLocation::from_unit(unit).map_or_else(
+        || {
+            std::path::Path::new(&unit.target.src_path)
+                .parent()
+                .map_or_else(String::new, |p| p.display().to_string())
+        },
+        |loc| loc.crate_root,
+    )
+}
+
+/// Returns the indices of all units that unit `i` depends on, transitively.
+fn transitive_deps(graph: &UnitGraph, i: usize) -> Vec<usize> {
+    let mut seen = FxHashSet::default();
+    let mut stack: Vec<usize> = graph.units[i]
+        .dependencies
+        .iter()
+        .map(|d| d.index)
+        .collect();
+    let mut result = Vec::new();
+    while let Some(idx) = stack.pop() {
+        let Some(unit) = graph.units.get(idx) else {
+            continue;
+        };
+        if seen.insert(idx) {
+            result.push(idx);
+            stack.extend(unit.dependencies.iter().map(|d| d.index));
+        }
+    }
+    result.sort_unstable();
+    result
+}
+
+/// Escapes a path for a `build` line (`$`, space, and `:` are special).
+fn escape_path(path: &str) -> String {
+    path.replace('$', "$$")
+        .replace(' ', "$ ")
+        .replace(':', "$:")
+}
+
+/// Escapes a variable value (only `$` is special).
+fn escape_value(value: &str) -> String {
+    value.replace('$', "$$")
+}
+
+/// Shell-quotes words and joins them into a variable value.
+fn escape_shell_words(words: &[String]) -> String {
+    words
+        .iter()
+        .map(|word| escape_value(&crate::shell::quote_arg(word)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_graph::parse_test_unit_graph;
+
+    #[test]
+    fn test_build_statement_per_unit() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-lib 0.1.0 (path+file:///workspace/my-lib)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_lib",
+                        "src_path": "/workspace/my-lib/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "my_lib", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let hashes = identity_hashes(&graph, &canonical_indices(&graph));
+        let ninja = generate_ninja(&graph);
+
+        let builds: Vec<&str> = ninja.lines().filter(|l| l.starts_with("build ")).collect();
+        assert_eq!(builds.len(), 2, "{ninja}");
+
+        let lib_rlib = format!(
+            "target/ninja/my_lib-0.1.0-{h}/libmy_lib-{h}.rlib",
+            h = hashes[0]
+        );
+        let app_bin = format!("target/ninja/app-0.1.0-{h}/app-{h}", h = hashes[1]);
+
+        assert_eq!(
+            builds[0],
+            format!("build {lib_rlib}: rustc /workspace/my-lib/src/lib.rs")
+        );
+        // The binary depends on the library's rlib and links it with --extern
+        assert_eq!(
+            builds[1],
+            format!("build {app_bin}: rustc /workspace/app/src/main.rs {lib_rlib}")
+        );
+        let app_flags = ninja.split(builds[1]).nth(1).unwrap();
+        assert!(app_flags.contains(&format!("--extern my_lib={lib_rlib}")));
+        assert!(app_flags.contains(&format!(
+            "-L dependency=target/ninja/my_lib-0.1.0-{}",
+            hashes[0]
+        )));
+
+        assert!(ninja.contains(&format!("\ndefault {app_bin}\n")));
+    }
+
+    #[test]
+    fn test_build_script_out_dir() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-lib 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-lib 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "my-lib 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_lib",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                }
+            ],
+            "roots": [2]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let hashes = identity_hashes(&graph, &canonical_indices(&graph));
+        let ninja = generate_ninja(&graph);
+
+        let script = format!(
+            "target/ninja/build-script-build-0.1.0-{h}/build_script_build-{h}",
+            h = hashes[0]
+        );
+        let run_dir = format!("target/ninja/build-script-build-0.1.0-{}", hashes[1]);
+        assert!(ninja.contains(&format!("build {run_dir}/output: build_script {script}\n")));
+
+        // The library waits for the script to run and sees its OUT_DIR,
+        // but does not link against it
+        let lib = ninja.split("\n  crate = my_lib\n").next().unwrap();
+        let lib_build = lib.lines().last().unwrap();
+        assert!(lib_build.ends_with(&format!("/workspace/src/lib.rs {run_dir}/output")));
+        let lib_vars = ninja.split("\n  crate = my_lib\n").nth(1).unwrap();
+        assert!(lib_vars.contains(&format!("OUT_DIR={run_dir}/out")));
+        assert!(!lib_vars.contains("--extern build_script_build"));
+    }
+}
@@ -8,6 +8,7 @@ use std::fmt::Write as _;
 use std::rc::Rc;
 
 use crate::build_script::{BuildScriptInfo, BuildScriptOutput};
+use crate::cargo_config::CargoEnvVar;
 
 /// Parsed version components from a semver string.
 #[derive(Debug, Clone)]
@@ -37,10 +38,14 @@ impl<'a> VersionParts<'a> {
 /// Generates shell script exports for CARGO_PKG_* environment variables.
 ///
 /// These are needed by crates that use `env!()` macros at compile time.
+/// Variables from a cargo config `[env]` table follow; unless `force`d, they
+/// leave an existing value alone. The result is meant for an interpolated
+/// Nix string, where `relative` values may reference `${src}`.
 pub fn generate_cargo_pkg_exports(
     package_name: &str,
     version: &str,
     features: &[String],
+    cargo_env: &[CargoEnvVar],
 ) -> String {
     // Pre-allocate: ~500 bytes base + ~40 bytes per feature
     let mut script = String::with_capacity(500 + features.len() * 40);
@@ -75,10 +80,51 @@ pub fn generate_cargo_pkg_exports(
         script.push_str("=1\n");
     }
 
+    for var in cargo_env {
+        let value = if var.relative {
+            crate::shell::quote_arg_double(&var.value).into_owned()
+        } else {
+            escape_nix_multiline(&crate::shell::quote_arg(&var.value))
+        };
+        if var.force {
+            let _ = writeln!(script, "export {}={value}", var.name);
+        } else {
+            let _ = writeln!(
+                script,
+                "[ -n \"''${{{0}+set}}\" ] || export {0}={value}",
+                var.name
+            );
+        }
+    }
+
     script
 }
+
 use crate::rustc_flags::RustcFlags;
-use crate::unit_graph::{Unit, UnitGraph};
+use crate::unit_graph::{IdentityInputs, Unit, UnitGraph};
+
+/// Maps a Rust target triple to a Nix system string.
+///
+/// For example, `aarch64-apple-darwin` becomes `aarch64-darwin` and
+/// `x86_64-unknown-linux-gnu` becomes `x86_64-linux`. Returns `None` for
+/// triples without a Nix equivalent.
+pub fn rust_triple_to_nix_system(triple: &str) -> Option<String> {
+    let arch = triple.split('-').next()?;
+    let arch = match arch {
+        "armv7" => "armv7l",
+        "armv6" | "arm" => "armv6l",
+        "riscv64gc" => "riscv64",
+        "i586" | "i686" | "x86_64" | "aarch64" | "powerpc64le" | "riscv32" | "wasm32"
+        | "wasm64" => arch,
+        _ => return None,
+    };
+    let os = [
+        "linux", "darwin", "windows", "freebsd", "netbsd", "openbsd", "wasi",
+    ]
+    .into_iter()
+    .find(|os| triple.split('-').skip(1).any(|part| part.starts_with(os)))?;
+    Some(format!("{arch}-{os}"))
+}
 
 /// A Nix string with proper escaping.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -164,6 +210,28 @@ pub fn escape_nix_multiline(s: &str) -> String {
     result
 }
 
+/// Renders an attribute name, quoting it unless it is a plain Nix identifier.
+///
+/// Identifiers match `[a-zA-Z_][a-zA-Z0-9_'-]*` and are not keywords; anything
+/// else (e.g. `foo.bar`, which would otherwise be an attribute path) becomes
+/// `"foo.bar"`.
+fn nix_attr_key(key: &str) -> std::borrow::Cow<'_, str> {
+    const KEYWORDS: &[&str] = &[
+        "assert", "else", "if", "in", "inherit", "let", "rec", "then", "with",
+    ];
+    let mut chars = key.chars();
+    let is_identifier = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '-'))
+        && !KEYWORDS.contains(&key);
+    if is_identifier {
+        std::borrow::Cow::Borrowed(key)
+    } else {
+        std::borrow::Cow::Owned(format!("\"{}\"", escape_nix_string(key)))
+    }
+}
+
 /// A builder for Nix attribute sets.
 #[derive(Debug, Default)]
 pub struct NixAttrSet {
@@ -214,10 +282,10 @@ impl NixAttrSet {
     }
 
     /// Adds content-addressed derivation attributes.
-    pub fn add_ca_attrs(&mut self) -> &mut Self {
+    pub fn add_ca_attrs(&mut self, hash_algo: &str) -> &mut Self {
         self.bool("__contentAddressed", true);
         self.string("outputHashMode", "recursive");
-        self.string("outputHashAlgo", "sha256");
+        self.string("outputHashAlgo", hash_algo);
         // Skip fixup phase entirely for CA derivations:
         // 1. Rust crates don't need stripping/patching that fixup provides
         // 2. fixupPhase runs chmod which fails on read-only CA store paths
@@ -226,9 +294,36 @@ impl NixAttrSet {
     }
 
     /// Adds an integer attribute.
+    ///
+    /// Negative numbers are Nix's unary minus applied to a literal, so
+    /// `i64::MIN`, whose magnitude overflows a literal, is written as a subtraction.
     pub fn int(&mut self, key: &str, value: i64) -> &mut Self {
+        let value = if value == i64::MIN {
+            format!("({} - 1)", i64::MIN + 1)
+        } else {
+            value.to_string()
+        };
+        self.attrs.push((key.to_string(), NixValue::Inline(value)));
+        self
+    }
+
+    /// Adds a floating-point attribute.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `value` is NaN or infinite, which Nix cannot represent.
+    pub fn float(&mut self, key: &str, value: f64) -> &mut Self {
+        assert!(value.is_finite(), "Nix has no literal for {value}");
+        // Debug keeps a fractional part (`1.0`, not `1`) so Nix doesn't read an int,
+        // but writes exponents as `1e300`, which Nix only accepts as `1.0e300`
+        let mut rendered = format!("{value:?}");
+        if !rendered.contains('.')
+            && let Some(e) = rendered.find('e')
+        {
+            rendered.insert_str(e, ".0");
+        }
         self.attrs
-            .push((key.to_string(), NixValue::Inline(value.to_string())));
+            .push((key.to_string(), NixValue::Inline(rendered)));
         self
     }
 
@@ -290,6 +385,55 @@ impl NixAttrSet {
         self
     }
 
+    /// Returns the accumulated attributes as `(key, rendered Nix value)` pairs.
+    ///
+    /// Values are already in Nix syntax (strings quoted, multiline strings
+    /// wrapped in `''...''`).
+    pub fn raw_attrs(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.attrs.iter().map(|(key, value)| {
+            let value = match value {
+                NixValue::Inline(value) | NixValue::Multiline(value) => value.as_str(),
+            };
+            (key.as_str(), value)
+        })
+    }
+
+    /// Merges another attribute set into this one.
+    ///
+    /// Attributes from `other` replace existing attributes with the same key
+    /// (keeping their original position); new keys are appended.
+    pub fn merge(&mut self, other: NixAttrSet) -> &mut Self {
+        for (key, value) in other.attrs {
+            if let Some(existing) = self.attrs.iter_mut().find(|(k, _)| *k == key) {
+                existing.1 = value;
+            } else {
+                self.attrs.push((key, value));
+            }
+        }
+        self
+    }
+
+    /// Renders the attribute set on a single line with minimal whitespace.
+    ///
+    /// Multiline strings keep their line breaks (Nix strips their indentation
+    /// anyway), but shell line continuations inside them are joined.
+    pub fn render_compact(&self) -> String {
+        let estimated_size: usize = self.attrs.iter().map(|(k, v)| k.len() + v.len() + 4).sum();
+        let mut out = String::with_capacity(estimated_size + 4);
+        out.push('{');
+        for (key, value) in &self.attrs {
+            out.push_str(&nix_attr_key(key));
+            out.push('=');
+            match value {
+                NixValue::Inline(value) => out.push_str(value),
+                NixValue::Multiline(value) => out.push_str(&join_line_continuations(value)),
+            }
+            out.push(';');
+        }
+        out.push('}');
+        out
+    }
+
     /// Renders the attribute set with the given indentation.
     pub fn render(&self, indent: usize) -> String {
         let base_indent = "  ".repeat(indent);
@@ -302,7 +446,7 @@ impl NixAttrSet {
 
         for (key, value) in &self.attrs {
             out.push_str(&inner_indent);
-            out.push_str(key);
+            out.push_str(&nix_attr_key(key));
             out.push_str(" = ");
             match value {
                 NixValue::Inline(value) => {
@@ -330,6 +474,84 @@ impl NixAttrSet {
     }
 }
 
+/// Joins shell line continuations (` \` at the end of a line) with the next
+/// line, e.g. to put every rustc flag of a build phase on one line.
+fn join_line_continuations(script: &str) -> String {
+    let mut out = String::with_capacity(script.len());
+    let mut continued = false;
+    for line in script.lines() {
+        if continued {
+            out.push(' ');
+        } else if !out.is_empty() {
+            out.push('\n');
+        }
+        let line = if continued { line.trim_start() } else { line };
+        match line.strip_suffix(" \\") {
+            Some(rest) => {
+                out.push_str(rest);
+                continued = true;
+            }
+            None => {
+                out.push_str(line);
+                continued = false;
+            }
+        }
+    }
+    out
+}
+
+/// Derivation names that differ between two generated outputs, as computed by
+/// [`diff_derivations`]. Each list is sorted.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DerivationDiff {
+    /// Names only in the new output.
+    pub added: Vec<String>,
+    /// Names only in the old output.
+    pub removed: Vec<String>,
+    /// Names in both outputs.
+    pub unchanged: Vec<String>,
+}
+
+/// Collects the derivation names bound in `units` of a generated expression:
+/// `"name" = mkUnit ...` lines, or `"name" = import ./name.nix scope;` lines in
+/// the `default.nix` written by [`NixGenerator::write_dir`].
+pub fn derivation_names_in(nix: &str) -> std::collections::BTreeSet<String> {
+    nix.lines()
+        .filter_map(|line| {
+            let (name, rest) = line.trim_start().strip_prefix('"')?.split_once('"')?;
+            (rest.starts_with(" = mkUnit") || rest.starts_with(" = import ./"))
+                .then(|| name.to_string())
+        })
+        .collect()
+}
+
+/// Compares the derivation names of two generated expressions.
+///
+/// Names embed the identity hash, so a changed unit shows up as one removed
+/// and one added name, without the noise of a textual diff.
+pub fn diff_derivations(old: &str, new: &str) -> DerivationDiff {
+    let old = derivation_names_in(old);
+    let new = derivation_names_in(new);
+    DerivationDiff {
+        added: new.difference(&old).cloned().collect(),
+        removed: old.difference(&new).cloned().collect(),
+        unchanged: old.intersection(&new).cloned().collect(),
+    }
+}
+
+/// One derivation's identity hash and its inputs, as written by
+/// [`NixGenerator::write_manifest`].
+#[derive(serde::Serialize)]
+struct ManifestEntry<'a> {
+    derivation: String,
+    identity_hash: &'a str,
+    inputs: IdentityInputs<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    toolchain_hash: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    extras: Vec<String>,
+}
+
 /// A dependency reference for a unit derivation.
 #[derive(Debug, Clone)]
 pub struct DepRef {
@@ -353,6 +575,15 @@ pub struct DepRef {
 
     /// Whether this is a proc-macro dependency.
     pub is_proc_macro: bool,
+
+    /// Whether the dependency was built metadata-only (`.rmeta` instead of `.rlib`).
+    pub metadata_only: bool,
+
+    /// Whether this is a Rust `dylib` dependency (linked as a shared library).
+    pub is_dylib: bool,
+
+    /// Whether to pass the dependency as `--extern noprelude:` (build-std crates).
+    pub noprelude: bool,
 }
 
 /// A build script output reference for a unit.
@@ -377,6 +608,9 @@ pub struct UnitDerivation {
     /// Package name.
     pub pname: String,
 
+    /// Crate name passed to `--crate-name` (the target name with `-` as `_`).
+    pub crate_name: String,
+
     /// Package version.
     pub version: String,
 
@@ -424,6 +658,89 @@ pub struct UnitDerivation {
     /// The Nix variable for the toolchain to use.
     /// Either "rustToolchain" or "hostRustToolchain" for cross-compilation.
     pub toolchain_var: String,
+
+    /// Whether to export `RUSTC_BOOTSTRAP=1` so nightly features work on stable.
+    pub rustc_bootstrap: bool,
+
+    /// Extra Nix expressions appended to `nativeBuildInputs` (e.g. `pkgs.protobuf`).
+    pub extra_native_build_inputs: Vec<String>,
+
+    /// Builder features the derivation requires (e.g. `big-parallel`).
+    pub required_system_features: Vec<String>,
+
+    /// Nixpkgs hardening flags to disable (e.g. `fortify`, `pie`).
+    pub hardening_disable: Vec<String>,
+
+    /// Variables from the cargo config `[env]` table, exported before compiling.
+    pub cargo_env: Vec<CargoEnvVar>,
+
+    /// Extra variables set to the build script's output directory, like `OUT_DIR`.
+    pub out_dir_env: Vec<String>,
+
+    /// Command used to invoke rustc (e.g. `sccache rustc`).
+    pub rustc_command: String,
+
+    /// Scratch directory for rustc outputs (see [`NixGenConfig::build_dir`]).
+    pub build_dir: String,
+
+    /// `outputHashAlgo` of the content-addressed derivation (see [`NixGenConfig::ca_hash_algo`]).
+    pub ca_hash_algo: String,
+
+    /// Whether to trace build phase commands with `set -x`.
+    pub trace: bool,
+
+    /// Whether to log proc-macro dylib lookup diagnostics in the build phase.
+    pub debug_procmacro: bool,
+
+    /// Whether to emit only metadata (`.rmeta`), skipping codegen (`cargo check`).
+    pub metadata_only: bool,
+
+    /// Whether this is a standard library unit (`-Zbuild-std`).
+    pub is_std: bool,
+
+    /// Triple the derivation builds on, used for `meta.platforms`: the unit's own
+    /// platform, or the build platform when cross-compiling.
+    pub platform: Option<String>,
+
+    /// Whether debuginfo is split into packed files (`.dwp`/`.dSYM`), which are
+    /// installed into a separate `debug` output.
+    pub split_debuginfo_packed: bool,
+
+    /// Whether the unit is instrumented for coverage; profiles written by
+    /// instrumented code run inside the derivation land in `$out/profraw`.
+    pub coverage: bool,
+
+    /// Whether `-C codegen-units` is taken from `$NIX_BUILD_CORES` at build time.
+    pub codegen_units_from_cores: bool,
+
+    /// Whether to link std dynamically (`-C prefer-dynamic`).
+    pub prefer_dynamic: bool,
+
+    /// Nix variables of every Rust dylib the unit depends on, directly or
+    /// through rlibs. Linking any of them requires std as a dylib too, and all
+    /// of them go on the runtime path.
+    pub dylib_deps: Vec<String>,
+
+    /// Whether Nixpkgs' `separateDebugInfo` moves the binary's debug symbols
+    /// into a `debug` output.
+    pub separate_debug_info: bool,
+
+    /// Whether `postInstall` links a hash-free `lib<name>.rlib` to the library
+    /// (see [`NixGenConfig::emit_stable_symlinks`]).
+    pub stable_symlinks: bool,
+
+    /// Whether to render with [`NixAttrSet::render_compact`].
+    pub compact: bool,
+
+    /// Whether the profile sets `rpath = true`. Runtime paths point at store
+    /// paths instead of rustc's `$ORIGIN`-relative ones.
+    pub rpath: bool,
+
+    /// Cargo package ID of the unit, exposed via `passthru.nix-cargo-unit`.
+    pub pkg_id: String,
+
+    /// Identity hash of the unit, exposed via `passthru.nix-cargo-unit`.
+    pub identity_hash: String,
 }
 
 impl UnitDerivation {
@@ -431,29 +748,39 @@ impl UnitDerivation {
     ///
     /// The `workspace_root` is used to remap absolute paths to Nix source paths.
     /// The `content_addressed` flag enables CA-derivation attributes.
+    /// Paths are remapped into the Nix source tree, checking `extra_sources`
+    /// (see [`NixGenConfig::extra_sources`]) after the workspace root.
     /// The `toolchain_var` specifies which toolchain to use (for cross-compilation).
     /// The `drv_name` and `identity_hash` should be pre-computed for efficiency.
-    /// The `is_external_dep` flag indicates if this is a dependency (registry/git)
-    /// vs a local workspace crate; external deps get `--cap-lints warn`.
+    /// External dependencies (registry/git) and std units get `--cap-lints warn`.
     pub fn from_unit(
         unit: &Unit,
         workspace_root: &str,
+        extra_sources: &[(String, String)],
         content_addressed: bool,
         toolchain_var: &str,
         drv_name: &str,
         identity_hash: &str,
-        is_external_dep: bool,
     ) -> Self {
         let pname = unit.target.name.clone();
         let version = unit.package_version().unwrap_or("0.0.0").to_string();
 
         // Remap source path
-        let src_path =
-            crate::source_filter::remap_source_path(&unit.target.src_path, workspace_root, "src");
+        let src_path = crate::source_filter::remap_source_path(
+            &unit.target.src_path,
+            workspace_root,
+            "src",
+            extra_sources,
+        );
 
         // Remap manifest directory (needed for CARGO_MANIFEST_DIR)
-        let manifest_dir =
-            crate::source_filter::remap_manifest_dir(unit, workspace_root, "src", "vendorDir");
+        let manifest_dir = crate::source_filter::remap_manifest_dir(
+            unit,
+            workspace_root,
+            "src",
+            "vendorDir",
+            extra_sources,
+        );
 
         let mut rustc_flags = RustcFlags::from_unit(unit);
         // Add metadata hash for stable crate identity across compilations.
@@ -464,20 +791,32 @@ impl UnitDerivation {
 
         // Cap lints to warn for external dependencies (same as cargo does)
         // This prevents #[deny(dead_code)] etc from breaking dependency builds
-        if is_external_dep {
+        if unit.is_external_dependency() || unit.is_std {
             rustc_flags.cap_lints_for_dependency();
         }
 
+        // rustc's rpaths are relative to where the libraries sit at build time,
+        // which doesn't survive installation into the store; the build phase
+        // adds absolute rpaths instead
+        if unit.profile.rpath {
+            rustc_flags.remove_codegen("rpath");
+        }
+
+        // Sorted like the identity hash, so reordered features build identically
+        let mut features = unit.features.clone();
+        features.sort_unstable();
+
         Self {
             name: drv_name.to_owned(),
             pname,
+            crate_name: unit.crate_name(),
             version,
-            edition: unit.target.edition.clone(),
+            edition: unit.target.effective_edition().to_string(),
             crate_types: unit.target.crate_types.clone(),
             src_path,
             manifest_dir,
-            features: unit.features.clone(),
-            opt_level: unit.profile.opt_level.clone(),
+            features,
+            opt_level: unit.profile.effective_opt_level().to_string(),
             is_test: unit.is_test(),
             is_proc_macro: unit.is_proc_macro(),
             deps: Vec::new(),
@@ -486,6 +825,31 @@ impl UnitDerivation {
             rustc_flags,
             content_addressed,
             toolchain_var: toolchain_var.to_owned(),
+            rustc_bootstrap: false,
+            extra_native_build_inputs: Vec::new(),
+            required_system_features: Vec::new(),
+            hardening_disable: Vec::new(),
+            cargo_env: Vec::new(),
+            out_dir_env: Vec::new(),
+            rustc_command: "rustc".to_string(),
+            build_dir: "build".to_string(),
+            ca_hash_algo: "sha256".to_string(),
+            trace: false,
+            debug_procmacro: false,
+            metadata_only: unit.mode == "check" && !unit.is_proc_macro(),
+            is_std: unit.is_std,
+            platform: unit.platform.clone(),
+            split_debuginfo_packed: unit.profile.split_debuginfo.as_deref() == Some("packed"),
+            coverage: false,
+            codegen_units_from_cores: false,
+            prefer_dynamic: false,
+            dylib_deps: Vec::new(),
+            separate_debug_info: false,
+            stable_symlinks: false,
+            compact: false,
+            rpath: unit.profile.rpath,
+            pkg_id: unit.pkg_id.clone(),
+            identity_hash: identity_hash.to_owned(),
         }
     }
 
@@ -506,6 +870,18 @@ impl UnitDerivation {
 
     /// Generates the Nix derivation expression.
     pub fn to_nix(&self) -> String {
+        let attrs = self.to_attrs();
+        if self.compact {
+            attrs.render_compact()
+        } else {
+            attrs.render(2)
+        }
+    }
+
+    /// Builds the derivation attributes without rendering them.
+    ///
+    /// Useful for extending a derivation with extra attributes via [`NixAttrSet::merge`].
+    pub fn to_attrs(&self) -> NixAttrSet {
         let mut attrs = NixAttrSet::new();
 
         attrs.string("pname", &self.pname);
@@ -526,14 +902,40 @@ impl UnitDerivation {
 
         // Native build inputs (rust toolchain)
         // Use hostRustToolchain for proc-macros when cross-compiling
-        attrs.expr("nativeBuildInputs", &format!("[ {} ]", self.toolchain_var));
+        let mut native_inputs = vec![self.toolchain_var.clone()];
+        native_inputs.extend(self.extra_native_build_inputs.iter().cloned());
+        attrs.expr_list("nativeBuildInputs", &native_inputs);
 
-        // Don't strip Rust libraries - it removes metadata required for compilation
-        attrs.bool("dontStrip", true);
+        // Packed debuginfo is kept out of the main output
+        if self.split_debuginfo_packed {
+            attrs.string_list("outputs", &["out".to_string(), "debug".to_string()]);
+        }
+
+        // Stripping is rustc's job: `-C strip` from the profile applies when it links
+        // bins, cdylibs, and dylibs, and leaves rlibs alone. Nixpkgs' fixup strip
+        // would remove the rlib metadata dependents compile against, so disable it.
+        //
+        // The exception is `separateDebugInfo` (bins only): its hook copies the debug
+        // symbols into the `debug` output (which mkDerivation adds), and the fixup
+        // strip then removes them from `$out`.
+        if self.separate_debug_info {
+            attrs.bool("separateDebugInfo", true);
+        }
+        attrs.bool("dontStrip", !self.separate_debug_info);
+
+        // Schedule heavy units only on builders that advertise these features
+        if !self.required_system_features.is_empty() {
+            attrs.string_list("requiredSystemFeatures", &self.required_system_features);
+        }
+
+        // Opt out of stdenv hardening for crates that break under it
+        if !self.hardening_disable.is_empty() {
+            attrs.string_list("hardeningDisable", &self.hardening_disable);
+        }
 
         // Content-addressed derivation attributes
         if self.content_addressed {
-            attrs.add_ca_attrs();
+            attrs.add_ca_attrs(&self.ca_hash_algo);
         }
 
         // Build phase with rustc invocation
@@ -542,10 +944,39 @@ impl UnitDerivation {
         attrs.multiline_interpolated("buildPhase", &build_phase);
 
         // Install phase - copy outputs from build directory to $out
-        let install_phase = self.generate_install_phase();
+        let mut install_phase = self.generate_install_phase();
+        if self.stable_symlinks {
+            // A custom installPhase only runs the hooks it calls itself
+            install_phase.push_str("\nrunHook postInstall");
+        }
         attrs.multiline("installPhase", &install_phase);
+        if self.stable_symlinks {
+            attrs.multiline("postInstall", &self.generate_stable_symlinks());
+        }
+
+        // Let Nix skip units built for another system
+        if let Some(system) = self.platform.as_deref().and_then(rust_triple_to_nix_system) {
+            attrs.expr(
+                "meta",
+                &format!("{{ platforms = [ \"{}\" ]; }}", escape_nix_string(&system)),
+            );
+        }
+
+        // Provenance for tooling mapping store paths back to crates.
+        // passthru is not passed to the derivation, so it doesn't affect the hash.
+        let mut provenance = NixAttrSet::new();
+        provenance.string("identityHash", &self.identity_hash);
+        provenance.string("pkgId", &self.pkg_id);
+        let mut passthru = NixAttrSet::new();
+        if self.compact {
+            passthru.expr("nix-cargo-unit", &provenance.render_compact());
+            attrs.expr("passthru", &passthru.render_compact());
+        } else {
+            passthru.expr("nix-cargo-unit", &provenance.render(4));
+            attrs.expr("passthru", &passthru.render(3));
+        }
 
-        attrs.render(2)
+        attrs
     }
 
     /// Generates the build phase script.
@@ -554,9 +985,13 @@ impl UnitDerivation {
         let mut script =
             String::with_capacity(1024 + (self.deps.len() + self.lib_search_deps.len()) * 100);
 
+        // Fail fast: a failing rustc must not let installPhase produce a broken output
+        script.push_str("set -euo pipefail\n");
+
         // Create build directory (NOT $out - $out is read-only during buildPhase in Nix sandbox)
         // We'll copy outputs to $out in installPhase
-        script.push_str("mkdir -p build\n");
+        let build_dir = &self.build_dir;
+        let _ = writeln!(script, "mkdir -p {build_dir}");
 
         // Initialize build script flags variable
         script.push_str("BUILD_SCRIPT_FLAGS=\"\"\n\n");
@@ -566,6 +1001,7 @@ impl UnitDerivation {
             &self.pname,
             &self.version,
             &self.features,
+            &self.cargo_env,
         ));
         script.push('\n');
 
@@ -577,6 +1013,17 @@ impl UnitDerivation {
             self.manifest_dir
         );
 
+        // Allow unstable features on a stable toolchain for crates that need them
+        // (the standard library always does)
+        if self.rustc_bootstrap || self.is_std {
+            script.push_str("export RUSTC_BOOTSTRAP=1\n");
+        }
+
+        // Collect coverage profiles from anything run later in the build (e.g. a checkPhase)
+        if self.coverage {
+            script.push_str("export LLVM_PROFILE_FILE=\"$out/profraw/%p-%m.profraw\"\n");
+        }
+
         // Read build script outputs if this unit depends on a build script
         if let Some(ref bs_ref) = self.build_script_ref {
             script.push('\n');
@@ -586,38 +1033,34 @@ impl UnitDerivation {
             shell_var.push_str(&bs_ref.run_drv_var);
             shell_var.push('}');
             script.push_str(&BuildScriptOutput::generate_nix_flag_reader(&shell_var));
+            for var in &self.out_dir_env {
+                let _ = writeln!(script, "export {var}={shell_var}/out-dir");
+            }
             script.push('\n');
         }
 
-        // Set up proc-macro path variables by locating the dylib in the output
-        for dep in &self.deps {
-            if dep.is_proc_macro {
-                let var_name = format!(
-                    "PROCMACRO_{}",
-                    dep.lib_name.to_uppercase().replace('-', "_")
-                );
-                script.push_str(&var_name);
-                script.push_str("=\"$(find ${");
-                script.push_str(&dep.nix_var);
-                script.push_str("}/lib -type f -name 'lib");
-                script.push_str(&dep.lib_name);
-                script.push_str(".*' -print -quit)\"\n");
-                script.push_str("[ -n \"$");
-                script.push_str(&var_name);
-                script.push_str("\" ] || { echo \"Proc-macro not found: ");
-                script.push_str(&dep.lib_name);
-                script.push_str("\"; exit 1; }\n");
-            }
+        self.write_dep_lookups(&mut script);
+
+        // Scale codegen units to the builder's cores. NIX_BUILD_CORES may be
+        // unset or 0 ("all cores"), neither of which rustc accepts.
+        if self.codegen_units_from_cores {
+            script.push_str(
+                "CODEGEN_UNITS=\"''${NIX_BUILD_CORES:-0}\"\n\
+                 [ \"$CODEGEN_UNITS\" -gt 0 ] 2>/dev/null || CODEGEN_UNITS=\"$(nproc 2>/dev/null || echo 1)\"\n",
+            );
         }
 
         // Debug: enable command tracing to see the actual rustc command
-        script.push_str("set -x\n");
+        if self.trace {
+            script.push_str("set -x\n");
+        }
 
         // Remap build directory paths to a stable prefix for reproducibility.
         // The Nix sandbox builds in a temp directory like /nix/var/nix/builds/nix-XXXXX
         // which gets embedded in proc-macro dylib metadata. Remapping to $out ensures
         // the embedded paths are stable across rebuilds.
-        script.push_str("rustc --remap-path-prefix=\"$(pwd)\"=\"$out\" \\\n");
+        script.push_str(&self.rustc_command);
+        script.push_str(" --remap-path-prefix=\"$(pwd)\"=\"$out\" \\\n");
 
         // Add each flag on its own line for readability
         for arg in self.rustc_flags.args() {
@@ -625,22 +1068,43 @@ impl UnitDerivation {
             script.push_str(&crate::shell::quote_arg(arg));
             script.push_str(" \\\n");
         }
+        if self.codegen_units_from_cores {
+            script.push_str("  -C codegen-units=\"$CODEGEN_UNITS\" \\\n");
+        }
+
+        // Rust dylib dependencies: link std dynamically and find the dylibs at runtime.
+        // Transitive dylibs need no `--extern`; rustc finds them through -L below.
+        let has_dylib_deps = !self.dylib_deps.is_empty();
+        if (self.prefer_dynamic || has_dylib_deps) && !self.is_proc_macro {
+            script.push_str("  -C prefer-dynamic \\\n");
+            // The dylibs link std dynamically, so find it in the toolchain at runtime
+            // (with `prefer_dynamic` alone, only when the profile sets `rpath = true`)
+            if self.rpath || has_dylib_deps {
+                let _ = write!(
+                    script,
+                    "  -C link-arg=-Wl,-rpath,\"$({} --print target-libdir",
+                    self.rustc_command
+                );
+                if let Some(target) = self.rustc_flags.target() {
+                    let _ = write!(script, " --target={target}");
+                }
+                script.push_str(")\" \\\n");
+            }
+        }
+        for nix_var in &self.dylib_deps {
+            let _ = writeln!(script, "  -C link-arg=-Wl,-rpath,${{{nix_var}}}/lib \\");
+        }
 
         // Add -L library search paths for ALL dependencies (direct and transitive).
         // This is required because when rustc loads a dependency's rlib (e.g., http),
         // it needs to resolve THAT crate's dependencies (e.g., bytes) via -L search paths.
         //
-        // Add -L for direct deps first (avoid format! - write directly)
-        for dep in &self.deps {
-            script.push_str("  -L dependency=${");
-            script.push_str(&dep.nix_var);
-            script.push_str("}/lib \\\n");
-        }
-        // Add -L for transitive deps (lib_search_deps)
-        for (lib_dep, _lib_name) in &self.lib_search_deps {
-            script.push_str("  -L dependency=${");
-            script.push_str(lib_dep);
-            script.push_str("}/lib \\\n");
+        // Direct proc-macros are passed by path via `$PROCMACRO_*` instead. Transitive
+        // ones stay searchable: a dependency that re-exports a proc-macro (e.g. serde
+        // with `derive`) makes rustc load the macro's dylib through -L.
+        //
+        for arg in self.dep_search_args() {
+            let _ = writeln!(script, "  {arg} \\");
         }
 
         // Proc-macro crates need --extern proc_macro (compiler-provided crate)
@@ -648,6 +1112,16 @@ impl UnitDerivation {
             script.push_str("  --extern proc_macro \\\n");
         }
 
+        // Standard library crates are built like the sysroot: unstable unless marked stable
+        if self.is_std {
+            script.push_str("  -Z force-unstable-if-unmarked \\\n");
+        }
+
+        // `--extern noprelude:` is unstable
+        if self.deps.iter().any(|d| d.noprelude) {
+            script.push_str("  -Z unstable-options \\\n");
+        }
+
         // Add --extern flags for each dependency
         // Note: extern_crate_name is the alias (used in --extern name=), while
         // lib_name is the actual library filename on disk (used in path to .rlib)
@@ -655,26 +1129,8 @@ impl UnitDerivation {
         // Always emit --extern for direct dependencies. Rustc needs explicit --extern
         // to resolve `extern crate foo;` or `use foo::...` in the source code.
         // Transitive deps (those only needed by our deps) are resolved via -L search.
-        for dep in &self.deps {
-            script.push_str("  --extern ");
-            if dep.is_proc_macro {
-                // Proc-macros use the variable set above
-                script.push_str(&dep.extern_crate_name);
-                script.push_str("=\"$PROCMACRO_");
-                script.push_str(&dep.lib_name.to_uppercase().replace('-', "_"));
-                script.push('"');
-            } else {
-                // Regular dependencies use .rlib
-                script.push_str(&dep.extern_crate_name);
-                script.push_str("=${");
-                script.push_str(&dep.nix_var);
-                script.push_str("}/lib/lib");
-                script.push_str(&dep.lib_name);
-                script.push('-');
-                script.push_str(&dep.identity_hash);
-                script.push_str(".rlib");
-            }
-            script.push_str(" \\\n");
+        for arg in self.dep_extern_args() {
+            let _ = writeln!(script, "  {arg} \\");
         }
 
         // Add source file
@@ -683,14 +1139,21 @@ impl UnitDerivation {
         script.push_str(" \\\n");
 
         // Add output options
-        if self.crate_types.iter().any(|t| t == "bin") {
+        if self.metadata_only {
+            // Check builds: metadata only, no codegen (applies to bins too)
+            let _ = writeln!(script, "  --out-dir {build_dir} \\");
+            script.push_str("  --emit=dep-info,metadata \\\n");
+        } else if self.has_mixed_bin_crate_types() {
+            // rustc ignores -o with several crate types; the binary lands in --out-dir
+            // as `{crate_name}-{hash}`, next to the library files
+            let _ = writeln!(script, "  --out-dir {build_dir} \\");
+            script.push_str("  --emit=dep-info,metadata,link \\\n");
+        } else if self.crate_types.iter().any(|t| t == "bin") {
             // Binaries use -o for direct output
-            script.push_str("  -o build/");
-            script.push_str(&self.pname);
-            script.push_str(" \\\n");
+            let _ = writeln!(script, "  -o {build_dir}/{} \\", self.pname);
         } else {
             // Libraries use --out-dir to produce output files
-            script.push_str("  --out-dir build \\\n");
+            let _ = writeln!(script, "  --out-dir {build_dir} \\");
             // Proc-macros: emit only dep-info,link (metadata embedded in dylib)
             // Regular libs: emit dep-info,metadata,link (rmeta needed for dependents)
             if self.is_proc_macro {
@@ -706,29 +1169,246 @@ impl UnitDerivation {
         script
     }
 
+    /// Locates proc-macro and Rust dylib dependencies in their outputs, setting
+    /// the `$PROCMACRO_*` and `$DYLIB_*` variables [`Self::dep_extern_args`] uses.
+    fn write_dep_lookups(&self, script: &mut String) {
+        // Proc-macros: locate the dylib in the output
+        for dep in &self.deps {
+            if dep.is_proc_macro {
+                let var_name = format!(
+                    "PROCMACRO_{}",
+                    dep.lib_name.to_uppercase().replace('-', "_")
+                );
+                script.push_str(&var_name);
+                script.push_str("=\"$(find ${");
+                script.push_str(&dep.nix_var);
+                script.push_str("}/lib -type f -name 'lib");
+                script.push_str(&dep.lib_name);
+                script.push_str(".*' -print -quit)\"\n");
+                script.push_str("[ -n \"$");
+                script.push_str(&var_name);
+                script.push_str("\" ] || { echo \"Proc-macro not found: ");
+                script.push_str(&dep.lib_name);
+                script.push_str("\"; exit 1; }\n");
+                if self.debug_procmacro {
+                    script.push_str("echo \"DEBUG: ");
+                    script.push_str(&var_name);
+                    script.push_str("=$");
+                    script.push_str(&var_name);
+                    script.push_str("\"\nls -la ${");
+                    script.push_str(&dep.nix_var);
+                    script.push_str("}/lib\n");
+                }
+            }
+        }
+
+        // Set up Rust dylib path variables (lib{name}-{hash}.so or .dylib depending on platform)
+        for dep in &self.deps {
+            if dep.is_dylib {
+                let var_name = format!("DYLIB_{}", dep.lib_name.to_uppercase().replace('-', "_"));
+                let _ = writeln!(
+                    script,
+                    "{var_name}=\"$(find ${{{nix_var}}}/lib -type f \\( -name 'lib{lib}-{hash}.so' -o -name 'lib{lib}-{hash}.dylib' \\) -print -quit)\"",
+                    nix_var = dep.nix_var,
+                    lib = dep.lib_name,
+                    hash = dep.identity_hash,
+                );
+                let _ = writeln!(
+                    script,
+                    "[ -n \"${var_name}\" ] || {{ echo \"Dylib not found: {}\"; exit 1; }}",
+                    dep.lib_name
+                );
+            }
+        }
+    }
+
+    /// Returns the `-L dependency=` search paths for all non-proc-macro dependencies,
+    /// direct and transitive.
+    fn dep_search_args(&self) -> Vec<String> {
+        self.deps
+            .iter()
+            .filter(|d| !d.is_proc_macro)
+            .map(|dep| &dep.nix_var)
+            .chain(
+                self.lib_search_deps
+                    .iter()
+                    .map(|(lib_dep, _lib_name)| lib_dep),
+            )
+            .map(|nix_var| format!("-L dependency=${{{nix_var}}}/lib"))
+            .collect()
+    }
+
+    /// Returns the `--extern` flag for each direct dependency.
+    ///
+    /// Proc-macros and Rust dylibs refer to the variables set by
+    /// [`Self::write_dep_lookups`].
+    fn dep_extern_args(&self) -> Vec<String> {
+        self.deps
+            .iter()
+            .map(|dep| {
+                let var_suffix = dep.lib_name.to_uppercase().replace('-', "_");
+                let name = &dep.extern_crate_name;
+                if dep.is_proc_macro {
+                    format!("--extern {name}=\"$PROCMACRO_{var_suffix}\"")
+                } else if dep.is_dylib {
+                    format!("--extern {name}=\"$DYLIB_{var_suffix}\"")
+                } else {
+                    // Regular dependencies use .rlib (.rmeta when the dependency was only checked)
+                    // build-std crates must not be injected into the prelude
+                    format!(
+                        "--extern {}{name}=${{{}}}/lib/lib{}-{}.{}",
+                        if dep.noprelude { "noprelude:" } else { "" },
+                        dep.nix_var,
+                        dep.lib_name,
+                        dep.identity_hash,
+                        if dep.metadata_only { "rmeta" } else { "rlib" },
+                    )
+                }
+            })
+            .collect()
+    }
+
+    /// Generates a `mkShell` expression for developing this crate interactively.
+    ///
+    /// The shell exports the unit's rustc flags as `$RUSTC_ARGS` (build script
+    /// flags included) and its dependencies' `-L`/`--extern` flags as
+    /// `$RUSTC_DEP_ARGS`, along with the environment the build phase sets, so
+    /// `rustc $RUSTC_ARGS $RUSTC_DEP_ARGS <src>` compiles it like the derivation.
+    pub fn devshell(&self) -> String {
+        let mut attrs = NixAttrSet::new();
+        attrs.string("name", &format!("{}-devshell", self.pname));
+
+        let mut native_inputs = vec![self.toolchain_var.clone()];
+        native_inputs.extend(self.extra_native_build_inputs.iter().cloned());
+        attrs.expr_list("nativeBuildInputs", &native_inputs);
+
+        attrs.string("RUSTC_ARGS", &self.rustc_flags.to_shell_string());
+
+        let mut hook =
+            generate_cargo_pkg_exports(&self.pname, &self.version, &self.features, &self.cargo_env);
+        let _ = writeln!(hook, "export CARGO_MANIFEST_DIR=\"{}\"", self.manifest_dir);
+        if self.rustc_bootstrap || self.is_std {
+            hook.push_str("export RUSTC_BOOTSTRAP=1\n");
+        }
+        if let Some(ref bs_ref) = self.build_script_ref {
+            let shell_var = format!("${{{}}}", bs_ref.run_drv_var);
+            hook.push_str("BUILD_SCRIPT_FLAGS=\"\"\n");
+            hook.push_str(&BuildScriptOutput::generate_nix_flag_reader(&shell_var));
+            for var in &self.out_dir_env {
+                let _ = writeln!(hook, "export {var}={shell_var}/out-dir");
+            }
+            hook.push_str("export RUSTC_ARGS=\"$RUSTC_ARGS$BUILD_SCRIPT_FLAGS\"\n");
+        }
+        self.write_dep_lookups(&mut hook);
+        // `echo` joins the arguments after the shell has expanded and unquoted them
+        hook.push_str("export RUSTC_DEP_ARGS=\"$(echo");
+        for arg in self.dep_search_args().iter().chain(&self.dep_extern_args()) {
+            let _ = write!(hook, " \\\n  {arg}");
+        }
+        hook.push_str(")\"\n");
+        let _ = write!(
+            hook,
+            "echo 'Build {}: {} $RUSTC_ARGS $RUSTC_DEP_ARGS {}'",
+            self.pname, self.rustc_command, self.src_path
+        );
+        attrs.multiline_interpolated("shellHook", &hook);
+
+        // Bound at the top level of the output, next to `default`
+        format!("pkgs.mkShell {}", attrs.render(1))
+    }
+
+    /// Returns whether the unit builds a binary alongside other crate types
+    /// (e.g. `["lib", "bin"]`).
+    fn has_mixed_bin_crate_types(&self) -> bool {
+        self.crate_types.len() > 1 && self.crate_types.iter().any(|t| t == "bin")
+    }
+
+    /// Generates the `postInstall` script linking `lib<name>.rlib` (`.rmeta` for
+    /// metadata-only builds) to the hashed library file.
+    ///
+    /// Dependents are unaffected: `--extern` names the hashed file, and rustc
+    /// canonicalizes paths found through `-L`, so the symlink and its target
+    /// are one candidate.
+    fn generate_stable_symlinks(&self) -> String {
+        let lib = format!("lib{}", self.crate_name);
+        let ext = if self.metadata_only { "rmeta" } else { "rlib" };
+        let hash = &self.identity_hash;
+        // Skip if it exists (CA-derivation reuse)
+        format!(
+            "[ -e \"$out/lib/{lib}.{ext}\" ] || ln -s {lib}-{hash}.{ext} \"$out/lib/{lib}.{ext}\""
+        )
+    }
+
     /// Generates the install phase script.
     fn generate_install_phase(&self) -> String {
         let mut script = String::with_capacity(200);
+        let build_dir = &self.build_dir;
+
+        // Move packed debuginfo to the debug output before copying the build dir
+        // Skip entirely if it exists (CA-derivation reuse)
+        if self.split_debuginfo_packed {
+            let _ = write!(
+                script,
+                r#"[ -d "$debug/lib/debug" ] || {{
+  mkdir -p $debug/lib/debug
+  for f in {build_dir}/*.dwp {build_dir}/*.dSYM; do
+    if [ -e "$f" ]; then mv "$f" $debug/lib/debug/; fi
+  done
+}}
+"#
+            );
+        }
 
-        if self.crate_types.iter().any(|t| t == "bin") {
+        if self.metadata_only {
+            // Check builds only produce .rmeta (and .d) files
+            // Skip entirely if $out/lib exists (CA-derivation reuse)
+            let _ = write!(
+                script,
+                r#"[ -d "$out/lib" ] || {{
+  mkdir -p $out/lib
+  cp {build_dir}/*.rmeta $out/lib/
+  chmod 644 $out/lib/*
+}}"#
+            );
+        } else if self.has_mixed_bin_crate_types() {
+            // Binary to $out/bin under its target name, library files to $out/lib
+            // Skip entirely if $out/lib exists (CA-derivation reuse)
+            let crate_name = &self.crate_name;
+            let _ = write!(
+                script,
+                r#"[ -d "$out/lib" ] || {{
+  mkdir -p $out/bin $out/lib
+  for f in {build_dir}/{crate_name}-*; do
+    case "$f" in
+      *.d|*.dwp|*.dSYM) ;;
+      *) cp "$f" $out/bin/{pname} && chmod 755 $out/bin/{pname} ;;
+    esac
+  done
+  for f in {build_dir}/lib*; do
+    [ -f "$f" ] && cp "$f" $out/lib/
+  done
+  chmod 644 $out/lib/*
+}}"#,
+                pname = self.pname
+            );
+        } else if self.crate_types.iter().any(|t| t == "bin") {
             // Skip entirely if binary exists (CA-derivation reuse)
-            script.push_str("[ -f \"$out/bin/");
-            script.push_str(&self.pname);
-            script.push_str("\" ] || {\n  mkdir -p $out/bin\n  cp build/");
-            script.push_str(&self.pname);
-            script.push_str(" $out/bin/\n  chmod 755 $out/bin/");
-            script.push_str(&self.pname);
-            script.push_str("\n}");
+            let _ = write!(
+                script,
+                "[ -f \"$out/bin/{pname}\" ] || {{\n  mkdir -p $out/bin\n  cp {build_dir}/{pname} $out/bin/\n  chmod 755 $out/bin/{pname}\n}}",
+                pname = self.pname
+            );
         } else {
             // For libraries and proc-macros, copy all outputs from --out-dir
             // This includes .rlib, .rmeta, .d files, and .dylib/.so for proc-macros
             // Skip entirely if $out/lib exists (CA-derivation reuse)
             // For proc-macro dylibs on macOS, fix the install name so rustc can load them
             // Dylibs need execute permission (755) to be dlopen'd
-            script.push_str(
-                r#"[ -d "$out/lib" ] || {
+            let _ = write!(
+                script,
+                r#"[ -d "$out/lib" ] || {{
   mkdir -p $out/lib
-  cp build/* $out/lib/
+  cp {build_dir}/* $out/lib/
   # Set permissions: 755 for shared libs (dylib/so), 644 for others
   for f in $out/lib/*; do
     case "$f" in
@@ -748,7 +1428,7 @@ impl UnitDerivation {
       [ -f "$dylib" ] && /usr/bin/codesign --force --sign - "$dylib"
     done
   fi
-}"#,
+}}"#
             );
         }
 
@@ -757,7 +1437,10 @@ impl UnitDerivation {
 }
 
 /// Configuration for the Nix code generator.
-#[derive(Debug, Clone, Default)]
+///
+/// Deserializable (e.g. from a TOML config file); missing fields take their defaults.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
 pub struct NixGenConfig {
     /// The workspace root path (for source remapping).
     pub workspace_root: String,
@@ -765,6 +1448,10 @@ pub struct NixGenConfig {
     /// Whether to include content-addressed derivation attributes.
     pub content_addressed: bool,
 
+    /// `outputHashAlgo` of content-addressed derivations: `sha256` (default),
+    /// `sha512`, or `sha1`.
+    pub ca_hash_algo: String,
+
     /// Whether cross-compilation is enabled.
     /// When true, proc-macros and build scripts use `hostRustToolchain`.
     pub cross_compiling: bool,
@@ -779,721 +1466,6681 @@ pub struct NixGenConfig {
     /// This ensures derivation names change when the Rust toolchain changes,
     /// preventing stale CA output reuse across nightly versions.
     pub toolchain_hash: Option<String>,
-}
 
-impl NixGenConfig {
-    /// Creates a config for cross-compilation.
-    pub fn with_cross_compilation(mut self, host: &str, target: &str) -> Self {
-        self.cross_compiling = true;
-        self.host_platform = Some(host.to_string());
-        self.target_platform = Some(target.to_string());
-        self
-    }
+    /// Whether to export `RUSTC_BOOTSTRAP=1` when compiling units.
+    pub rustc_bootstrap: bool,
+
+    /// Per-package overrides for `rustc_bootstrap`, keyed by package name.
+    pub rustc_bootstrap_packages: rustc_hash::FxHashMap<String, bool>,
 
-    /// Returns the toolchain variable name for a given unit.
-    ///
-    /// - `"hostRustToolchain"` for proc-macros and build scripts when cross-compiling
-    /// - `"rustToolchain"` otherwise
-    pub fn toolchain_var_for_unit(&self, unit: &Unit) -> &'static str {
-        if self.cross_compiling && crate::proc_macro::requires_host_toolchain(unit) {
-            "hostRustToolchain"
-        } else {
-            "rustToolchain"
-        }
-    }
-}
+    /// Additional `(nix_var, root_path)` source roots for path dependencies
+    /// outside the workspace. Each `nix_var` becomes a required argument of
+    /// the generated expression.
+    pub extra_sources: Vec<(String, String)>,
 
-/// Generates Nix code from a unit graph.
-pub struct NixGenerator {
-    config: NixGenConfig,
-}
+    /// Extra `nativeBuildInputs` Nix expressions per package name, added to
+    /// that package's compile and build script run derivations.
+    pub extra_native_build_inputs_per_package: rustc_hash::FxHashMap<String, Vec<String>>,
 
-impl NixGenerator {
-    /// Creates a new generator with the given configuration.
-    pub fn new(config: NixGenConfig) -> Self {
-        Self { config }
-    }
+    /// Extra variables per package name that point at its build script's
+    /// output directory, alongside `OUT_DIR`. For older build scripts that
+    /// write to a directory named by their own variable, and crates that
+    /// `include!` generated files through it.
+    pub build_script_out_dir_env: rustc_hash::FxHashMap<String, Vec<String>>,
 
-    /// Generates a complete Nix expression for the unit graph.
-    pub fn generate(&self, graph: &UnitGraph) -> String {
-        let mut out = String::new();
+    /// Packages whose build scripts are known to access the network, which the
+    /// Nix sandbox blocks. Generation warns about them (see
+    /// [`Self::network_build_script_warnings`]).
+    pub network_build_scripts: Vec<String>,
 
-        // Header
-        out.push_str("# Generated by nix-cargo-unit\n");
-        out.push_str("# Do not edit manually\n\n");
+    /// Whether to run the build scripts in `network_build_scripts` outside the
+    /// sandbox (`__noChroot = true`). Impure, and only honored by Nix with
+    /// `sandbox = relaxed`; prefer vendoring whatever they download.
+    pub allow_network_build_scripts: bool,
 
-        // Function signature
-        // Always include hostRustToolchain with default for compatibility with lib.nix
-        // extraNativeBuildInputs allows passing protobuf, cmake, etc. for build scripts
-        // vendorDir allows passing pre-vendored crate sources for registry deps
-        out.push_str("{ pkgs, rustToolchain, hostRustToolchain ? rustToolchain, src, extraNativeBuildInputs ? [], vendorDir ? null }:\n\n");
+    /// Packages whose build scripts probe the host system and cannot be fixed
+    /// any other way. Last resort: their run derivations escape the sandbox
+    /// (`__noChroot = true`) with the cc-wrapper's purity checks off
+    /// (`NIX_ENFORCE_PURITY = "0"`), so their output depends on the builder.
+    /// Only honored by Nix with `sandbox = relaxed`, and always warned about
+    /// (see [`Self::impure_build_script_warnings`]).
+    pub impure_build_scripts: Vec<String>,
 
-        // Let block
-        out.push_str("let\n");
+    /// `requiredSystemFeatures` for every compile derivation (e.g. `big-parallel`).
+    pub required_system_features: Vec<String>,
 
-        // Helper function for creating unit derivations
-        out.push_str("  mkUnit = attrs: pkgs.stdenv.mkDerivation (attrs // {\n");
-        out.push_str("    dontUnpack = true;\n");
-        out.push_str("    dontConfigure = true;\n");
-        out.push_str("  });\n\n");
+    /// Per-package overrides for `required_system_features`, keyed by package name.
+    pub required_system_features_per_package: rustc_hash::FxHashMap<String, Vec<String>>,
 
-        // DEDUPLICATION: Units with the same (pkg_id, target_name, mode) should map to a single
-        // derivation, even if they have different features. Build a mapping from unit index
-        // to "canonical" unit index.
-        //
-        // This is necessary because Cargo's unit graph can contain multiple entries for the
-        // same crate with different feature sets (e.g., serde_core with features [alloc, std]
-        // vs [alloc, default, rc, std]). Without deduplication, each feature set gets a
-        // different identity hash, cascading through the dependency tree and causing rustc
-        // SVH mismatches at compile time.
-        //
-        // Strategy: For units with the same (pkg_id, target_name, mode), pick the one with
-        // the most features as canonical. This ensures all code sees a superset of features.
-        let canonical_index: Vec<usize> = {
-            // Key: (pkg_id, target_name, mode) - ignores features for deduplication
-            let mut key_to_candidates: rustc_hash::FxHashMap<(String, String, String), Vec<usize>> =
-                rustc_hash::FxHashMap::default();
+    /// `hardeningDisable` for every compile and build script run derivation
+    /// (e.g. `fortify`), for crates that fail under Nixpkgs' hardened stdenv.
+    pub hardening_disable: Vec<String>,
 
-            // Collect all units with the same key
-            for (idx, unit) in graph.units.iter().enumerate() {
-                let key = (
-                    unit.pkg_id.clone(),
-                    unit.target.name.clone(),
-                    unit.mode.clone(),
-                );
-                key_to_candidates.entry(key).or_default().push(idx);
-            }
+    /// Per-package overrides for `hardening_disable`, keyed by package name.
+    pub hardening_disable_per_package: rustc_hash::FxHashMap<String, Vec<String>>,
 
-            // For each group, pick the unit with the most features as canonical
-            let mut idx_to_canonical: Vec<usize> = vec![0; graph.units.len()];
-            for candidates in key_to_candidates.values() {
-                // Find the candidate with the most features
-                let canonical_idx = *candidates
-                    .iter()
-                    .max_by_key(|&&idx| graph.units[idx].features.len())
-                    .unwrap();
+    /// Command used to invoke rustc, e.g. `sccache rustc` (default `rustc`).
+    pub rustc_command: Option<String>,
 
-                // Map all candidates to the canonical one
-                for &idx in candidates {
-                    idx_to_canonical[idx] = canonical_idx;
-                }
-            }
+    /// Nix expression for the stdenv units are built with, e.g.
+    /// `pkgs.pkgsMusl.stdenv` (default `pkgs.stdenv`). Build scripts also get
+    /// its C compiler. Parenthesize anything that is not a plain attribute path.
+    pub stdenv_expr: Option<String>,
 
-            idx_to_canonical
-        };
+    /// Overrides `-C embed-bitcode` for every unit (default: `yes` under LTO,
+    /// rustc's default otherwise). `false` is ignored for units built under LTO,
+    /// whose bitcode the LTO link needs.
+    pub embed_bitcode: Option<bool>,
 
-        // Pre-compute identity hashes and derivation names for all units (needed for dependency resolution)
-        //
-        // CRITICAL: Hashes must be computed in TOPOLOGICAL ORDER with dependency hashes included!
-        // This ensures rustc unification works correctly - when a dependency's hash changes,
-        // all dependents' hashes also change, matching how rustc embeds SVH into rlib metadata.
-        //
-        // NOTE: We use canonical_index to map dependency indices to their canonical form,
-        // ensuring duplicates get the same hash.
-        let identity_hashes: Vec<String> = {
-            let mut hashes: Vec<Option<String>> = vec![None; graph.units.len()];
-            let toolchain_hash = self.config.toolchain_hash.as_deref();
-
-            // Compute in topological order using DFS
-            fn compute_hash(
-                idx: usize,
-                graph: &UnitGraph,
-                hashes: &mut [Option<String>],
-                toolchain_hash: Option<&str>,
-                canonical_index: &[usize],
-            ) -> String {
-                // Use canonical index for looking up cached hashes
-                let canonical_idx = canonical_index[idx];
-                if let Some(ref h) = hashes[canonical_idx] {
-                    return h.clone();
-                }
-
-                // First, compute hashes for all dependencies (recursively)
-                // Use canonical unit to ensure consistent dependency set across duplicates
-                let canonical_unit = &graph.units[canonical_idx];
-                let dep_hashes: Vec<String> = canonical_unit
-                    .dependencies
-                    .iter()
-                    .filter_map(|dep| {
-                        // Skip build script run units - they don't contribute to binary identity
-                        graph.units.get(dep.index).and_then(|dep_unit| {
-                            if dep_unit.mode == "run-custom-build" {
-                                None
-                            } else {
-                                // Use canonical index for recursive calls
-                                Some(compute_hash(
-                                    dep.index,
-                                    graph,
-                                    hashes,
-                                    toolchain_hash,
-                                    canonical_index,
-                                ))
-                            }
-                        })
-                    })
-                    .collect();
-
-                // Now compute this unit's hash with dependency hashes included
-                let dep_refs: Vec<&str> = dep_hashes.iter().map(String::as_str).collect();
-                let mut hash = canonical_unit.identity_hash_with_deps(&dep_refs);
-
-                // Include toolchain hash to prevent stale CA outputs when rustc changes
-                // This ensures derivation names change when the Nix toolchain store path changes
-                if let Some(th) = toolchain_hash {
-                    use sha2::Digest as _;
-                    let mut hasher = sha2::Sha256::new();
-                    hasher.update(hash.as_bytes());
-                    hasher.update(b"\0");
-                    hasher.update(th.as_bytes());
-                    let combined = hasher.finalize();
-                    hash = format!(
-                        "{:016x}",
-                        u64::from_be_bytes(combined[..8].try_into().unwrap())
-                    );
-                }
+    /// Whether to render derivations compactly: one line per attribute set and
+    /// rustc flags joined onto one line, for smaller committed files.
+    pub compact: bool,
 
-                // Store at canonical index so all duplicates share the same hash
-                hashes[canonical_idx] = Some(hash.clone());
-                hash
-            }
+    /// Scratch directory rustc writes to before installPhase copies outputs
+    /// to `$out` (default `build`). Change it if it collides with a crate's files.
+    pub build_dir: String,
 
-            // Compute hashes for all units
-            for i in 0..graph.units.len() {
-                compute_hash(i, graph, &mut hashes, toolchain_hash, &canonical_index);
-            }
+    /// Extern crate name overrides, from the name in the graph to the name
+    /// passed as `--extern name=`. The rlib path keeps the library's real name.
+    pub extern_renames: rustc_hash::FxHashMap<String, String>,
 
-            // Map each unit to its canonical hash (duplicates share the same hash)
-            (0..graph.units.len())
-                .map(|i| hashes[canonical_index[i]].clone().unwrap())
-                .collect()
-        };
+    /// Extra `--cfg` values (e.g. `tokio_unstable`) for every unit except
+    /// host-only code (proc-macros and build scripts).
+    pub extra_cfgs: Vec<String>,
 
-        // Derivation names: all duplicates map to the same name (canonical unit's name)
-        let drv_names: Vec<String> = (0..graph.units.len())
-            .map(|i| {
-                let canonical_idx = canonical_index[i];
-                let u = &graph.units[canonical_idx];
-                let hash = &identity_hashes[i];
-                let name = &u.target.name;
-                let version = u.package_version().unwrap_or("0.0.0");
-                format!("{name}-{version}-{hash}")
-            })
-            .collect();
+    /// Extra `-C key=value` codegen options (e.g. `relro-level=full`) for every
+    /// unit except host-only code (proc-macros and build scripts).
+    pub extra_codegen: Vec<(String, String)>,
 
-        // Compute transitive dependencies for each unit (using canonical indices)
-        // This is needed for -L library search paths (rustc needs to find all transitive rlibs)
-        // Uses Rc<FxHashSet> to avoid O(n²) cloning - computed sets are shared via Rc
-        //
-        // IMPORTANT: We map all dependency indices to their canonical form to ensure
-        // that duplicate units result in the same transitive dep set.
-        let transitive_deps: Vec<Rc<rustc_hash::FxHashSet<usize>>> = {
-            type FxSet = rustc_hash::FxHashSet<usize>;
+    /// Lint level arguments (e.g. `["-D", "warnings"]`) passed to rustc for
+    /// local units only; external dependencies get `--cap-lints warn` instead.
+    /// As in rustc, a later level for the same lint wins.
+    pub lint_levels: Vec<String>,
 
-            // Build direct dependency map (unit index -> Vec of CANONICAL dep indices)
-            let direct_deps: Vec<Vec<usize>> = graph
-                .units
-                .iter()
-                .enumerate()
-                .map(|(i, _unit)| {
-                    // Use canonical unit's dependencies for consistency
-                    let canonical_unit = &graph.units[canonical_index[i]];
-                    canonical_unit
-                        .dependencies
-                        .iter()
-                        .filter_map(|d| {
-                            // Skip build script run units for transitive deps
-                            graph
-                                .units
-                                .get(d.index)
-                                .filter(|dep_unit| dep_unit.mode != "run-custom-build")
-                                // Map to canonical index!
-                                .map(|_| canonical_index[d.index])
-                        })
-                        .collect()
-                })
-                .collect();
+    /// Whether to allow the lints in [`crate::rustc_flags::COMPAT_ALLOWED_LINTS`]
+    /// on every unit (the default). Disable for toolchains older than those
+    /// lints, which would otherwise report them as unknown.
+    pub allow_compat_lints: bool,
 
-            // Compute transitive closure for each unit using DFS with Rc sharing
-            fn transitive_closure(
-                unit_idx: usize,
-                direct_deps: &[Vec<usize>],
-                cache: &mut [Option<Rc<FxSet>>],
-                canonical_index: &[usize],
-            ) -> Rc<FxSet> {
-                // Use canonical index for caching
-                let canonical_idx = canonical_index[unit_idx];
-                if let Some(cached) = &cache[canonical_idx] {
-                    return Rc::clone(cached); // Cheap Rc clone, not set clone
-                }
+    /// Variables from a cargo config `[env]` table, exported when compiling
+    /// every unit and when running build scripts.
+    pub cargo_env: Vec<CargoEnvVar>,
 
-                // Pre-size based on direct deps (heuristic)
-                let mut result = FxSet::with_capacity_and_hasher(
-                    direct_deps[canonical_idx].len() * 4,
-                    Default::default(),
-                );
-                for &dep_idx in &direct_deps[canonical_idx] {
-                    // dep_idx is already canonical (mapped above)
-                    result.insert(dep_idx);
-                    // Recursively add transitive deps
-                    let trans = transitive_closure(dep_idx, direct_deps, cache, canonical_index);
-                    result.extend(trans.iter().copied());
-                }
-                let rc = Rc::new(result);
-                cache[canonical_idx] = Some(Rc::clone(&rc));
-                rc
-            }
+    /// Linker passed as `-C linker=` to linked units (bins, tests, cdylibs).
+    pub linker: Option<String>,
+
+    /// Linker flavor passed as `-C linker-flavor=` to linked units.
+    pub linker_flavor: Option<String>,
+
+    /// Whether to trace the rustc invocation with `set -x` (off by default to reduce log noise).
+    pub trace: bool,
+
+    /// Whether to log proc-macro dylib lookup diagnostics (`DEBUG:` echo and `ls -la`).
+    pub debug_procmacro: bool,
 
-            let mut cache: Vec<Option<Rc<FxSet>>> = vec![None; graph.units.len()];
-            (0..graph.units.len())
-                .map(|i| transitive_closure(i, &direct_deps, &mut cache, &canonical_index))
-                .collect()
-        };
+    /// Whether to instrument units for source-based coverage (`-C instrument-coverage`),
+    /// as `cargo llvm-cov` does. Proc-macros, build scripts, and std are not instrumented.
+    pub coverage: bool,
 
-        // First pass: identify build script RUN units and their corresponding COMPILE units
-        // Build a map from run unit index -> BuildScriptRef for units that depend on build scripts
-        //
-        // Build scripts appear as two units in the graph:
-        // 1. COMPILE unit: mode="build", kind=["custom-build"] - compiles build.rs with its deps
-        // 2. RUN unit: mode="run-custom-build" - executes the compiled binary
-        //
-        // The RUN unit depends on the COMPILE unit. We process COMPILE units as normal
-        // derivations (to get their dependencies like tonic-build), and generate special
-        // RUN derivations that execute the binary and capture cargo: directives.
-        let mut build_script_run_derivations: Vec<String> = Vec::new();
-        let mut build_script_refs: rustc_hash::FxHashMap<usize, BuildScriptRef> =
-            rustc_hash::FxHashMap::default();
+    /// Whether to pass `-C codegen-units=$NIX_BUILD_CORES` (read when the derivation
+    /// builds) instead of the profile's fixed value.
+    ///
+    /// Off by default: code generation then depends on the builder's core count,
+    /// so outputs are no longer bit-for-bit reproducible across machines.
+    pub codegen_units_from_cores: bool,
+
+    /// Whether binaries link the toolchain's std dylib (`-C prefer-dynamic`)
+    /// instead of embedding std, for smaller binaries. The resulting binaries
+    /// need `libstd-*.so` at runtime (e.g. from the toolchain's `lib`), which
+    /// whatever runs them must provide.
+    pub prefer_dynamic: bool,
+
+    /// Whether binaries built with debuginfo set `separateDebugInfo`, moving
+    /// their debug symbols into a `debug` output so the main output's closure
+    /// stays small. Units with packed split debuginfo already have a `debug`
+    /// output and are left alone.
+    pub separate_debug_info: bool,
+
+    /// Whether library derivations also provide a hash-free `lib<name>.rlib`
+    /// symlink to `lib<name>-<hash>.rlib`, for tools that expect canonical names.
+    pub emit_stable_symlinks: bool,
+
+    /// Whether to build target units metadata-only (`--emit=metadata`), like `cargo check`.
+    /// This covers tests and benches, which could not link against `.rmeta` dependencies.
+    /// Proc-macros, build scripts, and their dependencies are still fully compiled.
+    pub check_only: bool,
+
+    /// Number of SHA-256 bytes kept in identity hashes (4..=32, default 8).
+    ///
+    /// Changing this renames every derivation and therefore invalidates all
+    /// cached outputs. [`NixGenConfig::validate`] rejects other lengths; the
+    /// generator itself clamps them into range (see [`NixGenConfig::hash_len`]).
+    pub hash_len_bytes: usize,
+
+    /// Whether a source path that cannot be remapped into the Nix source tree
+    /// is a generation error instead of falling back to the absolute path.
+    pub strict_sandbox: bool,
+
+    /// Whether to skip units that cannot be generated (and everything depending
+    /// on them) instead of failing, listing them in a trailing comment.
+    pub keep_going: bool,
+
+    /// Whether to start the output with the `# Generated by nix-cargo-unit` comment.
+    pub emit_header: bool,
+
+    /// Whether to emit a `tests` attrset of every test harness unit, roots or
+    /// not, keyed by target name (`package-target` where names collide).
+    pub emit_tests_attrset: bool,
+
+    /// Crate (package or target name) to emit a `devShell` for, with the
+    /// rustc flags its derivation uses (see [`UnitDerivation::devshell`]).
+    pub devshell: Option<String>,
+
+    /// Replaces the default `{ pkgs, rustToolchain, ... }` argument pattern of
+    /// the generated function. An empty string omits the function entirely,
+    /// leaving a bare `let ... in { ... }` for embedding in a larger expression.
+    pub function_args: Option<String>,
+
+    /// Whether to define `vendorDir` in the output from fetchers for every
+    /// registry and git crate, instead of taking it as an argument.
+    pub emit_fetchers: bool,
+
+    /// Registry crate checksums (from `Cargo.lock`) keyed by `name-version`,
+    /// used as fetcher hashes in place of placeholders.
+    pub crate_checksums: rustc_hash::FxHashMap<String, String>,
+}
 
-        // First pass: identify all build script RUN units and their info
-        // We need this map to wire up DEP_* variables between build scripts
-        struct BuildScriptRunInfo {
-            unit_index: usize,
-            package_name: String,
-            compile_dep_index: usize,
-            info: BuildScriptInfo,
+impl Default for NixGenConfig {
+    fn default() -> Self {
+        Self {
+            workspace_root: String::new(),
+            content_addressed: false,
+            ca_hash_algo: "sha256".to_string(),
+            cross_compiling: false,
+            target_platform: None,
+            host_platform: None,
+            toolchain_hash: None,
+            rustc_bootstrap: false,
+            rustc_bootstrap_packages: rustc_hash::FxHashMap::default(),
+            extra_sources: Vec::new(),
+            extra_native_build_inputs_per_package: rustc_hash::FxHashMap::default(),
+            build_script_out_dir_env: rustc_hash::FxHashMap::default(),
+            network_build_scripts: Vec::new(),
+            allow_network_build_scripts: false,
+            impure_build_scripts: Vec::new(),
+            required_system_features: Vec::new(),
+            required_system_features_per_package: rustc_hash::FxHashMap::default(),
+            hardening_disable: Vec::new(),
+            hardening_disable_per_package: rustc_hash::FxHashMap::default(),
+            rustc_command: None,
+            stdenv_expr: None,
+            embed_bitcode: None,
+            compact: false,
+            build_dir: "build".to_string(),
+            extern_renames: rustc_hash::FxHashMap::default(),
+            extra_cfgs: Vec::new(),
+            lint_levels: Vec::new(),
+            allow_compat_lints: true,
+            extra_codegen: Vec::new(),
+            cargo_env: Vec::new(),
+            linker: None,
+            linker_flavor: None,
+            trace: false,
+            debug_procmacro: false,
+            coverage: false,
+            codegen_units_from_cores: false,
+            prefer_dynamic: false,
+            separate_debug_info: false,
+            emit_stable_symlinks: false,
+            check_only: false,
+            hash_len_bytes: crate::unit_graph::DEFAULT_HASH_LEN_BYTES,
+            strict_sandbox: false,
+            keep_going: false,
+            emit_header: true,
+            emit_tests_attrset: false,
+            devshell: None,
+            function_args: None,
+            emit_fetchers: false,
+            crate_checksums: rustc_hash::FxHashMap::default(),
         }
-        let mut build_script_runs: Vec<BuildScriptRunInfo> = Vec::new();
-        let mut package_to_bs_run: rustc_hash::FxHashMap<String, usize> =
-            rustc_hash::FxHashMap::default();
-
-        for (i, unit) in graph.units.iter().enumerate() {
-            if unit.mode == "run-custom-build" {
-                // Skip duplicate units - only process canonical indices
-                if canonical_index[i] != i {
-                    continue;
-                }
+    }
+}
 
-                // This is a build script RUN unit - find its compile unit dependency
-                let compile_dep = unit.dependencies.iter().find(|dep| {
-                    graph.units.get(dep.index).is_some_and(|u| {
-                        u.mode == "build" && u.target.kind.contains(&"custom-build".to_string())
-                    })
-                });
+impl NixGenConfig {
+    /// Parses a configuration from TOML.
+    pub fn from_toml(s: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(s)
+    }
 
-                if let Some(compile_dep) = compile_dep {
-                    let info = BuildScriptInfo::from_unit(
-                        unit,
-                        &self.config.workspace_root,
-                        self.config.content_addressed,
-                    );
-                    if let Some(info) = info {
-                        let package_name = unit.package_name().to_string();
-                        package_to_bs_run.insert(package_name.clone(), build_script_runs.len());
-                        build_script_runs.push(BuildScriptRunInfo {
-                            unit_index: i,
-                            package_name,
-                            // Use canonical index for compile dep
-                            compile_dep_index: canonical_index[compile_dep.index],
-                            info,
-                        });
-                    }
-                }
-            }
+    /// Checks that configuration values are within supported ranges.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(4..=32).contains(&self.hash_len_bytes) {
+            return Err(format!(
+                "hash_len_bytes must be between 4 and 32, got {}",
+                self.hash_len_bytes
+            ));
+        }
+        // `.` and `..` would make installPhase copy the source tree or its parent
+        if matches!(self.build_dir.as_str(), "" | "." | "..")
+            || !self
+                .build_dir
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        {
+            return Err(format!(
+                "build_dir must be a directory name of [A-Za-z0-9_.-] other than `.` and `..`, \
+                 got {:?}",
+                self.build_dir
+            ));
+        }
+        if !["sha1", "sha256", "sha512"].contains(&self.ca_hash_algo.as_str()) {
+            return Err(format!(
+                "ca_hash_algo must be sha1, sha256, or sha512, got {:?}",
+                self.ca_hash_algo
+            ));
         }
+        Ok(())
+    }
 
-        // Second pass: for each build script RUN, find which other build scripts' outputs
-        // it should receive DEP_* variables from (based on library dependencies)
-        for bs_run in &build_script_runs {
-            let compile_drv_name = drv_names[bs_run.compile_dep_index].clone();
-            let compile_var = format!("units.\"{}\"", compile_drv_name);
+    /// Returns the identity hash length in bytes, `hash_len_bytes` clamped to
+    /// 4..=32 so configs that skipped [`Self::validate`] cannot overrun SHA-256.
+    pub fn hash_len(&self) -> usize {
+        self.hash_len_bytes.clamp(4, 32)
+    }
 
-            // Find dependency build script outputs:
-            // Look at the library unit for this package and collect build script outputs
-            // from its dependencies
-            let mut dep_bs_outputs: Vec<String> = Vec::new();
+    /// Creates a config for cross-compilation.
+    pub fn with_cross_compilation(mut self, host: &str, target: &str) -> Self {
+        self.cross_compiling = true;
+        self.host_platform = Some(host.to_string());
+        self.target_platform = Some(target.to_string());
+        self
+    }
 
-            // Find the library unit for this package (same pkg_id, mode="build", kind contains "lib")
-            let unit = &graph.units[bs_run.unit_index];
-            let lib_unit_idx = graph.units.iter().enumerate().find(|(_, u)| {
-                u.pkg_id == unit.pkg_id
-                    && u.mode == "build"
-                    && (u.target.kind.contains(&"lib".to_string())
-                        || u.target.kind.contains(&"rlib".to_string()))
-            });
+    /// Returns the toolchain variable name for a given unit.
+    ///
+    /// - `"hostRustToolchain"` when cross-compiling, for proc-macros, build
+    ///   scripts, and `host_dependency` units (only built for one of those)
+    /// - `"rustToolchain"` otherwise
+    pub fn toolchain_var_for_unit(&self, unit: &Unit, host_dependency: bool) -> &'static str {
+        if self.cross_compiling
+            && (host_dependency || crate::proc_macro::requires_host_toolchain(unit))
+        {
+            "hostRustToolchain"
+        } else {
+            "rustToolchain"
+        }
+    }
 
-            if let Some((_, lib_unit)) = lib_unit_idx {
-                // For each dependency of the library unit, check if it has a build script
-                for dep in &lib_unit.dependencies {
-                    if let Some(dep_unit) = graph.units.get(dep.index) {
-                        // If this dependency is a build script RUN, add it
-                        // Skip the current package's own build script to avoid self-reference
-                        if dep_unit.mode == "run-custom-build"
-                            && dep_unit.package_name() != bs_run.package_name
-                            && let Some(other_bs_run_idx) =
-                                package_to_bs_run.get(dep_unit.package_name())
-                        {
-                            let other_bs = &build_script_runs[*other_bs_run_idx];
-                            dep_bs_outputs
-                                .push(format!("units.\"{}\"", other_bs.info.run_drv_name));
-                        }
-                        // Also check if the dependency's package has a build script
-                        // (in case it's a lib unit that depends on another lib)
-                        // Skip the current package's own build script to avoid self-reference
-                        let dep_pkg_name = dep_unit.package_name();
-                        if dep_pkg_name != bs_run.package_name
-                            && let Some(other_bs_run_idx) = package_to_bs_run.get(dep_pkg_name)
-                        {
-                            let other_bs = &build_script_runs[*other_bs_run_idx];
-                            let run_var = format!("units.\"{}\"", other_bs.info.run_drv_name);
-                            if !dep_bs_outputs.contains(&run_var) {
-                                dep_bs_outputs.push(run_var);
-                            }
-                        }
-                    }
-                }
-            }
+    /// Returns the `--target` triple for a unit when cross-compiling.
+    ///
+    /// Uses [`Unit::target_triple`]. Proc-macros and build scripts run on the
+    /// host and never get a target, nor do units whose triple is the host's.
+    pub fn rustc_target_for_unit(&self, unit: &Unit) -> Option<String> {
+        if !self.cross_compiling || crate::proc_macro::requires_host_toolchain(unit) {
+            return None;
+        }
+        let target = unit.target_triple(self);
+        let host = self
+            .host_platform
+            .clone()
+            .unwrap_or_else(crate::unit_graph::host_triple);
+        (target != host).then_some(target)
+    }
 
-            // Generate run derivation with dependency build script outputs
-            build_script_run_derivations.push(format!(
-                "    \"{}\" = mkUnit {};\n",
-                bs_run.info.run_drv_name,
-                bs_run.info.run_derivation(&compile_var, &dep_bs_outputs)
-            ));
+    /// Returns whether `RUSTC_BOOTSTRAP=1` should be exported for a unit.
+    ///
+    /// A per-package entry in `rustc_bootstrap_packages` takes precedence over
+    /// the global `rustc_bootstrap` setting.
+    pub fn rustc_bootstrap_for_unit(&self, unit: &Unit) -> bool {
+        self.rustc_bootstrap_packages
+            .get(unit.package_name())
+            .copied()
+            .unwrap_or(self.rustc_bootstrap)
+    }
 
-            // Store the reference for units that depend on this build script
-            build_script_refs.insert(
-                bs_run.unit_index,
-                BuildScriptRef {
-                    run_drv_var: format!("units.\"{}\"", bs_run.info.run_drv_name),
-                    compile_drv_name,
-                    run_drv_name: bs_run.info.run_drv_name.clone(),
-                },
-            );
+    /// Returns the extra `--cfg` values applied to a unit.
+    pub fn extra_cfgs_for_unit(&self, unit: &Unit) -> &[String] {
+        if unit.is_proc_macro() || unit.is_build_script() {
+            &[]
+        } else {
+            &self.extra_cfgs
         }
+    }
 
-        // Generate derivations for each unit
-        out.push_str("  units = {\n");
+    /// Returns the extra `-C` codegen options applied to a unit.
+    pub fn extra_codegen_for_unit(&self, unit: &Unit) -> &[(String, String)] {
+        if unit.is_proc_macro() || unit.is_build_script() {
+            &[]
+        } else {
+            &self.extra_codegen
+        }
+    }
 
-        // First, output all build script RUN derivations
-        // (COMPILE derivations are generated as normal units in the main loop)
-        for drv_str in &build_script_run_derivations {
-            out.push_str(drv_str);
-            out.push('\n');
+    /// Returns the lint level arguments applied to a unit (none for external
+    /// dependencies and the standard library).
+    pub fn lint_levels_for_unit(&self, unit: &Unit) -> &[String] {
+        if unit.is_external_dependency() || unit.is_std {
+            &[]
+        } else {
+            &self.lint_levels
         }
+    }
 
-        for (i, unit) in graph.units.iter().enumerate() {
-            // Skip build script run units - they're already generated above
-            if unit.mode == "run-custom-build" {
-                continue;
-            }
+    /// Returns whether a unit is instrumented for coverage.
+    ///
+    /// Host-only code (proc-macros, build scripts) and the standard library
+    /// are never instrumented.
+    pub fn coverage_for_unit(&self, unit: &Unit) -> bool {
+        self.coverage && !unit.is_proc_macro() && !unit.is_build_script() && !unit.is_std
+    }
 
-            // Skip duplicate units - only generate for canonical indices
-            // Duplicates will reference the canonical unit's derivation via drv_names[i]
-            if canonical_index[i] != i {
-                continue;
-            }
+    /// Returns whether a unit's derivation sets `separateDebugInfo`.
+    ///
+    /// Only pure binaries qualify: the fixup strip would also hit the rlib of a
+    /// mixed lib+bin unit.
+    pub fn separate_debug_info_for_unit(&self, unit: &Unit) -> bool {
+        self.separate_debug_info
+            && unit.target.crate_types == ["bin"]
+            && unit.profile.debuginfo != crate::unit_graph::DebugInfo::None
+            && unit.profile.split_debuginfo.as_deref() != Some("packed")
+    }
 
-            let toolchain_var = self.config.toolchain_var_for_unit(unit);
-            let mut drv = UnitDerivation::from_unit(
-                unit,
-                &self.config.workspace_root,
-                self.config.content_addressed,
-                toolchain_var,
-                &drv_names[i],
-                &identity_hashes[i],
-                unit.is_external_dependency(),
-            );
+    /// Returns whether a unit's derivation gets a hash-free library symlink
+    /// (`emit_stable_symlinks`, for units that produce an rlib).
+    pub fn stable_symlinks_for_unit(&self, unit: &Unit) -> bool {
+        self.emit_stable_symlinks
+            && unit
+                .target
+                .crate_types
+                .iter()
+                .any(|t| t == "lib" || t == "rlib")
+    }
 
-            // Wire up dependencies, and detect if any dependency is a build script
-            for dep in &unit.dependencies {
-                if let Some(dep_unit) = graph.units.get(dep.index) {
-                    // Check if this dependency is a build script execution unit
-                    if dep_unit.mode == "run-custom-build" {
-                        // This unit depends on a build script - wire up the build script outputs
-                        if let Some(bs_ref) = build_script_refs.get(&dep.index) {
-                            drv.set_build_script_ref(bs_ref.clone());
-                        }
-                        // Don't add build script as a regular extern dependency
-                        continue;
-                    }
+    /// Returns the extra `nativeBuildInputs` configured for a unit's package.
+    pub fn extra_native_build_inputs_for_unit(&self, unit: &Unit) -> &[String] {
+        self.extra_native_build_inputs_per_package
+            .get(unit.package_name())
+            .map_or(&[], Vec::as_slice)
+    }
 
-                    let dep_drv_name = &drv_names[dep.index];
-                    // Get the actual library name from the dependency unit's target
-                    // This is the filename used for the .rlib (may differ from extern_crate_name if renamed)
-                    let lib_name = dep_unit.target.name.replace('-', "_");
-                    drv.add_dep(DepRef {
-                        nix_var: format!("units.\"{}\"", dep_drv_name),
-                        extern_crate_name: dep.extern_crate_name.clone(),
-                        lib_name,
-                        identity_hash: identity_hashes[dep.index].clone(),
-                        derivation_name: dep_drv_name.clone(),
-                        is_proc_macro: dep_unit.is_proc_macro(),
-                    });
-                }
-            }
+    /// Returns the extra build script output directory variables for a unit's package.
+    pub fn build_script_out_dir_env_for_unit(&self, unit: &Unit) -> &[String] {
+        self.build_script_out_dir_env
+            .get(unit.package_name())
+            .map_or(&[], Vec::as_slice)
+    }
 
-            // Set lib search deps (transitive closure for -L flags)
-            // Include (nix_var, lib_name) so we can filter out direct deps by name
-            let lib_deps: Vec<(String, String)> = transitive_deps[i]
-                .iter()
-                .filter_map(|&idx| {
-                    let dep_unit = graph.units.get(idx)?;
-                    let nix_var = format!("units.\"{}\"", drv_names[idx]);
-                    let lib_name = dep_unit.target.name.replace('-', "_");
-                    Some((nix_var, lib_name))
-                })
-                .collect();
-            drv.set_lib_search_deps(lib_deps);
+    /// Returns whether a unit's package has a build script listed in `network_build_scripts`.
+    pub fn network_build_script_for_unit(&self, unit: &Unit) -> bool {
+        self.network_build_scripts
+            .iter()
+            .any(|package| package == unit.package_name())
+    }
 
-            // NOTE: Conflicting crate detection was removed. Cargo always emits --extern for
-            // direct dependencies, and "conflicts" only occur in transitive deps (which are
-            // resolved via -L search paths and SVH matching). The previous logic tried to skip
-            // --extern for conflicting crates, but this was incorrect - direct deps always need
-            // --extern. See commit 2ddfc10 "fix: always emit --extern for direct deps".
+    /// Warns about build scripts in `network_build_scripts` that will run in the
+    /// sandbox, where their network access fails, often with a confusing error.
+    pub fn network_build_script_warnings(&self, graph: &UnitGraph) -> Vec<String> {
+        graph
+            .units
+            .iter()
+            .enumerate()
+            .filter(|(_, unit)| {
+                unit.mode == "run-custom-build" && self.network_build_script_for_unit(unit)
+            })
+            .map(|(i, unit)| {
+                let action = if self.allow_network_build_scripts {
+                    "running it with __noChroot, which requires `sandbox = relaxed`"
+                } else {
+                    "the Nix sandbox blocks this; vendor its downloads, or allow \
+                     network build scripts to run it with __noChroot"
+                };
+                format!(
+                    "unit {i} ({}): build script may need network access; {action}",
+                    unit.pkg_id
+                )
+            })
+            .collect()
+    }
 
-            let drv_name = &drv.name;
+    /// Returns whether a unit's package has a build script listed in `impure_build_scripts`.
+    pub fn impure_build_script_for_unit(&self, unit: &Unit) -> bool {
+        self.impure_build_scripts
+            .iter()
+            .any(|package| package == unit.package_name())
+    }
 
-            out.push_str(&format!("    \"{}\" = mkUnit ", drv_name));
-            out.push_str(&drv.to_nix());
-            out.push_str(";\n\n");
+    /// Warns about every build script run impurely because of `impure_build_scripts`.
+    pub fn impure_build_script_warnings(&self, graph: &UnitGraph) -> Vec<String> {
+        graph
+            .units
+            .iter()
+            .enumerate()
+            .filter(|(_, unit)| {
+                unit.mode == "run-custom-build" && self.impure_build_script_for_unit(unit)
+            })
+            .map(|(i, unit)| {
+                format!(
+                    "unit {i} ({}): build script runs impurely (__noChroot, \
+                     NIX_ENFORCE_PURITY=0), which requires `sandbox = relaxed`; \
+                     its output may differ between builders",
+                    unit.pkg_id
+                )
+            })
+            .collect()
+    }
 
-            // Also add an alias by index for dependency resolution
-            out.push_str(&format!(
-                "    \"_idx_{}\" = units.\"{}\"; # index alias\n\n",
-                i, drv_name
-            ));
-        }
+    /// Returns the command used to invoke rustc.
+    pub fn rustc_command(&self) -> &str {
+        self.rustc_command.as_deref().unwrap_or("rustc")
+    }
 
-        out.push_str("  };\n\n");
+    /// Returns the `-C embed-bitcode` override for a unit, if any.
+    ///
+    /// `false` is dropped for every unit built under LTO: the LTO unit rejects it,
+    /// and its rlib dependencies must keep the bitcode the LTO link reads.
+    pub fn embed_bitcode_for_unit(&self, unit: &Unit) -> Option<bool> {
+        let under_lto = unit.profile.lto != crate::unit_graph::LtoSetting::Off;
+        self.embed_bitcode.filter(|&embed| embed || !under_lto)
+    }
 
-        // Root outputs
-        out.push_str("in {\n");
-        out.push_str("  inherit units;\n");
+    /// Returns the Nix expression for the stdenv.
+    pub fn stdenv_expr(&self) -> &str {
+        self.stdenv_expr.as_deref().unwrap_or("pkgs.stdenv")
+    }
 
-        // Root units - use precomputed drv_names for consistency with dep-aware hashes
-        let root_refs: Vec<String> = graph
-            .roots
-            .iter()
-            .map(|&i| format!("units.\"{}\"", &drv_names[i]))
-            .collect();
+    /// Returns the `--extern` name for a dependency, applying `extern_renames`.
+    pub fn extern_crate_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.extern_renames.get(name).map_or(name, String::as_str)
+    }
 
-        out.push_str(&format!("  roots = [ {} ];\n", root_refs.join(" ")));
+    /// Returns the `requiredSystemFeatures` for a unit.
+    ///
+    /// A per-package entry replaces the global list.
+    pub fn required_system_features_for_unit(&self, unit: &Unit) -> &[String] {
+        self.required_system_features_per_package
+            .get(unit.package_name())
+            .unwrap_or(&self.required_system_features)
+    }
 
-        // Packages attrset - maps package target name to derivation for workspace support
-        // This allows accessing individual workspace members by name
-        out.push_str("\n  # Workspace packages by target name\n");
-        out.push_str("  packages = {\n");
-        for &root_idx in &graph.roots {
-            if let Some(unit) = graph.units.get(root_idx) {
-                let target_name = &unit.target.name;
-                let drv_name = &drv_names[root_idx];
-                out.push_str(&format!(
-                    "    \"{}\" = units.\"{}\";\n",
-                    escape_nix_string(target_name),
-                    drv_name
+    /// Returns the `hardeningDisable` flags for a unit.
+    ///
+    /// A per-package entry replaces the global list.
+    pub fn hardening_disable_for_unit(&self, unit: &Unit) -> &[String] {
+        self.hardening_disable_per_package
+            .get(unit.package_name())
+            .unwrap_or(&self.hardening_disable)
+    }
+
+    /// Remaps a unit's source path and manifest directory, including `extra_sources`.
+    ///
+    /// Returns `(src_path, manifest_dir)` as Nix interpolations.
+    pub fn remap_unit_paths(&self, unit: &Unit) -> (String, String) {
+        let src_path = crate::source_filter::remap_source_path(
+            &unit.target.src_path,
+            &self.workspace_root,
+            "src",
+            &self.extra_sources,
+        );
+        let manifest_dir = crate::source_filter::remap_manifest_dir(
+            unit,
+            &self.workspace_root,
+            "src",
+            "vendorDir",
+            &self.extra_sources,
+        );
+        (src_path, manifest_dir)
+    }
+
+    /// Returns the cargo config `[env]` variables with `relative` paths
+    /// remapped into the Nix source tree.
+    pub fn sandbox_cargo_env(&self) -> Vec<CargoEnvVar> {
+        self.cargo_env
+            .iter()
+            .map(|var| {
+                let mut var = var.clone();
+                if var.relative {
+                    var.value = crate::source_filter::remap_source_path(
+                        &var.value,
+                        &self.workspace_root,
+                        "src",
+                        &self.extra_sources,
+                    );
+                }
+                var
+            })
+            .collect()
+    }
+
+    /// Returns the units that cannot be generated, as `(unit index, reason)`.
+    ///
+    /// With `strict_sandbox`, source paths that cannot be remapped into the
+    /// sandbox are errors. With `keep_going`, units with an unrecognized pkg_id
+    /// or source type are too, so they are dropped rather than emitted as a
+    /// best-effort derivation.
+    pub fn unit_errors(&self, graph: &UnitGraph) -> Vec<(usize, String)> {
+        let mut errors = Vec::new();
+        for (i, unit) in graph.units.iter().enumerate() {
+            if self.keep_going && crate::source_filter::SourceLocation::from_unit(unit).is_none() {
+                errors.push((i, "unrecognized pkg_id or source type".to_string()));
+            } else if self.strict_sandbox
+                && crate::source_filter::try_remap_source_path(
+                    &unit.target.src_path,
+                    &self.workspace_root,
+                    "src",
+                    &self.extra_sources,
+                )
+                .is_none()
+            {
+                errors.push((
+                    i,
+                    format!(
+                        "source path `{}` is outside the workspace, extra sources, registry, \
+                         and sysroot",
+                        unit.target.src_path
+                    ),
                 ));
             }
         }
-        out.push_str("  };\n");
+        errors
+    }
 
-        // Binaries attrset - only binary targets for convenient access
-        out.push_str("\n  # Binary targets only\n");
-        out.push_str("  binaries = {\n");
-        for &root_idx in &graph.roots {
-            if let Some(unit) = graph.units.get(root_idx)
-                && unit.is_bin()
-            {
-                let target_name = &unit.target.name;
-                let drv_name = &drv_names[root_idx];
-                out.push_str(&format!(
-                    "    \"{}\" = units.\"{}\";\n",
-                    escape_nix_string(target_name),
-                    drv_name
-                ));
+    /// Returns configuration-derived identity inputs for a unit.
+    ///
+    /// Options that change how rustc compiles a unit must also change its
+    /// derivation name, otherwise CA outputs built under different settings
+    /// would be reused. Returns an empty list when nothing applies, so default
+    /// configurations keep their existing hashes.
+    pub fn identity_extras(&self, unit: &Unit) -> Vec<String> {
+        let mut extras = Vec::new();
+        if self.rustc_bootstrap_for_unit(unit) {
+            extras.push("rustc-bootstrap".to_string());
+        }
+        if self.coverage_for_unit(unit) {
+            extras.push("coverage".to_string());
+        }
+        if self.codegen_units_from_cores {
+            extras.push("codegen-units-from-cores".to_string());
+        }
+        if self.prefer_dynamic && unit.is_bin() {
+            extras.push("prefer-dynamic".to_string());
+        }
+        if self.separate_debug_info_for_unit(unit) {
+            extras.push("separate-debug-info".to_string());
+        }
+        if self.stable_symlinks_for_unit(unit) {
+            extras.push("stable-symlinks".to_string());
+        }
+        for cfg in self.extra_cfgs_for_unit(unit) {
+            extras.push(format!("cfg={cfg}"));
+        }
+        for (key, value) in self.extra_codegen_for_unit(unit) {
+            extras.push(format!("codegen={key}={value}"));
+        }
+        if !self.allow_compat_lints {
+            extras.push("no-compat-lint-allows".to_string());
+        }
+        if let Some(embed) = self.embed_bitcode_for_unit(unit) {
+            extras.push(format!(
+                "embed-bitcode={}",
+                if embed { "yes" } else { "no" }
+            ));
+        }
+        let lint_levels = self.lint_levels_for_unit(unit);
+        if !lint_levels.is_empty() {
+            extras.push(format!("lints={}", lint_levels.join(" ")));
+        }
+        // Hash the sandbox values so relative paths don't depend on the checkout
+        for var in self.sandbox_cargo_env() {
+            extras.push(format!("env={}={}", var.name, var.value));
+        }
+        for var in self.build_script_out_dir_env_for_unit(unit) {
+            extras.push(format!("out-dir-env={var}"));
+        }
+        // A unit's own `platform` is already part of its identity hash
+        if unit.platform.is_none()
+            && let Some(target) = self.rustc_target_for_unit(unit)
+        {
+            extras.push(format!("target={target}"));
+        }
+        for dep in &unit.dependencies {
+            if let Some(renamed) = self.extern_renames.get(&dep.extern_crate_name) {
+                extras.push(format!("extern-rename={}={renamed}", dep.extern_crate_name));
             }
         }
-        out.push_str("  };\n");
+        let hardening_disable = self.hardening_disable_for_unit(unit);
+        if !hardening_disable.is_empty() {
+            extras.push(format!("hardening-disable={}", hardening_disable.join(",")));
+        }
+        // The stdenv's C compiler builds native code and links binaries
+        if self.stdenv_expr() != "pkgs.stdenv" {
+            extras.push(format!("stdenv={}", self.stdenv_expr()));
+        }
+        if unit.is_linked() {
+            if let Some(ref linker) = self.linker {
+                extras.push(format!("linker={linker}"));
+            }
+            if let Some(ref flavor) = self.linker_flavor {
+                extras.push(format!("linker-flavor={flavor}"));
+            }
+        }
+        extras
+    }
+}
 
-        // Libraries attrset - only library targets
-        out.push_str("\n  # Library targets only\n");
-        out.push_str("  libraries = {\n");
-        for &root_idx in &graph.roots {
-            if let Some(unit) = graph.units.get(root_idx)
-                && (unit.is_lib() || unit.is_proc_macro())
-            {
-                let target_name = &unit.target.name;
-                let drv_name = &drv_names[root_idx];
-                out.push_str(&format!(
-                    "    \"{}\" = units.\"{}\";\n",
-                    escape_nix_string(target_name),
-                    drv_name
-                ));
+/// Returns each unit's derivation name, `{target}-{version}-{identity hash}`.
+///
+/// All duplicates map to the same name (the canonical unit's).
+pub(crate) fn derivation_names(
+    graph: &UnitGraph,
+    canonical_index: &[usize],
+    identity_hashes: &[String],
+) -> Vec<String> {
+    (0..graph.units.len())
+        .map(|i| {
+            let u = &graph.units[canonical_index[i]];
+            let hash = &identity_hashes[i];
+            let version = u.package_version().unwrap_or("0.0.0");
+            format!("{}-{version}-{hash}", u.target.name)
+        })
+        .collect()
+}
+
+/// Maps each unit to its canonical unit index.
+///
+/// DEDUPLICATION: Units with the same (pkg_id, target_name, mode, platform) should map
+/// to a single derivation, even if they have different features. Build a mapping from
+/// unit index to "canonical" unit index.
+///
+/// This is necessary because Cargo's unit graph can contain multiple entries for the
+/// same crate with different feature sets (e.g., serde_core with features [alloc, std]
+/// vs [alloc, default, rc, std]). Without deduplication, each feature set gets a
+/// different identity hash, cascading through the dependency tree and causing rustc
+/// SVH mismatches at compile time.
+///
+/// Strategy: For units with the same (pkg_id, target_name, mode, platform), pick the one
+/// with the most features as canonical. This ensures all code sees a superset of features.
+pub(crate) fn canonical_indices(graph: &UnitGraph) -> Vec<usize> {
+    // Key: (pkg_id, target_name, mode, platform) - ignores features for deduplication.
+    // The platform keeps a host build (for proc-macros and build scripts) apart from
+    // the same crate built for the target, so each edge links the right variant.
+    let mut key_to_candidates: rustc_hash::FxHashMap<(&str, &str, &str, Option<&str>), Vec<usize>> =
+        rustc_hash::FxHashMap::default();
+
+    // Collect all units with the same key
+    for (idx, unit) in graph.units.iter().enumerate() {
+        let key = (
+            unit.pkg_id.as_str(),
+            unit.target.name.as_str(),
+            unit.mode.as_str(),
+            unit.platform.as_deref(),
+        );
+        key_to_candidates.entry(key).or_default().push(idx);
+    }
+
+    // For each group, pick the unit with the most features as canonical
+    let mut idx_to_canonical: Vec<usize> = vec![0; graph.units.len()];
+    for candidates in key_to_candidates.values() {
+        // Find the candidate with the most features
+        let canonical_idx = *candidates
+            .iter()
+            .max_by_key(|&&idx| graph.units[idx].features.len())
+            .unwrap();
+
+        // Map all candidates to the canonical one
+        for &idx in candidates {
+            idx_to_canonical[idx] = canonical_idx;
+        }
+    }
+
+    idx_to_canonical
+}
+
+/// Marks the units that run at build time (proc-macros, build scripts) and
+/// everything they depend on, indexed by canonical unit index.
+pub(crate) fn host_needed_units(graph: &UnitGraph, canonical_index: &[usize]) -> Vec<bool> {
+    let mut host_needed = vec![false; graph.units.len()];
+    let mut stack: Vec<usize> = graph
+        .units
+        .iter()
+        .enumerate()
+        .filter(|(_, unit)| crate::proc_macro::requires_host_toolchain(unit))
+        .map(|(i, _)| canonical_index[i])
+        .collect();
+    while let Some(idx) = stack.pop() {
+        if std::mem::replace(&mut host_needed[idx], true) {
+            continue;
+        }
+        // Build script runs are not linked, like in the transitive dependency sets
+        for dep in &graph.units[idx].dependencies {
+            if dep.index < graph.units.len() && !dep.is_build_dependency(graph) {
+                stack.push(canonical_index[dep.index]);
             }
         }
-        out.push_str("  };\n");
+    }
+    host_needed
+}
 
-        // Convenience: default is the first root
-        if let Some(&first_root) = graph.roots.first() {
-            out.push_str(&format!(
-                "\n  default = units.\"{}\";\n",
-                &drv_names[first_root]
-            ));
+/// Generates Nix code from a unit graph.
+pub struct NixGenerator {
+    config: NixGenConfig,
+}
+
+/// Receives each derivation's name and file contents (see [`NixGenerator::write_split_to`]).
+type UnitFileSink<'a> = &'a mut dyn FnMut(&str, &str) -> std::io::Result<()>;
+
+impl NixGenerator {
+    /// Creates a new generator with the given configuration.
+    pub fn new(config: NixGenConfig) -> Self {
+        Self { config }
+    }
+
+    /// Returns whether each unit is built metadata-only: `check` units, and with
+    /// `check_only` every `build`, `test`, and `bench` unit. Host units are always
+    /// fully compiled.
+    fn metadata_only_units(&self, graph: &UnitGraph, canonical_index: &[usize]) -> Vec<bool> {
+        let host_needed = host_needed_units(graph, canonical_index);
+        graph
+            .units
+            .iter()
+            .enumerate()
+            .map(|(i, unit)| {
+                !host_needed[canonical_index[i]]
+                    && (unit.mode == "check"
+                        || (self.config.check_only
+                            && matches!(unit.mode.as_str(), "build" | "test" | "bench")))
+            })
+            .collect()
+    }
+
+    /// Returns whether `check_only` is what makes each unit metadata-only, which
+    /// then adds `check-only` to its identity. Units it leaves fully compiled keep
+    /// their hashes.
+    fn check_only_units(&self, graph: &UnitGraph, canonical_index: &[usize]) -> Vec<bool> {
+        if !self.config.check_only {
+            return vec![false; graph.units.len()];
         }
+        self.metadata_only_units(graph, canonical_index)
+            .into_iter()
+            .zip(&graph.units)
+            .map(|(metadata_only, unit)| metadata_only && unit.mode != "check")
+            .collect()
+    }
 
-        out.push_str("}\n");
+    /// Computes every unit's identity hash, including dependency hashes, the
+    /// toolchain hash, and [`NixGenConfig::identity_extras`].
+    ///
+    /// CRITICAL: Hashes must be computed in TOPOLOGICAL ORDER with dependency hashes included!
+    /// This ensures rustc unification works correctly - when a dependency's hash changes,
+    /// all dependents' hashes also change, matching how rustc embeds SVH into rlib metadata.
+    ///
+    /// NOTE: We use canonical_index to map dependency indices to their canonical form,
+    /// ensuring duplicates get the same hash. Edges closing a dependency cycle are
+    /// left out of the hash rather than recursing forever.
+    pub(crate) fn identity_hashes(
+        &self,
+        graph: &UnitGraph,
+        canonical_index: &[usize],
+    ) -> Vec<String> {
+        let mut hashes: Vec<Option<String>> = vec![None; graph.units.len()];
+        let mut visiting = vec![false; graph.units.len()];
+        let check_only = self.check_only_units(graph, canonical_index);
+
+        // Compute in topological order using DFS
+        fn compute_hash(
+            idx: usize,
+            graph: &UnitGraph,
+            hashes: &mut [Option<String>],
+            visiting: &mut [bool],
+            config: &NixGenConfig,
+            canonical_index: &[usize],
+            check_only: &[bool],
+        ) -> String {
+            // Use canonical index for looking up cached hashes
+            let canonical_idx = canonical_index[idx];
+            if let Some(ref h) = hashes[canonical_idx] {
+                return h.clone();
+            }
+
+            // First, compute hashes for all dependencies (recursively)
+            // Use canonical unit to ensure consistent dependency set across duplicates
+            visiting[canonical_idx] = true;
+            let canonical_unit = &graph.units[canonical_idx];
+            let mut dep_hashes = Vec::with_capacity(canonical_unit.dependencies.len());
+            for dep in &canonical_unit.dependencies {
+                // Skip build script run units - they don't contribute to binary identity
+                if dep.index >= graph.units.len()
+                    || dep.is_build_dependency(graph)
+                    || visiting[canonical_index[dep.index]]
+                {
+                    continue;
+                }
+                dep_hashes.push(compute_hash(
+                    dep.index,
+                    graph,
+                    hashes,
+                    visiting,
+                    config,
+                    canonical_index,
+                    check_only,
+                ));
+            }
+            visiting[canonical_idx] = false;
+
+            // Now compute this unit's hash with dependency hashes included
+            let dep_refs: Vec<&str> = dep_hashes.iter().map(String::as_str).collect();
+            let len = config.hash_len();
+            let mut hash = canonical_unit.identity_hash_with_len(&dep_refs, len);
+
+            // Include toolchain hash to prevent stale CA outputs when rustc changes
+            // This ensures derivation names change when the Nix toolchain store path changes
+            if let Some(th) = config.toolchain_hash.as_deref() {
+                use sha2::Digest as _;
+                let mut hasher = sha2::Sha256::new();
+                hasher.update(hash.as_bytes());
+                hasher.update(b"\0");
+                hasher.update(th.as_bytes());
+                let combined = hasher.finalize();
+                hash = hex::encode(&combined[..len]);
+            }
+
+            // Include generator options that change compilation output
+            let mut extras = config.identity_extras(canonical_unit);
+            if check_only[canonical_idx] {
+                extras.insert(0, "check-only".to_string());
+            }
+            if !extras.is_empty() {
+                use sha2::Digest as _;
+                let mut hasher = sha2::Sha256::new();
+                hasher.update(hash.as_bytes());
+                for extra in &extras {
+                    hasher.update(b"\0");
+                    hasher.update(extra.as_bytes());
+                }
+                let combined = hasher.finalize();
+                hash = hex::encode(&combined[..len]);
+            }
+
+            // Store at canonical index so all duplicates share the same hash
+            hashes[canonical_idx] = Some(hash.clone());
+            hash
+        }
+
+        // Compute hashes for all units
+        for i in 0..graph.units.len() {
+            compute_hash(
+                i,
+                graph,
+                &mut hashes,
+                &mut visiting,
+                &self.config,
+                canonical_index,
+                &check_only,
+            );
+        }
+
+        // Map each unit to its canonical hash (duplicates share the same hash)
+        (0..graph.units.len())
+            .map(|i| hashes[canonical_index[i]].clone().unwrap())
+            .collect()
+    }
+
+    /// Generates a complete Nix expression for the unit graph.
+    ///
+    /// Convenience wrapper around [`Self::write_to`] that collects the output.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a unit cannot be generated (see [`NixGenConfig::unit_errors`])
+    /// and `keep_going` is not set.
+    pub fn generate(&self, graph: &UnitGraph) -> String {
+        let mut buf = Vec::new();
+        self.write_to(graph, &mut buf)
+            .expect("writing to a Vec fails only on unit errors");
+        String::from_utf8(buf).expect("generated Nix is valid UTF-8")
+    }
+
+    /// Writes the `vendorDir` binding assembled from per-crate fetchers.
+    ///
+    /// Each registry and git crate is linked in as `name-version`, the layout
+    /// source remapping expects. Crates that cannot be fetched are listed in
+    /// a comment and must still be provided some other way.
+    fn write_fetchers(&self, graph: &UnitGraph, out: &mut String) {
+        let mut fetchers = std::collections::BTreeMap::new();
+        for unit in &graph.units {
+            let Some(loc) = crate::source_filter::SourceLocation::from_unit(unit) else {
+                continue;
+            };
+            if loc.is_path() {
+                continue;
+            }
+            let name_version = format!("{}-{}", loc.name, loc.version);
+            if fetchers.contains_key(&name_version) {
+                continue;
+            }
+            let checksum = self.config.crate_checksums.get(&name_version);
+            let fetcher = loc.to_nix_fetcher(checksum.map(String::as_str));
+            fetchers.insert(name_version, fetcher);
+        }
+
+        out.push_str(
+            "  # Crate sources fetched in Nix; fill in any `pkgs.lib.fakeHash` placeholders\n",
+        );
+        for (name_version, fetcher) in &fetchers {
+            if fetcher.is_none() {
+                let _ = writeln!(
+                    out,
+                    "  # no fetcher for {name_version} (unsupported source)"
+                );
+            }
+        }
+        out.push_str("  vendorDir = pkgs.linkFarm \"vendor\" [\n");
+        for (name_version, fetcher) in &fetchers {
+            if let Some(fetcher) = fetcher {
+                let _ = writeln!(
+                    out,
+                    "    {{ name = \"{name_version}\"; path = {fetcher}; }}"
+                );
+            }
+        }
+        out.push_str("  ];\n\n");
+    }
+
+    /// Returns the index of the unit [`NixGenConfig::devshell`] names: the first
+    /// compiled unit (roots first) whose package or target has that name.
+    pub fn devshell_unit(&self, graph: &UnitGraph) -> Option<usize> {
+        let name = self.config.devshell.as_deref()?;
+        let matches = |&i: &usize| {
+            graph.units.get(i).is_some_and(|unit| {
+                unit.mode != "run-custom-build"
+                    && !unit.is_build_script()
+                    && !unit.is_doctest()
+                    && (unit.package_name() == name || unit.target.name == name)
+            })
+        };
+        graph
+            .roots
+            .iter()
+            .copied()
+            .find(matches)
+            .or_else(|| (0..graph.units.len()).find(matches))
+    }
+
+    /// Returns the derivation name of every unit, in graph order.
+    ///
+    /// Duplicate units share their canonical unit's name.
+    pub fn derivation_names(&self, graph: &UnitGraph) -> Vec<String> {
+        let canonical_index = canonical_indices(graph);
+        let identity_hashes = self.identity_hashes(graph, &canonical_index);
+        derivation_names(graph, &canonical_index, &identity_hashes)
+    }
+
+    /// Writes a JSON manifest of every derivation's identity inputs to `w`.
+    ///
+    /// Each entry lists the derivation name, its identity hash, the unit fields
+    /// that were hashed ([`IdentityInputs`]), and the toolchain hash and
+    /// [`NixGenConfig::identity_extras`] folded in afterwards. Diffing two
+    /// manifests shows why a derivation name changed between runs.
+    pub fn write_manifest<W: std::io::Write>(
+        &self,
+        graph: &UnitGraph,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        let canonical_index = canonical_indices(graph);
+        let identity_hashes = self.identity_hashes(graph, &canonical_index);
+        let entries = self.manifest_entries(graph, &canonical_index, &identity_hashes);
+
+        serde_json::to_writer_pretty(&mut *w, &entries)?;
+        writeln!(w)
+    }
+
+    /// Writes what went into the identity hash of the derivation named `name`
+    /// to `w`: one `field: value` line per [`IdentityInputs`] field (values as
+    /// JSON), then the toolchain hash and extras, ending with the resulting hash.
+    ///
+    /// Returns `false`, writing nothing, if no derivation has that name.
+    pub fn write_identity<W: std::io::Write>(
+        &self,
+        graph: &UnitGraph,
+        name: &str,
+        w: &mut W,
+    ) -> std::io::Result<bool> {
+        let canonical_index = canonical_indices(graph);
+        let identity_hashes = self.identity_hashes(graph, &canonical_index);
+        let Some(entry) = self
+            .manifest_entries(graph, &canonical_index, &identity_hashes)
+            .into_iter()
+            .find(|entry| entry.derivation == name)
+        else {
+            return Ok(false);
+        };
+
+        writeln!(w, "derivation: {}", entry.derivation)?;
+        // Serialized so every hashed field is listed, in alphabetical order
+        let inputs = serde_json::to_value(&entry.inputs)?;
+        for (field, value) in inputs.as_object().into_iter().flatten() {
+            writeln!(w, "{field}: {value}")?;
+        }
+        if let Some(toolchain_hash) = entry.toolchain_hash {
+            writeln!(
+                w,
+                "toolchain_hash: {}",
+                serde_json::to_string(toolchain_hash)?
+            )?;
+        }
+        if !entry.extras.is_empty() {
+            writeln!(w, "extras: {}", serde_json::to_string(&entry.extras)?)?;
+        }
+        writeln!(w, "identity_hash: {}", entry.identity_hash)?;
+        Ok(true)
+    }
+
+    /// Collects the identity inputs of every derivation, for
+    /// [`Self::write_manifest`] and [`Self::write_identity`].
+    fn manifest_entries<'a>(
+        &'a self,
+        graph: &'a UnitGraph,
+        canonical_index: &[usize],
+        identity_hashes: &'a [String],
+    ) -> Vec<ManifestEntry<'a>> {
+        let drv_names = derivation_names(graph, canonical_index, identity_hashes);
+        let check_only = self.check_only_units(graph, canonical_index);
+
+        let mut entries = Vec::new();
+        for (i, unit) in graph.units.iter().enumerate() {
+            // Duplicates share their canonical unit's derivation
+            if canonical_index[i] != i || unit.is_doctest() {
+                continue;
+            }
+            let dep_hashes: Vec<&str> = unit
+                .dependencies
+                .iter()
+                .filter(|dep| dep.index < graph.units.len() && !dep.is_build_dependency(graph))
+                .map(|dep| identity_hashes[dep.index].as_str())
+                .collect();
+            entries.push(ManifestEntry {
+                derivation: drv_names[i].clone(),
+                identity_hash: &identity_hashes[i],
+                inputs: unit.identity_inputs(&dep_hashes),
+                toolchain_hash: self.config.toolchain_hash.as_deref(),
+                extras: {
+                    let mut extras = self.config.identity_extras(unit);
+                    if check_only[i] {
+                        extras.insert(0, "check-only".to_string());
+                    }
+                    extras
+                },
+            });
+        }
+        entries
+    }
+
+    /// Writes a complete Nix expression for the unit graph to `w`.
+    ///
+    /// Each derivation is flushed to the writer as soon as it is generated,
+    /// so peak memory stays proportional to one derivation rather than the
+    /// whole output.
+    ///
+    /// Units that cannot be generated (see [`NixGenConfig::unit_errors`]) are
+    /// reported as an [`std::io::ErrorKind::InvalidInput`] error before anything
+    /// is written, unless `keep_going` is set, in which case they and their
+    /// dependents are skipped and listed in a trailing comment.
+    pub fn write_to<W: std::io::Write>(&self, graph: &UnitGraph, w: &mut W) -> std::io::Result<()> {
+        self.write_nix(graph, w, None)
+    }
+
+    /// Like [`Self::write_to`], but hands each derivation to `unit_file` instead
+    /// of inlining it.
+    ///
+    /// `unit_file` receives the derivation name and the contents of
+    /// `<name>.nix`, a function of the shared `scope` that returns the
+    /// derivation. The expression written to `w` imports each of those files
+    /// from its own directory, so it belongs in the same directory as them.
+    pub fn write_split_to<W, F>(
+        &self,
+        graph: &UnitGraph,
+        w: &mut W,
+        mut unit_file: F,
+    ) -> std::io::Result<()>
+    where
+        W: std::io::Write,
+        F: FnMut(&str, &str) -> std::io::Result<()>,
+    {
+        self.write_nix(graph, w, Some(&mut unit_file))
+    }
+
+    /// Writes one `<derivation>.nix` file per derivation into `dir`, plus a
+    /// `default.nix` importing them (see [`Self::write_split_to`]).
+    ///
+    /// Returns the number of derivation files written.
+    pub fn write_dir(&self, graph: &UnitGraph, dir: &std::path::Path) -> std::io::Result<usize> {
+        std::fs::create_dir_all(dir)?;
+        let mut count = 0;
+        let mut index = std::io::BufWriter::new(std::fs::File::create(dir.join("default.nix"))?);
+        self.write_split_to(graph, &mut index, |name, contents| {
+            count += 1;
+            std::fs::write(dir.join(format!("{name}.nix")), contents)
+        })?;
+        std::io::Write::flush(&mut index)?;
+        Ok(count)
+    }
+
+    /// Renders the expression, inlining derivations or passing them to `unit_file`.
+    fn write_nix<W: std::io::Write>(
+        &self,
+        graph: &UnitGraph,
+        w: &mut W,
+        mut unit_file: Option<UnitFileSink<'_>>,
+    ) -> std::io::Result<()> {
+        let unit_errors = self.config.unit_errors(graph);
+        if !self.config.keep_going && !unit_errors.is_empty() {
+            let problems: Vec<String> = unit_errors
+                .iter()
+                .map(|(i, reason)| {
+                    let unit = &graph.units[*i];
+                    format!(
+                        "unit {i} ({} target `{}`): {reason}",
+                        unit.pkg_id, unit.target.name
+                    )
+                })
+                .collect();
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("strict sandbox: {}", problems.join("; ")),
+            ));
+        }
+
+        let mut out = String::new();
+
+        // Header
+        if self.config.emit_header {
+            out.push_str("# Generated by nix-cargo-unit\n");
+            out.push_str("# Do not edit manually\n\n");
+        }
+
+        // Function signature
+        // Always include hostRustToolchain with default for compatibility with lib.nix
+        // extraNativeBuildInputs allows passing protobuf, cmake, etc. for build scripts
+        // vendorDir allows passing pre-vendored crate sources for registry deps
+        // Extra source roots are appended as required arguments
+        match self.config.function_args.as_deref() {
+            None => {
+                out.push_str("{ pkgs, rustToolchain, hostRustToolchain ? rustToolchain, src, extraNativeBuildInputs ? []");
+                if !self.config.emit_fetchers {
+                    out.push_str(", vendorDir ? null");
+                }
+                for (nix_var, _) in &self.config.extra_sources {
+                    out.push_str(", ");
+                    out.push_str(nix_var);
+                }
+                out.push_str(" }:\n\n");
+            }
+            // Embedded: the enclosing expression provides the arguments
+            Some("") => {}
+            Some(args) => {
+                out.push_str(args);
+                out.push_str(":\n\n");
+            }
+        }
+
+        // Let block
+        out.push_str("let\n");
+
+        // Helper function for creating unit derivations
+        let _ = writeln!(
+            out,
+            "  mkUnit = attrs: {}.mkDerivation (attrs // {{",
+            self.config.stdenv_expr()
+        );
+        out.push_str("    dontUnpack = true;\n");
+        out.push_str("    dontConfigure = true;\n");
+        out.push_str("  });\n\n");
+
+        if self.config.emit_fetchers {
+            self.write_fetchers(graph, &mut out);
+        }
+
+        // Everything the per-derivation files refer to
+        let scope_names: Vec<&str> = [
+            "pkgs",
+            "rustToolchain",
+            "hostRustToolchain",
+            "src",
+            "extraNativeBuildInputs",
+            "vendorDir",
+            "mkUnit",
+            "units",
+        ]
+        .into_iter()
+        .chain(
+            self.config
+                .extra_sources
+                .iter()
+                .map(|(var, _)| var.as_str()),
+        )
+        .collect();
+        if unit_file.is_some() {
+            out.push_str("  # Shared arguments of the per-derivation files\n");
+            let _ = writeln!(out, "  scope = {{ inherit {}; }};\n", scope_names.join(" "));
+        }
+
+        let canonical_index = canonical_indices(graph);
+        let cargo_env = self.config.sandbox_cargo_env();
+        let devshell_unit = self.devshell_unit(graph).map(|i| canonical_index[i]);
+        let mut devshell = None;
+
+        // With keep_going, units that cannot be generated are skipped along with
+        // everything that depends on them (directly, or through a duplicate)
+        let mut skip_reasons: Vec<Option<String>> = vec![None; graph.units.len()];
+        for (i, reason) in &unit_errors {
+            skip_reasons[*i] = Some(reason.clone());
+        }
+        let mut changed = !unit_errors.is_empty();
+        while changed {
+            changed = false;
+            for (i, unit) in graph.units.iter().enumerate() {
+                if skip_reasons[i].is_some() {
+                    continue;
+                }
+                let skipped_dep = unit.dependencies.iter().find(|dep| {
+                    dep.index < graph.units.len()
+                        && (skip_reasons[dep.index].is_some()
+                            || skip_reasons[canonical_index[dep.index]].is_some())
+                });
+                if let Some(dep) = skipped_dep {
+                    skip_reasons[i] = Some(format!("depends on skipped unit {}", dep.index));
+                    changed = true;
+                }
+            }
+        }
+
+        let identity_hashes = self.identity_hashes(graph, &canonical_index);
+
+        let drv_names = derivation_names(graph, &canonical_index, &identity_hashes);
+
+        // Compute transitive dependencies for each unit (using canonical indices)
+        // This is needed for -L library search paths (rustc needs to find all transitive rlibs)
+        // Uses Rc<FxHashSet> to avoid O(n²) cloning - computed sets are shared via Rc
+        //
+        // IMPORTANT: We map all dependency indices to their canonical form to ensure
+        // that duplicate units result in the same transitive dep set.
+        let transitive_deps: Vec<Rc<rustc_hash::FxHashSet<usize>>> = {
+            type FxSet = rustc_hash::FxHashSet<usize>;
+
+            // Build direct dependency map (unit index -> Vec of CANONICAL dep indices)
+            let direct_deps: Vec<Vec<usize>> = graph
+                .units
+                .iter()
+                .enumerate()
+                .map(|(i, _unit)| {
+                    // Use canonical unit's dependencies for consistency
+                    let canonical_unit = &graph.units[canonical_index[i]];
+                    canonical_unit
+                        .dependencies
+                        .iter()
+                        .filter_map(|d| {
+                            // Skip build script run units for transitive deps
+                            graph
+                                .units
+                                .get(d.index)
+                                .filter(|_| !d.is_build_dependency(graph))
+                                // Map to canonical index!
+                                .map(|_| canonical_index[d.index])
+                        })
+                        .collect()
+                })
+                .collect();
+
+            // Compute transitive closure for each unit using DFS with Rc sharing
+            fn transitive_closure(
+                unit_idx: usize,
+                direct_deps: &[Vec<usize>],
+                cache: &mut [Option<Rc<FxSet>>],
+                canonical_index: &[usize],
+            ) -> Rc<FxSet> {
+                // Use canonical index for caching
+                let canonical_idx = canonical_index[unit_idx];
+                if let Some(cached) = &cache[canonical_idx] {
+                    return Rc::clone(cached); // Cheap Rc clone, not set clone
+                }
+
+                // Pre-size based on direct deps (heuristic)
+                let mut result = FxSet::with_capacity_and_hasher(
+                    direct_deps[canonical_idx].len() * 4,
+                    Default::default(),
+                );
+                for &dep_idx in &direct_deps[canonical_idx] {
+                    // dep_idx is already canonical (mapped above)
+                    result.insert(dep_idx);
+                    // Recursively add transitive deps
+                    let trans = transitive_closure(dep_idx, direct_deps, cache, canonical_index);
+                    result.extend(trans.iter().copied());
+                }
+                let rc = Rc::new(result);
+                cache[canonical_idx] = Some(Rc::clone(&rc));
+                rc
+            }
+
+            let mut cache: Vec<Option<Rc<FxSet>>> = vec![None; graph.units.len()];
+            (0..graph.units.len())
+                .map(|i| transitive_closure(i, &direct_deps, &mut cache, &canonical_index))
+                .collect()
+        };
+
+        let host_needed = host_needed_units(graph, &canonical_index);
+
+        // Units the roots need without going through a proc-macro or build script.
+        // The rest of `host_needed` is built only for the host, e.g. `syn` under a
+        // proc-macro, and uses the host toolchain when cross-compiling.
+        let host_dependency: Vec<bool> = {
+            let mut target_needed = vec![false; graph.units.len()];
+            let mut stack: Vec<usize> = graph
+                .roots
+                .iter()
+                .filter(|&&i| i < graph.units.len())
+                .map(|&i| canonical_index[i])
+                .collect();
+            while let Some(idx) = stack.pop() {
+                let unit = &graph.units[idx];
+                if target_needed[idx] || crate::proc_macro::requires_host_toolchain(unit) {
+                    continue;
+                }
+                target_needed[idx] = true;
+                for dep in &unit.dependencies {
+                    if dep.index < graph.units.len() {
+                        stack.push(canonical_index[dep.index]);
+                    }
+                }
+            }
+            (0..graph.units.len())
+                .map(|i| host_needed[canonical_index[i]] && !target_needed[canonical_index[i]])
+                .collect()
+        };
+
+        let metadata_only = self.metadata_only_units(graph, &canonical_index);
+
+        // First pass: identify build script RUN units and their corresponding COMPILE units
+        // Build a map from run unit index -> BuildScriptRef for units that depend on build scripts
+        //
+        // Build scripts appear as two units in the graph:
+        // 1. COMPILE unit: mode="build", kind=["custom-build"] - compiles build.rs with its deps
+        // 2. RUN unit: mode="run-custom-build" - executes the compiled binary
+        //
+        // The RUN unit depends on the COMPILE unit. We process COMPILE units as normal
+        // derivations (to get their dependencies like tonic-build), and generate special
+        // RUN derivations that execute the binary and capture cargo: directives.
+        let mut build_script_refs: rustc_hash::FxHashMap<usize, BuildScriptRef> =
+            rustc_hash::FxHashMap::default();
+
+        // First pass: identify all build script RUN units and their info
+        // We need this map to wire up DEP_* variables between build scripts
+        struct BuildScriptRunInfo {
+            unit_index: usize,
+            package_name: String,
+            compile_dep_index: usize,
+            info: BuildScriptInfo,
+        }
+        let mut build_script_runs: Vec<BuildScriptRunInfo> = Vec::new();
+        let mut package_to_bs_run: rustc_hash::FxHashMap<String, usize> =
+            rustc_hash::FxHashMap::default();
+
+        for (i, unit) in graph.units.iter().enumerate() {
+            if unit.mode == "run-custom-build" {
+                // Skip duplicate units - only process canonical indices
+                if canonical_index[i] != i || skip_reasons[i].is_some() {
+                    continue;
+                }
+
+                // This is a build script RUN unit - find its compile unit dependency
+                let compile_dep = unit.dependencies.iter().find(|dep| {
+                    graph.units.get(dep.index).is_some_and(|u| {
+                        u.mode == "build" && u.target.kind.contains(&"custom-build".to_string())
+                    })
+                });
+
+                if let Some(compile_dep) = compile_dep {
+                    let info = BuildScriptInfo::from_unit(
+                        unit,
+                        &self.config.workspace_root,
+                        &self.config.extra_sources,
+                        self.config.content_addressed,
+                    );
+                    if let Some(mut info) = info {
+                        info.set_identity_hash(
+                            &unit.identity_hash_with_len(&[], self.config.hash_len()),
+                        );
+                        info.extra_native_build_inputs = self
+                            .config
+                            .extra_native_build_inputs_for_unit(unit)
+                            .to_vec();
+                        info.rustc_command = self.config.rustc_command().to_string();
+                        info.stdenv_expr = self.config.stdenv_expr().to_string();
+                        info.hardening_disable =
+                            self.config.hardening_disable_for_unit(unit).to_vec();
+                        info.cargo_env.clone_from(&cargo_env);
+                        info.out_dir_env =
+                            self.config.build_script_out_dir_env_for_unit(unit).to_vec();
+                        info.no_chroot = self.config.allow_network_build_scripts
+                            && self.config.network_build_script_for_unit(unit);
+                        info.impure = self.config.impure_build_script_for_unit(unit);
+                        if self.config.cross_compiling {
+                            info.target_triple = Some(unit.target_triple(&self.config));
+                        }
+                        info.compact = self.config.compact;
+                        info.ca_hash_algo.clone_from(&self.config.ca_hash_algo);
+                        let package_name = unit.package_name().to_string();
+                        package_to_bs_run.insert(package_name.clone(), build_script_runs.len());
+                        build_script_runs.push(BuildScriptRunInfo {
+                            unit_index: i,
+                            package_name,
+                            // Use canonical index for compile dep
+                            compile_dep_index: canonical_index[compile_dep.index],
+                            info,
+                        });
+                    }
+                }
+            }
+        }
+
+        // Binds a derivation in `units`, inline or imported from its own file
+        let mut write_unit = |out: &mut String, name: &str, body: &str| -> std::io::Result<()> {
+            match unit_file.as_mut() {
+                None => out.push_str(&format!("    \"{name}\" = mkUnit {body};\n")),
+                Some(unit_file) => {
+                    unit_file(
+                        name,
+                        &format!("{{ {}, ... }}:\n\nmkUnit {body}\n", scope_names.join(", ")),
+                    )?;
+                    out.push_str(&format!("    \"{name}\" = import ./{name}.nix scope;\n"));
+                }
+            }
+            Ok(())
+        };
+
+        // Generate derivations for each unit
+        out.push_str("  units = {\n");
+
+        // Second pass: for each build script RUN, find which other build scripts' outputs
+        // it should receive DEP_* variables from (based on library dependencies)
+        for bs_run in &build_script_runs {
+            let compile_drv_name = drv_names[bs_run.compile_dep_index].clone();
+            let compile_var = format!("units.\"{}\"", compile_drv_name);
+
+            // Find dependency build script outputs:
+            // Look at the library unit for this package and collect build script outputs
+            // from its dependencies
+            let mut dep_bs_outputs: Vec<String> = Vec::new();
+
+            // Find the library unit for this package (same pkg_id, mode="build", kind contains "lib")
+            let unit = &graph.units[bs_run.unit_index];
+            let lib_unit_idx = graph.units.iter().enumerate().find(|(_, u)| {
+                u.pkg_id == unit.pkg_id
+                    && u.mode == "build"
+                    && (u.target.kind.contains(&"lib".to_string())
+                        || u.target.kind.contains(&"rlib".to_string()))
+            });
+
+            if let Some((_, lib_unit)) = lib_unit_idx {
+                // For each dependency of the library unit, check if it has a build script
+                for dep in &lib_unit.dependencies {
+                    if let Some(dep_unit) = graph.units.get(dep.index) {
+                        // If this dependency is a build script RUN, add it
+                        // Skip the current package's own build script to avoid self-reference
+                        if dep.is_build_dependency(graph)
+                            && dep_unit.package_name() != bs_run.package_name
+                            && let Some(other_bs_run_idx) =
+                                package_to_bs_run.get(dep_unit.package_name())
+                        {
+                            let other_bs = &build_script_runs[*other_bs_run_idx];
+                            dep_bs_outputs
+                                .push(format!("units.\"{}\"", other_bs.info.run_drv_name));
+                        }
+                        // Also check if the dependency's package has a build script
+                        // (in case it's a lib unit that depends on another lib)
+                        // Skip the current package's own build script to avoid self-reference
+                        let dep_pkg_name = dep_unit.package_name();
+                        if dep_pkg_name != bs_run.package_name
+                            && let Some(other_bs_run_idx) = package_to_bs_run.get(dep_pkg_name)
+                        {
+                            let other_bs = &build_script_runs[*other_bs_run_idx];
+                            let run_var = format!("units.\"{}\"", other_bs.info.run_drv_name);
+                            if !dep_bs_outputs.contains(&run_var) {
+                                dep_bs_outputs.push(run_var);
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Generate run derivation with dependency build script outputs. These
+            // come first; COMPILE derivations are generated as normal units below
+            write_unit(
+                &mut out,
+                &bs_run.info.run_drv_name,
+                &bs_run.info.run_derivation(&compile_var, &dep_bs_outputs),
+            )?;
+            out.push('\n');
+            w.write_all(out.as_bytes())?;
+            out.clear();
+
+            // Store the reference for units that depend on this build script
+            build_script_refs.insert(
+                bs_run.unit_index,
+                BuildScriptRef {
+                    run_drv_var: format!("units.\"{}\"", bs_run.info.run_drv_name),
+                    compile_drv_name,
+                    run_drv_name: bs_run.info.run_drv_name.clone(),
+                },
+            );
+        }
+
+        for (i, unit) in graph.units.iter().enumerate() {
+            // Skip build script run units - they're already generated above
+            if unit.mode == "run-custom-build" {
+                continue;
+            }
+
+            // Skip doctest units - they need `rustdoc --test`, not rustc
+            if unit.is_doctest() {
+                continue;
+            }
+
+            // Skip units that cannot be generated (keep_going)
+            if skip_reasons[i].is_some() {
+                continue;
+            }
+
+            // Skip duplicate units - only generate for canonical indices
+            // Duplicates will reference the canonical unit's derivation via drv_names[i]
+            if canonical_index[i] != i {
+                continue;
+            }
+
+            let toolchain_var = self.config.toolchain_var_for_unit(unit, host_dependency[i]);
+            let mut drv = UnitDerivation::from_unit(
+                unit,
+                &self.config.workspace_root,
+                &self.config.extra_sources,
+                self.config.content_addressed,
+                toolchain_var,
+                &drv_names[i],
+                &identity_hashes[i],
+            );
+            drv.rustc_bootstrap = self.config.rustc_bootstrap_for_unit(unit);
+            drv.trace = self.config.trace;
+            drv.debug_procmacro = self.config.debug_procmacro;
+            drv.metadata_only = metadata_only[i];
+            drv.extra_native_build_inputs = self
+                .config
+                .extra_native_build_inputs_for_unit(unit)
+                .to_vec();
+            drv.required_system_features =
+                self.config.required_system_features_for_unit(unit).to_vec();
+            drv.hardening_disable = self.config.hardening_disable_for_unit(unit).to_vec();
+            drv.cargo_env.clone_from(&cargo_env);
+            drv.out_dir_env = self.config.build_script_out_dir_env_for_unit(unit).to_vec();
+            drv.rustc_command = self.config.rustc_command().to_string();
+            drv.build_dir.clone_from(&self.config.build_dir);
+            drv.ca_hash_algo.clone_from(&self.config.ca_hash_algo);
+            drv.compact = self.config.compact;
+            if let Some(embed) = self.config.embed_bitcode_for_unit(unit) {
+                drv.rustc_flags.remove_codegen("embed-bitcode");
+                drv.rustc_flags
+                    .add_codegen("embed-bitcode", if embed { "yes" } else { "no" });
+            }
+            if !host_dependency[i]
+                && let Some(target) = self.config.rustc_target_for_unit(unit)
+            {
+                drv.rustc_flags.add_target(&target);
+            }
+            for cfg in self.config.extra_cfgs_for_unit(unit) {
+                drv.rustc_flags.add_cfg(cfg);
+            }
+            for (key, value) in self.config.extra_codegen_for_unit(unit) {
+                drv.rustc_flags.add_codegen(key, value);
+            }
+            if !self.config.allow_compat_lints {
+                for lint in crate::rustc_flags::COMPAT_ALLOWED_LINTS {
+                    drv.rustc_flags.remove_allow(lint);
+                }
+            }
+            for arg in self.config.lint_levels_for_unit(unit) {
+                drv.rustc_flags.push_arg(arg);
+            }
+            if self.config.coverage_for_unit(unit) {
+                drv.coverage = true;
+                drv.rustc_flags.add_coverage(unit.is_linked());
+            }
+            drv.prefer_dynamic = self.config.prefer_dynamic && unit.is_bin();
+            drv.separate_debug_info =
+                self.config.separate_debug_info_for_unit(unit) && !metadata_only[i];
+            drv.stable_symlinks = self.config.stable_symlinks_for_unit(unit);
+            if self.config.cross_compiling {
+                // Target units build on the host; their own triple would make Nix refuse them
+                drv.platform.clone_from(&self.config.host_platform);
+            }
+            if self.config.codegen_units_from_cores {
+                drv.codegen_units_from_cores = true;
+                drv.rustc_flags.remove_codegen("codegen-units");
+            }
+            if unit.is_linked() {
+                if let Some(ref linker) = self.config.linker {
+                    drv.rustc_flags.add_linker(linker);
+                }
+                if let Some(ref flavor) = self.config.linker_flavor {
+                    drv.rustc_flags.add_linker_flavor(flavor);
+                }
+            }
+
+            // Wire up dependencies, and detect if any dependency is a build script
+            for dep in &unit.dependencies {
+                if let Some(dep_unit) = graph.units.get(dep.index) {
+                    // Check if this dependency is a build script execution unit
+                    if dep.is_build_dependency(graph) {
+                        // This unit depends on a build script - wire up the build script outputs
+                        if let Some(bs_ref) = build_script_refs.get(&dep.index) {
+                            drv.set_build_script_ref(bs_ref.clone());
+                        }
+                        // Don't add build script as a regular extern dependency
+                        continue;
+                    }
+
+                    let dep_drv_name = &drv_names[dep.index];
+                    // Get the actual library name from the dependency unit's target
+                    // This is the filename used for the .rlib (may differ from extern_crate_name if renamed)
+                    let lib_name = dep_unit.crate_name();
+                    drv.add_dep(DepRef {
+                        nix_var: format!("units.\"{}\"", dep_drv_name),
+                        extern_crate_name: self
+                            .config
+                            .extern_crate_name(&dep.extern_crate_name)
+                            .to_string(),
+                        lib_name,
+                        identity_hash: identity_hashes[dep.index].clone(),
+                        derivation_name: dep_drv_name.clone(),
+                        is_proc_macro: dep_unit.is_proc_macro(),
+                        metadata_only: metadata_only[dep.index],
+                        is_dylib: dep_unit.target.crate_types.iter().any(|t| t == "dylib"),
+                        noprelude: dep.noprelude,
+                    });
+                }
+            }
+
+            // Set lib search deps (transitive closure for -L flags)
+            // Include (nix_var, lib_name) so we can filter out direct deps by name
+            let lib_deps: Vec<(String, String)> = transitive_deps[i]
+                .iter()
+                .filter_map(|&idx| {
+                    let dep_unit = graph.units.get(idx)?;
+                    // Direct proc-macros are located via `$PROCMACRO_*` instead
+                    let direct_proc_macro = dep_unit.is_proc_macro()
+                        && drv.deps.iter().any(|d| d.derivation_name == drv_names[idx]);
+                    if direct_proc_macro {
+                        return None;
+                    }
+                    let nix_var = format!("units.\"{}\"", drv_names[idx]);
+                    let lib_name = dep_unit.crate_name();
+                    Some((nix_var, lib_name))
+                })
+                .collect();
+            drv.set_lib_search_deps(lib_deps);
+            drv.dylib_deps = transitive_deps[i]
+                .iter()
+                .filter(|&&idx| {
+                    graph.units[idx]
+                        .target
+                        .crate_types
+                        .iter()
+                        .any(|t| t == "dylib")
+                })
+                .map(|&idx| format!("units.\"{}\"", drv_names[idx]))
+                .collect();
+            drv.dylib_deps.sort_unstable();
+
+            // NOTE: Conflicting crate detection was removed. Cargo always emits --extern for
+            // direct dependencies, and "conflicts" only occur in transitive deps (which are
+            // resolved via -L search paths and SVH matching). The previous logic tried to skip
+            // --extern for conflicting crates, but this was incorrect - direct deps always need
+            // --extern. See commit 2ddfc10 "fix: always emit --extern for direct deps".
+
+            if devshell_unit == Some(i) {
+                devshell = Some(drv.devshell());
+            }
+
+            let drv_name = &drv.name;
+
+            write_unit(&mut out, drv_name, &drv.to_nix())?;
+            out.push('\n');
+
+            // Also add an alias by index for dependency resolution
+            out.push_str(&format!(
+                "    \"_idx_{}\" = units.\"{}\"; # index alias\n\n",
+                i, drv_name
+            ));
+
+            // Stream this derivation out before building the next one
+            w.write_all(out.as_bytes())?;
+            out.clear();
+        }
+
+        out.push_str("  };\n\n");
+
+        // Root outputs
+        out.push_str("in {\n");
+        out.push_str("  inherit units;\n");
+
+        // Doctest and skipped roots have no derivation (see above)
+        let roots: Vec<usize> = graph
+            .roots
+            .iter()
+            .copied()
+            .filter(|&i| {
+                !graph.units.get(i).is_some_and(Unit::is_doctest)
+                    && skip_reasons.get(i).is_some_and(Option::is_none)
+            })
+            .collect();
+
+        // Root units - use precomputed drv_names for consistency with dep-aware hashes
+        let root_refs: Vec<String> = roots
+            .iter()
+            .map(|&i| format!("units.\"{}\"", &drv_names[i]))
+            .collect();
+
+        out.push_str(&format!("  roots = [ {} ];\n", root_refs.join(" ")));
+
+        // Packages attrset - maps package target name to derivation for workspace support
+        // This allows accessing individual workspace members by name
+        out.push_str("\n  # Workspace packages by target name\n");
+        out.push_str("  packages = {\n");
+        for &root_idx in &roots {
+            if let Some(unit) = graph.units.get(root_idx) {
+                let target_name = &unit.target.name;
+                let drv_name = &drv_names[root_idx];
+                out.push_str(&format!(
+                    "    \"{}\" = units.\"{}\";\n",
+                    escape_nix_string(target_name),
+                    drv_name
+                ));
+            }
+        }
+        out.push_str("  };\n");
+
+        // Binaries attrset - only binary targets for convenient access
+        out.push_str("\n  # Binary targets only\n");
+        out.push_str("  binaries = {\n");
+        for &root_idx in &roots {
+            if let Some(unit) = graph.units.get(root_idx)
+                && unit.is_bin()
+            {
+                let target_name = &unit.target.name;
+                let drv_name = &drv_names[root_idx];
+                out.push_str(&format!(
+                    "    \"{}\" = units.\"{}\";\n",
+                    escape_nix_string(target_name),
+                    drv_name
+                ));
+            }
+        }
+        out.push_str("  };\n");
+
+        // All binaries joined into one output (e.g. for deployment)
+        let bin_refs: Vec<String> = roots
+            .iter()
+            .filter(|&&i| graph.units.get(i).is_some_and(Unit::is_bin))
+            .map(|&i| format!("units.\"{}\"", drv_names[i]))
+            .collect();
+        if !bin_refs.is_empty() {
+            out.push_str("\n  # Every binary target in one $out/bin\n");
+            out.push_str("  all-bins = pkgs.symlinkJoin {\n");
+            out.push_str("    name = \"all-bins\";\n");
+            out.push_str(&format!("    paths = [ {} ];\n", bin_refs.join(" ")));
+            out.push_str("  };\n");
+        }
+
+        // Libraries attrset - only library targets
+        out.push_str("\n  # Library targets only\n");
+        out.push_str("  libraries = {\n");
+        for &root_idx in &roots {
+            if let Some(unit) = graph.units.get(root_idx)
+                && (unit.is_lib() || unit.is_proc_macro())
+            {
+                let target_name = &unit.target.name;
+                let drv_name = &drv_names[root_idx];
+                out.push_str(&format!(
+                    "    \"{}\" = units.\"{}\";\n",
+                    escape_nix_string(target_name),
+                    drv_name
+                ));
+            }
+        }
+        out.push_str("  };\n");
+
+        // Test harnesses are rarely roots, so collect them from the whole graph
+        if self.config.emit_tests_attrset {
+            let mut seen = rustc_hash::FxHashSet::default();
+            let tests: Vec<usize> = (0..graph.units.len())
+                .filter(|&i| {
+                    graph.units[i].mode == "test"
+                        && skip_reasons[i].is_none()
+                        && seen.insert(&drv_names[i])
+                })
+                .collect();
+            let mut name_counts: rustc_hash::FxHashMap<&str, usize> =
+                rustc_hash::FxHashMap::default();
+            for &i in &tests {
+                *name_counts.entry(&graph.units[i].target.name).or_default() += 1;
+            }
+
+            out.push_str("\n  # Test harnesses (`--test` units), roots or not\n");
+            out.push_str("  tests = {\n");
+            for &i in &tests {
+                let unit = &graph.units[i];
+                let name = if name_counts[unit.target.name.as_str()] > 1 {
+                    format!("{}-{}", unit.package_name(), unit.target.name)
+                } else {
+                    unit.target.name.clone()
+                };
+                out.push_str(&format!(
+                    "    \"{}\" = units.\"{}\";\n",
+                    escape_nix_string(&name),
+                    drv_names[i]
+                ));
+            }
+            out.push_str("  };\n");
+        }
+
+        // Standard library units (build-std), grouped separately from workspace outputs
+        if graph.units.iter().any(|u| u.is_std) {
+            out.push_str("\n  # Standard library units (build-std)\n");
+            out.push_str("  sysroot = {\n");
+            let mut seen = rustc_hash::FxHashSet::default();
+            for (i, unit) in graph.units.iter().enumerate() {
+                if unit.is_std && skip_reasons[i].is_none() && seen.insert(&drv_names[i]) {
+                    out.push_str(&format!(
+                        "    \"{}\" = units.\"{}\";\n",
+                        escape_nix_string(&unit.target.name),
+                        drv_names[i]
+                    ));
+                }
+            }
+            out.push_str("  };\n");
+        }
+
+        if let (Some(shell), Some(name)) = (&devshell, &self.config.devshell) {
+            out.push_str(&format!(
+                "\n  # Dev shell with the rustc flags of {name}\n  devShell = {shell};\n"
+            ));
+        }
+
+        // Convenience: default is the first root
+        if let Some(&first_root) = roots.first() {
+            out.push_str(&format!(
+                "\n  default = units.\"{}\";\n",
+                &drv_names[first_root]
+            ));
+        } else {
+            // An empty (or fully filtered) graph still yields a valid, if empty, attrset
+            out.push_str("\n  # No root units: `default` is not defined\n");
+        }
+
+        out.push_str("}\n");
+
+        // Manifest of skipped units, so partial output is never silent
+        if skip_reasons.iter().any(Option::is_some) {
+            out.push_str("\n# Skipped units (keep-going):\n");
+            for (i, reason) in skip_reasons.iter().enumerate() {
+                if let Some(reason) = reason {
+                    out.push_str(&format!(
+                        "#   unit {i} ({}): {reason}\n",
+                        graph.units[i].pkg_id
+                    ));
+                }
+            }
+        }
+
+        w.write_all(out.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_graph::{drv_line, parse_test_unit_graph, unit_section};
+
+    #[test]
+    fn test_escape_nix_string() {
+        assert_eq!(escape_nix_string("hello"), "hello");
+        assert_eq!(escape_nix_string("hello\"world"), "hello\\\"world");
+        assert_eq!(escape_nix_string("path\\to"), "path\\\\to");
+        assert_eq!(escape_nix_string("${var}"), "\\${var}");
+        assert_eq!(escape_nix_string("line\nbreak"), "line\\nbreak");
+    }
+
+    #[test]
+    fn test_escape_nix_multiline() {
+        assert_eq!(escape_nix_multiline("hello"), "hello");
+        assert_eq!(escape_nix_multiline("end ''"), "end '''");
+        assert_eq!(escape_nix_multiline("${var}"), "''${var}");
+    }
+
+    #[test]
+    fn test_nix_string_escaping() {
+        let s = NixString::new("hello \"world\"");
+        assert_eq!(s.as_str(), "hello \\\"world\\\"");
+
+        let raw = NixString::raw("pkgs.hello");
+        assert_eq!(raw.as_str(), "pkgs.hello");
+    }
+
+    #[test]
+    fn test_nix_attr_set() {
+        let mut attrs = NixAttrSet::new();
+        attrs.string("pname", "my-crate");
+        attrs.string("version", "0.1.0");
+        attrs.bool("dontUnpack", true);
+        attrs.int("priority", 10);
+        attrs.string_list("features", &["std".to_string(), "alloc".to_string()]);
+
+        let rendered = attrs.render(0);
+
+        assert!(rendered.contains("pname = \"my-crate\""));
+        assert!(rendered.contains("version = \"0.1.0\""));
+        assert!(rendered.contains("dontUnpack = true"));
+        assert!(rendered.contains("priority = 10"));
+        assert!(rendered.contains("features = [ \"std\" \"alloc\" ]"));
+    }
+
+    #[test]
+    fn test_nix_attr_set_numbers() {
+        let mut attrs = NixAttrSet::new();
+        attrs.int("negative", -5);
+        attrs.int("max", i64::MAX);
+        attrs.int("min", i64::MIN);
+        attrs.float("half", 1.5);
+        attrs.float("whole", 2.0);
+        attrs.float("below", -0.25);
+        attrs.float("huge", 1e300);
+        attrs.float("tiny", 1e-7);
+
+        let rendered = attrs.render(0);
+
+        assert!(rendered.contains("negative = -5;"));
+        assert!(rendered.contains("max = 9223372036854775807;"));
+        assert!(rendered.contains("min = (-9223372036854775807 - 1);"));
+        assert!(rendered.contains("half = 1.5;"));
+        assert!(rendered.contains("whole = 2.0;"));
+        assert!(rendered.contains("below = -0.25;"));
+        assert!(rendered.contains("huge = 1.0e300;"));
+        assert!(rendered.contains("tiny = 1.0e-7;"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Nix has no literal for NaN")]
+    fn test_nix_attr_set_float_rejects_nan() {
+        NixAttrSet::new().float("x", f64::NAN);
+    }
+
+    #[test]
+    fn test_nix_attr_set_quotes_non_identifier_keys() {
+        let mut attrs = NixAttrSet::new();
+        attrs.string("foo.bar", "baz");
+        attrs.bool("with", true);
+        attrs.string("has\"quote", "x");
+        attrs.string("pkg-name'", "kept");
+
+        let rendered = attrs.render(0);
+
+        assert!(rendered.contains("  \"foo.bar\" = \"baz\";"));
+        assert!(rendered.contains("  \"with\" = true;"));
+        assert!(rendered.contains("  \"has\\\"quote\" = \"x\";"));
+        // Dashes and primes are valid in identifiers
+        assert!(rendered.contains("  pkg-name' = \"kept\";"));
+    }
+
+    #[test]
+    fn test_config_from_toml() {
+        let config = NixGenConfig::from_toml(
+            r#"
+            workspace_root = "/home/user/project"
+            content_addressed = true
+            extra_sources = [["siblingSrc", "/home/user/sibling"]]
+
+            [extra_native_build_inputs_per_package]
+            my-proto = ["pkgs.protobuf"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.workspace_root, "/home/user/project");
+        assert!(config.content_addressed);
+        assert_eq!(
+            config.extra_sources,
+            vec![("siblingSrc".to_string(), "/home/user/sibling".to_string())]
+        );
+        assert_eq!(
+            config.extra_native_build_inputs_per_package["my-proto"],
+            vec!["pkgs.protobuf"]
+        );
+        // Unset fields keep their defaults
+        assert!(!config.cross_compiling);
+        assert_eq!(
+            config.hash_len_bytes,
+            crate::unit_graph::DEFAULT_HASH_LEN_BYTES
+        );
+
+        assert!(NixGenConfig::from_toml("content_addressed = \"yes\"").is_err());
+    }
+
+    #[test]
+    fn test_nix_attr_set_merge() {
+        let mut attrs = NixAttrSet::new();
+        attrs.string("pname", "my-crate");
+        attrs.bool("dontStrip", true);
+
+        let mut extra = NixAttrSet::new();
+        extra.bool("dontStrip", false);
+        extra.expr("meta", "{ mainProgram = \"my-crate\"; }");
+        extra.multiline("postInstall", "echo done");
+        attrs.merge(extra);
+
+        let keys: Vec<&str> = attrs.raw_attrs().map(|(k, _)| k).collect();
+        assert_eq!(keys, ["pname", "dontStrip", "meta", "postInstall"]);
+        assert!(
+            attrs
+                .raw_attrs()
+                .any(|(k, v)| k == "pname" && v == "\"my-crate\"")
+        );
+
+        let rendered = attrs.render(0);
+        assert!(rendered.contains("pname = \"my-crate\";"));
+        assert!(rendered.contains("dontStrip = false;"));
+        assert!(!rendered.contains("dontStrip = true;"));
+        assert!(rendered.contains("meta = { mainProgram = \"my-crate\"; };"));
+        assert!(rendered.contains("postInstall = ''"));
+    }
+
+    #[test]
+    fn test_unit_derivation_from_unit() {
+        let json = r#"{
+            "version": 1,
+            "units": [{
+                "pkg_id": "my-crate 0.1.0 (path+file:///workspace/crates/my-crate)",
+                "target": {
+                    "kind": ["lib"],
+                    "crate_types": ["lib"],
+                    "name": "my_crate",
+                    "src_path": "/workspace/crates/my-crate/src/lib.rs",
+                    "edition": "2021"
+                },
+                "profile": {"name": "dev", "opt_level": "0"},
+                "features": ["default", "std"],
+                "mode": "build",
+                "dependencies": []
+            }],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let unit = &graph.units[0];
+        let identity_hash = unit.identity_hash();
+        let drv_name = unit.derivation_name();
+
+        let drv = UnitDerivation::from_unit(
+            unit,
+            "/workspace",
+            &[],
+            false,
+            "rustToolchain",
+            &drv_name,
+            &identity_hash,
+        );
+
+        assert_eq!(drv.pname, "my_crate");
+        assert_eq!(drv.version, "0.1.0");
+        assert_eq!(drv.edition, "2021");
+        assert_eq!(drv.features, vec!["default", "std"]);
+        assert!(drv.src_path.contains("${src}"));
+        assert_eq!(drv.toolchain_var, "rustToolchain");
+    }
+
+    #[test]
+    fn test_nix_generator_simple() {
+        let json = r#"{
+            "version": 1,
+            "units": [{
+                "pkg_id": "test 0.1.0 (path+file:///workspace)",
+                "target": {
+                    "kind": ["lib"],
+                    "crate_types": ["lib"],
+                    "name": "test",
+                    "src_path": "/workspace/src/lib.rs",
+                    "edition": "2024"
+                },
+                "profile": {"name": "dev", "opt_level": "0"},
+                "features": [],
+                "mode": "build",
+                "dependencies": []
+            }],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            content_addressed: false,
+            ..Default::default()
+        };
+
+        let generator = NixGenerator::new(config);
+        let nix = generator.generate(&graph);
+
+        // Check structure
+        assert!(nix.contains("{ pkgs, rustToolchain, hostRustToolchain ? rustToolchain, src, extraNativeBuildInputs ? [], vendorDir ? null }:"));
+        assert!(nix.contains("mkUnit = attrs:"));
+        assert!(nix.contains("units = {"));
+        assert!(nix.contains("roots = ["));
+        assert!(nix.contains("default ="));
+
+        // Check derivation content
+        assert!(nix.contains("pname = \"test\""));
+        assert!(nix.contains("version = \"0.1.0\""));
+        assert!(nix.contains("--edition"));
+        assert!(nix.contains("2024"));
+    }
+
+    #[test]
+    fn test_nix_generator_with_deps() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "dep 0.1.0 (path+file:///workspace/dep)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "dep",
+                        "src_path": "/workspace/dep/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "dep", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            content_addressed: false,
+            ..Default::default()
+        };
+
+        let generator = NixGenerator::new(config);
+        let nix = generator.generate(&graph);
+
+        // Should have both units
+        assert!(nix.contains("pname = \"dep\""));
+        assert!(nix.contains("pname = \"app\""));
+
+        // Should have bin output in installPhase
+        assert!(nix.contains("cp build/app $out/bin/"));
+
+        // Should have --extern flag for dependency (with identity hash in filename)
+        assert!(nix.contains("--extern"));
+        assert!(nix.contains("dep="));
+        // Library files include identity hash: libdep-{hash}.rlib
+        assert!(nix.contains("/lib/libdep-") && nix.contains(".rlib"));
+
+        // -L flags are NOT added for direct deps (they're covered by --extern with explicit path)
+        // This test only has one direct dep, so no -L flags are generated
+    }
+
+    #[test]
+    fn test_extern_crate_wiring() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "serde 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "serde",
+                        "src_path": "/registry/serde/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": ["default", "std"],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "serde_derive 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "serde_derive",
+                        "src_path": "/registry/serde_derive/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [],
+                    "platform": "aarch64-apple-darwin"
+                },
+                {
+                    "pkg_id": "my_app 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "my_app",
+                        "src_path": "/workspace/src/main.rs",
+                        "edition": "2024"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "serde", "public": false},
+                        {"index": 1, "extern_crate_name": "serde_derive", "public": false}
+                    ]
+                }
+            ],
+            "roots": [2]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            content_addressed: false,
+            ..Default::default()
+        };
+
+        let generator = NixGenerator::new(config);
+        let nix = generator.generate(&graph);
+
+        // Should have all three units
+        assert!(nix.contains("pname = \"serde\""));
+        assert!(nix.contains("pname = \"serde_derive\""));
+        assert!(nix.contains("pname = \"my_app\""));
+
+        // my_app should have buildInputs with both dependencies
+        assert!(nix.contains("buildInputs = ["));
+
+        // Should have --extern flags for both dependencies
+        assert!(nix.contains("serde="));
+        assert!(nix.contains("serde_derive="));
+
+        // Regular lib dep should use .rlib (with identity hash in filename)
+        assert!(nix.contains("libserde-") && nix.contains(".rlib"));
+
+        // Proc-macro dep should use variable with platform fallback
+        // Should have variable setup: PROCMACRO_SERDE_DERIVE="..."
+        assert!(nix.contains("PROCMACRO_SERDE_DERIVE="));
+        // Should locate proc-macro dylib via find
+        assert!(nix.contains("libserde_derive.*"));
+        // Should use the variable in --extern: serde_derive="$PROCMACRO_SERDE_DERIVE"
+        assert!(nix.contains("serde_derive=\"$PROCMACRO_SERDE_DERIVE\""));
+    }
+
+    #[test]
+    fn test_dep_ref_in_build_inputs() {
+        let mut drv = UnitDerivation {
+            name: "test-0.1.0-abc123".to_string(),
+            pname: "test".to_string(),
+            crate_name: "test".to_string(),
+            version: "0.1.0".to_string(),
+            edition: "2024".to_string(),
+            crate_types: vec!["lib".to_string()],
+            src_path: "${src}/src/lib.rs".to_string(),
+            manifest_dir: "${src}".to_string(),
+            features: vec![],
+            opt_level: "0".to_string(),
+            is_test: false,
+            is_proc_macro: false,
+            deps: vec![],
+            lib_search_deps: vec![],
+            build_script_ref: None,
+            rustc_flags: RustcFlags::new(),
+            content_addressed: false,
+            toolchain_var: "rustToolchain".to_string(),
+            rustc_bootstrap: false,
+            extra_native_build_inputs: Vec::new(),
+            required_system_features: Vec::new(),
+            hardening_disable: Vec::new(),
+            cargo_env: Vec::new(),
+            out_dir_env: Vec::new(),
+            rustc_command: "rustc".to_string(),
+            build_dir: "build".to_string(),
+            ca_hash_algo: "sha256".to_string(),
+            trace: false,
+            debug_procmacro: false,
+            metadata_only: false,
+            is_std: false,
+            platform: None,
+            split_debuginfo_packed: false,
+            coverage: false,
+            codegen_units_from_cores: false,
+            prefer_dynamic: false,
+            dylib_deps: Vec::new(),
+            separate_debug_info: false,
+            stable_symlinks: false,
+            compact: false,
+            rpath: false,
+            pkg_id: "test 0.1.0 (path+file:///workspace)".to_string(),
+            identity_hash: "abc123".to_string(),
+        };
+
+        // Add a dependency
+        drv.add_dep(DepRef {
+            nix_var: "units.\"dep-0.1.0-xyz789\"".to_string(),
+            extern_crate_name: "dep".to_string(),
+            lib_name: "dep".to_string(),
+            identity_hash: "xyz789".to_string(),
+            derivation_name: "dep-0.1.0-xyz789".to_string(),
+            is_proc_macro: false,
+            metadata_only: false,
+            is_dylib: false,
+            noprelude: false,
+        });
+
+        let nix = drv.to_nix();
+
+        // Should have the dependency in buildInputs
+        assert!(nix.contains("buildInputs = [ units.\"dep-0.1.0-xyz789\" ]"));
+    }
+
+    #[test]
+    fn test_multiline_build_phase() {
+        // Use bin crate type so LTO is applied (LTO only works for bin/cdylib/staticlib)
+        let json = r#"{
+            "version": 1,
+            "units": [{
+                "pkg_id": "test 0.1.0 (path+file:///workspace)",
+                "target": {
+                    "kind": ["bin"],
+                    "crate_types": ["bin"],
+                    "name": "test",
+                    "src_path": "/workspace/src/main.rs",
+                    "edition": "2021"
+                },
+                "profile": {"name": "release", "opt_level": "3", "lto": "thin"},
+                "features": ["std", "derive"],
+                "mode": "build",
+                "dependencies": []
+            }],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let unit = &graph.units[0];
+        let identity_hash = unit.identity_hash();
+        let drv_name = unit.derivation_name();
+
+        let drv = UnitDerivation::from_unit(
+            unit,
+            "/workspace",
+            &[],
+            false,
+            "rustToolchain",
+            &drv_name,
+            &identity_hash,
+        );
+        let build_phase = drv.generate_build_phase();
+
+        // Check for proper flag formatting
+        assert!(build_phase.contains("--crate-name"));
+        assert!(build_phase.contains("test"));
+        assert!(build_phase.contains("--edition"));
+        assert!(build_phase.contains("2021"));
+        assert!(build_phase.contains("opt-level=3"));
+        assert!(build_phase.contains("lto=thin"));
+        assert!(
+            build_phase.contains("feature=\\\"std\\\"") || build_phase.contains("feature=\"std\"")
+        );
+    }
+
+    #[test]
+    fn test_content_addressed_derivation() {
+        let json = r#"{
+            "version": 1,
+            "units": [{
+                "pkg_id": "test 0.1.0 (path+file:///workspace)",
+                "target": {
+                    "kind": ["lib"],
+                    "crate_types": ["lib"],
+                    "name": "test",
+                    "src_path": "/workspace/src/lib.rs",
+                    "edition": "2021"
+                },
+                "profile": {"name": "dev", "opt_level": "0"},
+                "features": [],
+                "mode": "build",
+                "dependencies": []
+            }],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let unit = &graph.units[0];
+        let identity_hash = unit.identity_hash();
+        let drv_name = unit.derivation_name();
+
+        // Without content-addressed
+        let drv = UnitDerivation::from_unit(
+            unit,
+            "/workspace",
+            &[],
+            false,
+            "rustToolchain",
+            &drv_name,
+            &identity_hash,
+        );
+        let nix = drv.to_nix();
+        assert!(!nix.contains("__contentAddressed"));
+        assert!(!nix.contains("outputHashMode"));
+        assert!(!nix.contains("outputHashAlgo"));
+
+        // With content-addressed
+        let drv_ca = UnitDerivation::from_unit(
+            unit,
+            "/workspace",
+            &[],
+            true,
+            "rustToolchain",
+            &drv_name,
+            &identity_hash,
+        );
+        let nix_ca = drv_ca.to_nix();
+        assert!(nix_ca.contains("__contentAddressed = true"));
+        assert!(nix_ca.contains("outputHashMode = \"recursive\""));
+        assert!(nix_ca.contains("outputHashAlgo = \"sha256\""));
+    }
+
+    #[test]
+    fn test_nix_generator_content_addressed() {
+        let json = r#"{
+            "version": 1,
+            "units": [{
+                "pkg_id": "test 0.1.0 (path+file:///workspace)",
+                "target": {
+                    "kind": ["lib"],
+                    "crate_types": ["lib"],
+                    "name": "test",
+                    "src_path": "/workspace/src/lib.rs",
+                    "edition": "2024"
+                },
+                "profile": {"name": "dev", "opt_level": "0"},
+                "features": [],
+                "mode": "build",
+                "dependencies": []
+            }],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+
+        // Without CA
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            content_addressed: false,
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+        assert!(!nix.contains("__contentAddressed"));
+
+        // With CA
+        let config_ca = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            content_addressed: true,
+            ..Default::default()
+        };
+        let nix_ca = NixGenerator::new(config_ca).generate(&graph);
+        assert!(nix_ca.contains("__contentAddressed = true"));
+        assert!(nix_ca.contains("outputHashMode = \"recursive\""));
+        assert!(nix_ca.contains("outputHashAlgo = \"sha256\""));
+    }
+
+    #[test]
+    fn test_ca_hash_algo() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_sys",
+                        "src_path": "/workspace/my-sys/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 2, "extern_crate_name": "my_sys", "public": false}
+                    ]
+                }
+            ],
+            "roots": [3]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            content_addressed: true,
+            ca_hash_algo: "sha512".to_string(),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        // Unit, build script compile, and build script run derivations all use it
+        let nix = NixGenerator::new(config.clone()).generate(&graph);
+        assert_eq!(nix.matches("__contentAddressed = true;").count(), 4);
+        assert_eq!(nix.matches("outputHashAlgo = \"sha512\";").count(), 4);
+        assert!(!nix.contains("sha256"));
+
+        let config = NixGenConfig {
+            ca_hash_algo: "md5".to_string(),
+            ..config
+        };
+        assert!(config.validate().unwrap_err().contains("ca_hash_algo"));
+        assert!(NixGenConfig::from_toml("ca_hash_algo = \"sha512\"").is_ok());
+    }
+
+    #[test]
+    fn test_build_script_output_wiring() {
+        // Test a unit graph where a library depends on a build script
+        // Real cargo output has THREE units for build scripts:
+        // 1. COMPILE unit: mode="build", kind=["custom-build"] - compiles build.rs
+        // 2. RUN unit: mode="run-custom-build" - executes the compiled binary
+        // 3. LIB unit: depends on RUN unit for build script outputs
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": ["feature-x"],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": ["feature-x"],
+                    "mode": "run-custom-build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_crate",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": ["feature-x"],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                }
+            ],
+            "roots": [2]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            content_addressed: false,
+            ..Default::default()
+        };
+
+        let generator = NixGenerator::new(config);
+        let nix = generator.generate(&graph);
+
+        // Should have build script compile derivation (now uses target name "build-script-build")
+        assert!(
+            nix.contains("pname = \"build-script-build\""),
+            "missing build script compile derivation"
+        );
+
+        // Should have build script run derivation
+        assert!(
+            nix.contains("my-crate-build-script-run-"),
+            "missing build script run derivation name"
+        );
+        assert!(
+            nix.contains("pname = \"my-crate-build-script-output\""),
+            "missing build script output pname"
+        );
+
+        // The library should read build script outputs
+        assert!(
+            nix.contains("BUILD_SCRIPT_FLAGS"),
+            "missing BUILD_SCRIPT_FLAGS"
+        );
+        assert!(
+            nix.contains("# Read build script outputs"),
+            "missing build script outputs comment"
+        );
+        assert!(nix.contains("rustc-cfg"), "missing rustc-cfg handling");
+
+        // Library build phase should include $BUILD_SCRIPT_FLAGS
+        assert!(
+            nix.contains("$BUILD_SCRIPT_FLAGS"),
+            "missing $BUILD_SCRIPT_FLAGS in build phase"
+        );
+
+        // Library should have build script run derivation in buildInputs
+        assert!(
+            nix.contains("my-crate-build-script-run-"),
+            "missing build script run derivation reference"
+        );
+    }
+
+    #[test]
+    fn test_build_script_ref_in_build_inputs() {
+        let mut drv = UnitDerivation {
+            name: "test-0.1.0-abc123".to_string(),
+            pname: "test".to_string(),
+            crate_name: "test".to_string(),
+            version: "0.1.0".to_string(),
+            edition: "2024".to_string(),
+            crate_types: vec!["lib".to_string()],
+            src_path: "${src}/src/lib.rs".to_string(),
+            manifest_dir: "${src}".to_string(),
+            features: vec![],
+            opt_level: "0".to_string(),
+            is_test: false,
+            is_proc_macro: false,
+            deps: vec![],
+            lib_search_deps: vec![],
+            build_script_ref: Some(BuildScriptRef {
+                run_drv_var: "units.\"my-build-script-run\"".to_string(),
+                compile_drv_name: "my-build-script".to_string(),
+                run_drv_name: "my-build-script-run".to_string(),
+            }),
+            rustc_flags: RustcFlags::new(),
+            content_addressed: false,
+            toolchain_var: "rustToolchain".to_string(),
+            rustc_bootstrap: false,
+            extra_native_build_inputs: Vec::new(),
+            required_system_features: Vec::new(),
+            hardening_disable: Vec::new(),
+            cargo_env: Vec::new(),
+            out_dir_env: Vec::new(),
+            rustc_command: "rustc".to_string(),
+            build_dir: "build".to_string(),
+            ca_hash_algo: "sha256".to_string(),
+            trace: false,
+            debug_procmacro: false,
+            metadata_only: false,
+            is_std: false,
+            platform: None,
+            split_debuginfo_packed: false,
+            coverage: false,
+            codegen_units_from_cores: false,
+            prefer_dynamic: false,
+            dylib_deps: Vec::new(),
+            separate_debug_info: false,
+            stable_symlinks: false,
+            compact: false,
+            rpath: false,
+            pkg_id: "test 0.1.0 (path+file:///workspace)".to_string(),
+            identity_hash: "abc123".to_string(),
+        };
+
+        // Add a regular dependency too
+        drv.add_dep(DepRef {
+            nix_var: "units.\"dep-0.1.0-xyz789\"".to_string(),
+            extern_crate_name: "dep".to_string(),
+            lib_name: "dep".to_string(),
+            identity_hash: "xyz789".to_string(),
+            derivation_name: "dep-0.1.0-xyz789".to_string(),
+            is_proc_macro: false,
+            metadata_only: false,
+            is_dylib: false,
+            noprelude: false,
+        });
+
+        let nix = drv.to_nix();
+
+        // Should have both regular dep and build script in buildInputs
+        assert!(nix.contains("buildInputs = ["));
+        assert!(nix.contains("units.\"dep-0.1.0-xyz789\""));
+        assert!(nix.contains("units.\"my-build-script-run\""));
+
+        // Build phase should read build script outputs
+        let build_phase = drv.generate_build_phase();
+        assert!(build_phase.contains("BUILD_SCRIPT_FLAGS"));
+        assert!(build_phase.contains("units.\"my-build-script-run\""));
+        assert!(build_phase.contains("rustc-cfg"));
+
+        // rustc-env variables are exported before rustc runs, for env!()
+        let export = build_phase
+            .find("units.\"my-build-script-run\"}/rustc-env ]")
+            .expect("rustc-env not read");
+        assert!(build_phase[export..].contains("export \"$line\""));
+        assert!(export < build_phase.find("rustc --remap-path-prefix").unwrap());
+    }
+
+    #[test]
+    fn test_proc_macro_host_toolchain() {
+        // Test that proc-macros use hostRustToolchain in cross-compilation
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "serde_derive 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "serde_derive",
+                        "src_path": "/registry/serde_derive/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [],
+                    "platform": "aarch64-apple-darwin"
+                },
+                {
+                    "pkg_id": "my_app 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "my_app",
+                        "src_path": "/workspace/src/main.rs",
+                        "edition": "2024"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "serde_derive", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+
+        // Without cross-compilation: both use rustToolchain
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            content_addressed: false,
+            cross_compiling: false,
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+
+        // Should use rustToolchain for both (hostRustToolchain is in signature but defaults to rustToolchain)
+        assert!(nix.contains("{ pkgs, rustToolchain, hostRustToolchain ? rustToolchain, src, extraNativeBuildInputs ? [], vendorDir ? null }:"));
+        // Proc-macro should use rustToolchain when not cross-compiling
+        assert!(nix.contains("nativeBuildInputs = [ rustToolchain ]"));
+        // Should NOT have hostRustToolchain in nativeBuildInputs when not cross-compiling
+        assert!(!nix.contains("nativeBuildInputs = [ hostRustToolchain ]"));
+
+        // With cross-compilation: proc-macro uses hostRustToolchain
+        let config_cross = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            content_addressed: false,
+            cross_compiling: true,
+            host_platform: Some("aarch64-apple-darwin".to_string()),
+            target_platform: Some("x86_64-unknown-linux-gnu".to_string()),
+            ..Default::default()
+        };
+        let nix_cross = NixGenerator::new(config_cross).generate(&graph);
+
+        // Should have hostRustToolchain in function signature
+        assert!(nix_cross.contains("hostRustToolchain"));
+        assert!(
+            nix_cross.contains("{ pkgs, rustToolchain, hostRustToolchain ? rustToolchain, src, extraNativeBuildInputs ? [], vendorDir ? null }:")
+        );
+
+        // Proc-macro should use hostRustToolchain
+        // Regular bin should use rustToolchain
+        // Check that both toolchains appear in nativeBuildInputs
+        assert!(nix_cross.contains("nativeBuildInputs = [ hostRustToolchain ]"));
+        assert!(nix_cross.contains("nativeBuildInputs = [ rustToolchain ]"));
+    }
+
+    #[test]
+    fn test_proc_macro_output_path() {
+        // Test that proc-macros output to shared library path
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my_macro 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "my_macro",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [],
+                    "platform": "x86_64-unknown-linux-gnu"
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let unit = &graph.units[0];
+        let identity_hash = unit.identity_hash();
+        let drv_name = unit.derivation_name();
+
+        let drv = UnitDerivation::from_unit(
+            unit,
+            "/workspace",
+            &[],
+            false,
+            "rustToolchain",
+            &drv_name,
+            &identity_hash,
+        );
+        let build_phase = drv.generate_build_phase();
+
+        // Should use --out-dir for libraries (including proc-macros)
+        assert!(build_phase.contains("--out-dir build"));
+        // Proc-macros use dep-info,link only (metadata embedded in dylib)
+        assert!(build_phase.contains("--emit=dep-info,link"));
+        assert!(!build_phase.contains("--emit=dep-info,metadata,link"));
+        assert!(drv.is_proc_macro);
+
+        // Check install phase copies all outputs to $out
+        let install_phase = drv.generate_install_phase();
+        assert!(install_phase.contains("$out/lib"));
+        assert!(install_phase.contains("cp build/*"));
+    }
+
+    #[test]
+    fn test_workspace_packages_attrset() {
+        // Test workspace with multiple root units
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "core-lib 0.1.0 (path+file:///workspace/crates/core)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "core_lib",
+                        "src_path": "/workspace/crates/core/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-app 0.1.0 (path+file:///workspace/crates/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "my_app",
+                        "src_path": "/workspace/crates/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "core_lib", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "cli-tool 0.1.0 (path+file:///workspace/crates/cli)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "cli_tool",
+                        "src_path": "/workspace/crates/cli/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "core_lib", "public": false}
+                    ]
+                }
+            ],
+            "roots": [0, 1, 2]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            content_addressed: false,
+            ..Default::default()
+        };
+
+        let generator = NixGenerator::new(config);
+        let nix = generator.generate(&graph);
+
+        // Should have packages attrset with all roots
+        assert!(nix.contains("packages = {"));
+        assert!(nix.contains("\"core_lib\" = units.\""));
+        assert!(nix.contains("\"my_app\" = units.\""));
+        assert!(nix.contains("\"cli_tool\" = units.\""));
+
+        // Should have binaries attrset with only binaries
+        assert!(nix.contains("binaries = {"));
+        // binaries should contain my_app and cli_tool but NOT core_lib
+        let binaries_section = nix
+            .split("# Binary targets only")
+            .nth(1)
+            .unwrap()
+            .split("# Library targets only")
+            .next()
+            .unwrap();
+        assert!(binaries_section.contains("\"my_app\""));
+        assert!(binaries_section.contains("\"cli_tool\""));
+        assert!(!binaries_section.contains("\"core_lib\""));
+
+        // all-bins joins every binary derivation into one output
+        let all_bins = nix
+            .split("all-bins = pkgs.symlinkJoin {")
+            .nth(1)
+            .unwrap()
+            .split("};")
+            .next()
+            .unwrap();
+        assert!(all_bins.contains("name = \"all-bins\";"));
+        for bin in ["my_app", "cli_tool"] {
+            let drv_ref = binaries_section
+                .split(&format!("\"{bin}\" = "))
+                .nth(1)
+                .unwrap()
+                .split(';')
+                .next()
+                .unwrap();
+            assert!(all_bins.contains(drv_ref), "{bin} missing from all-bins");
+        }
+        assert_eq!(all_bins.matches("units.").count(), 2);
+
+        // Should have libraries attrset with only libraries
+        assert!(nix.contains("libraries = {"));
+        let libraries_section = nix.split("# Library targets only").nth(1).unwrap();
+        assert!(libraries_section.contains("\"core_lib\""));
+        // Libraries should NOT contain binaries
+        assert!(
+            !libraries_section
+                .split("default =")
+                .next()
+                .unwrap()
+                .contains("\"my_app\"")
+        );
+    }
+
+    #[test]
+    fn test_rustc_bootstrap_per_package() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "needs-nightly 0.1.0 (path+file:///workspace/needs-nightly)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "needs_nightly",
+                        "src_path": "/workspace/needs-nightly/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "needs_nightly", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+
+        // Disabled by default
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+        assert!(!nix.contains("RUSTC_BOOTSTRAP"));
+
+        // Enabled only for the configured package
+        let mut config_bootstrap = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        config_bootstrap
+            .rustc_bootstrap_packages
+            .insert("needs-nightly".to_string(), true);
+        let nix_bootstrap = NixGenerator::new(config_bootstrap).generate(&graph);
+        assert_eq!(nix_bootstrap.matches("export RUSTC_BOOTSTRAP=1").count(), 1);
+
+        let lib_section = unit_section(&nix_bootstrap, "needs_nightly");
+        assert!(lib_section.contains("export RUSTC_BOOTSTRAP=1"));
+
+        // Bootstrapping changes compilation, so it must change the identity hash
+        let lib_drv = |nix: &str| {
+            nix.lines()
+                .find(|l| l.trim_start().starts_with("\"needs_nightly-0.1.0-"))
+                .unwrap()
+                .to_string()
+        };
+        assert_ne!(lib_drv(&nix), lib_drv(&nix_bootstrap));
+    }
+
+    #[test]
+    fn test_extra_sources() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "sibling 0.1.0 (path+file:///home/user/sibling)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "sibling",
+                        "src_path": "/home/user/sibling/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///home/user/project)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/home/user/project/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "sibling", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/home/user/project".to_string(),
+            extra_sources: vec![("siblingSrc".to_string(), "/home/user/sibling".to_string())],
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+
+        // Extra source variables become arguments of the expression
+        assert!(nix.contains("vendorDir ? null, siblingSrc }:"));
+        assert!(nix.contains("${siblingSrc}/src/lib.rs"));
+        assert!(nix.contains("export CARGO_MANIFEST_DIR=\"${siblingSrc}\""));
+        assert!(nix.contains("${src}/src/main.rs"));
+        assert!(!nix.contains("/home/user/sibling/src/lib.rs"));
+    }
+
+    #[test]
+    fn test_extra_native_build_inputs_per_package() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "proto-types 0.1.0 (path+file:///workspace/proto-types)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "proto_types",
+                        "src_path": "/workspace/proto-types/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "proto_types", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let mut config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        config
+            .extra_native_build_inputs_per_package
+            .insert("proto-types".to_string(), vec!["pkgs.protobuf".to_string()]);
+        let nix = NixGenerator::new(config).generate(&graph);
+
+        assert!(
+            unit_section(&nix, "proto_types")
+                .contains("nativeBuildInputs = [ rustToolchain pkgs.protobuf ]")
+        );
+        assert!(unit_section(&nix, "app").contains("nativeBuildInputs = [ rustToolchain ]"));
+        assert_eq!(nix.matches("pkgs.protobuf").count(), 1);
+    }
+
+    #[test]
+    fn test_linker_config() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-lib 0.1.0 (path+file:///workspace/my-lib)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_lib",
+                        "src_path": "/workspace/my-lib/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "my_lib", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            linker: Some("${pkgs.mold}/bin/mold".to_string()),
+            linker_flavor: Some("gcc".to_string()),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+
+        let app = unit_section(&nix, "app");
+        assert!(app.contains("linker=${pkgs.mold}/bin/mold"));
+        assert!(app.contains("linker-flavor=gcc"));
+        assert!(!unit_section(&nix, "my_lib").contains("linker"));
+
+        // The linker is part of the binary's identity, but not the rlib's
+        let default_nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+        assert_eq!(
+            drv_line(&nix, "\"my_lib-0.1.0-"),
+            drv_line(&default_nix, "\"my_lib-0.1.0-")
+        );
+        assert_ne!(
+            drv_line(&nix, "\"app-0.1.0-"),
+            drv_line(&default_nix, "\"app-0.1.0-")
+        );
+    }
+
+    #[test]
+    fn test_build_phase_fails_fast() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_crate",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+        assert!(nix.contains("set -euo pipefail"));
+        assert!(!nix.contains("set -x"));
+
+        let nix_trace = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            trace: true,
+            ..Default::default()
+        })
+        .generate(&graph);
+        assert!(nix_trace.contains("set -euo pipefail"));
+        assert!(nix_trace.contains("set -x"));
+    }
+
+    #[test]
+    fn test_debug_procmacro_logging() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my_derive 0.1.0 (path+file:///workspace/my_derive)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "my_derive",
+                        "src_path": "/workspace/my_derive/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my_app 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "my_app",
+                        "src_path": "/workspace/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "my_derive", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+
+        // Quiet by default
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+        assert!(nix.contains("PROCMACRO_MY_DERIVE="));
+        assert!(!nix.contains("DEBUG:"));
+        assert!(!nix.contains("ls -la"));
+
+        let nix_debug = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            debug_procmacro: true,
+            ..Default::default()
+        })
+        .generate(&graph);
+        assert!(nix_debug.contains("echo \"DEBUG: PROCMACRO_MY_DERIVE=$PROCMACRO_MY_DERIVE\""));
+        assert!(nix_debug.contains("ls -la ${units."));
+    }
+
+    #[test]
+    fn test_check_only_test_units() {
+        let unit = |pkg: &str, kind: &str, mode: &str, deps: &str| {
+            format!(
+                r#"{{
+                    "pkg_id": "{pkg} 0.1.0 (path+file:///workspace/{pkg})",
+                    "target": {{
+                        "kind": ["{kind}"],
+                        "crate_types": ["{kind}"],
+                        "name": "{pkg}",
+                        "src_path": "/workspace/{pkg}/src/lib.rs",
+                        "edition": "2021"
+                    }},
+                    "profile": {{"name": "dev", "opt_level": "0"}},
+                    "features": [],
+                    "mode": "{mode}",
+                    "dependencies": [{deps}]
+                }}"#
+            )
+        };
+        let deps = r#"{"index": 0, "extern_crate_name": "a", "public": false},
+            {"index": 1, "extern_crate_name": "my_macro", "public": false}"#;
+        let json = format!(
+            r#"{{"version": 1, "units": [{}, {}, {}], "roots": [2]}}"#,
+            unit("a", "lib", "build", ""),
+            unit("my_macro", "proc-macro", "build", ""),
+            unit("app", "lib", "test", deps),
+        );
+
+        let graph = parse_test_unit_graph(&json);
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let default_names = NixGenerator::new(base.clone()).derivation_names(&graph);
+        let generator = NixGenerator::new(NixGenConfig {
+            check_only: true,
+            ..base
+        });
+        let nix = generator.generate(&graph);
+        let names = generator.derivation_names(&graph);
+
+        // The test harness is checked against the rmeta, not linked against it
+        let app = unit_section(&nix, "app");
+        assert!(app.contains("/lib/liba-"));
+        assert!(app.contains(".rmeta \\"));
+        assert!(app.contains("--emit=dep-info,metadata \\"));
+        assert!(!app.contains("--emit=dep-info,metadata,link"));
+
+        // Only units check_only turns metadata-only get a new identity
+        assert_ne!(names[0], default_names[0]);
+        assert_ne!(names[2], default_names[2]);
+        assert_eq!(names[1], default_names[1]);
+    }
+
+    #[test]
+    fn test_check_mode_metadata_only() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-lib 0.1.0 (path+file:///workspace/my-lib)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_lib",
+                        "src_path": "/workspace/my-lib/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "check",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "check",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "my_lib", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+
+        assert!(nix.contains("--emit=dep-info,metadata \\"));
+        assert!(!nix.contains("--emit=dep-info,metadata,link"));
+        assert!(nix.contains("cp build/*.rmeta $out/lib/"));
+        assert!(!nix.contains("cp build/* $out/lib/"));
+        assert!(!nix.contains(".rlib"));
+        // The binary is checked too, so nothing is linked into $out/bin
+        assert!(!nix.contains("-o build/app"));
+        assert!(nix.contains(".rmeta"));
+
+        // The consumer's --extern points at the dependency's .rmeta
+        let dep_drv = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .derivation_names(&graph)
+        .swap_remove(0);
+        let hash = dep_drv.rsplit('-').next().unwrap();
+        assert!(nix.contains(&format!(
+            "--extern my_lib=${{units.\"{dep_drv}\"}}/lib/libmy_lib-{hash}.rmeta \\"
+        )));
+    }
+
+    #[test]
+    fn test_proc_macro_depending_on_proc_macro() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "helper-derive 0.1.0 (path+file:///workspace/helper-derive)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "helper_derive",
+                        "src_path": "/workspace/helper-derive/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "outer-derive 0.1.0 (path+file:///workspace/outer-derive)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "outer_derive",
+                        "src_path": "/workspace/outer-derive/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "helper_derive", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+
+        let outer = unit_section(&nix, "outer_derive");
+
+        // The outer proc-macro locates the helper's dylib and passes it via --extern
+        assert!(outer.contains("PROCMACRO_HELPER_DERIVE=\"$(find ${units."));
+        assert!(outer.contains("--extern helper_derive=\"$PROCMACRO_HELPER_DERIVE\""));
+        assert!(outer.contains("--extern proc_macro"));
+    }
+
+    #[test]
+    fn test_hash_len_bytes() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_crate",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            hash_len_bytes: 16,
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        let nix = NixGenerator::new(config.clone()).generate(&graph);
+
+        let hash = graph.units[0].identity_hash_with_len(&[], 16);
+        assert_eq!(hash.len(), 32);
+        assert!(nix.contains(&format!("\"my_crate-0.1.0-{hash}\" = mkUnit")));
+        // Deterministic across runs
+        assert_eq!(nix, NixGenerator::new(config).generate(&graph));
+
+        // Out-of-range lengths are rejected, and clamped if validation is skipped
+        for (hash_len_bytes, clamped) in [(3, 4), (33, 32)] {
+            let config = NixGenConfig {
+                workspace_root: "/workspace".to_string(),
+                hash_len_bytes,
+                ..Default::default()
+            };
+            assert!(config.validate().is_err());
+            let hash = graph.units[0].identity_hash_with_len(&[], clamped);
+            let nix = NixGenerator::new(config).generate(&graph);
+            assert!(nix.contains(&format!("\"my_crate-0.1.0-{hash}\" = mkUnit")));
+        }
+    }
+
+    #[test]
+    fn test_write_to_matches_generate() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-lib 0.1.0 (path+file:///workspace/my-lib)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_lib",
+                        "src_path": "/workspace/my-lib/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "my_lib", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let generator = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        });
+
+        let mut buf = Vec::new();
+        generator.write_to(&graph, &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), generator.generate(&graph));
+    }
+
+    #[test]
+    fn test_dylib_dependency() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "plugin-api 0.1.0 (path+file:///workspace/plugin-api)",
+                    "target": {
+                        "kind": ["dylib"],
+                        "crate_types": ["dylib"],
+                        "name": "plugin_api",
+                        "src_path": "/workspace/plugin-api/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "host 0.1.0 (path+file:///workspace/host)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "host",
+                        "src_path": "/workspace/host/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "plugin_api", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+
+        let host = unit_section(&nix, "host");
+        let hash = graph.units[0].identity_hash();
+
+        // The dylib is located by its hashed file name and passed via --extern
+        assert!(host.contains(&format!("-name 'libplugin_api-{hash}.so'")));
+        assert!(host.contains(&format!("-name 'libplugin_api-{hash}.dylib'")));
+        assert!(host.contains("--extern plugin_api=\"$DYLIB_PLUGIN_API\""));
+        assert!(!host.contains(&format!("libplugin_api-{hash}.rlib")));
+        // The consumer links std dynamically and gets runtime paths to the dylib and std
+        assert!(host.contains("-C prefer-dynamic"));
+        assert!(host.contains("-C link-arg=-Wl,-rpath,${units."));
+        assert!(host.contains("-C link-arg=-Wl,-rpath,\"$(rustc --print target-libdir)\" \\"));
+    }
+
+    #[test]
+    fn test_transitive_dylib_dependency() {
+        let unit = |name: &str, crate_type: &str, kind: &str, deps: &str| {
+            format!(
+                r#"{{
+                    "pkg_id": "{name} 0.1.0 (path+file:///workspace/{name})",
+                    "target": {{
+                        "kind": ["{kind}"],
+                        "crate_types": ["{crate_type}"],
+                        "name": "{name}",
+                        "src_path": "/workspace/{name}/src/lib.rs",
+                        "edition": "2021"
+                    }},
+                    "profile": {{"name": "dev", "opt_level": "0"}},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [{deps}]
+                }}"#
+            )
+        };
+        let dep = |index: usize, name: &str| {
+            format!(r#"{{"index": {index}, "extern_crate_name": "{name}", "public": false}}"#)
+        };
+        let json = format!(
+            r#"{{"version": 1, "units": [{}, {}, {}], "roots": [2]}}"#,
+            unit("plugin_api", "dylib", "dylib", ""),
+            unit("wrapper", "lib", "lib", &dep(0, "plugin_api")),
+            unit("host", "bin", "bin", &dep(1, "wrapper")),
+        );
+
+        let graph = parse_test_unit_graph(&json);
+        let generator = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        });
+        let nix = generator.generate(&graph);
+        let drv_names = generator.derivation_names(&graph);
+        let host = unit_section(&nix, "host");
+
+        // Reached only through the rlib: no --extern, but rustc finds it through -L
+        assert!(!host.contains("DYLIB_PLUGIN_API"));
+        assert!(host.contains(&format!(
+            "-L dependency=${{units.\"{}\"}}/lib \\",
+            drv_names[0]
+        )));
+        // Linking it still needs std as a dylib and runtime paths to both
+        assert!(host.contains("-C prefer-dynamic"));
+        assert!(host.contains(&format!(
+            "-C link-arg=-Wl,-rpath,${{units.\"{}\"}}/lib \\",
+            drv_names[0]
+        )));
+        assert!(host.contains("-C link-arg=-Wl,-rpath,\"$(rustc --print target-libdir)\" \\"));
+    }
+
+    #[test]
+    fn test_extern_proc_macro_only_for_proc_macro_crates() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my_derive 0.1.0 (path+file:///workspace/my_derive)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "my_derive",
+                        "src_path": "/workspace/my_derive/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my_lib 0.1.0 (path+file:///workspace/my_lib)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_lib",
+                        "src_path": "/workspace/my_lib/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "my_derive", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+
+        // The proc-macro crate itself links against the compiler-provided crate
+        assert!(unit_section(&nix, "my_derive").contains("--extern proc_macro \\"));
+        // A crate that merely uses the proc-macro must not
+        let lib = unit_section(&nix, "my_lib");
+        assert!(lib.contains("--extern my_derive=\"$PROCMACRO_MY_DERIVE\""));
+        assert!(!lib.contains("--extern proc_macro"));
+    }
+
+    #[test]
+    fn test_hyphenated_binary_name() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "example-app 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "example-app",
+                        "src_path": "/workspace/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+
+        // The crate name is normalized, but -o fixes the output file name,
+        // so the compiled binary and the copied file agree
+        assert!(nix.contains("--crate-name \\"));
+        assert!(nix.contains("example_app \\"));
+        assert!(nix.contains("-o build/example-app \\"));
+        assert!(nix.contains("cp build/example-app $out/bin/"));
+        assert!(nix.contains("chmod 755 $out/bin/example-app"));
+
+        // Consumers see the original (hyphenated) name
+        let binaries = nix.split("binaries = {").nth(1).unwrap();
+        assert!(binaries.contains("\"example-app\" = units."));
+    }
+
+    #[test]
+    fn test_build_std_units() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "core 0.0.0 (path+file:///home/user/.rustup/toolchains/nightly/lib/rustlib/src/rust/library/core)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "core",
+                        "src_path": "/home/user/.rustup/toolchains/nightly/lib/rustlib/src/rust/library/core/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [],
+                    "is_std": true
+                },
+                {
+                    "pkg_id": "firmware 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "firmware",
+                        "src_path": "/workspace/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "core", "public": true, "noprelude": true}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+
+        // The std unit is built from the toolchain's rust-src like the sysroot
+        let core = unit_section(&nix, "core");
+        assert!(core.contains("${rustToolchain}/lib/rustlib/src/rust/library/core/src/lib.rs"));
+        assert!(core.contains("-Z force-unstable-if-unmarked"));
+        assert!(core.contains("export RUSTC_BOOTSTRAP=1"));
+
+        // Dependents get noprelude extern wiring
+        let firmware = unit_section(&nix, "firmware");
+        assert!(firmware.contains("--extern noprelude:core=${units."));
+        assert!(firmware.contains("-Z unstable-options"));
+        assert!(!firmware.contains("force-unstable-if-unmarked"));
+
+        // Std units are grouped under `sysroot`, not mixed into workspace outputs
+        let sysroot = nix
+            .split("sysroot = {")
+            .nth(1)
+            .unwrap()
+            .split("};")
+            .next()
+            .unwrap();
+        assert!(sysroot.contains("\"core\" = units."));
+        assert!(!sysroot.contains("firmware"));
+    }
+
+    #[test]
+    fn test_strict_sandbox_rejects_unremappable_path() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "stray 0.1.0 (path+file:///opt/elsewhere/stray)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "stray",
+                        "src_path": "/opt/elsewhere/stray/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+
+        // Lenient (default): falls back to the absolute path
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config.clone()).generate(&graph);
+        assert!(nix.contains("/opt/elsewhere/stray/src/lib.rs"));
+
+        // Strict: hard error naming the unit and path, nothing written
+        let config = NixGenConfig {
+            strict_sandbox: true,
+            ..config
+        };
+        let mut out = Vec::new();
+        let err = NixGenerator::new(config)
+            .write_to(&graph, &mut out)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let msg = err.to_string();
+        assert!(msg.contains("stray 0.1.0"), "{msg}");
+        assert!(msg.contains("/opt/elsewhere/stray/src/lib.rs"), "{msg}");
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_cross_compile_target_flag() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "serde_derive 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "serde_derive",
+                        "src_path": "/registry/serde_derive/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my_app 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "my_app",
+                        "src_path": "/workspace/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "serde_derive", "public": false}
+                    ],
+                    "platform": "x86_64-unknown-linux-gnu"
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        }
+        .with_cross_compilation("aarch64-apple-darwin", "x86_64-unknown-linux-gnu");
+        let nix = NixGenerator::new(config).generate(&graph);
+
+        let app = unit_section(&nix, "my_app");
+        assert!(app.contains("--target=x86_64-unknown-linux-gnu"));
+
+        let proc_macro = unit_section(&nix, "serde_derive");
+        assert!(!proc_macro.contains("--target"));
+
+        // Not cross-compiling: no --target at all
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+        assert!(!nix.contains("--target"));
+    }
+
+    #[test]
+    fn test_rust_triple_to_nix_system() {
+        assert_eq!(
+            rust_triple_to_nix_system("aarch64-apple-darwin").as_deref(),
+            Some("aarch64-darwin")
+        );
+        assert_eq!(
+            rust_triple_to_nix_system("x86_64-unknown-linux-gnu").as_deref(),
+            Some("x86_64-linux")
+        );
+        assert_eq!(
+            rust_triple_to_nix_system("armv7-unknown-linux-gnueabihf").as_deref(),
+            Some("armv7l-linux")
+        );
+        assert_eq!(
+            rust_triple_to_nix_system("x86_64-pc-windows-msvc").as_deref(),
+            Some("x86_64-windows")
+        );
+        assert_eq!(rust_triple_to_nix_system("thumbv7em-none-eabihf"), None);
+    }
+
+    #[test]
+    fn test_meta_platforms_from_unit_platform() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_crate",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [],
+                    "platform": "aarch64-apple-darwin"
+                },
+                {
+                    "pkg_id": "host-only 0.1.0 (path+file:///workspace/host-only)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "host_only",
+                        "src_path": "/workspace/host-only/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0, 1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+
+        let my_crate = unit_section(&nix, "my_crate");
+        assert!(my_crate.contains("meta = { platforms = [ \"aarch64-darwin\" ]; };"));
+
+        // No platform recorded: no meta.platforms
+        let host_only = unit_section(&nix, "host_only");
+        assert!(!host_only.contains("platforms = "));
+
+        // Cross-compiling: everything builds on the build platform
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        }
+        .with_cross_compilation("x86_64-unknown-linux-gnu", "aarch64-apple-darwin");
+        let nix = NixGenerator::new(config).generate(&graph);
+        assert!(!nix.contains("\"aarch64-darwin\""));
+        assert_eq!(
+            nix.matches("meta = { platforms = [ \"x86_64-linux\" ]; };")
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_required_system_features_per_package() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "llvm-sys 0.1.0 (path+file:///workspace/llvm-sys)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "llvm_sys",
+                        "src_path": "/workspace/llvm-sys/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "llvm_sys", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let mut config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        config
+            .required_system_features_per_package
+            .insert("llvm-sys".to_string(), vec!["big-parallel".to_string()]);
+        let nix = NixGenerator::new(config.clone()).generate(&graph);
+
+        assert!(
+            unit_section(&nix, "llvm_sys")
+                .contains("requiredSystemFeatures = [ \"big-parallel\" ];")
+        );
+        assert!(!unit_section(&nix, "app").contains("requiredSystemFeatures"));
+
+        // The global list applies to packages without an override
+        config.required_system_features = vec!["kvm".to_string()];
+        let nix = NixGenerator::new(config).generate(&graph);
+        assert!(unit_section(&nix, "app").contains("requiredSystemFeatures = [ \"kvm\" ];"));
+        assert!(
+            unit_section(&nix, "llvm_sys")
+                .contains("requiredSystemFeatures = [ \"big-parallel\" ];")
+        );
+    }
+
+    #[test]
+    fn test_doctest_units_skipped() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_crate",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_crate",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "test", "opt_level": "0"},
+                    "features": [],
+                    "mode": "doctest",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "my_crate", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1, 0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        assert!(!graph.units[0].is_doctest());
+        assert!(graph.units[1].is_doctest());
+
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+
+        // Only the library derivation is generated
+        assert_eq!(nix.matches("pname = \"my_crate\"").count(), 1);
+        assert!(!nix.contains(&graph.units[1].identity_hash()));
+
+        // The doctest root is dropped; the library becomes the default
+        let lib_drv = graph.units[0].derivation_name();
+        assert!(nix.contains(&format!("roots = [ units.\"{lib_drv}\" ];")));
+        assert!(nix.contains(&format!("default = units.\"{lib_drv}\";")));
+    }
+
+    #[test]
+    fn test_rustc_command_wrapper() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_crate",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config.clone()).generate(&graph);
+        assert!(nix.contains(" rustc --remap-path-prefix="));
+
+        let config = NixGenConfig {
+            rustc_command: Some("sccache rustc".to_string()),
+            ..config
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+        assert!(nix.contains(" sccache rustc --remap-path-prefix="));
+    }
+
+    #[test]
+    fn test_no_incremental_flag() {
+        // Cargo's profile.incremental must never become `-C incremental=...`:
+        // each unit is a fresh sandboxed build with no incremental state.
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_crate",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0", "incremental": true},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        assert!(graph.units[0].profile.incremental);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+        assert!(!nix.contains("incremental"));
+    }
+
+    #[test]
+    fn test_keep_going_skips_unsupported_units() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "good-lib 0.1.0 (path+file:///workspace/good-lib)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "good_lib",
+                        "src_path": "/workspace/good-lib/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "mystery-crate",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "mystery_crate",
+                        "src_path": "/workspace/mystery/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "needs-mystery 0.1.0 (path+file:///workspace/needs-mystery)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "needs_mystery",
+                        "src_path": "/workspace/needs-mystery/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "mystery_crate", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "good_lib", "public": false}
+                    ]
+                }
+            ],
+            "roots": [3, 2]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            keep_going: true,
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+
+        // Supported units are emitted
+        assert!(nix.contains("pname = \"good_lib\""));
+        assert!(nix.contains("pname = \"app\""));
+
+        // The unsupported unit and its dependent are skipped and reported
+        assert!(!nix.contains("pname = \"mystery_crate\""));
+        assert!(!nix.contains("pname = \"needs_mystery\""));
+        assert!(nix.contains("# Skipped units (keep-going):"));
+        assert!(nix.contains("#   unit 1 (mystery-crate): unrecognized pkg_id or source type"));
+        assert!(nix.contains("#   unit 2 (needs-mystery 0.1.0 (path+file:///workspace/needs-mystery)): depends on skipped unit 1"));
+        assert!(!nix.contains("#   unit 0 "));
+
+        // Only the surviving root is exported
+        let roots = nix
+            .lines()
+            .find(|line| line.trim_start().starts_with("roots = "))
+            .unwrap();
+        assert_eq!(roots.matches("units.").count(), 1);
+        assert!(roots.contains("units.\"app-0.1.0-"));
+
+        // Without keep_going the unsupported unit is emitted best-effort
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+        assert!(nix.contains("pname = \"mystery_crate\""));
+        assert!(!nix.contains("Skipped units"));
+    }
+
+    #[test]
+    fn test_extern_renames() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "serde 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "serde",
+                        "src_path": "/registry/serde/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "serde", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let plain = NixGenerator::new(config.clone()).generate(&graph);
+
+        let mut config = config;
+        config
+            .extern_renames
+            .insert("serde".to_string(), "serde2".to_string());
+        let nix = NixGenerator::new(config).generate(&graph);
+
+        // The alias changes, but the rlib path keeps the real library name
+        assert!(nix.contains("--extern serde2=${units."));
+        assert!(nix.contains("/lib/libserde-"));
+        assert!(!nix.contains("--extern serde="));
+
+        // The dependent's identity changes; the renamed crate's does not
+        let serde_drv = plain.split("\"serde-1.0.0-").nth(1).unwrap();
+        let serde_hash = &serde_drv[..serde_drv.find('"').unwrap()];
+        assert!(nix.contains(&format!("\"serde-1.0.0-{serde_hash}\" = mkUnit")));
+        let app_drv = plain.split("\"app-0.1.0-").nth(1).unwrap();
+        let app_hash = &app_drv[..app_drv.find('"').unwrap()];
+        assert!(!nix.contains(app_hash));
+    }
+
+    #[test]
+    fn test_split_debuginfo_packed_debug_output() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-app 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "my-app",
+                        "src_path": "/workspace/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {
+                        "name": "release",
+                        "opt_level": "3",
+                        "debuginfo": 2,
+                        "split_debuginfo": "packed"
+                    },
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+
+        assert!(nix.contains("split-debuginfo=packed"));
+        assert!(nix.contains("outputs = [ \"out\" \"debug\" ];"));
+        assert!(nix.contains("mkdir -p $debug/lib/debug"));
+        assert!(nix.contains("for f in build/*.dwp build/*.dSYM; do"));
+
+        // Debug files are moved out before the binary is installed
+        let relocate = nix.find("mv \"$f\" $debug/lib/debug/").unwrap();
+        let install = nix.find("mkdir -p $out/bin").unwrap();
+        assert!(relocate < install);
+    }
+
+    #[test]
+    fn test_header_and_function_args() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_crate",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            emit_header: false,
+            function_args: Some("{ pkgs, rustToolchain, src, ... }".to_string()),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config.clone()).generate(&graph);
+        assert!(!nix.contains("# Generated by nix-cargo-unit"));
+        assert!(nix.starts_with("{ pkgs, rustToolchain, src, ... }:\n\nlet\n"));
+        assert!(!nix.contains("vendorDir ? null"));
+
+        // Empty args: just the body, for embedding
+        let config = NixGenConfig {
+            function_args: Some(String::new()),
+            ..config
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+        assert!(nix.starts_with("let\n"));
+    }
+
+    #[test]
+    fn test_coverage_flags() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-lib 0.1.0 (path+file:///workspace/my-lib)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_lib",
+                        "src_path": "/workspace/my-lib/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-macro 0.1.0 (path+file:///workspace/my-macro)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "my_macro",
+                        "src_path": "/workspace/my-macro/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "my_lib", "public": false},
+                        {"index": 1, "extern_crate_name": "my_macro", "public": false}
+                    ]
+                }
+            ],
+            "roots": [2]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            coverage: true,
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(config).generate(&graph);
+
+        let app = unit_section(&nix, "app");
+        assert!(app.contains(" instrument-coverage"));
+        assert!(app.contains(" link-dead-code"));
+        assert!(app.contains("export LLVM_PROFILE_FILE=\"$out/profraw/%p-%m.profraw\""));
+
+        // Libraries are instrumented but not linked
+        let lib = unit_section(&nix, "my_lib");
+        assert!(lib.contains(" instrument-coverage"));
+        assert!(!lib.contains("link-dead-code"));
+
+        // Proc-macros run in the compiler and are never instrumented
+        let mac = unit_section(&nix, "my_macro");
+        assert!(!mac.contains("instrument-coverage"));
+        assert!(!mac.contains("LLVM_PROFILE_FILE"));
+
+        // Coverage is part of the instrumented units' identity
+        let default_nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+        assert!(!default_nix.contains("instrument-coverage"));
+        assert_ne!(
+            drv_line(&nix, "\"app-0.1.0-"),
+            drv_line(&default_nix, "\"app-0.1.0-")
+        );
+        assert_ne!(
+            drv_line(&nix, "\"my_lib-0.1.0-"),
+            drv_line(&default_nix, "\"my_lib-0.1.0-")
+        );
+        assert_eq!(
+            drv_line(&nix, "\"my_macro-0.1.0-"),
+            drv_line(&default_nix, "\"my_macro-0.1.0-")
+        );
+    }
+
+    #[test]
+    fn test_build_override_opt_level() {
+        // `[profile.release.build-override] opt-level = 0`: cargo records the
+        // override on the build script compile unit only
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_crate",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                }
+            ],
+            "roots": [2]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+
+        let script = unit_section(&nix, "build-script-build");
+        assert!(script.contains("opt-level=0"));
+        assert!(!script.contains("opt-level=3"));
+        let lib = unit_section(&nix, "my_crate");
+        assert!(lib.contains("opt-level=3"));
+        assert!(!lib.contains("opt-level=0"));
+    }
+
+    #[test]
+    fn test_extra_cfgs() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-macro 0.1.0 (path+file:///workspace/my-macro)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "my_macro",
+                        "src_path": "/workspace/my-macro/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "my_macro", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            extra_cfgs: vec!["tokio_unstable".to_string()],
+            ..Default::default()
+        })
+        .generate(&graph);
+        let default_nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+
+        // Each flag is on its own continuation line
+        let app = unit_section(&nix, "app");
+        let words: Vec<&str> = app.split_whitespace().collect();
+        assert!(
+            words
+                .windows(3)
+                .any(|w| w == ["--cfg", "\\", "tokio_unstable"])
+        );
+        // Host-only units are not affected
+        assert!(!unit_section(&nix, "my_macro").contains("tokio_unstable"));
+
+        assert_ne!(
+            drv_line(&nix, "\"app-0.1.0-"),
+            drv_line(&default_nix, "\"app-0.1.0-")
+        );
+        assert_eq!(
+            drv_line(&nix, "\"my_macro-0.1.0-"),
+            drv_line(&default_nix, "\"my_macro-0.1.0-")
+        );
+    }
+
+    #[test]
+    fn test_hardening_disable_per_package() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_sys",
+                        "src_path": "/workspace/my-sys/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 2, "extern_crate_name": "my_sys", "public": false}
+                    ]
+                }
+            ],
+            "roots": [3]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let mut config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let default_names = NixGenerator::new(config.clone()).derivation_names(&graph);
+        config
+            .hardening_disable_per_package
+            .insert("my-sys".to_string(), vec!["fortify".to_string()]);
+        let generator = NixGenerator::new(config);
+        let nix = generator.generate(&graph);
+
+        // Different hardening flags compile different code: my-sys and everything
+        // built on it get new names
+        let names = generator.derivation_names(&graph);
+        assert!(names.iter().zip(&default_names).all(|(a, b)| a != b));
+
+        // Both the crate and the build script that compiles its C code
+        assert!(unit_section(&nix, "my_sys").contains("hardeningDisable = [ \"fortify\" ];"));
+        assert!(
+            unit_section(&nix, "my-sys-build-script-output")
+                .contains("hardeningDisable = [ \"fortify\" ];")
+        );
+        assert!(!unit_section(&nix, "app").contains("hardeningDisable"));
+    }
+
+    #[test]
+    fn test_extra_codegen() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-macro 0.1.0 (path+file:///workspace/my-macro)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "my_macro",
+                        "src_path": "/workspace/my-macro/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "my_macro", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            extra_codegen: vec![("relro-level".to_string(), "full".to_string())],
+            ..Default::default()
+        })
+        .generate(&graph);
+        let default_nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+
+        // Each flag is on its own continuation line
+        let app = unit_section(&nix, "app");
+        let words: Vec<&str> = app.split_whitespace().collect();
+        assert!(
+            words
+                .windows(3)
+                .any(|w| w == ["-C", "\\", "relro-level=full"])
+        );
+        // Host-only units are not affected
+        assert!(!unit_section(&nix, "my_macro").contains("relro-level"));
+
+        assert_ne!(
+            drv_line(&nix, "\"app-0.1.0-"),
+            drv_line(&default_nix, "\"app-0.1.0-")
+        );
+        assert_eq!(
+            drv_line(&nix, "\"my_macro-0.1.0-"),
+            drv_line(&default_nix, "\"my_macro-0.1.0-")
+        );
+    }
+
+    #[test]
+    fn test_transitive_proc_macro_in_lib_search_path() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-macro 0.1.0 (path+file:///workspace/my-macro)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "my_macro",
+                        "src_path": "/workspace/my-macro/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-lib 0.1.0 (path+file:///workspace/my-lib)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_lib",
+                        "src_path": "/workspace/my-lib/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "my_macro", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "my_lib", "public": false}
+                    ]
+                }
+            ],
+            "roots": [2]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let generator = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        });
+        let nix = generator.generate(&graph);
+
+        // Direct dependency: only the --extern through the located dylib
+        let lib = unit_section(&nix, "my_lib");
+        assert!(lib.contains("--extern my_macro=\"$PROCMACRO_MY_MACRO\""));
+        assert!(!lib.contains("-L dependency=${units.\"my_macro-"));
+
+        // Transitive dependency: searched, so rustc finds the macro my_lib re-exports
+        let app = unit_section(&nix, "app");
+        assert!(app.contains("-L dependency=${units.\"my_lib-"));
+        assert!(app.contains(&format!(
+            "  -L dependency=${{units.\"{}\"}}/lib \\\n",
+            generator.derivation_names(&graph)[0]
+        )));
+    }
+
+    #[test]
+    fn test_write_manifest() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-lib 0.1.0 (path+file:///workspace/my-lib)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_lib",
+                        "src_path": "/workspace/my-lib/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": ["std", "default"],
+                    "mode": "build",
+                    "platform": "x86_64-unknown-linux-gnu",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "my_lib", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let generator = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            toolchain_hash: Some("abc123".to_string()),
+            check_only: true,
+            ..Default::default()
+        });
+        let nix = generator.generate(&graph);
+        let mut buf = Vec::new();
+        generator.write_manifest(&graph, &mut buf).unwrap();
+        let manifest: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+
+        let entries = manifest.as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+
+        let lib = &entries[0];
+        let derivation = lib["derivation"].as_str().unwrap();
+        assert!(derivation.starts_with("my_lib-0.1.0-"));
+        assert!(nix.contains(&format!("\"{derivation}\" = mkUnit")));
+        assert!(derivation.ends_with(lib["identity_hash"].as_str().unwrap()));
+
+        let inputs = &lib["inputs"];
+        assert_eq!(
+            inputs["pkg_id"],
+            "my-lib 0.1.0 (path+file:///workspace/my-lib)"
+        );
+        assert_eq!(inputs["target_name"], "my_lib");
+        assert_eq!(inputs["crate_types"], serde_json::json!(["lib"]));
+        assert_eq!(inputs["features"], serde_json::json!(["default", "std"]));
+        assert_eq!(inputs["profile"], "release");
+        assert_eq!(inputs["opt_level"], "3");
+        assert_eq!(inputs["mode"], "build");
+        assert_eq!(inputs["platform"], "x86_64-unknown-linux-gnu");
+        assert_eq!(inputs["dep_hashes"], serde_json::json!([]));
+        assert_eq!(lib["toolchain_hash"], "abc123");
+        assert_eq!(lib["extras"], serde_json::json!(["check-only"]));
+
+        // The binary's inputs include its dependency's final hash
+        let app = &entries[1];
+        assert_eq!(
+            app["inputs"]["dep_hashes"],
+            serde_json::json!([lib["identity_hash"]])
+        );
+        assert_eq!(app["inputs"]["platform"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_dev_dependencies_only_in_test_units() {
+        // `my-lib` has a dev-dependency on `pretty-assertions`: cargo lists it
+        // only in the test unit's dependencies
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "pretty-assertions 1.4.0 (path+file:///workspace/pretty-assertions)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "pretty_assertions",
+                        "src_path": "/workspace/pretty-assertions/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-lib 0.1.0 (path+file:///workspace/my-lib)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_lib",
+                        "src_path": "/workspace/my-lib/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-lib 0.1.0 (path+file:///workspace/my-lib)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_lib",
+                        "src_path": "/workspace/my-lib/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "test", "opt_level": "0"},
+                    "features": [],
+                    "mode": "test",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "pretty_assertions", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1, 2]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+
+        // One mkUnit block per derivation; tell the lib and its test apart by `--test`
+        let blocks: Vec<&str> = nix
+            .split("pname = \"my_lib\"")
+            .skip(1)
+            .map(|rest| rest.split("pname = ").next().unwrap())
+            .collect();
+        assert_eq!(blocks.len(), 2);
+        let (tests, libs): (Vec<&str>, Vec<&str>) =
+            blocks.into_iter().partition(|b| b.contains(" --test "));
+        assert_eq!(tests.len(), 1);
+        assert!(tests[0].contains("--extern pretty_assertions="));
+        assert!(!libs[0].contains("pretty_assertions"));
+    }
+
+    #[test]
+    fn test_cargo_config_env_exports() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_sys",
+                        "src_path": "/workspace/my-sys/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 2, "extern_crate_name": "my_sys", "public": false}
+                    ]
+                }
+            ],
+            "roots": [3]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let plain = NixGenerator::new(base.clone()).generate(&graph);
+        let config = NixGenConfig {
+            cargo_env: vec![
+                CargoEnvVar {
+                    name: "MY_VAR".to_string(),
+                    value: "x".to_string(),
+                    force: false,
+                    relative: false,
+                },
+                CargoEnvVar {
+                    name: "ASSETS".to_string(),
+                    value: "/workspace/my-sys/assets".to_string(),
+                    force: true,
+                    relative: true,
+                },
+            ],
+            ..base
+        };
+        let extras = config.identity_extras(&graph.units[3]);
+        let nix = NixGenerator::new(config).generate(&graph);
+
+        // Compiled crates and build script runs both see the variables
+        for pname in ["my_sys", "app", "my-sys-build-script-output"] {
+            let section = unit_section(&nix, pname);
+            assert!(
+                section.contains("[ -n \"''${MY_VAR+set}\" ] || export MY_VAR=x\n"),
+                "{pname}: {section}"
+            );
+            assert!(section.contains("export ASSETS=\"${src}/my-sys/assets\"\n"));
+        }
+
+        // The variables are part of every unit's identity, with relative
+        // paths hashed as they resolve in the sandbox
+        assert!(extras.contains(&"env=ASSETS=${src}/my-sys/assets".to_string()));
+        assert_ne!(
+            drv_line(&plain, "\"app-0.1.0-"),
+            drv_line(&nix, "\"app-0.1.0-")
+        );
+    }
+
+    #[test]
+    fn test_codegen_units_from_cores() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3", "codegen_units": 16},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let fixed = NixGenerator::new(base.clone()).generate(&graph);
+        assert!(fixed.contains(" codegen-units=16 "));
+        assert!(!fixed.contains("NIX_BUILD_CORES"));
+
+        let nix = NixGenerator::new(NixGenConfig {
+            codegen_units_from_cores: true,
+            ..base
+        })
+        .generate(&graph);
+        assert!(nix.contains("CODEGEN_UNITS=\"''${NIX_BUILD_CORES:-0}\""));
+        assert!(nix.contains("-C codegen-units=\"$CODEGEN_UNITS\" \\"));
+        assert!(!nix.contains("codegen-units=16"));
+    }
+
+    #[test]
+    fn test_emit_fetchers() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "serde 1.0.219 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "serde",
+                        "src_path": "/home/user/.cargo/registry/src/index.crates.io-6f17d22bba15001f/serde-1.0.219/src/lib.rs",
+                        "edition": "2018"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "dep 0.2.0 (git+https://github.com/user/repo?rev=abc#abc123)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "dep",
+                        "src_path": "/home/user/.cargo/git/checkouts/repo-1a2b3c/abc123/crates/dep/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "serde", "public": false},
+                        {"index": 1, "extern_crate_name": "dep", "public": false}
+                    ]
+                }
+            ],
+            "roots": [2]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            emit_fetchers: true,
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(base.clone()).generate(&graph);
+
+        // vendorDir is defined rather than taken as an argument
+        assert!(!nix.contains("vendorDir ? null"));
+        assert!(nix.contains("vendorDir = pkgs.linkFarm \"vendor\" ["));
+        assert!(
+            nix.contains("{ name = \"serde-1.0.219\"; path = pkgs.runCommand \"serde-1.0.219\"")
+        );
+        assert!(
+            nix.contains("url = \"https://static.crates.io/crates/serde/serde-1.0.219.crate\"; hash = pkgs.lib.fakeHash;")
+        );
+        assert!(nix.contains(
+            "{ name = \"dep-0.2.0\"; path = \"${pkgs.fetchgit { url = \"https://github.com/user/repo\"; rev = \"abc123\"; hash = pkgs.lib.fakeHash; }}/crates/dep\"; }"
+        ));
+        assert!(!nix.contains("name = \"app-0.1.0\";"));
+
+        // Lockfile checksums replace the registry placeholder
+        let mut config = base;
+        config
+            .crate_checksums
+            .insert("serde-1.0.219".to_string(), "abcd".to_string());
+        let nix = NixGenerator::new(config).generate(&graph);
+        assert!(nix.contains("serde-1.0.219.crate\"; sha256 = \"abcd\";"));
+    }
+
+    #[test]
+    fn test_build_script_out_dir_env() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_sys",
+                        "src_path": "/workspace/my-sys/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 2, "extern_crate_name": "my_sys", "public": false}
+                    ]
+                }
+            ],
+            "roots": [3]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let mut config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        config
+            .build_script_out_dir_env
+            .insert("my-sys".to_string(), vec!["GEN_DIR".to_string()]);
+        let nix = NixGenerator::new(config).generate(&graph);
+
+        // The build script writes there, and the crate reads from its output
+        assert!(
+            unit_section(&nix, "my-sys-build-script-output")
+                .contains("export GEN_DIR=$out/out-dir\n")
+        );
+        let lib = unit_section(&nix, "my_sys");
+        let export = lib
+            .lines()
+            .find(|l| l.trim_start().starts_with("export GEN_DIR="))
+            .map(str::trim)
+            .expect("GEN_DIR not exported");
+        assert!(export.starts_with("export GEN_DIR=${units.\"my-sys-build-script-run-0.1.0-"));
+        assert!(export.ends_with("}/out-dir"));
+        assert!(!unit_section(&nix, "app").contains("GEN_DIR"));
+    }
+
+    #[test]
+    fn test_strip_symbols_left_to_rustc() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "dep 0.1.0 (path+file:///workspace/dep)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "dep",
+                        "src_path": "/workspace/dep/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3", "strip": "symbols"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3", "strip": "symbols"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "dep", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+
+        // Nixpkgs never strips, so the rlib keeps its metadata
+        let lib = unit_section(&nix, "dep");
+        assert!(lib.contains("dontStrip = true;"));
+
+        // The binary is stripped by rustc as it links
+        let app = unit_section(&nix, "app");
+        assert!(app.contains("dontStrip = true;"));
+        assert!(
+            app.split_whitespace()
+                .filter(|t| *t != "\\")
+                .collect::<Vec<_>>()
+                .windows(2)
+                .any(|w| w == ["-C", "strip=symbols"])
+        );
+    }
+
+    #[test]
+    fn test_prefer_dynamic() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "dep 0.1.0 (path+file:///workspace/dep)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "dep",
+                        "src_path": "/workspace/dep/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "dep", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let plain = NixGenerator::new(base.clone()).generate(&graph);
+        assert!(!plain.contains("prefer-dynamic"));
+
+        let nix = NixGenerator::new(NixGenConfig {
+            prefer_dynamic: true,
+            ..base
+        })
+        .generate(&graph);
+        assert_eq!(
+            unit_section(&nix, "app")
+                .matches("-C prefer-dynamic")
+                .count(),
+            1
+        );
+        assert!(!unit_section(&nix, "dep").contains("prefer-dynamic"));
+
+        // Only the binary's identity changes
+        assert_eq!(
+            drv_line(&plain, "\"dep-0.1.0-"),
+            drv_line(&nix, "\"dep-0.1.0-")
+        );
+        assert_ne!(
+            drv_line(&plain, "\"app-0.1.0-"),
+            drv_line(&nix, "\"app-0.1.0-")
+        );
+    }
+
+    #[test]
+    fn test_empty_unit_graph() {
+        let graph = parse_test_unit_graph(r#"{"version": 1, "units": [], "roots": []}"#);
+        let nix = NixGenerator::new(NixGenConfig::default()).generate(&graph);
+
+        assert!(nix.contains("units = {\n  };"));
+        assert!(nix.contains("roots = [  ];"));
+        assert!(nix.contains("# No root units: `default` is not defined"));
+        assert!(!nix.contains("default = "));
+
+        // Still a complete function returning an attrset
+        let code: String = nix
+            .lines()
+            .filter(|l| !l.trim_start().starts_with('#'))
+            .collect();
+        for (open, close) in [('{', '}'), ('[', ']'), ('(', ')')] {
+            assert_eq!(code.matches(open).count(), code.matches(close).count());
+        }
+        assert!(code.trim_end().ends_with('}'));
+    }
+
+    #[test]
+    fn test_same_name_crates_resolve_to_own_rlib() {
+        let unit = |pkg_id: &str, name: &str, src: &str, deps: &str| {
+            format!(
+                r#"{{
+                    "pkg_id": "{pkg_id}",
+                    "target": {{
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "{name}",
+                        "src_path": "{src}",
+                        "edition": "2021"
+                    }},
+                    "profile": {{"name": "dev", "opt_level": "0"}},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [{deps}]
+                }}"#
+            )
+        };
+        let foo_dep = |index: usize| {
+            format!(r#"{{"index": {index}, "extern_crate_name": "foo", "public": false}}"#)
+        };
+        // Two forks of `foo`, each used by a different crate that `app` depends on
+        let units = [
+            unit(
+                "foo 0.1.0 (path+file:///workspace/fork-a/foo)",
+                "foo",
+                "/workspace/fork-a/foo/src/lib.rs",
+                "",
+            ),
+            unit(
+                "foo 0.1.0 (path+file:///workspace/fork-b/foo)",
+                "foo",
+                "/workspace/fork-b/foo/src/lib.rs",
+                "",
+            ),
+            unit(
+                "a 0.1.0 (path+file:///workspace/a)",
+                "a",
+                "/workspace/a/src/lib.rs",
+                &foo_dep(0),
+            ),
+            unit(
+                "b 0.1.0 (path+file:///workspace/b)",
+                "b",
+                "/workspace/b/src/lib.rs",
+                &foo_dep(1),
+            ),
+            unit(
+                "app 0.1.0 (path+file:///workspace/app)",
+                "app",
+                "/workspace/app/src/lib.rs",
+                r#"{"index": 2, "extern_crate_name": "a", "public": false},
+                   {"index": 3, "extern_crate_name": "b", "public": false}"#,
+            ),
+        ];
+        let json = format!(
+            r#"{{"version": 1, "units": [{}], "roots": [4]}}"#,
+            units.join(",")
+        );
+
+        let graph = parse_test_unit_graph(&json);
+        let generator = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        });
+        let names = generator.derivation_names(&graph);
+        let nix = generator.generate(&graph);
+
+        let (foo_a, foo_b) = (&names[0], &names[1]);
+        assert_ne!(foo_a, foo_b);
+        let hash = |drv: &str| drv.rsplit('-').next().unwrap().to_string();
+        let (hash_a, hash_b) = (hash(foo_a), hash(foo_b));
+
+        let section = |drv: &str| {
+            nix.split(&format!("\"{drv}\" = mkUnit"))
+                .nth(1)
+                .unwrap()
+                .split(" = mkUnit")
+                .next()
+                .unwrap()
+                .to_string()
+        };
+
+        // Each fork gets its own symbol metadata and output filename
+        assert!(section(foo_a).contains(&format!("metadata={hash_a} ")));
+        assert!(section(foo_b).contains(&format!("metadata={hash_b} ")));
+
+        // Each consumer externs its own fork by exact path
+        let extern_foo =
+            |drv: &str, h: &str| format!("--extern foo=${{units.\"{drv}\"}}/lib/libfoo-{h}.rlib");
+        let a = section(&names[2]);
+        assert!(a.contains(&extern_foo(foo_a, &hash_a)));
+        assert!(!a.contains(foo_b.as_str()));
+        let b = section(&names[3]);
+        assert!(b.contains(&extern_foo(foo_b, &hash_b)));
+        assert!(!b.contains(foo_a.as_str()));
+
+        // The app sees both forks only as per-derivation search directories, each
+        // holding a single libfoo; rustc resolves them by crate hash, not name
+        let app = section(&names[4]);
+        assert!(!app.contains("--extern foo="));
+        for drv in [foo_a, foo_b] {
+            assert!(app.contains(&format!("-L dependency=${{units.\"{drv}\"}}/lib")));
+        }
+    }
+
+    #[test]
+    fn test_write_dir_per_unit_files() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_sys",
+                        "src_path": "/workspace/my-sys/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 2, "extern_crate_name": "my_sys", "public": false}
+                    ]
+                }
+            ],
+            "roots": [3]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let generator = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        });
+        let dir = std::env::temp_dir().join(format!("nix-cargo-unit-split-{}", std::process::id()));
+        let count = generator.write_dir(&graph, &dir).unwrap();
+
+        // Build script compile and run, the library, and the binary
+        assert_eq!(count, 4);
+        let mut files: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files.len(), count + 1);
+        assert!(files.contains(&"default.nix".to_string()));
+
+        let index = std::fs::read_to_string(dir.join("default.nix")).unwrap();
+        assert!(index.contains("scope = { inherit pkgs rustToolchain hostRustToolchain src"));
+        assert!(!index.contains("= mkUnit {"));
+        for file in files.iter().filter(|f| *f != "default.nix") {
+            let name = file.strip_suffix(".nix").unwrap();
+            assert!(index.contains(&format!("    \"{name}\" = import ./{file} scope;")));
+
+            let contents = std::fs::read_to_string(dir.join(file)).unwrap();
+            assert!(contents.starts_with("{ pkgs, rustToolchain, "));
+            assert!(contents.contains(", mkUnit, units, ... }:\n\nmkUnit {"));
+        }
+
+        // Same derivations as the single-file output
+        let nix = generator.generate(&graph);
+        assert_eq!(nix.matches(" = mkUnit {").count(), count);
+        for file in &files {
+            if let Some(name) = file.strip_suffix(".nix").filter(|n| *n != "default") {
+                assert!(nix.contains(&format!("\"{name}\" = mkUnit {{")));
+            }
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_proc_macro_deps_use_host_toolchain() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "helper 0.1.0 (path+file:///workspace/helper)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "helper",
+                        "src_path": "/workspace/helper/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-derive 0.1.0 (path+file:///workspace/my-derive)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "my_derive",
+                        "src_path": "/workspace/my-derive/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "helper", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "core-lib 0.1.0 (path+file:///workspace/core-lib)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "core_lib",
+                        "src_path": "/workspace/core-lib/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "platform": "x86_64-unknown-linux-gnu",
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "platform": "x86_64-unknown-linux-gnu",
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "my_derive", "public": false},
+                        {"index": 2, "extern_crate_name": "core_lib", "public": false}
+                    ]
+                }
+            ],
+            "roots": [3]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(
+            NixGenConfig {
+                workspace_root: "/workspace".to_string(),
+                ..Default::default()
+            }
+            .with_cross_compilation("aarch64-unknown-linux-gnu", "x86_64-unknown-linux-gnu"),
+        )
+        .generate(&graph);
+
+        // The proc-macro and the library only it uses are built for the host
+        for pname in ["my_derive", "helper"] {
+            let host = unit_section(&nix, pname);
+            assert!(
+                host.contains("nativeBuildInputs = [ hostRustToolchain ]"),
+                "{pname}"
+            );
+            assert!(!host.contains("--target"), "{pname}");
+        }
+        for pname in ["core_lib", "app"] {
+            let target = unit_section(&nix, pname);
+            assert!(
+                target.contains("nativeBuildInputs = [ rustToolchain ]"),
+                "{pname}"
+            );
+            assert!(target.contains("x86_64-unknown-linux-gnu"), "{pname}");
+        }
+    }
+
+    #[test]
+    fn test_separate_debug_info() {
+        let unit = |name: &str, kind: &str, debuginfo: u8, deps: &str| {
+            format!(
+                r#"{{
+                    "pkg_id": "{name} 0.1.0 (path+file:///workspace/{name})",
+                    "target": {{
+                        "kind": ["{kind}"],
+                        "crate_types": ["{kind}"],
+                        "name": "{name}",
+                        "src_path": "/workspace/{name}/src/main.rs",
+                        "edition": "2021"
+                    }},
+                    "profile": {{"name": "release", "opt_level": "3", "debuginfo": {debuginfo}}},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [{deps}]
+                }}"#
+            )
+        };
+        let dep = r#"{"index": 0, "extern_crate_name": "dep", "public": false}"#;
+        let json = format!(
+            r#"{{"version": 1, "units": [{}, {}, {}, {}], "roots": [1, 2, 3]}}"#,
+            unit("dep", "lib", 2, ""),
+            unit("app", "bin", 2, dep),
+            unit("tool", "bin", 0, dep),
+            unit("mixed", r#"lib", "bin"#, 2, dep),
+        );
+
+        let graph = parse_test_unit_graph(&json);
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let plain = NixGenerator::new(base.clone()).generate(&graph);
+        assert!(!plain.contains("separateDebugInfo"));
+
+        let nix = NixGenerator::new(NixGenConfig {
+            separate_debug_info: true,
+            ..base
+        })
+        .generate(&graph);
+
+        // The debug binary lets the fixup strip run so symbols leave $out
+        let app = unit_section(&nix, "app");
+        assert!(app.contains("separateDebugInfo = true;"));
+        assert!(app.contains("dontStrip = false;"));
+
+        // Binaries without debuginfo, libraries, and lib+bin units (whose rlib
+        // must not be stripped) are unchanged
+        for pname in ["tool", "dep", "mixed"] {
+            let other = unit_section(&nix, pname);
+            assert!(!other.contains("separateDebugInfo"), "{pname}");
+            assert!(other.contains("dontStrip = true;"), "{pname}");
+        }
+    }
+
+    #[test]
+    fn test_lint_levels_local_units_only() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "serde 1.0.219 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "serde",
+                        "src_path": "/home/user/.cargo/registry/src/index.crates.io-1234/serde-1.0.219/src/lib.rs",
+                        "edition": "2018"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "serde", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            lint_levels: vec![
+                "-D".to_string(),
+                "warnings".to_string(),
+                "-A".to_string(),
+                "dead_code".to_string(),
+            ],
+            ..Default::default()
+        })
+        .generate(&graph);
+        let args = |section: &str| -> Vec<String> {
+            section
+                .split_whitespace()
+                .filter(|w| *w != "\\")
+                .map(str::to_string)
+                .collect()
+        };
+
+        // Levels keep their order, so the allow overrides the blanket deny
+        let app = args(unit_section(&nix, "app"));
+        assert!(
+            app.windows(4)
+                .any(|w| w == ["-D", "warnings", "-A", "dead_code"])
+        );
+
+        // The registry crate only has its lints capped
+        let serde = args(unit_section(&nix, "serde"));
+        assert!(!serde.iter().any(|a| a == "-D" || a == "dead_code"));
+        assert!(serde.windows(2).any(|w| w == ["--cap-lints", "warn"]));
+    }
+
+    #[test]
+    fn test_disable_compat_lint_allows() {
+        let json = r#"{
+            "version": 1,
+            "units": [{
+                "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                "target": {
+                    "kind": ["bin"],
+                    "crate_types": ["bin"],
+                    "name": "app",
+                    "src_path": "/workspace/app/src/main.rs",
+                    "edition": "2021"
+                },
+                "profile": {"name": "dev", "opt_level": "0"},
+                "features": [],
+                "mode": "build",
+                "dependencies": []
+            }],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(base.clone()).generate(&graph);
+        for lint in crate::rustc_flags::COMPAT_ALLOWED_LINTS {
+            assert!(nix.contains(lint));
+        }
+
+        let nix = NixGenerator::new(NixGenConfig {
+            allow_compat_lints: false,
+            lint_levels: vec!["-D".to_string(), "warnings".to_string()],
+            ..base
+        })
+        .generate(&graph);
+        for lint in crate::rustc_flags::COMPAT_ALLOWED_LINTS {
+            assert!(!nix.contains(lint));
+        }
+        assert!(nix.contains("  warnings \\"));
+    }
+
+    #[test]
+    fn test_network_build_script_warnings() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_sys",
+                        "src_path": "/workspace/my-sys/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 2, "extern_crate_name": "my_sys", "public": false}
+                    ]
+                }
+            ],
+            "roots": [3]
+        }"#;
 
-        out
-    }
-}
+        let graph = parse_test_unit_graph(json);
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        assert!(base.network_build_script_warnings(&graph).is_empty());
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::unit_graph::parse_test_unit_graph;
+        let config = NixGenConfig {
+            network_build_scripts: vec!["my-sys".to_string()],
+            ..base
+        };
+        let warnings = config.network_build_script_warnings(&graph);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("unit 1 (my-sys 0.1.0 "));
+        assert!(warnings[0].contains("network access"));
+        assert!(warnings[0].contains("sandbox blocks"));
 
-    #[test]
-    fn test_escape_nix_string() {
-        assert_eq!(escape_nix_string("hello"), "hello");
-        assert_eq!(escape_nix_string("hello\"world"), "hello\\\"world");
-        assert_eq!(escape_nix_string("path\\to"), "path\\\\to");
-        assert_eq!(escape_nix_string("${var}"), "\\${var}");
-        assert_eq!(escape_nix_string("line\nbreak"), "line\\nbreak");
+        // Only an explicit opt-in lets the build script out of the sandbox
+        assert!(
+            !NixGenerator::new(config.clone())
+                .generate(&graph)
+                .contains("__noChroot")
+        );
+        let config = NixGenConfig {
+            allow_network_build_scripts: true,
+            ..config
+        };
+        assert!(config.network_build_script_warnings(&graph)[0].contains("sandbox = relaxed"));
+        let nix = NixGenerator::new(config).generate(&graph);
+        assert_eq!(nix.matches("__noChroot = true;").count(), 1);
+        let run = unit_section(&nix, "my-sys-build-script-output");
+        assert!(run.contains("__noChroot = true;"));
     }
 
     #[test]
-    fn test_escape_nix_multiline() {
-        assert_eq!(escape_nix_multiline("hello"), "hello");
-        assert_eq!(escape_nix_multiline("end ''"), "end '''");
-        assert_eq!(escape_nix_multiline("${var}"), "''${var}");
-    }
+    fn test_stdenv_expr() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_sys",
+                        "src_path": "/workspace/my-sys/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 2, "extern_crate_name": "my_sys", "public": false}
+                    ]
+                }
+            ],
+            "roots": [3]
+        }"#;
 
-    #[test]
-    fn test_nix_string_escaping() {
-        let s = NixString::new("hello \"world\"");
-        assert_eq!(s.as_str(), "hello \\\"world\\\"");
+        let graph = parse_test_unit_graph(json);
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let generator = NixGenerator::new(base.clone());
+        let nix = generator.generate(&graph);
+        assert!(nix.contains("  mkUnit = attrs: pkgs.stdenv.mkDerivation (attrs // {\n"));
+        assert!(nix.contains("rustToolchain pkgs.stdenv.cc"));
+        let default_names = generator.derivation_names(&graph);
 
-        let raw = NixString::raw("pkgs.hello");
-        assert_eq!(raw.as_str(), "pkgs.hello");
+        let generator = NixGenerator::new(NixGenConfig {
+            stdenv_expr: Some("pkgs.pkgsMusl.stdenv".to_string()),
+            ..base.clone()
+        });
+        let nix = generator.generate(&graph);
+        assert!(nix.contains("  mkUnit = attrs: pkgs.pkgsMusl.stdenv.mkDerivation (attrs // {\n"));
+        assert!(nix.contains("rustToolchain pkgs.pkgsMusl.stdenv.cc"));
+        assert!(!nix.contains("pkgs.stdenv"));
+
+        // A different stdenv produces different outputs, so every name changes
+        let names = generator.derivation_names(&graph);
+        assert!(names.iter().zip(&default_names).all(|(a, b)| a != b));
+
+        // Spelling out the default keeps the default hashes
+        let generator = NixGenerator::new(NixGenConfig {
+            stdenv_expr: Some("pkgs.stdenv".to_string()),
+            ..base
+        });
+        assert_eq!(generator.derivation_names(&graph), default_names);
     }
 
     #[test]
-    fn test_nix_attr_set() {
-        let mut attrs = NixAttrSet::new();
-        attrs.string("pname", "my-crate");
-        attrs.string("version", "0.1.0");
-        attrs.bool("dontUnpack", true);
-        attrs.int("priority", 10);
-        attrs.string_list("features", &["std".to_string(), "alloc".to_string()]);
+    fn test_emit_tests_attrset() {
+        let unit = |pkg: &str, kind: &str, name: &str, mode: &str| {
+            format!(
+                r#"{{
+                    "pkg_id": "{pkg} 0.1.0 (path+file:///workspace/{pkg})",
+                    "target": {{
+                        "kind": ["{kind}"],
+                        "crate_types": ["bin"],
+                        "name": "{name}",
+                        "src_path": "/workspace/{pkg}/src/lib.rs",
+                        "edition": "2021"
+                    }},
+                    "profile": {{"name": "dev", "opt_level": "0"}},
+                    "features": [],
+                    "mode": "{mode}",
+                    "dependencies": []
+                }}"#
+            )
+        };
+        let units = [
+            unit("my-lib", "lib", "my_lib", "build"),
+            unit("my-lib", "lib", "my_lib", "test"),
+            unit("a", "test", "integration", "test"),
+            unit("b", "test", "integration", "test"),
+        ];
+        let json = format!(
+            r#"{{"version": 1, "units": [{}], "roots": [0]}}"#,
+            units.join(",")
+        );
 
-        let rendered = attrs.render(0);
+        let graph = parse_test_unit_graph(&json);
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(base.clone()).generate(&graph);
+        assert!(!nix.contains("tests = {"));
 
-        assert!(rendered.contains("pname = \"my-crate\""));
-        assert!(rendered.contains("version = \"0.1.0\""));
-        assert!(rendered.contains("dontUnpack = true"));
-        assert!(rendered.contains("priority = 10"));
-        assert!(rendered.contains("features = [ \"std\" \"alloc\" ]"));
+        let generator = NixGenerator::new(NixGenConfig {
+            emit_tests_attrset: true,
+            ..base
+        });
+        let names = generator.derivation_names(&graph);
+        let nix = generator.generate(&graph);
+        let tests = nix
+            .split("  tests = {\n")
+            .nth(1)
+            .unwrap()
+            .split("  };")
+            .next()
+            .unwrap();
+
+        // The library's test harness, not the library itself
+        assert!(tests.contains(&format!("\"my_lib\" = units.\"{}\";", names[1])));
+        assert!(!tests.contains(&names[0]));
+        // Colliding target names are qualified by package
+        assert!(tests.contains(&format!("\"a-integration\" = units.\"{}\";", names[2])));
+        assert!(tests.contains(&format!("\"b-integration\" = units.\"{}\";", names[3])));
+        assert_eq!(tests.lines().count(), 3);
     }
 
     #[test]
-    fn test_unit_derivation_from_unit() {
+    fn test_mixed_lib_bin_crate_types() {
         let json = r#"{
             "version": 1,
-            "units": [{
-                "pkg_id": "my-crate 0.1.0 (path+file:///workspace/crates/my-crate)",
-                "target": {
-                    "kind": ["lib"],
-                    "crate_types": ["lib"],
-                    "name": "my_crate",
-                    "src_path": "/workspace/crates/my-crate/src/lib.rs",
-                    "edition": "2021"
-                },
-                "profile": {"name": "dev", "opt_level": "0"},
-                "features": ["default", "std"],
-                "mode": "build",
-                "dependencies": []
-            }],
+            "units": [
+                {
+                    "pkg_id": "my-tool 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["lib", "bin"],
+                        "crate_types": ["lib", "bin"],
+                        "name": "my-tool",
+                        "src_path": "/workspace/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
             "roots": [0]
         }"#;
 
         let graph = parse_test_unit_graph(json);
-        let unit = &graph.units[0];
-        let identity_hash = unit.identity_hash();
-        let drv_name = unit.derivation_name();
-
-        let drv = UnitDerivation::from_unit(
-            unit,
-            "/workspace",
-            false,
-            "rustToolchain",
-            &drv_name,
-            &identity_hash,
-            false, // not an external dep (path source)
-        );
-
-        assert_eq!(drv.pname, "my_crate");
-        assert_eq!(drv.version, "0.1.0");
-        assert_eq!(drv.edition, "2021");
-        assert_eq!(drv.features, vec!["default", "std"]);
-        assert!(drv.src_path.contains("${src}"));
-        assert_eq!(drv.toolchain_var, "rustToolchain");
+        let nix = NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .generate(&graph);
+
+        // Both crate types are requested, output goes to a directory
+        let tokens: Vec<&str> = nix.split_whitespace().filter(|t| *t != "\\").collect();
+        assert!(tokens.windows(2).any(|w| w == ["--crate-type", "lib"]));
+        assert!(tokens.windows(2).any(|w| w == ["--crate-type", "bin"]));
+        assert!(nix.contains("--out-dir build"));
+        assert!(nix.contains("--emit=dep-info,metadata,link"));
+        assert!(!nix.contains("-o build/my-tool"));
+
+        // The binary is installed to $out/bin and the rlib to $out/lib
+        assert!(nix.contains("mkdir -p $out/bin $out/lib"));
+        assert!(nix.contains("for f in build/my_tool-*; do"));
+        assert!(nix.contains("cp \"$f\" $out/bin/my-tool"));
+        assert!(nix.contains("for f in build/lib*; do"));
+        assert!(nix.contains("cp \"$f\" $out/lib/"));
     }
 
     #[test]
-    fn test_nix_generator_simple() {
+    fn test_passthru_provenance() {
         let json = r#"{
             "version": 1,
-            "units": [{
-                "pkg_id": "test 0.1.0 (path+file:///workspace)",
-                "target": {
-                    "kind": ["lib"],
-                    "crate_types": ["lib"],
-                    "name": "test",
-                    "src_path": "/workspace/src/lib.rs",
-                    "edition": "2024"
-                },
-                "profile": {"name": "dev", "opt_level": "0"},
-                "features": [],
-                "mode": "build",
-                "dependencies": []
-            }],
+            "units": [
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_crate",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
             "roots": [0]
         }"#;
 
         let graph = parse_test_unit_graph(json);
-        let config = NixGenConfig {
+        let generator = NixGenerator::new(NixGenConfig {
             workspace_root: "/workspace".to_string(),
-            content_addressed: false,
             ..Default::default()
-        };
-
-        let generator = NixGenerator::new(config);
+        });
         let nix = generator.generate(&graph);
-
-        // Check structure
-        assert!(nix.contains("{ pkgs, rustToolchain, hostRustToolchain ? rustToolchain, src, extraNativeBuildInputs ? [], vendorDir ? null }:"));
-        assert!(nix.contains("mkUnit = attrs:"));
-        assert!(nix.contains("units = {"));
-        assert!(nix.contains("roots = ["));
-        assert!(nix.contains("default ="));
-
-        // Check derivation content
-        assert!(nix.contains("pname = \"test\""));
-        assert!(nix.contains("version = \"0.1.0\""));
-        assert!(nix.contains("--edition"));
-        assert!(nix.contains("2024"));
+        let canonical_index = canonical_indices(&graph);
+        let hash = &generator.identity_hashes(&graph, &canonical_index)[0];
+
+        assert!(nix.contains(&format!(
+            "passthru = {{\n        nix-cargo-unit = {{\n          identityHash = \"{hash}\";\n          pkgId = \"my-crate 0.1.0 (path+file:///workspace)\";\n        }};\n      }};"
+        )));
     }
 
     #[test]
-    fn test_nix_generator_with_deps() {
+    fn test_custom_build_dir() {
         let json = r#"{
             "version": 1,
             "units": [
@@ -1506,7 +8153,7 @@ mod tests {
                         "src_path": "/workspace/dep/src/lib.rs",
                         "edition": "2021"
                     },
-                    "profile": {"name": "dev", "opt_level": "0"},
+                    "profile": {"name": "release", "opt_level": "3"},
                     "features": [],
                     "mode": "build",
                     "dependencies": []
@@ -1520,7 +8167,7 @@ mod tests {
                         "src_path": "/workspace/app/src/main.rs",
                         "edition": "2021"
                     },
-                    "profile": {"name": "dev", "opt_level": "0"},
+                    "profile": {"name": "release", "opt_level": "3"},
                     "features": [],
                     "mode": "build",
                     "dependencies": [
@@ -1534,702 +8181,818 @@ mod tests {
         let graph = parse_test_unit_graph(json);
         let config = NixGenConfig {
             workspace_root: "/workspace".to_string(),
-            content_addressed: false,
+            build_dir: "_ncu_build".to_string(),
             ..Default::default()
         };
+        assert!(config.validate().is_ok());
+        let nix = NixGenerator::new(config).generate(&graph);
 
-        let generator = NixGenerator::new(config);
-        let nix = generator.generate(&graph);
-
-        // Should have both units
-        assert!(nix.contains("pname = \"dep\""));
-        assert!(nix.contains("pname = \"app\""));
-
-        // Should have bin output in installPhase
-        assert!(nix.contains("cp build/app $out/bin/"));
-
-        // Should have --extern flag for dependency (with identity hash in filename)
-        assert!(nix.contains("--extern"));
-        assert!(nix.contains("dep="));
-        // Library files include identity hash: libdep-{hash}.rlib
-        assert!(nix.contains("/lib/libdep-") && nix.contains(".rlib"));
-
-        // -L flags are NOT added for direct deps (they're covered by --extern with explicit path)
-        // This test only has one direct dep, so no -L flags are generated
+        // Build phase
+        assert_eq!(nix.matches("mkdir -p _ncu_build\n").count(), 2);
+        assert!(nix.contains("--out-dir _ncu_build \\"));
+        assert!(nix.contains("-o _ncu_build/app \\"));
+        // Install phase
+        assert!(nix.contains("cp _ncu_build/* $out/lib/"));
+        assert!(nix.contains("cp _ncu_build/app $out/bin/"));
+        assert!(!nix.contains("mkdir -p build\n"));
+        assert!(!nix.contains(" build/"));
+
+        for bad in ["", ".", "..", "../out", "a/../b", "a/b", "a b", "$(x)"] {
+            let config = NixGenConfig {
+                build_dir: bad.to_string(),
+                ..Default::default()
+            };
+            assert!(config.validate().is_err(), "{bad:?} should be rejected");
+        }
     }
 
     #[test]
-    fn test_extern_crate_wiring() {
+    fn test_profile_rpath_uses_store_paths() {
         let json = r#"{
             "version": 1,
             "units": [
                 {
-                    "pkg_id": "serde 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
-                    "target": {
-                        "kind": ["lib"],
-                        "crate_types": ["lib"],
-                        "name": "serde",
-                        "src_path": "/registry/serde/src/lib.rs",
-                        "edition": "2021"
-                    },
-                    "profile": {"name": "dev", "opt_level": "0"},
-                    "features": ["default", "std"],
-                    "mode": "build",
-                    "dependencies": []
-                },
-                {
-                    "pkg_id": "serde_derive 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "pkg_id": "plugin-api 0.1.0 (path+file:///workspace/plugin-api)",
                     "target": {
-                        "kind": ["proc-macro"],
-                        "crate_types": ["proc-macro"],
-                        "name": "serde_derive",
-                        "src_path": "/registry/serde_derive/src/lib.rs",
+                        "kind": ["dylib"],
+                        "crate_types": ["dylib"],
+                        "name": "plugin_api",
+                        "src_path": "/workspace/plugin-api/src/lib.rs",
                         "edition": "2021"
                     },
-                    "profile": {"name": "dev", "opt_level": "0"},
+                    "profile": {"name": "dev", "opt_level": "0", "rpath": true},
                     "features": [],
                     "mode": "build",
-                    "dependencies": [],
-                    "platform": "aarch64-apple-darwin"
+                    "dependencies": []
                 },
                 {
-                    "pkg_id": "my_app 0.1.0 (path+file:///workspace)",
+                    "pkg_id": "host 0.1.0 (path+file:///workspace/host)",
                     "target": {
                         "kind": ["bin"],
                         "crate_types": ["bin"],
-                        "name": "my_app",
-                        "src_path": "/workspace/src/main.rs",
-                        "edition": "2024"
+                        "name": "host",
+                        "src_path": "/workspace/host/src/main.rs",
+                        "edition": "2021"
                     },
-                    "profile": {"name": "dev", "opt_level": "0"},
+                    "profile": {"name": "dev", "opt_level": "0", "rpath": true},
                     "features": [],
                     "mode": "build",
                     "dependencies": [
-                        {"index": 0, "extern_crate_name": "serde", "public": false},
-                        {"index": 1, "extern_crate_name": "serde_derive", "public": false}
+                        {"index": 0, "extern_crate_name": "plugin_api", "public": false}
                     ]
                 }
-            ],
-            "roots": [2]
-        }"#;
-
-        let graph = parse_test_unit_graph(json);
-        let config = NixGenConfig {
-            workspace_root: "/workspace".to_string(),
-            content_addressed: false,
-            ..Default::default()
-        };
-
-        let generator = NixGenerator::new(config);
-        let nix = generator.generate(&graph);
-
-        // Should have all three units
-        assert!(nix.contains("pname = \"serde\""));
-        assert!(nix.contains("pname = \"serde_derive\""));
-        assert!(nix.contains("pname = \"my_app\""));
-
-        // my_app should have buildInputs with both dependencies
-        assert!(nix.contains("buildInputs = ["));
-
-        // Should have --extern flags for both dependencies
-        assert!(nix.contains("serde="));
-        assert!(nix.contains("serde_derive="));
-
-        // Regular lib dep should use .rlib (with identity hash in filename)
-        assert!(nix.contains("libserde-") && nix.contains(".rlib"));
-
-        // Proc-macro dep should use variable with platform fallback
-        // Should have variable setup: PROCMACRO_SERDE_DERIVE="..."
-        assert!(nix.contains("PROCMACRO_SERDE_DERIVE="));
-        // Should locate proc-macro dylib via find
-        assert!(nix.contains("libserde_derive.*"));
-        // Should use the variable in --extern: serde_derive="$PROCMACRO_SERDE_DERIVE"
-        assert!(nix.contains("serde_derive=\"$PROCMACRO_SERDE_DERIVE\""));
-    }
-
-    #[test]
-    fn test_dep_ref_in_build_inputs() {
-        let mut drv = UnitDerivation {
-            name: "test-0.1.0-abc123".to_string(),
-            pname: "test".to_string(),
-            version: "0.1.0".to_string(),
-            edition: "2024".to_string(),
-            crate_types: vec!["lib".to_string()],
-            src_path: "${src}/src/lib.rs".to_string(),
-            manifest_dir: "${src}".to_string(),
-            features: vec![],
-            opt_level: "0".to_string(),
-            is_test: false,
-            is_proc_macro: false,
-            deps: vec![],
-            lib_search_deps: vec![],
-            build_script_ref: None,
-            rustc_flags: RustcFlags::new(),
-            content_addressed: false,
-            toolchain_var: "rustToolchain".to_string(),
-        };
-
-        // Add a dependency
-        drv.add_dep(DepRef {
-            nix_var: "units.\"dep-0.1.0-xyz789\"".to_string(),
-            extern_crate_name: "dep".to_string(),
-            lib_name: "dep".to_string(),
-            identity_hash: "xyz789".to_string(),
-            derivation_name: "dep-0.1.0-xyz789".to_string(),
-            is_proc_macro: false,
-        });
-
-        let nix = drv.to_nix();
-
-        // Should have the dependency in buildInputs
-        assert!(nix.contains("buildInputs = [ units.\"dep-0.1.0-xyz789\" ]"));
-    }
-
-    #[test]
-    fn test_multiline_build_phase() {
-        // Use bin crate type so LTO is applied (LTO only works for bin/cdylib/staticlib)
-        let json = r#"{
-            "version": 1,
-            "units": [{
-                "pkg_id": "test 0.1.0 (path+file:///workspace)",
-                "target": {
-                    "kind": ["bin"],
-                    "crate_types": ["bin"],
-                    "name": "test",
-                    "src_path": "/workspace/src/main.rs",
-                    "edition": "2021"
-                },
-                "profile": {"name": "release", "opt_level": "3", "lto": "thin"},
-                "features": ["std", "derive"],
-                "mode": "build",
-                "dependencies": []
-            }],
-            "roots": [0]
-        }"#;
-
-        let graph = parse_test_unit_graph(json);
-        let unit = &graph.units[0];
-        let identity_hash = unit.identity_hash();
-        let drv_name = unit.derivation_name();
-
-        let drv = UnitDerivation::from_unit(
-            unit,
-            "/workspace",
-            false,
-            "rustToolchain",
-            &drv_name,
-            &identity_hash,
-            false, // not an external dep
-        );
-        let build_phase = drv.generate_build_phase();
-
-        // Check for proper flag formatting
-        assert!(build_phase.contains("--crate-name"));
-        assert!(build_phase.contains("test"));
-        assert!(build_phase.contains("--edition"));
-        assert!(build_phase.contains("2021"));
-        assert!(build_phase.contains("opt-level=3"));
-        assert!(build_phase.contains("lto=thin"));
-        assert!(
-            build_phase.contains("feature=\\\"std\\\"") || build_phase.contains("feature=\"std\"")
-        );
-    }
-
-    #[test]
-    fn test_content_addressed_derivation() {
-        let json = r#"{
-            "version": 1,
-            "units": [{
-                "pkg_id": "test 0.1.0 (path+file:///workspace)",
-                "target": {
-                    "kind": ["lib"],
-                    "crate_types": ["lib"],
-                    "name": "test",
-                    "src_path": "/workspace/src/lib.rs",
-                    "edition": "2021"
-                },
-                "profile": {"name": "dev", "opt_level": "0"},
-                "features": [],
-                "mode": "build",
-                "dependencies": []
-            }],
-            "roots": [0]
-        }"#;
-
-        let graph = parse_test_unit_graph(json);
-        let unit = &graph.units[0];
-        let identity_hash = unit.identity_hash();
-        let drv_name = unit.derivation_name();
-
-        // Without content-addressed
-        let drv = UnitDerivation::from_unit(
-            unit,
-            "/workspace",
-            false,
-            "rustToolchain",
-            &drv_name,
-            &identity_hash,
-            false, // not an external dep
-        );
-        let nix = drv.to_nix();
-        assert!(!nix.contains("__contentAddressed"));
-        assert!(!nix.contains("outputHashMode"));
-        assert!(!nix.contains("outputHashAlgo"));
-
-        // With content-addressed
-        let drv_ca = UnitDerivation::from_unit(
-            unit,
-            "/workspace",
-            true,
-            "rustToolchain",
-            &drv_name,
-            &identity_hash,
-            false, // not an external dep
-        );
-        let nix_ca = drv_ca.to_nix();
-        assert!(nix_ca.contains("__contentAddressed = true"));
-        assert!(nix_ca.contains("outputHashMode = \"recursive\""));
-        assert!(nix_ca.contains("outputHashAlgo = \"sha256\""));
-    }
-
-    #[test]
-    fn test_nix_generator_content_addressed() {
-        let json = r#"{
-            "version": 1,
-            "units": [{
-                "pkg_id": "test 0.1.0 (path+file:///workspace)",
-                "target": {
-                    "kind": ["lib"],
-                    "crate_types": ["lib"],
-                    "name": "test",
-                    "src_path": "/workspace/src/lib.rs",
-                    "edition": "2024"
-                },
-                "profile": {"name": "dev", "opt_level": "0"},
-                "features": [],
-                "mode": "build",
-                "dependencies": []
-            }],
-            "roots": [0]
+            ],
+            "roots": [1]
         }"#;
 
         let graph = parse_test_unit_graph(json);
-
-        // Without CA
-        let config = NixGenConfig {
-            workspace_root: "/workspace".to_string(),
-            content_addressed: false,
-            ..Default::default()
-        };
-        let nix = NixGenerator::new(config).generate(&graph);
-        assert!(!nix.contains("__contentAddressed"));
-
-        // With CA
-        let config_ca = NixGenConfig {
+        let generator = NixGenerator::new(NixGenConfig {
             workspace_root: "/workspace".to_string(),
-            content_addressed: true,
             ..Default::default()
-        };
-        let nix_ca = NixGenerator::new(config_ca).generate(&graph);
-        assert!(nix_ca.contains("__contentAddressed = true"));
-        assert!(nix_ca.contains("outputHashMode = \"recursive\""));
-        assert!(nix_ca.contains("outputHashAlgo = \"sha256\""));
+        });
+        let nix = generator.generate(&graph);
+        let drv_names = generator.derivation_names(&graph);
+
+        let host = unit_section(&nix, "host");
+
+        // rustc's build-time relative rpath is replaced by absolute store paths
+        assert!(!nix.contains("rpath=yes"));
+        assert!(host.contains(&format!(
+            "-C link-arg=-Wl,-rpath,${{units.\"{}\"}}/lib \\",
+            drv_names[0]
+        )));
+        // std is linked dynamically, so the toolchain's libdir is on the rpath too
+        assert!(host.contains("-C link-arg=-Wl,-rpath,\"$(rustc --print target-libdir)\" \\"));
     }
 
     #[test]
-    fn test_build_script_output_wiring() {
-        // Test a unit graph where a library depends on a build script
-        // Real cargo output has THREE units for build scripts:
-        // 1. COMPILE unit: mode="build", kind=["custom-build"] - compiles build.rs
-        // 2. RUN unit: mode="run-custom-build" - executes the compiled binary
-        // 3. LIB unit: depends on RUN unit for build script outputs
+    fn test_compact_output() {
         let json = r#"{
             "version": 1,
             "units": [
                 {
-                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "pkg_id": "dep 0.1.0 (path+file:///workspace/dep)",
                     "target": {
-                        "kind": ["custom-build"],
-                        "crate_types": ["bin"],
-                        "name": "build-script-build",
-                        "src_path": "/workspace/build.rs",
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "dep",
+                        "src_path": "/workspace/dep/src/lib.rs",
                         "edition": "2021"
                     },
-                    "profile": {"name": "dev", "opt_level": "0"},
-                    "features": ["feature-x"],
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": ["std"],
                     "mode": "build",
                     "dependencies": []
                 },
                 {
-                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
                     "target": {
-                        "kind": ["custom-build"],
+                        "kind": ["bin"],
                         "crate_types": ["bin"],
-                        "name": "build-script-build",
-                        "src_path": "/workspace/build.rs",
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
                         "edition": "2021"
                     },
-                    "profile": {"name": "dev", "opt_level": "0"},
-                    "features": ["feature-x"],
-                    "mode": "run-custom-build",
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
+                    "mode": "build",
                     "dependencies": [
-                        {"index": 0, "extern_crate_name": "build_script_build", "public": false}
+                        {"index": 0, "extern_crate_name": "dep", "public": false}
                     ]
-                },
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let pretty = NixGenerator::new(base.clone()).generate(&graph);
+        let compact = NixGenerator::new(NixGenConfig {
+            compact: true,
+            ..base
+        })
+        .generate(&graph);
+
+        assert!(compact.len() < pretty.len());
+        assert!(compact.lines().count() < pretty.lines().count());
+
+        // Same derivations and key attributes
+        for name in NixGenerator::new(NixGenConfig::default()).derivation_names(&graph) {
+            assert!(compact.contains(&format!("\"{name}\" = mkUnit {{pname=")));
+        }
+        for attr in [
+            "pname=\"app\";",
+            "version=\"0.1.0\";",
+            "buildInputs=[ units.",
+            "nativeBuildInputs=[ rustToolchain ];",
+            "buildPhase=''",
+            "installPhase=''",
+            "passthru={nix-cargo-unit={",
+        ] {
+            assert!(compact.contains(attr), "missing {attr}");
+        }
+        assert_eq!(compact.matches('{').count(), compact.matches('}').count());
+
+        // The whole rustc invocation is on one line, without continuations
+        let rustc_line = compact
+            .lines()
+            .find(|line| line.starts_with("rustc ") && line.contains("--crate-name app"))
+            .unwrap();
+        assert!(rustc_line.contains("--extern dep="));
+        assert!(rustc_line.ends_with("$BUILD_SCRIPT_FLAGS"));
+        assert!(!compact.contains(" \\\n"));
+    }
+
+    #[test]
+    fn test_write_identity() {
+        let json = r#"{
+            "version": 1,
+            "units": [
                 {
-                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "pkg_id": "dep 0.1.0 (path+file:///workspace/dep)",
                     "target": {
                         "kind": ["lib"],
                         "crate_types": ["lib"],
-                        "name": "my_crate",
-                        "src_path": "/workspace/src/lib.rs",
+                        "name": "dep",
+                        "src_path": "/workspace/dep/src/lib.rs",
                         "edition": "2021"
                     },
-                    "profile": {"name": "dev", "opt_level": "0"},
-                    "features": ["feature-x"],
+                    "profile": {"name": "release", "opt_level": "3", "codegen_units": 16},
+                    "features": ["std", "alloc"],
+                    "mode": "build",
+                    "dependencies": [],
+                    "platform": "x86_64-unknown-linux-gnu"
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
                     "mode": "build",
                     "dependencies": [
-                        {"index": 1, "extern_crate_name": "build_script_build", "public": false}
+                        {"index": 0, "extern_crate_name": "dep", "public": false}
                     ]
                 }
             ],
-            "roots": [2]
+            "roots": [1]
         }"#;
 
         let graph = parse_test_unit_graph(json);
-        let config = NixGenConfig {
+        let generator = NixGenerator::new(NixGenConfig {
             workspace_root: "/workspace".to_string(),
-            content_addressed: false,
+            toolchain_hash: Some("toolchain".to_string()),
             ..Default::default()
-        };
-
-        let generator = NixGenerator::new(config);
-        let nix = generator.generate(&graph);
-
-        // Should have build script compile derivation (now uses target name "build-script-build")
-        assert!(
-            nix.contains("pname = \"build-script-build\""),
-            "missing build script compile derivation"
-        );
-
-        // Should have build script run derivation
-        assert!(
-            nix.contains("my-crate-build-script-run-"),
-            "missing build script run derivation name"
-        );
-        assert!(
-            nix.contains("pname = \"my-crate-build-script-output\""),
-            "missing build script output pname"
-        );
+        });
+        let drv_names = generator.derivation_names(&graph);
+        let hashes = generator.identity_hashes(&graph, &canonical_indices(&graph));
 
-        // The library should read build script outputs
+        let mut buf = Vec::new();
         assert!(
-            nix.contains("BUILD_SCRIPT_FLAGS"),
-            "missing BUILD_SCRIPT_FLAGS"
+            generator
+                .write_identity(&graph, &drv_names[0], &mut buf)
+                .unwrap()
         );
-        assert!(
-            nix.contains("# Read build script outputs"),
-            "missing build script outputs comment"
+        let out = String::from_utf8(buf).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines[0], format!("derivation: {}", drv_names[0]));
+        for line in [
+            "pkg_id: \"dep 0.1.0 (path+file:///workspace/dep)\"",
+            "target_name: \"dep\"",
+            "crate_types: [\"lib\"]",
+            "features: [\"alloc\",\"std\"]",
+            "profile: \"release\"",
+            "opt_level: \"3\"",
+            "codegen_units: 16",
+            "mode: \"build\"",
+            "platform: \"x86_64-unknown-linux-gnu\"",
+            "dep_hashes: []",
+            "toolchain_hash: \"toolchain\"",
+        ] {
+            assert!(lines.contains(&line), "missing {line:?} in:\n{out}");
+        }
+        assert_eq!(
+            lines.last().unwrap(),
+            &format!("identity_hash: {}", hashes[0])
         );
-        assert!(nix.contains("rustc-cfg"), "missing rustc-cfg handling");
 
-        // Library build phase should include $BUILD_SCRIPT_FLAGS
-        assert!(
-            nix.contains("$BUILD_SCRIPT_FLAGS"),
-            "missing $BUILD_SCRIPT_FLAGS in build phase"
-        );
+        // The dependent lists its dependency's hash
+        let mut buf = Vec::new();
+        generator
+            .write_identity(&graph, &drv_names[1], &mut buf)
+            .unwrap();
+        let out = String::from_utf8(buf).unwrap();
+        assert!(out.contains(&format!("dep_hashes: [\"{}\"]", hashes[0])));
 
-        // Library should have build script run derivation in buildInputs
+        let mut buf = Vec::new();
         assert!(
-            nix.contains("my-crate-build-script-run-"),
-            "missing build script run derivation reference"
+            !generator
+                .write_identity(&graph, "missing-0.1.0-0000", &mut buf)
+                .unwrap()
         );
+        assert!(buf.is_empty());
     }
 
     #[test]
-    fn test_build_script_ref_in_build_inputs() {
-        let mut drv = UnitDerivation {
-            name: "test-0.1.0-abc123".to_string(),
-            pname: "test".to_string(),
-            version: "0.1.0".to_string(),
-            edition: "2024".to_string(),
-            crate_types: vec!["lib".to_string()],
-            src_path: "${src}/src/lib.rs".to_string(),
-            manifest_dir: "${src}".to_string(),
-            features: vec![],
-            opt_level: "0".to_string(),
-            is_test: false,
-            is_proc_macro: false,
-            deps: vec![],
-            lib_search_deps: vec![],
-            build_script_ref: Some(BuildScriptRef {
-                run_drv_var: "units.\"my-build-script-run\"".to_string(),
-                compile_drv_name: "my-build-script".to_string(),
-                run_drv_name: "my-build-script-run".to_string(),
-            }),
-            rustc_flags: RustcFlags::new(),
-            content_addressed: false,
-            toolchain_var: "rustToolchain".to_string(),
+    fn test_embed_bitcode() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "dep 0.1.0 (path+file:///workspace/dep)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "dep",
+                        "src_path": "/workspace/dep/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3", "lto": "fat"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3", "lto": "fat"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "dep", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let codegen = |nix: &str, pname: &str| -> Vec<String> {
+            let tokens: Vec<&str> = unit_section(nix, pname)
+                .split_whitespace()
+                .filter(|t| *t != "\\")
+                .collect();
+            tokens
+                .windows(2)
+                .filter(|w| w[0] == "-C" && w[1].starts_with("embed-bitcode="))
+                .map(|w| w[1].to_string())
+                .collect()
         };
 
-        // Add a regular dependency too
-        drv.add_dep(DepRef {
-            nix_var: "units.\"dep-0.1.0-xyz789\"".to_string(),
-            extern_crate_name: "dep".to_string(),
-            lib_name: "dep".to_string(),
-            identity_hash: "xyz789".to_string(),
-            derivation_name: "dep-0.1.0-xyz789".to_string(),
-            is_proc_macro: false,
+        // LTO keeps bitcode in the rlib the LTO link reads, and in the bin itself
+        let generator = NixGenerator::new(base.clone());
+        let nix = generator.generate(&graph);
+        assert_eq!(codegen(&nix, "dep"), ["embed-bitcode=yes"]);
+        assert_eq!(codegen(&nix, "app"), ["embed-bitcode=yes"]);
+        assert!(nix.contains("lto=fat"));
+        let default_names = generator.derivation_names(&graph);
+
+        // Disabling it is ignored under LTO, which needs the rlib's bitcode too
+        let no_bitcode = NixGenConfig {
+            embed_bitcode: Some(false),
+            ..base.clone()
+        };
+        let generator = NixGenerator::new(no_bitcode.clone());
+        let nix = generator.generate(&graph);
+        assert_eq!(codegen(&nix, "dep"), ["embed-bitcode=yes"]);
+        assert_eq!(codegen(&nix, "app"), ["embed-bitcode=yes"]);
+        assert_eq!(generator.derivation_names(&graph), default_names);
+
+        // Without LTO it applies everywhere
+        let no_lto = parse_test_unit_graph(&json.replace(r#", "lto": "fat""#, ""));
+        let generator = NixGenerator::new(no_bitcode);
+        let nix = generator.generate(&no_lto);
+        assert_eq!(codegen(&nix, "dep"), ["embed-bitcode=no"]);
+        assert_eq!(codegen(&nix, "app"), ["embed-bitcode=no"]);
+
+        // Forcing it on is part of the identity even where it matches the default
+        let generator = NixGenerator::new(NixGenConfig {
+            embed_bitcode: Some(true),
+            ..base
         });
-
-        let nix = drv.to_nix();
-
-        // Should have both regular dep and build script in buildInputs
-        assert!(nix.contains("buildInputs = ["));
-        assert!(nix.contains("units.\"dep-0.1.0-xyz789\""));
-        assert!(nix.contains("units.\"my-build-script-run\""));
-
-        // Build phase should read build script outputs
-        let build_phase = drv.generate_build_phase();
-        assert!(build_phase.contains("BUILD_SCRIPT_FLAGS"));
-        assert!(build_phase.contains("units.\"my-build-script-run\""));
-        assert!(build_phase.contains("rustc-cfg"));
+        assert_eq!(
+            codegen(&generator.generate(&graph), "dep"),
+            ["embed-bitcode=yes"]
+        );
+        assert_ne!(generator.derivation_names(&graph)[0], default_names[0]);
     }
 
     #[test]
-    fn test_proc_macro_host_toolchain() {
-        // Test that proc-macros use hostRustToolchain in cross-compilation
+    fn test_impure_build_scripts() {
         let json = r#"{
             "version": 1,
             "units": [
                 {
-                    "pkg_id": "serde_derive 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/my-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "my-sys 0.1.0 (path+file:///workspace/my-sys)",
                     "target": {
-                        "kind": ["proc-macro"],
-                        "crate_types": ["proc-macro"],
-                        "name": "serde_derive",
-                        "src_path": "/registry/serde_derive/src/lib.rs",
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_sys",
+                        "src_path": "/workspace/my-sys/src/lib.rs",
                         "edition": "2021"
                     },
                     "profile": {"name": "dev", "opt_level": "0"},
                     "features": [],
                     "mode": "build",
-                    "dependencies": [],
-                    "platform": "aarch64-apple-darwin"
+                    "dependencies": [
+                        {"index": 1, "extern_crate_name": "build_script_build", "public": false}
+                    ]
                 },
                 {
-                    "pkg_id": "my_app 0.1.0 (path+file:///workspace)",
+                    "pkg_id": "other-sys 0.1.0 (path+file:///workspace/other-sys)",
                     "target": {
-                        "kind": ["bin"],
+                        "kind": ["custom-build"],
                         "crate_types": ["bin"],
-                        "name": "my_app",
-                        "src_path": "/workspace/src/main.rs",
-                        "edition": "2024"
+                        "name": "build-script-build",
+                        "src_path": "/workspace/other-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "other-sys 0.1.0 (path+file:///workspace/other-sys)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/workspace/other-sys/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": [
+                        {"index": 3, "extern_crate_name": "build_script_build", "public": false}
+                    ]
+                },
+                {
+                    "pkg_id": "other-sys 0.1.0 (path+file:///workspace/other-sys)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "other_sys",
+                        "src_path": "/workspace/other-sys/src/lib.rs",
+                        "edition": "2021"
                     },
                     "profile": {"name": "dev", "opt_level": "0"},
                     "features": [],
                     "mode": "build",
                     "dependencies": [
-                        {"index": 0, "extern_crate_name": "serde_derive", "public": false}
+                        {"index": 4, "extern_crate_name": "build_script_build", "public": false}
                     ]
                 }
             ],
-            "roots": [1]
+            "roots": [2, 5]
         }"#;
 
         let graph = parse_test_unit_graph(json);
-
-        // Without cross-compilation: both use rustToolchain
-        let config = NixGenConfig {
+        let base = NixGenConfig {
             workspace_root: "/workspace".to_string(),
-            content_addressed: false,
-            cross_compiling: false,
             ..Default::default()
         };
-        let nix = NixGenerator::new(config).generate(&graph);
+        assert!(base.impure_build_script_warnings(&graph).is_empty());
+        let nix = NixGenerator::new(base.clone()).generate(&graph);
+        assert!(!nix.contains("__noChroot"));
+        assert!(!nix.contains("NIX_ENFORCE_PURITY"));
 
-        // Should use rustToolchain for both (hostRustToolchain is in signature but defaults to rustToolchain)
-        assert!(nix.contains("{ pkgs, rustToolchain, hostRustToolchain ? rustToolchain, src, extraNativeBuildInputs ? [], vendorDir ? null }:"));
-        // Proc-macro should use rustToolchain when not cross-compiling
-        assert!(nix.contains("nativeBuildInputs = [ rustToolchain ]"));
-        // Should NOT have hostRustToolchain in nativeBuildInputs when not cross-compiling
-        assert!(!nix.contains("nativeBuildInputs = [ hostRustToolchain ]"));
+        let config = NixGenConfig {
+            impure_build_scripts: vec!["my-sys".to_string()],
+            ..base
+        };
+        let warnings = config.impure_build_script_warnings(&graph);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("unit 1 (my-sys 0.1.0 "));
+        assert!(warnings[0].contains("sandbox = relaxed"));
 
-        // With cross-compilation: proc-macro uses hostRustToolchain
-        let config_cross = NixGenConfig {
+        let nix = NixGenerator::new(config).generate(&graph);
+        assert_eq!(nix.matches("__noChroot = true;").count(), 1);
+        assert_eq!(nix.matches("NIX_ENFORCE_PURITY = \"0\";").count(), 1);
+        assert_eq!(nix.matches("# WARNING: impure build script").count(), 1);
+
+        // Only the listed package's run derivation is impure
+        let run = nix
+            .split("pname = \"")
+            .find(|section| section.contains("NIX_ENFORCE_PURITY"))
+            .unwrap();
+        assert!(run.starts_with("my-sys"));
+        assert!(run.contains("__noChroot = true;"));
+    }
+
+    #[test]
+    fn test_diff_derivations() {
+        let graph_json = |features: &str| {
+            format!(
+                r#"{{
+                    "version": 1,
+                    "units": [
+                        {{
+                            "pkg_id": "dep 0.1.0 (path+file:///workspace/dep)",
+                            "target": {{
+                                "kind": ["lib"],
+                                "crate_types": ["lib"],
+                                "name": "dep",
+                                "src_path": "/workspace/dep/src/lib.rs",
+                                "edition": "2021"
+                            }},
+                            "profile": {{"name": "dev", "opt_level": "0"}},
+                            "features": [],
+                            "mode": "build",
+                            "dependencies": []
+                        }},
+                        {{
+                            "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                            "target": {{
+                                "kind": ["bin"],
+                                "crate_types": ["bin"],
+                                "name": "app",
+                                "src_path": "/workspace/app/src/main.rs",
+                                "edition": "2021"
+                            }},
+                            "profile": {{"name": "dev", "opt_level": "0"}},
+                            "features": [{features}],
+                            "mode": "build",
+                            "dependencies": [
+                                {{"index": 0, "extern_crate_name": "dep", "public": false}}
+                            ]
+                        }}
+                    ],
+                    "roots": [1]
+                }}"#
+            )
+        };
+        let config = NixGenConfig {
             workspace_root: "/workspace".to_string(),
-            content_addressed: false,
-            cross_compiling: true,
-            host_platform: Some("aarch64-apple-darwin".to_string()),
-            target_platform: Some("x86_64-unknown-linux-gnu".to_string()),
             ..Default::default()
         };
-        let nix_cross = NixGenerator::new(config_cross).generate(&graph);
+        let old_graph = parse_test_unit_graph(&graph_json(""));
+        let new_graph = parse_test_unit_graph(&graph_json(r#""fast""#));
+        let old_names = NixGenerator::new(config.clone()).derivation_names(&old_graph);
+        let new_names = NixGenerator::new(config.clone()).derivation_names(&new_graph);
+        let old = NixGenerator::new(config.clone()).generate(&old_graph);
+        let new = NixGenerator::new(config).generate(&new_graph);
+
+        assert_eq!(derivation_names_in(&old).into_iter().collect::<Vec<_>>(), {
+            let mut names = old_names.clone();
+            names.sort();
+            names
+        });
 
-        // Should have hostRustToolchain in function signature
-        assert!(nix_cross.contains("hostRustToolchain"));
-        assert!(
-            nix_cross.contains("{ pkgs, rustToolchain, hostRustToolchain ? rustToolchain, src, extraNativeBuildInputs ? [], vendorDir ? null }:")
+        // Only the unit whose features changed gets a new name
+        let diff = diff_derivations(&old, &new);
+        assert_eq!(
+            diff,
+            DerivationDiff {
+                added: vec![new_names[1].clone()],
+                removed: vec![old_names[1].clone()],
+                unchanged: vec![old_names[0].clone()],
+            }
         );
 
-        // Proc-macro should use hostRustToolchain
-        // Regular bin should use rustToolchain
-        // Check that both toolchains appear in nativeBuildInputs
-        assert!(nix_cross.contains("nativeBuildInputs = [ hostRustToolchain ]"));
-        assert!(nix_cross.contains("nativeBuildInputs = [ rustToolchain ]"));
+        assert_eq!(
+            diff_derivations(&new, &new),
+            DerivationDiff {
+                unchanged: derivation_names_in(&new).into_iter().collect(),
+                ..Default::default()
+            }
+        );
+
+        // A per-unit-file index names the same derivations as the inline output
+        let mut index = Vec::new();
+        NixGenerator::new(NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        })
+        .write_split_to(&new_graph, &mut index, |_, _| Ok(()))
+        .unwrap();
+        let index = String::from_utf8(index).unwrap();
+        assert_eq!(derivation_names_in(&index), derivation_names_in(&new));
     }
 
     #[test]
-    fn test_proc_macro_output_path() {
-        // Test that proc-macros output to shared library path
+    fn test_devshell() {
         let json = r#"{
             "version": 1,
             "units": [
                 {
-                    "pkg_id": "my_macro 0.1.0 (path+file:///workspace)",
-                    "target": {
-                        "kind": ["proc-macro"],
-                        "crate_types": ["proc-macro"],
-                        "name": "my_macro",
-                        "src_path": "/workspace/src/lib.rs",
-                        "edition": "2021"
-                    },
+                    "pkg_id": "dep 0.1.0 (path+file:///workspace/dep)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "dep", "src_path": "/workspace/dep/src/lib.rs", "edition": "2021"},
                     "profile": {"name": "dev", "opt_level": "0"},
                     "features": [],
                     "mode": "build",
-                    "dependencies": [],
-                    "platform": "x86_64-unknown-linux-gnu"
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-macro 0.1.0 (path+file:///workspace/my-macro)",
+                    "target": {"kind": ["proc-macro"], "crate_types": ["proc-macro"], "name": "my_macro", "src_path": "/workspace/my-macro/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {"kind": ["bin"], "crate_types": ["bin"], "name": "app", "src_path": "/workspace/app/src/main.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": ["fast"],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "dep"},
+                        {"index": 1, "extern_crate_name": "my_macro"}
+                    ]
                 }
             ],
-            "roots": [0]
+            "roots": [2]
         }"#;
 
         let graph = parse_test_unit_graph(json);
-        let unit = &graph.units[0];
-        let identity_hash = unit.identity_hash();
-        let drv_name = unit.derivation_name();
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        assert!(
+            !NixGenerator::new(base.clone())
+                .generate(&graph)
+                .contains("devShell")
+        );
 
-        let drv = UnitDerivation::from_unit(
-            unit,
-            "/workspace",
-            false,
-            "rustToolchain",
-            &drv_name,
-            &identity_hash,
-            false, // not an external dep
+        let config = NixGenConfig {
+            devshell: Some("app".to_string()),
+            ..base
+        };
+        let generator = NixGenerator::new(config.clone());
+        assert_eq!(generator.devshell_unit(&graph), Some(2));
+        assert_eq!(
+            NixGenerator::new(NixGenConfig {
+                devshell: Some("missing".to_string()),
+                ..config
+            })
+            .devshell_unit(&graph),
+            None
         );
-        let build_phase = drv.generate_build_phase();
 
-        // Should use --out-dir for libraries (including proc-macros)
-        assert!(build_phase.contains("--out-dir build"));
-        // Proc-macros use dep-info,link only (metadata embedded in dylib)
-        assert!(build_phase.contains("--emit=dep-info,link"));
-        assert!(!build_phase.contains("--emit=dep-info,metadata,link"));
-        assert!(drv.is_proc_macro);
+        let nix = generator.generate(&graph);
+        let names = generator.derivation_names(&graph);
+        let shell = nix.split("  devShell = pkgs.mkShell {\n").nth(1).unwrap();
+        let shell = &shell[..shell.find("\n  };\n").unwrap()];
 
-        // Check install phase copies all outputs to $out
-        let install_phase = drv.generate_install_phase();
-        assert!(install_phase.contains("$out/lib"));
-        assert!(install_phase.contains("cp build/*"));
+        // The crate's own flags, as the derivation passes them to rustc
+        let flags = UnitDerivation::from_unit(
+            &graph.units[2],
+            "/workspace",
+            &[],
+            false,
+            "rustToolchain",
+            &names[2],
+            names[2].rsplit('-').next().unwrap(),
+        )
+        .rustc_flags
+        .to_shell_string();
+        assert!(flags.contains("--crate-name app"));
+        assert!(flags.contains(r#"'feature="fast"'"#));
+        assert!(shell.contains(&format!("RUSTC_ARGS = \"{}\";", escape_nix_string(&flags))));
+
+        // Dependency flags resolve against the dependencies' derivations
+        assert!(shell.contains(&format!("-L dependency=${{units.\"{}\"}}/lib", names[0])));
+        let dep_hash = names[0].rsplit('-').next().unwrap();
+        assert!(shell.contains(&format!(
+            "--extern dep=${{units.\"{}\"}}/lib/libdep-{dep_hash}.rlib",
+            names[0]
+        )));
+        assert!(shell.contains("PROCMACRO_MY_MACRO=\"$(find"));
+        assert!(shell.contains("--extern my_macro=\"$PROCMACRO_MY_MACRO\""));
+        assert!(shell.contains("export CARGO_PKG_NAME=\"app\""));
     }
 
     #[test]
-    fn test_workspace_packages_attrset() {
-        // Test workspace with multiple root units
+    fn test_host_and_target_variants_wired_per_edge() {
+        // `shared` is built twice: for the host (a proc-macro's dependency) and
+        // for the target (the app's dependency)
         let json = r#"{
             "version": 1,
             "units": [
                 {
-                    "pkg_id": "core-lib 0.1.0 (path+file:///workspace/crates/core)",
-                    "target": {
-                        "kind": ["lib"],
-                        "crate_types": ["lib"],
-                        "name": "core_lib",
-                        "src_path": "/workspace/crates/core/src/lib.rs",
-                        "edition": "2021"
-                    },
+                    "pkg_id": "shared 0.1.0 (path+file:///workspace/shared)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "shared", "src_path": "/workspace/shared/src/lib.rs", "edition": "2021"},
                     "profile": {"name": "dev", "opt_level": "0"},
                     "features": [],
                     "mode": "build",
                     "dependencies": []
                 },
                 {
-                    "pkg_id": "my-app 0.1.0 (path+file:///workspace/crates/app)",
-                    "target": {
-                        "kind": ["bin"],
-                        "crate_types": ["bin"],
-                        "name": "my_app",
-                        "src_path": "/workspace/crates/app/src/main.rs",
-                        "edition": "2021"
-                    },
+                    "pkg_id": "shared 0.1.0 (path+file:///workspace/shared)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "shared", "src_path": "/workspace/shared/src/lib.rs", "edition": "2021"},
                     "profile": {"name": "dev", "opt_level": "0"},
                     "features": [],
                     "mode": "build",
-                    "dependencies": [
-                        {"index": 0, "extern_crate_name": "core_lib", "public": false}
-                    ]
+                    "platform": "aarch64-unknown-linux-gnu",
+                    "dependencies": []
                 },
                 {
-                    "pkg_id": "cli-tool 0.1.0 (path+file:///workspace/crates/cli)",
-                    "target": {
-                        "kind": ["bin"],
-                        "crate_types": ["bin"],
-                        "name": "cli_tool",
-                        "src_path": "/workspace/crates/cli/src/main.rs",
-                        "edition": "2021"
-                    },
+                    "pkg_id": "my-derive 0.1.0 (path+file:///workspace/my-derive)",
+                    "target": {"kind": ["proc-macro"], "crate_types": ["proc-macro"], "name": "my_derive", "src_path": "/workspace/my-derive/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [{"index": 0, "extern_crate_name": "shared"}]
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {"kind": ["bin"], "crate_types": ["bin"], "name": "app", "src_path": "/workspace/app/src/main.rs", "edition": "2021"},
                     "profile": {"name": "dev", "opt_level": "0"},
                     "features": [],
                     "mode": "build",
+                    "platform": "aarch64-unknown-linux-gnu",
                     "dependencies": [
-                        {"index": 0, "extern_crate_name": "core_lib", "public": false}
+                        {"index": 1, "extern_crate_name": "shared"},
+                        {"index": 2, "extern_crate_name": "my_derive"}
                     ]
                 }
             ],
-            "roots": [0, 1, 2]
+            "roots": [3]
         }"#;
 
         let graph = parse_test_unit_graph(json);
         let config = NixGenConfig {
             workspace_root: "/workspace".to_string(),
-            content_addressed: false,
             ..Default::default()
         };
-
         let generator = NixGenerator::new(config);
+        let names = generator.derivation_names(&graph);
         let nix = generator.generate(&graph);
+        assert_ne!(names[0], names[1]);
+
+        let extern_shared = |i: usize| {
+            let hash = names[i].rsplit('-').next().unwrap();
+            format!(
+                "--extern shared=${{units.\"{}\"}}/lib/libshared-{hash}.rlib",
+                names[i]
+            )
+        };
 
-        // Should have packages attrset with all roots
-        assert!(nix.contains("packages = {"));
-        assert!(nix.contains("\"core_lib\" = units.\""));
-        assert!(nix.contains("\"my_app\" = units.\""));
-        assert!(nix.contains("\"cli_tool\" = units.\""));
+        // Each consumer links the variant its own dependency edge points at
+        let derive = unit_section(&nix, "my_derive");
+        assert!(derive.contains(&extern_shared(0)));
+        assert!(!derive.contains(&names[1]));
 
-        // Should have binaries attrset with only binaries
-        assert!(nix.contains("binaries = {"));
-        // binaries should contain my_app and cli_tool but NOT core_lib
-        let binaries_section = nix
-            .split("# Binary targets only")
-            .nth(1)
-            .unwrap()
-            .split("# Library targets only")
-            .next()
-            .unwrap();
-        assert!(binaries_section.contains("\"my_app\""));
-        assert!(binaries_section.contains("\"cli_tool\""));
-        assert!(!binaries_section.contains("\"core_lib\""));
+        let app = unit_section(&nix, "app");
+        assert!(app.contains(&extern_shared(1)));
+        assert!(!app.contains(&extern_shared(0)));
+        assert!(app.contains("--extern my_derive=\"$PROCMACRO_MY_DERIVE\""));
+        assert!(app.contains(&format!("find ${{units.\"{}\"}}/lib", names[2])));
+    }
 
-        // Should have libraries attrset with only libraries
-        assert!(nix.contains("libraries = {"));
-        let libraries_section = nix.split("# Library targets only").nth(1).unwrap();
-        assert!(libraries_section.contains("\"core_lib\""));
-        // Libraries should NOT contain binaries
+    #[test]
+    fn test_stable_symlinks() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-dep 0.1.0 (path+file:///workspace/my-dep)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "my-dep", "src_path": "/workspace/my-dep/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {"kind": ["bin"], "crate_types": ["bin"], "name": "app", "src_path": "/workspace/app/src/main.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [{"index": 0, "extern_crate_name": "my_dep"}]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let base = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let nix = NixGenerator::new(base.clone()).generate(&graph);
+        assert!(!nix.contains("postInstall"));
+        assert!(!nix.contains("runHook"));
+
+        let config = NixGenConfig {
+            emit_stable_symlinks: true,
+            ..base
+        };
+        let generator = NixGenerator::new(config.clone());
+        let names = generator.derivation_names(&graph);
+        let hash = names[0].rsplit('-').next().unwrap();
+        let nix = generator.generate(&graph);
+
+        let dep = unit_section(&nix, "my-dep");
+        assert!(dep.contains("runHook postInstall\n"));
+        assert!(dep.contains(&format!(
+            "[ -e \"$out/lib/libmy_dep.rlib\" ] || ln -s libmy_dep-{hash}.rlib \"$out/lib/libmy_dep.rlib\""
+        )));
         assert!(
-            !libraries_section
-                .split("default =")
-                .next()
-                .unwrap()
-                .contains("\"my_app\"")
+            config
+                .identity_extras(&graph.units[0])
+                .contains(&"stable-symlinks".to_string())
         );
+
+        // Binaries have no rlib to link, and dependents still use the hashed file
+        let app = unit_section(&nix, "app");
+        assert!(!app.contains("postInstall"));
+        assert!(app.contains(&format!(
+            "--extern my_dep=${{units.\"{}\"}}/lib/libmy_dep-{hash}.rlib",
+            names[0]
+        )));
     }
 }
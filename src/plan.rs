@@ -0,0 +1,221 @@
+//! Versioned JSON build plan (`--format json-plan`).
+//!
+//! Unlike the `json` format, which passes cargo's unit graph through, the plan
+//! describes the derivations the Nix generator emits: deduplicated units under
+//! their derivation names, and the edges between them. The schema is a stable
+//! interface for external tooling: fields may be added, but removing or
+//! changing the meaning of one bumps [`SCHEMA_VERSION`].
+
+use crate::nix_gen::{NixGenConfig, NixGenerator, canonical_indices, derivation_names};
+use crate::unit_graph::UnitGraph;
+
+/// Version of the [`BuildPlan`] schema.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A build plan: every derivation and the dependency edges between them.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BuildPlan {
+    /// Schema version ([`SCHEMA_VERSION`] when produced by this crate).
+    pub schema_version: u32,
+
+    /// One entry per derivation, in unit graph order.
+    pub units: Vec<PlanUnit>,
+
+    /// Dependency edges, one per `(from, to, extern_crate_name)`.
+    pub edges: Vec<PlanEdge>,
+
+    /// Derivation names of the root units.
+    pub roots: Vec<String>,
+}
+
+/// A single derivation in a [`BuildPlan`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PlanUnit {
+    /// Derivation name, `{target}-{version}-{identity_hash}`.
+    pub derivation: String,
+
+    /// Identity hash embedded in the derivation name.
+    pub identity_hash: String,
+
+    /// Cargo package ID.
+    pub pkg_id: String,
+
+    /// Package name.
+    pub package: String,
+
+    /// Package version.
+    pub version: String,
+
+    /// Target name.
+    pub target: String,
+
+    /// Target kinds (e.g. `lib`, `bin`, `proc-macro`, `custom-build`).
+    pub kind: Vec<String>,
+
+    /// Compile mode (`build`, `test`, `run-custom-build`, ...).
+    pub mode: String,
+
+    /// Target platform, if cargo recorded one.
+    pub platform: Option<String>,
+
+    /// Enabled features, sorted.
+    pub features: Vec<String>,
+
+    /// Derivation names of direct dependencies, sorted and deduplicated.
+    pub dependencies: Vec<String>,
+}
+
+/// A dependency edge in a [`BuildPlan`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PlanEdge {
+    /// Derivation name of the dependent.
+    pub from: String,
+
+    /// Derivation name of the dependency.
+    pub to: String,
+
+    /// Name the dependency is passed to rustc under.
+    pub extern_crate_name: String,
+}
+
+/// Builds the plan for the derivations `config` generates from `graph`.
+///
+/// Duplicate units (same package, target, and mode) collapse into one entry,
+/// as they do in the Nix output. Doctest units are omitted.
+pub fn to_plan(graph: &UnitGraph, config: &NixGenConfig) -> BuildPlan {
+    let canonical_index = canonical_indices(graph);
+    let identity_hashes =
+        NixGenerator::new(config.clone()).identity_hashes(graph, &canonical_index);
+    let drv_names = derivation_names(graph, &canonical_index, &identity_hashes);
+
+    let mut units = Vec::new();
+    let mut edges = Vec::new();
+    for (i, unit) in graph.units.iter().enumerate() {
+        if canonical_index[i] != i || unit.is_doctest() {
+            continue;
+        }
+
+        let mut dependencies = Vec::with_capacity(unit.dependencies.len());
+        for dep in &unit.dependencies {
+            let Some(to) = drv_names.get(dep.index) else {
+                continue;
+            };
+            dependencies.push(to.clone());
+            edges.push(PlanEdge {
+                from: drv_names[i].clone(),
+                to: to.clone(),
+                extern_crate_name: dep.extern_crate_name.clone(),
+            });
+        }
+        dependencies.sort_unstable();
+        dependencies.dedup();
+
+        let mut features = unit.features.clone();
+        features.sort_unstable();
+
+        units.push(PlanUnit {
+            derivation: drv_names[i].clone(),
+            identity_hash: identity_hashes[i].clone(),
+            pkg_id: unit.pkg_id.clone(),
+            package: unit.package_name().to_string(),
+            version: unit.package_version().unwrap_or("0.0.0").to_string(),
+            target: unit.target.name.clone(),
+            kind: unit.target.kind.clone(),
+            mode: unit.mode.clone(),
+            platform: unit.platform.clone(),
+            features,
+            dependencies,
+        });
+    }
+    edges.dedup();
+
+    let mut roots: Vec<String> = graph
+        .roots
+        .iter()
+        .filter_map(|&i| drv_names.get(i).cloned())
+        .collect();
+    roots.dedup();
+
+    BuildPlan {
+        schema_version: SCHEMA_VERSION,
+        units,
+        edges,
+        roots,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_graph::parse_test_unit_graph;
+
+    #[test]
+    fn test_to_plan() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "dep 0.2.0 (path+file:///workspace/dep)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "dep",
+                        "src_path": "/workspace/dep/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": ["std", "alloc"],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["bin"],
+                        "crate_types": ["bin"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/main.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "dep_renamed", "public": false}
+                    ]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let config = NixGenConfig {
+            workspace_root: "/workspace".to_string(),
+            ..Default::default()
+        };
+        let plan = to_plan(&graph, &config);
+
+        let value = serde_json::to_value(&plan).unwrap();
+        assert_eq!(value["schema_version"], SCHEMA_VERSION);
+
+        assert_eq!(plan.units.len(), 2);
+        let (dep, app) = (&plan.units[0], &plan.units[1]);
+        assert!(dep.derivation.starts_with("dep-0.2.0-"));
+        assert_eq!(dep.features, ["alloc", "std"]);
+        assert!(app.derivation.starts_with("app-0.1.0-"));
+        assert_eq!(app.dependencies, std::slice::from_ref(&dep.derivation));
+        assert_eq!(
+            plan.edges,
+            [PlanEdge {
+                from: app.derivation.clone(),
+                to: dep.derivation.clone(),
+                extern_crate_name: "dep_renamed".to_string(),
+            }]
+        );
+        assert_eq!(plan.roots, std::slice::from_ref(&app.derivation));
+
+        // Names match the generated Nix
+        let nix = NixGenerator::new(config).generate(&graph);
+        assert!(nix.contains(&format!("\"{}\" = mkUnit", app.derivation)));
+    }
+}
@@ -48,14 +48,14 @@ pub struct ProcMacroInfo {
     /// The package name.
     pub package_name: String,
 
-    /// The crate name (used for library file naming).
+    /// The crate name with hyphens normalized (used for library file naming).
     pub crate_name: String,
 
     /// Package version.
     pub version: String,
 
     /// The host platform this proc-macro compiles for.
-    /// This comes from the unit's `platform` field.
+    /// Resolved with [`Unit::target_triple`].
     pub host_platform: String,
 
     /// Whether this is for a cross-compilation scenario.
@@ -67,26 +67,22 @@ impl ProcMacroInfo {
     /// Extracts proc-macro information from a unit.
     ///
     /// Returns `None` if the unit is not a proc-macro.
-    pub fn from_unit(unit: &Unit, target_platform: Option<&str>) -> Option<Self> {
+    pub fn from_unit(unit: &Unit, config: &crate::nix_gen::NixGenConfig) -> Option<Self> {
         if !unit.is_proc_macro() {
             return None;
         }
 
         let package_name = unit.package_name().to_string();
-        let crate_name = unit.target.name.clone();
+        let crate_name = unit.crate_name();
         let version = unit.package_version().unwrap_or("0.0.0").to_string();
 
-        // Proc-macros always have a platform field indicating host
-        let host_platform = unit.platform.clone().unwrap_or_else(|| {
-            // Fallback: if platform is not set, assume current platform
-            // This shouldn't happen for proc-macros in practice
-            std::env::consts::ARCH.to_string()
-        });
+        let host_platform = unit.target_triple(config);
 
         // Detect cross-compilation
-        let is_cross_compile = target_platform
-            .map(|target| target != host_platform)
-            .unwrap_or(false);
+        let is_cross_compile = config
+            .target_platform
+            .as_deref()
+            .is_some_and(|target| target != host_platform);
 
         Some(Self {
             package_name,
@@ -104,9 +100,8 @@ impl ProcMacroInfo {
 
     /// Returns the full library file name (e.g., `libmy_macro.so`).
     pub fn library_filename(&self) -> String {
-        let normalized_name = self.crate_name.replace('-', "_");
         let ext = self.library_extension();
-        format!("lib{normalized_name}.{ext}")
+        format!("lib{}.{ext}", self.crate_name)
     }
 }
 
@@ -216,6 +211,7 @@ impl ProcMacroConfig {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::nix_gen::NixGenConfig;
     use crate::unit_graph::parse_test_unit_graph;
 
     #[test]
@@ -248,7 +244,7 @@ mod tests {
         assert!(is_proc_macro_unit(unit));
         assert!(requires_host_toolchain(unit));
 
-        let info = ProcMacroInfo::from_unit(unit, None);
+        let info = ProcMacroInfo::from_unit(unit, &NixGenConfig::default());
         assert!(info.is_some());
 
         let info = info.unwrap();
@@ -288,7 +284,7 @@ mod tests {
         assert!(!is_proc_macro_unit(unit));
         assert!(!requires_host_toolchain(unit));
 
-        let info = ProcMacroInfo::from_unit(unit, None);
+        let info = ProcMacroInfo::from_unit(unit, &NixGenConfig::default());
         assert!(info.is_none());
     }
 
@@ -320,11 +316,19 @@ mod tests {
         let unit = &graph.units[0];
 
         // Not cross-compiling when target matches host
-        let info_same = ProcMacroInfo::from_unit(unit, Some("aarch64-apple-darwin"));
+        let info_same = ProcMacroInfo::from_unit(
+            unit,
+            &NixGenConfig::default()
+                .with_cross_compilation("aarch64-apple-darwin", "aarch64-apple-darwin"),
+        );
         assert!(!info_same.unwrap().is_cross_compile);
 
         // Cross-compiling when target differs from host
-        let info_cross = ProcMacroInfo::from_unit(unit, Some("x86_64-unknown-linux-gnu"));
+        let info_cross = ProcMacroInfo::from_unit(
+            unit,
+            &NixGenConfig::default()
+                .with_cross_compilation("aarch64-apple-darwin", "x86_64-unknown-linux-gnu"),
+        );
         assert!(info_cross.unwrap().is_cross_compile);
     }
 
@@ -362,7 +366,7 @@ mod tests {
 
         let graph = parse_test_unit_graph(json);
         let unit = &graph.units[0];
-        let info = ProcMacroInfo::from_unit(unit, None).unwrap();
+        let info = ProcMacroInfo::from_unit(unit, &NixGenConfig::default()).unwrap();
 
         assert_eq!(info.library_filename(), "libmy_macro.so");
     }
@@ -393,7 +397,7 @@ mod tests {
 
         let graph = parse_test_unit_graph(json);
         let unit = &graph.units[0];
-        let info = ProcMacroInfo::from_unit(unit, None).unwrap();
+        let info = ProcMacroInfo::from_unit(unit, &NixGenConfig::default()).unwrap();
 
         // Hyphens should be converted to underscores
         assert_eq!(info.library_filename(), "libmy_derive_macro.dylib");
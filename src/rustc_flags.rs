@@ -4,6 +4,17 @@
 //! the unit metadata from cargo's unit graph. The goal is to reproduce exactly
 //! what cargo would pass to rustc.
 
+/// Lints allowed on every unit so older crates keep building on new toolchains.
+///
+/// - `mismatched_lifetime_syntaxes` (Rust 1.89+): errors on crates without explicit `'_`
+/// - `dangerous_implicit_autorefs` (Rust 1.89+): errors on raw pointer autorefs
+///
+/// Toolchains that predate these lints warn about unknown lints instead.
+pub const COMPAT_ALLOWED_LINTS: &[&str] = &[
+    "mismatched_lifetime_syntaxes",
+    "dangerous_implicit_autorefs",
+];
+
 /// A builder for rustc command-line arguments.
 ///
 /// This struct accumulates flags and can produce either a `Vec<String>` of arguments
@@ -32,7 +43,7 @@ impl RustcFlags {
 
         // Crate name - normalize hyphens to underscores as required by rustc
         flags.push_arg("--crate-name");
-        flags.push_arg(&unit.target.name.replace('-', "_"));
+        flags.push_arg(&unit.crate_name());
 
         // Edition
         flags.add_edition(&unit.target);
@@ -41,7 +52,7 @@ impl RustcFlags {
         flags.add_crate_types(&unit.target);
 
         // Profile-based codegen options
-        flags.add_profile_flags(&unit.profile, &unit.target);
+        flags.add_profile_flags(unit);
 
         // Features as --cfg
         flags.add_features(&unit.features);
@@ -51,13 +62,11 @@ impl RustcFlags {
             flags.push_arg("--test");
         }
 
-        // Allow nightly lints that error on older crates for compatibility.
-        // mismatched_lifetime_syntaxes (Rust 1.89+): errors on crates without explicit `'_`
-        // dangerous_implicit_autorefs (Rust 1.89+): errors on raw pointer autorefs
-        flags.push_arg("-A");
-        flags.push_arg("mismatched_lifetime_syntaxes");
-        flags.push_arg("-A");
-        flags.push_arg("dangerous_implicit_autorefs");
+        // Allow nightly lints that error on older crates for compatibility
+        for lint in COMPAT_ALLOWED_LINTS {
+            flags.push_arg("-A");
+            flags.push_arg(lint);
+        }
 
         flags
     }
@@ -65,7 +74,7 @@ impl RustcFlags {
     /// Adds the edition flag.
     fn add_edition(&mut self, target: &crate::unit_graph::Target) {
         self.push_arg("--edition");
-        self.push_arg(&target.edition);
+        self.push_arg(target.effective_edition());
     }
 
     /// Adds crate type flags.
@@ -85,13 +94,12 @@ impl RustcFlags {
     }
 
     /// Adds all profile-related codegen flags.
-    fn add_profile_flags(
-        &mut self,
-        profile: &crate::unit_graph::Profile,
-        target: &crate::unit_graph::Target,
-    ) {
+    fn add_profile_flags(&mut self, unit: &crate::unit_graph::Unit) {
+        let profile = &unit.profile;
+        let target = &unit.target;
+
         // Optimization level
-        self.push_codegen_flag("opt-level", &profile.opt_level);
+        self.push_codegen_flag("opt-level", profile.effective_opt_level());
 
         // Debug info
         self.add_debuginfo(profile.debuginfo);
@@ -99,14 +107,14 @@ impl RustcFlags {
         // LTO - only valid for executables, cdylibs, and staticlibs
         // rustc errors: "lto can only be run for executables, cdylibs and static library outputs"
         // If ANY crate type is not in the allowed set, skip LTO entirely
-        let lto_allowed_types = ["bin", "cdylib", "staticlib"];
-        let can_use_lto = target
-            .crate_types
-            .iter()
-            .all(|t| lto_allowed_types.contains(&t.as_str()));
-        if can_use_lto {
+        if target.supports_lto() {
             self.add_lto(&profile.lto);
         }
+        // The LTO link reads bitcode from every rlib, so keep it embedded
+        // (rustc's default, but an explicit `embed-bitcode=no` elsewhere breaks LTO)
+        if profile.lto != crate::unit_graph::LtoSetting::Off {
+            self.push_codegen_flag("embed-bitcode", "yes");
+        }
 
         // Codegen units
         if let Some(cgu) = profile.codegen_units {
@@ -117,10 +125,10 @@ impl RustcFlags {
         self.push_codegen_bool("debug-assertions", profile.debug_assertions);
 
         // Overflow checks
-        self.push_codegen_bool("overflow-checks", profile.overflow_checks);
+        self.push_codegen_bool("overflow-checks", profile.overflow_checks());
 
-        // Panic strategy
-        self.add_panic(&profile.panic);
+        // Panic strategy (test harnesses always unwind)
+        self.add_panic(&unit.panic_strategy());
 
         // Strip
         self.add_strip(&profile.strip);
@@ -137,7 +145,9 @@ impl RustcFlags {
             self.push_arg("rpath=yes");
         }
 
-        // Note: incremental is NOT passed to rustc directly; cargo handles it
+        // Note: `profile.incremental` is deliberately ignored. Cargo passes
+        // `-C incremental=<dir>` itself, but every unit here builds in a fresh
+        // sandbox, so there is no incremental state to reuse.
     }
 
     /// Adds debuginfo flag.
@@ -176,6 +186,10 @@ impl RustcFlags {
     }
 
     /// Adds strip flag.
+    ///
+    /// rustc applies it when linking, so it strips bins, cdylibs, and dylibs
+    /// but never the metadata in rlibs. Derivations set `dontStrip` so Nixpkgs
+    /// does not strip on top of this.
     fn add_strip(&mut self, strip: &crate::unit_graph::StripSetting) {
         use crate::unit_graph::StripSetting;
         let value = match strip {
@@ -187,8 +201,11 @@ impl RustcFlags {
         self.push_arg(&format!("strip={value}"));
     }
 
-    /// Adds feature cfg flags.
+    /// Adds feature cfg flags, sorted so the command matches the identity hash
+    /// (which ignores feature order).
     fn add_features(&mut self, features: &[String]) {
+        let mut features: Vec<&String> = features.iter().collect();
+        features.sort_unstable();
         for feature in features {
             self.push_arg("--cfg");
             self.push_arg(&format!("feature=\"{feature}\""));
@@ -217,6 +234,91 @@ impl RustcFlags {
         self.push_arg("warn");
     }
 
+    /// Adds a configuration option, e.g. `tokio_unstable` or `key="value"`.
+    ///
+    /// This generates: `--cfg cfg`
+    pub fn add_cfg(&mut self, cfg: &str) {
+        self.push_arg("--cfg");
+        self.push_arg(cfg);
+    }
+
+    /// Sets the target triple to compile for.
+    ///
+    /// This generates: `--target=triple`
+    pub fn add_target(&mut self, triple: &str) {
+        self.push_arg(&format!("--target={triple}"));
+    }
+
+    /// Adds an arbitrary codegen option.
+    ///
+    /// This generates: `-C key=value`
+    pub fn add_codegen(&mut self, key: &str, value: &str) {
+        self.push_codegen_flag(key, value);
+    }
+
+    /// Sets the linker used for the final link step.
+    ///
+    /// This generates: `-C linker=path`
+    pub fn add_linker(&mut self, linker: &str) {
+        self.push_codegen_flag("linker", linker);
+    }
+
+    /// Sets the linker flavor (e.g. `gcc`, `ld.lld`).
+    ///
+    /// This generates: `-C linker-flavor=flavor`
+    pub fn add_linker_flavor(&mut self, flavor: &str) {
+        self.push_codegen_flag("linker-flavor", flavor);
+    }
+
+    /// Instruments the crate for source-based coverage.
+    ///
+    /// This generates: `-C instrument-coverage`, plus `-C link-dead-code` when
+    /// `link_dead_code` is set so unused functions still show up as uncovered.
+    pub fn add_coverage(&mut self, link_dead_code: bool) {
+        self.push_arg("-C");
+        self.push_arg("instrument-coverage");
+        if link_dead_code {
+            self.push_arg("-C");
+            self.push_arg("link-dead-code");
+        }
+    }
+
+    /// Removes every `-C key=...` codegen option, e.g. to replace a profile setting.
+    pub fn remove_codegen(&mut self, key: &str) {
+        let prefix = format!("{key}=");
+        let mut kept = Vec::with_capacity(self.args.len());
+        let mut args = std::mem::take(&mut self.args).into_iter().peekable();
+        while let Some(arg) = args.next() {
+            if arg == "-C" && args.peek().is_some_and(|next| next.starts_with(&prefix)) {
+                args.next();
+                continue;
+            }
+            kept.push(arg);
+        }
+        self.args = kept;
+    }
+
+    /// Removes every `-A lint` for the given lint.
+    pub fn remove_allow(&mut self, lint: &str) {
+        let mut kept = Vec::with_capacity(self.args.len());
+        let mut args = std::mem::take(&mut self.args).into_iter().peekable();
+        while let Some(arg) = args.next() {
+            if arg == "-A" && args.peek().is_some_and(|next| next == lint) {
+                args.next();
+                continue;
+            }
+            kept.push(arg);
+        }
+        self.args = kept;
+    }
+
+    /// Returns the `--target` triple, if one was added.
+    pub fn target(&self) -> Option<&str> {
+        self.args
+            .iter()
+            .find_map(|arg| arg.strip_prefix("--target="))
+    }
+
     /// Adds an extern crate reference.
     ///
     /// This generates: `--extern name=path`
@@ -407,6 +509,125 @@ mod tests {
         assert!(args.contains(&"codegen-units=16".to_string()));
     }
 
+    #[test]
+    fn test_overflow_checks_default_by_profile() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "test 0.1.0 (path+file:///test)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "test",
+                        "src_path": "/test/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "test 0.1.0 (path+file:///test)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "test",
+                        "src_path": "/test/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0, 1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+
+        // Field absent: dev enables overflow checks like cargo, release does not
+        let dev = &graph.units[0];
+        assert_eq!(dev.profile.overflow_checks, None);
+        assert!(
+            RustcFlags::from_unit(dev)
+                .args()
+                .contains(&"overflow-checks=yes".to_string())
+        );
+        let release = &graph.units[1];
+        assert!(
+            RustcFlags::from_unit(release)
+                .args()
+                .contains(&"overflow-checks=no".to_string())
+        );
+    }
+
+    #[test]
+    fn test_empty_edition_defaults_to_2015() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "test 0.1.0 (path+file:///test)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "test",
+                        "src_path": "/test/src/lib.rs",
+                        "edition": ""
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let args = RustcFlags::from_unit(&graph.units[0]).into_args();
+        let pos = args.iter().position(|a| a == "--edition").unwrap();
+        assert_eq!(args[pos + 1], "2015");
+        // An empty edition is not a validation problem
+        assert!(graph.validate().is_empty());
+    }
+
+    #[test]
+    fn test_opt_level_default_by_profile() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "test 0.1.0 (path+file:///test)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "test",
+                        "src_path": "/test/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let unit = &graph.units[0];
+        assert_eq!(unit.profile.effective_opt_level(), "3");
+        assert!(
+            RustcFlags::from_unit(unit)
+                .args()
+                .contains(&"opt-level=3".to_string())
+        );
+    }
+
     #[test]
     fn test_multiple_crate_types() {
         let json = r#"{
@@ -467,6 +688,55 @@ mod tests {
         assert!(args.contains(&"--test".to_string()));
     }
 
+    #[test]
+    fn test_test_mode_forces_panic_unwind() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "test 0.1.0 (path+file:///test)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "test",
+                        "src_path": "/test/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3", "panic": "abort"},
+                    "features": [],
+                    "mode": "test",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "test 0.1.0 (path+file:///test)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "test",
+                        "src_path": "/test/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "release", "opt_level": "3", "panic": "abort"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0, 1]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+
+        // The test harness unwinds so #[should_panic] works
+        let args = RustcFlags::from_unit(&graph.units[0]).args().to_vec();
+        assert!(args.contains(&"panic=unwind".to_string()));
+        assert!(!args.contains(&"panic=abort".to_string()));
+
+        // Regular builds keep the profile's strategy
+        let args = RustcFlags::from_unit(&graph.units[1]).args().to_vec();
+        assert!(args.contains(&"panic=abort".to_string()));
+    }
+
     #[test]
     fn test_extern_and_lib_path() {
         let mut flags = RustcFlags::new();
@@ -672,4 +942,102 @@ mod tests {
             args
         );
     }
+
+    #[test]
+    fn test_remove_codegen() {
+        let mut flags = RustcFlags::new();
+        flags.add_codegen("codegen-units", "16");
+        flags.add_codegen("opt-level", "3");
+        flags.add_codegen("codegen-units", "1");
+        flags.remove_codegen("codegen-units");
+        assert_eq!(flags.args(), ["-C", "opt-level=3"]);
+    }
+
+    #[test]
+    fn test_remove_allow() {
+        let mut flags = RustcFlags::new();
+        for arg in ["-A", "dead_code", "-A", "unused", "-D", "dead_code"] {
+            flags.push_arg(arg);
+        }
+        flags.remove_allow("dead_code");
+        assert_eq!(flags.args(), ["-A", "unused", "-D", "dead_code"]);
+    }
+
+    #[test]
+    fn test_crate_name_normalizes_hyphens() {
+        let json = r#"{
+            "version": 1,
+            "units": [{
+                "pkg_id": "my-tool 0.1.0 (path+file:///test)",
+                "target": {
+                    "kind": ["bin"],
+                    "crate_types": ["bin"],
+                    "name": "my-tool",
+                    "src_path": "/test/src/main.rs",
+                    "edition": "2021"
+                },
+                "profile": {"name": "dev", "opt_level": "0"},
+                "features": [],
+                "mode": "build",
+                "dependencies": []
+            }],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let unit = &graph.units[0];
+        assert_eq!(unit.crate_name(), "my_tool");
+
+        let flags = RustcFlags::from_unit(unit);
+        assert!(
+            flags
+                .args()
+                .windows(2)
+                .any(|w| w == ["--crate-name", "my_tool"])
+        );
+    }
+
+    #[test]
+    fn test_feature_cfgs_sorted() {
+        let json = r#"{
+            "version": 1,
+            "units": [{
+                "pkg_id": "my-crate 0.1.0 (path+file:///test)",
+                "target": {
+                    "kind": ["lib"],
+                    "crate_types": ["lib"],
+                    "name": "my_crate",
+                    "src_path": "/test/src/lib.rs",
+                    "edition": "2021"
+                },
+                "profile": {"name": "dev", "opt_level": "0"},
+                "features": ["std", "alloc", "derive"],
+                "mode": "build",
+                "dependencies": []
+            }],
+            "roots": [0]
+        }"#;
+
+        let mut graph = parse_test_unit_graph(json);
+        let features = |flags: &RustcFlags| -> Vec<String> {
+            flags
+                .args()
+                .iter()
+                .filter(|a| a.starts_with("feature="))
+                .cloned()
+                .collect()
+        };
+        let flags = RustcFlags::from_unit(&graph.units[0]);
+        assert_eq!(
+            features(&flags),
+            ["feature=\"alloc\"", "feature=\"derive\"", "feature=\"std\""]
+        );
+
+        // Input order does not matter
+        graph.units[0].features.reverse();
+        assert_eq!(
+            features(&RustcFlags::from_unit(&graph.units[0])),
+            features(&flags)
+        );
+    }
 }
@@ -11,3 +11,64 @@ pub fn quote_arg(arg: &str) -> std::borrow::Cow<'_, str> {
         std::borrow::Cow::Borrowed(arg)
     }
 }
+
+/// Quotes a shell argument in double quotes, preserving `${...}` interpolation.
+///
+/// Backslashes, double quotes, and backticks are escaped. A `$` is escaped
+/// unless it starts a `${...}` expansion, so intended interpolations still
+/// expand while stray `$VAR` text stays literal.
+pub fn quote_arg_double(arg: &str) -> std::borrow::Cow<'_, str> {
+    if !arg.contains([' ', '"', '$', '\'', '\\', '`']) {
+        return std::borrow::Cow::Borrowed(arg);
+    }
+
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    let mut chars = arg.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\\' | '`' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '$' if chars.peek() != Some(&'{') => quoted.push_str("\\$"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    std::borrow::Cow::Owned(quoted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_arg_double_plain() {
+        assert!(matches!(
+            quote_arg_double("--crate-name"),
+            std::borrow::Cow::Borrowed("--crate-name")
+        ));
+    }
+
+    #[test]
+    fn test_quote_arg_double_spaces() {
+        assert_eq!(quote_arg_double("hello world"), "\"hello world\"");
+    }
+
+    #[test]
+    fn test_quote_arg_double_quotes() {
+        assert_eq!(quote_arg_double(r#"feature="std""#), r#""feature=\"std\"""#);
+        assert_eq!(quote_arg_double("it's"), "\"it's\"");
+        assert_eq!(quote_arg_double(r"a\b`c`"), r#""a\\b\`c\`""#);
+    }
+
+    #[test]
+    fn test_quote_arg_double_dollar() {
+        // Bare variables are kept literal
+        assert_eq!(quote_arg_double("$VAR"), "\"\\$VAR\"");
+        // Braced interpolations are preserved
+        assert_eq!(quote_arg_double("${out}/lib"), "\"${out}/lib\"");
+        assert_eq!(quote_arg_double("$HOME/${dep}/x"), "\"\\$HOME/${dep}/x\"");
+    }
+}
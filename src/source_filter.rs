@@ -60,6 +60,29 @@ pub enum SourceType {
     },
 }
 
+impl std::fmt::Display for SourceType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Path { path } => write!(f, "path {path}"),
+            Self::Registry { url } => write!(f, "registry {url}"),
+            Self::Git {
+                url,
+                reference,
+                commit,
+            } => {
+                write!(f, "git {url}")?;
+                if let Some(reference) = reference {
+                    write!(f, " ({reference})")?;
+                }
+                if let Some(commit) = commit {
+                    write!(f, " #{commit}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 impl SourceLocation {
     /// Extracts source location from a unit.
     ///
@@ -139,6 +162,47 @@ impl SourceLocation {
         )
     }
 
+    /// Returns a Nix expression fetching this crate's source, for registry and git crates.
+    ///
+    /// Registry crates download the `.crate` archive from crates.io and unpack
+    /// it; `checksum` is its SHA-256 from `Cargo.lock`. Git crates fetch the
+    /// locked commit, descending into the crate's directory within the
+    /// checkout. Unknown hashes are left as `pkgs.lib.fakeHash` so the first
+    /// build reports the real value. Returns `None` for path sources, other
+    /// registries, and git sources without a locked commit.
+    pub fn to_nix_fetcher(&self, checksum: Option<&str>) -> Option<String> {
+        match &self.source {
+            SourceType::Registry { url } if is_crates_io(url) => {
+                let name_version = format!("{}-{}", self.name, self.version);
+                let hash = match checksum {
+                    Some(checksum) => format!("sha256 = \"{checksum}\""),
+                    None => "hash = pkgs.lib.fakeHash".to_string(),
+                };
+                Some(format!(
+                    "pkgs.runCommand \"{name_version}\" {{ src = pkgs.fetchurl {{ \
+                     name = \"{name_version}.tar.gz\"; \
+                     url = \"https://static.crates.io/crates/{}/{name_version}.crate\"; {hash}; }}; }} \
+                     \"mkdir -p $out && tar -xzf $src -C $out --strip-components=1\"",
+                    self.name
+                ))
+            }
+            SourceType::Git {
+                url,
+                commit: Some(commit),
+                ..
+            } => {
+                let fetch = format!(
+                    "pkgs.fetchgit {{ url = \"{url}\"; rev = \"{commit}\"; hash = pkgs.lib.fakeHash; }}"
+                );
+                Some(match git_checkout_subdir(&self.crate_root) {
+                    Some(subdir) if !subdir.is_empty() => format!("\"${{{fetch}}}/{subdir}\""),
+                    _ => fetch,
+                })
+            }
+            _ => None,
+        }
+    }
+
     /// Returns the crate root relative to the workspace root, if it can be determined.
     pub fn relative_crate_root(&self) -> Option<&str> {
         // For workspace crates, the path might be like /workspace/crates/foo
@@ -171,60 +235,45 @@ impl SourceLocation {
 /// - `"my-crate 0.1.0 (path+file:///home/user/project)"`
 /// - `"path+file:///home/user/project#my-crate@0.1.0"`
 fn parse_pkg_id(pkg_id: &str) -> Option<(String, String, SourceType)> {
-    // Try new format first: "source#name@version" or "git+url#version"
-    if let Some(hash_pos) = pkg_id.find('#') {
-        let source_str = &pkg_id[..hash_pos];
-        let name_version = &pkg_id[hash_pos + 1..];
-
-        // Parse name@version
-        if let Some(at_pos) = name_version.find('@') {
-            let name = name_version[..at_pos].to_string();
-            let version = name_version[at_pos + 1..].to_string();
-            let source = parse_source_type(source_str)?;
-            return Some((name, version, source));
-        }
-
-        // Git format: "git+url#version" - extract name from URL
-        if source_str.starts_with("git+") {
-            let version = name_version.to_string();
-            // Extract name from git URL (last path segment before any query/fragment)
-            let url_part = source_str.strip_prefix("git+").unwrap_or(source_str);
-            let url_without_query = url_part.split('?').next().unwrap_or(url_part);
-            let name = url_without_query
-                .rsplit('/')
-                .next()
-                .map(|s| s.strip_suffix(".git").unwrap_or(s))
-                .unwrap_or("unknown")
-                .to_string();
-            let source = parse_source_type(source_str)?;
-            return Some((name, version, source));
-        }
+    // Old format: "name version (source)" - the source path may contain spaces
+    if let Some((name, version, source)) = crate::unit_graph::split_old_pkg_id(pkg_id) {
+        return Some((
+            name.to_string(),
+            version.to_string(),
+            parse_source_type(source)?,
+        ));
     }
 
-    // Try old format: "name version (source)"
-    let paren_start = pkg_id.find('(')?;
-    let paren_end = pkg_id.rfind(')')?;
-
-    if paren_start >= paren_end {
+    // Try new format: "source#name@version" or "git+url#version"
+    let (source_str, name_version) = pkg_id.split_once('#')?;
+    if source_str.is_empty() || name_version.is_empty() {
         return None;
     }
 
-    let name_version = pkg_id[..paren_start].trim();
-    let source_str = &pkg_id[paren_start + 1..paren_end];
-
-    // Split name and version
-    let mut parts = name_version.split_whitespace();
-    let name = parts.next()?.to_string();
-    let version = parts.next()?.to_string();
+    // Parse name@version
+    if let Some((name, version)) = name_version.split_once('@') {
+        if name.is_empty() || version.is_empty() {
+            return None;
+        }
+        let source = parse_source_type(source_str)?;
+        return Some((name.to_string(), version.to_string(), source));
+    }
 
-    // Parse source type
+    // Git format: "git+url#version" - extract name from URL
+    let url_part = source_str.strip_prefix("git+")?;
+    // Last path segment before any query/fragment
+    let url_without_query = url_part.split('?').next().unwrap_or(url_part);
+    let last_segment = url_without_query.trim_end_matches('/').rsplit('/').next()?;
+    let name = last_segment.strip_suffix(".git").unwrap_or(last_segment);
+    if name.is_empty() {
+        return None;
+    }
     let source = parse_source_type(source_str)?;
-
-    Some((name, version, source))
+    Some((name.to_string(), name_version.to_string(), source))
 }
 
 /// Parses the source type string.
-fn parse_source_type(source: &str) -> Option<SourceType> {
+pub(crate) fn parse_source_type(source: &str) -> Option<SourceType> {
     if let Some(path) = source.strip_prefix("path+file://") {
         Some(SourceType::Path {
             path: path.to_string(),
@@ -316,19 +365,124 @@ fn extract_crate_root(src_path: &str, source: &SourceType) -> Option<(String, St
     Some((parent.to_string(), file_name.to_string()))
 }
 
+/// Returns whether a registry index URL is crates.io.
+fn is_crates_io(url: &str) -> bool {
+    url.contains("github.com/rust-lang/crates.io-index") || url.contains("index.crates.io")
+}
+
+/// Returns a git crate's directory within its repository.
+///
+/// Cargo checks git dependencies out to
+/// `.../git/checkouts/<repo>-<hash>/<short-commit>/<subdir>`; the result is
+/// empty for a crate at the repository root.
+fn git_checkout_subdir(crate_root: &str) -> Option<&str> {
+    let (_, rest) = crate_root.split_once("/git/checkouts/")?;
+    let mut parts = rest.splitn(3, '/');
+    parts.next()?;
+    parts.next()?;
+    Some(parts.next().unwrap_or(""))
+}
+
+/// The parts of a `Cargo.lock` read here.
+#[derive(serde::Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(serde::Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    checksum: Option<String>,
+}
+
+/// Reads registry checksums from a `Cargo.lock`, keyed by `name-version`.
+pub fn parse_lock_checksums(
+    contents: &str,
+) -> Result<rustc_hash::FxHashMap<String, String>, String> {
+    let lockfile: Lockfile = toml::from_str(contents).map_err(|e| e.to_string())?;
+    Ok(lockfile
+        .package
+        .into_iter()
+        .filter_map(|p| Some((format!("{}-{}", p.name, p.version), p.checksum?)))
+        .collect())
+}
+
+/// Reads every locked package from a `Cargo.lock` as `name-version`.
+pub fn parse_lock_packages(contents: &str) -> Result<rustc_hash::FxHashSet<String>, String> {
+    let lockfile: Lockfile = toml::from_str(contents).map_err(|e| e.to_string())?;
+    Ok(lockfile
+        .package
+        .into_iter()
+        .map(|p| format!("{}-{}", p.name, p.version))
+        .collect())
+}
+
+/// Returns one message per registry or git package in the graph that is
+/// missing from the lockfile's `locked` packages (see [`parse_lock_packages`]).
+///
+/// A mismatch means the unit graph is stale relative to `Cargo.lock`.
+/// Standard library units (`-Zbuild-std`) are locked by the sysroot, not the
+/// workspace, and are skipped.
+pub fn lockfile_mismatches(
+    graph: &crate::unit_graph::UnitGraph,
+    locked: &rustc_hash::FxHashSet<String>,
+) -> Vec<String> {
+    let mut seen = rustc_hash::FxHashSet::default();
+    let mut mismatches = Vec::new();
+    for unit in graph.units.iter().filter(|u| !u.is_std) {
+        let Some((name, version, source)) = parse_pkg_id(&unit.pkg_id) else {
+            continue;
+        };
+        if matches!(source, SourceType::Path { .. }) {
+            continue;
+        }
+        let name_version = format!("{name}-{version}");
+        if !locked.contains(&name_version) && seen.insert(name_version) {
+            mismatches.push(format!("{name} {version} is not in Cargo.lock"));
+        }
+    }
+    mismatches
+}
+
 /// Utility to convert an absolute path to a workspace-relative path.
 ///
 /// Given a workspace root and an absolute path, returns the relative path.
+/// Both paths are normalized lexically first (relative paths are joined to the
+/// current directory, `.`/`..` resolved, trailing slashes dropped), so a root
+/// like `.` or `/workspace/` matches. Symlinks are not resolved.
 pub fn make_relative(workspace_root: &str, absolute_path: &str) -> Option<String> {
-    let abs = std::path::Path::new(absolute_path);
-    let root = std::path::Path::new(workspace_root);
+    let abs = normalize_path(absolute_path)?;
+    let root = normalize_path(workspace_root)?;
 
-    abs.strip_prefix(root)
+    abs.strip_prefix(&root)
         .ok()
         .and_then(|p| p.to_str())
         .map(|s| s.to_string())
 }
 
+/// Makes a path absolute and resolves `.` and `..` without touching the filesystem.
+///
+/// Returns `None` for an empty path.
+fn normalize_path(path: &str) -> Option<std::path::PathBuf> {
+    use std::path::Component;
+
+    let absolute = std::path::absolute(path).ok()?;
+    let mut normalized = std::path::PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            // `..` at the root stays at the root
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    Some(normalized)
+}
+
 /// Generates a Nix expression for source remapping.
 ///
 /// Cargo's unit graph contains absolute paths from the machine that ran cargo.
@@ -338,25 +492,52 @@ pub fn make_relative(workspace_root: &str, absolute_path: &str) -> Option<String
 /// * `src_path` - The absolute path from unit graph (e.g., `/home/user/project/src/lib.rs`)
 /// * `workspace_root` - The workspace root path
 /// * `nix_src_var` - The Nix variable containing the source (e.g., `src` or `${src}`)
-pub fn remap_source_path(src_path: &str, workspace_root: &str, nix_src_var: &str) -> String {
+/// * `extra_sources` - Additional `(nix_var, root_path)` pairs for path sources outside the workspace
+pub fn remap_source_path(
+    src_path: &str,
+    workspace_root: &str,
+    nix_src_var: &str,
+    extra_sources: &[(String, String)],
+) -> String {
+    // Fallback: use the original path (might fail in Nix sandbox)
+    try_remap_source_path(src_path, workspace_root, nix_src_var, extra_sources)
+        .unwrap_or_else(|| src_path.to_string())
+}
+
+/// Like [`remap_source_path`], but returns `None` instead of falling back to
+/// the original absolute path, which would not exist inside the Nix sandbox.
+pub fn try_remap_source_path(
+    src_path: &str,
+    workspace_root: &str,
+    nix_src_var: &str,
+    extra_sources: &[(String, String)],
+) -> Option<String> {
     // First, try remapping to workspace source
     if let Some(relative) = make_relative(workspace_root, src_path) {
-        return format!("${{{nix_src_var}}}/{relative}");
+        return Some(format!("${{{nix_src_var}}}/{relative}"));
+    }
+
+    // Then any additional source roots (e.g. `../sibling-crate`)
+    for (nix_var, root) in extra_sources {
+        if let Some(relative) = make_relative(root, src_path) {
+            return Some(format!("${{{nix_var}}}/{relative}"));
+        }
     }
 
     // Try to detect and remap registry crate paths
     // Pattern: /.cargo/registry/src/index.crates.io-xxxxx/cratename-version/...
     if let Some(remapped) = remap_registry_path(src_path) {
-        return remapped;
+        return Some(remapped);
     }
 
-    // Fallback: use the original path (might fail in Nix sandbox)
-    src_path.to_string()
+    // Standard library sources (build-std) come from the toolchain's rust-src
+    remap_sysroot_path(src_path)
 }
 
 /// Remaps a unit's manifest directory (CARGO_MANIFEST_DIR) to Nix paths.
 ///
 /// For workspace/local crates: Returns `${src}` or `${src}/relative/path`
+/// For local crates under an extra source root: Returns `${var}` or `${var}/relative/path`
 /// For registry crates: Returns `${vendorDir}/cratename-version`
 ///
 /// # Arguments
@@ -364,11 +545,13 @@ pub fn remap_source_path(src_path: &str, workspace_root: &str, nix_src_var: &str
 /// * `workspace_root` - The workspace root path
 /// * `nix_src_var` - Nix variable for workspace source (e.g., "src")
 /// * `nix_vendor_var` - Nix variable for vendored crates (e.g., "vendorDir")
+/// * `extra_sources` - Additional `(nix_var, root_path)` pairs for path sources outside the workspace
 pub fn remap_manifest_dir(
     unit: &Unit,
     workspace_root: &str,
     nix_src_var: &str,
     nix_vendor_var: &str,
+    extra_sources: &[(String, String)],
 ) -> String {
     let source_loc = SourceLocation::from_unit(unit);
 
@@ -379,18 +562,28 @@ pub fn remap_manifest_dir(
             format!("${{{}}}/{}-{}", nix_vendor_var, loc.name, loc.version)
         }
         Some(loc) if loc.is_path() => {
+            // Standard library crates (build-std): the toolchain's rust-src
+            if let Some(sysroot_dir) = remap_sysroot_path(&loc.crate_root) {
+                return sysroot_dir;
+            }
+
             // Workspace/local crates: compute relative path from crate_root
-            if let Some(relative) = make_relative(workspace_root, &loc.crate_root) {
-                if relative.is_empty() {
-                    // Root crate - just ${src}
-                    format!("${{{}}}", nix_src_var)
-                } else {
-                    format!("${{{}}}/{}", nix_src_var, relative)
+            let roots = std::iter::once((nix_src_var, workspace_root)).chain(
+                extra_sources
+                    .iter()
+                    .map(|(var, root)| (var.as_str(), root.as_str())),
+            );
+            for (var, root) in roots {
+                if let Some(relative) = make_relative(root, &loc.crate_root) {
+                    if relative.is_empty() {
+                        // Root crate - just ${src}
+                        return format!("${{{}}}", var);
+                    }
+                    return format!("${{{}}}/{}", var, relative);
                 }
-            } else {
-                // Fallback to just ${src}
-                format!("${{{}}}", nix_src_var)
             }
+            // Fallback to just ${src}
+            format!("${{{}}}", nix_src_var)
         }
         _ => {
             // Fallback: just ${src}
@@ -399,6 +592,19 @@ pub fn remap_manifest_dir(
     }
 }
 
+/// Attempts to remap a standard library source path to the Rust toolchain.
+///
+/// With `-Zbuild-std`, std units point into the sysroot's rust-src component:
+/// `/home/user/.rustup/toolchains/nightly-x86_64-unknown-linux-gnu/lib/rustlib/src/rust/library/core/src/lib.rs`
+///
+/// These get remapped to:
+/// `${rustToolchain}/lib/rustlib/src/rust/library/core/src/lib.rs`
+fn remap_sysroot_path(src_path: &str) -> Option<String> {
+    let sysroot_marker = "/lib/rustlib/src/rust/";
+    let marker_pos = src_path.find(sysroot_marker)?;
+    Some(format!("${{rustToolchain}}{}", &src_path[marker_pos..]))
+}
+
 /// Attempts to remap a cargo registry path to vendorDir.
 ///
 /// Registry paths look like:
@@ -406,6 +612,10 @@ pub fn remap_manifest_dir(
 ///
 /// These get remapped to:
 /// `${vendorDir}/cratename-1.2.3/src/lib.rs`
+///
+/// Returns `None` if the component after the index directory is not a
+/// `name-version` directory (an unusual registry layout), so the caller falls
+/// back rather than pointing into a `vendorDir` entry that cannot exist.
 fn remap_registry_path(src_path: &str) -> Option<String> {
     // Look for registry/src/ in the path
     let registry_marker = "/registry/src/";
@@ -421,9 +631,28 @@ fn remap_registry_path(src_path: &str) -> Option<String> {
 
     // remainder is now: cratename-version/src/lib.rs
     // We want to remap to: ${vendorDir}/cratename-version/src/lib.rs
+    let (crate_dir, _) = remainder.split_once('/')?;
+    if !is_name_version_dir(crate_dir) {
+        return None;
+    }
     Some(format!("${{vendorDir}}/{remainder}"))
 }
 
+/// Returns whether a directory name looks like `name-version` (e.g.
+/// `serde-1.0.219` or `foo-bar-0.1.0-beta.1`), the layout of registry sources.
+fn is_name_version_dir(dir: &str) -> bool {
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    dir.match_indices('-').any(|(i, _)| {
+        let mut parts = dir[i + 1..].splitn(3, '.');
+        i > 0
+            && parts.next().is_some_and(is_number)
+            && parts.next().is_some_and(is_number)
+            && parts
+                .next()
+                .is_some_and(|patch| patch.starts_with(|c: char| c.is_ascii_digit()))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -479,6 +708,16 @@ mod tests {
         assert!(matches!(source, SourceType::Path { path } if path == "/home/user/project"));
     }
 
+    #[test]
+    fn test_parse_pkg_id_path_with_spaces() {
+        let (name, version, source) =
+            parse_pkg_id("my-crate 0.1.0 (path+file:///Users/My User/my project)").unwrap();
+
+        assert_eq!(name, "my-crate");
+        assert_eq!(version, "0.1.0");
+        assert!(matches!(source, SourceType::Path { path } if path == "/Users/My User/my project"));
+    }
+
     #[test]
     fn test_parse_git_pkg_id() {
         let (name, version, source) =
@@ -501,6 +740,89 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_pkg_id_malformed() {
+        for input in [
+            "",
+            "#",
+            "@",
+            "#@",
+            "git+",
+            "git+#",
+            "git+#1.0.0",
+            "registry+https://x#@1.0.0",
+            "registry+https://x#name@",
+            "#name@1.0.0",
+            "(",
+            ")",
+            "name 1.0.0 (",
+            "name (path+file:///x)",
+            "name 1.0.0 (unknown+x)",
+        ] {
+            assert!(parse_pkg_id(input).is_none(), "{input:?} should not parse");
+        }
+
+        // Git shorthand still derives the name from the URL
+        let (name, version, _) =
+            parse_pkg_id("git+https://github.com/user/repo.git#0.2.0").unwrap();
+        assert_eq!(name, "repo");
+        assert_eq!(version, "0.2.0");
+    }
+
+    #[test]
+    fn test_parse_pkg_id_never_panics() {
+        // Random strings over the characters the parser splits on, plus
+        // multi-byte characters to catch bad slice boundaries
+        const ALPHABET: &[&str] = &[
+            "#",
+            "@",
+            "(",
+            ")",
+            " ",
+            "?",
+            "&",
+            "/",
+            "+",
+            ".",
+            "=",
+            "git+",
+            "path+file://",
+            "registry+",
+            "rev=",
+            "a",
+            "1",
+            "é",
+            "🦀",
+        ];
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..20_000 {
+            let len = (next() % 12) as usize;
+            let input: String = (0..len)
+                .map(|_| ALPHABET[(next() % ALPHABET.len() as u64) as usize])
+                .collect();
+            let _ = parse_pkg_id(&input);
+        }
+
+        // Every prefix and suffix of valid pkg_ids
+        for valid in [
+            "serde 1.0.219 (registry+https://github.com/rust-lang/crates.io-index)",
+            "registry+https://github.com/rust-lang/crates.io-index#serde@1.0.219",
+            "dep 0.1.0 (git+https://github.com/user/repo?rev=abc123#abc123def)",
+            "git+https://github.com/user/repo.git#0.2.0",
+        ] {
+            for i in 0..=valid.len() {
+                let _ = parse_pkg_id(&valid[..i]);
+                let _ = parse_pkg_id(&valid[i..]);
+            }
+        }
+    }
+
     #[test]
     fn test_source_location_from_unit() {
         let json = r#"{
@@ -603,13 +925,157 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_make_relative_normalizes_paths() {
+        // `.` is the current directory (the CLI default)
+        let cwd = std::env::current_dir().unwrap();
+        let lib = cwd.join("crates/foo/src/lib.rs");
+        assert_eq!(
+            make_relative(".", lib.to_str().unwrap()),
+            Some("crates/foo/src/lib.rs".to_string())
+        );
+
+        // Trailing slashes
+        assert_eq!(
+            make_relative("/workspace/", "/workspace/src/lib.rs"),
+            Some("src/lib.rs".to_string())
+        );
+        assert_eq!(
+            make_relative("/workspace//", "/workspace/src/lib.rs"),
+            Some("src/lib.rs".to_string())
+        );
+
+        // `.` and `..` components on either side
+        assert_eq!(
+            make_relative("/workspace/crates/../", "/workspace/./src/lib.rs"),
+            Some("src/lib.rs".to_string())
+        );
+        assert_eq!(
+            make_relative("/workspace", "/workspace/crates/foo/../bar/src/lib.rs"),
+            Some("crates/bar/src/lib.rs".to_string())
+        );
+        assert_eq!(
+            make_relative("/workspace/crates/..", "/other/../workspace-2/src/lib.rs"),
+            None
+        );
+    }
+
     #[test]
     fn test_remap_source_path() {
-        let remapped = remap_source_path("/workspace/crates/foo/src/lib.rs", "/workspace", "src");
+        let remapped =
+            remap_source_path("/workspace/crates/foo/src/lib.rs", "/workspace", "src", &[]);
 
         assert_eq!(remapped, "${src}/crates/foo/src/lib.rs");
     }
 
+    #[test]
+    fn test_remap_registry_path_unusual_layout() {
+        let sparse = "/home/user/.cargo/registry/src/index.crates.io-1949cf8c6b5b557f/";
+        assert_eq!(
+            try_remap_source_path(
+                &format!("{sparse}serde-1.0.219/src/lib.rs"),
+                "/ws",
+                "src",
+                &[]
+            ),
+            Some("${vendorDir}/serde-1.0.219/src/lib.rs".to_string())
+        );
+        assert_eq!(
+            try_remap_source_path(
+                &format!("{sparse}foo-bar-0.1.0-beta.1/lib.rs"),
+                "/ws",
+                "src",
+                &[]
+            ),
+            Some("${vendorDir}/foo-bar-0.1.0-beta.1/lib.rs".to_string())
+        );
+
+        // Nested index directories, or none at all, are left unremapped
+        for path in [
+            "/home/user/.cargo/registry/src/my-registry/nested/serde-1.0.219/src/lib.rs",
+            "/home/user/.cargo/registry/src/serde-1.0.219/src/lib.rs",
+            "/home/user/.cargo/registry/src/index.crates.io-1949cf8c6b5b557f/lib.rs",
+        ] {
+            assert_eq!(
+                try_remap_source_path(path, "/ws", "src", &[]),
+                None,
+                "{path}"
+            );
+            assert_eq!(remap_source_path(path, "/ws", "src", &[]), path);
+        }
+    }
+
+    #[test]
+    fn test_remap_sysroot_path() {
+        let remapped = remap_source_path(
+            "/home/user/.rustup/toolchains/nightly/lib/rustlib/src/rust/library/core/src/lib.rs",
+            "/workspace",
+            "src",
+            &[],
+        );
+        assert_eq!(
+            remapped,
+            "${rustToolchain}/lib/rustlib/src/rust/library/core/src/lib.rs"
+        );
+    }
+
+    #[test]
+    fn test_remap_source_path_extra_sources() {
+        let extra_sources = vec![
+            ("libsSrc".to_string(), "/home/user/libs".to_string()),
+            (
+                "siblingSrc".to_string(),
+                "/home/user/sibling-crate".to_string(),
+            ),
+        ];
+
+        // Workspace paths still map to the main source
+        let remapped = remap_source_path(
+            "/home/user/project/src/main.rs",
+            "/home/user/project",
+            "src",
+            &extra_sources,
+        );
+        assert_eq!(remapped, "${src}/src/main.rs");
+
+        // A crate under the second root maps to its own variable
+        let remapped = remap_source_path(
+            "/home/user/sibling-crate/src/lib.rs",
+            "/home/user/project",
+            "src",
+            &extra_sources,
+        );
+        assert_eq!(remapped, "${siblingSrc}/src/lib.rs");
+
+        let json = r#"{
+            "version": 1,
+            "units": [{
+                "pkg_id": "sibling-crate 0.1.0 (path+file:///home/user/sibling-crate)",
+                "target": {
+                    "kind": ["lib"],
+                    "crate_types": ["lib"],
+                    "name": "sibling_crate",
+                    "src_path": "/home/user/sibling-crate/src/lib.rs",
+                    "edition": "2021"
+                },
+                "profile": {"name": "dev", "opt_level": "0"},
+                "features": [],
+                "mode": "build",
+                "dependencies": []
+            }],
+            "roots": [0]
+        }"#;
+        let graph = parse_test_unit_graph(json);
+        let manifest_dir = remap_manifest_dir(
+            &graph.units[0],
+            "/home/user/project",
+            "src",
+            "vendorDir",
+            &extra_sources,
+        );
+        assert_eq!(manifest_dir, "${siblingSrc}");
+    }
+
     #[test]
     fn test_nix_fileset_generation() {
         let json = r#"{
@@ -717,4 +1183,96 @@ mod tests {
         assert!(!git_loc.is_registry());
         assert!(git_loc.is_git());
     }
+
+    #[test]
+    fn test_parse_lock_checksums() {
+        let lock = r#"
+            version = 3
+
+            [[package]]
+            name = "app"
+            version = "0.1.0"
+
+            [[package]]
+            name = "serde"
+            version = "1.0.219"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+            checksum = "5f0e2c6ed6606019b4e29e69dbaba95b11854410e5347d525002456dbbb786b6"
+        "#;
+        let checksums = parse_lock_checksums(lock).unwrap();
+        assert_eq!(checksums.len(), 1);
+        assert_eq!(
+            checksums["serde-1.0.219"],
+            "5f0e2c6ed6606019b4e29e69dbaba95b11854410e5347d525002456dbbb786b6"
+        );
+    }
+
+    #[test]
+    fn test_lockfile_mismatches() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "serde 1.0.219 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "serde", "src_path": "/registry/serde/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "registry+https://github.com/rust-lang/crates.io-index#itoa@1.0.15",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "itoa", "src_path": "/registry/itoa/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "itoa 1.0.15 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "itoa", "src_path": "/registry/itoa/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace)",
+                    "target": {"kind": ["bin"], "crate_types": ["bin"], "name": "app", "src_path": "/workspace/src/main.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "serde", "public": false},
+                        {"index": 1, "extern_crate_name": "itoa", "public": false}
+                    ]
+                }
+            ],
+            "roots": [3]
+        }"#;
+        let graph = parse_test_unit_graph(json);
+
+        // itoa is missing: the graph was regenerated without updating the lock
+        let lock = r#"
+            version = 3
+
+            [[package]]
+            name = "app"
+            version = "0.1.0"
+
+            [[package]]
+            name = "serde"
+            version = "1.0.219"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+        "#;
+        let locked = parse_lock_packages(lock).unwrap();
+        assert_eq!(
+            lockfile_mismatches(&graph, &locked),
+            ["itoa 1.0.15 is not in Cargo.lock"]
+        );
+
+        let mut locked = locked;
+        locked.insert("itoa-1.0.15".to_string());
+        assert!(lockfile_mismatches(&graph, &locked).is_empty());
+    }
 }
@@ -3,6 +3,12 @@
 //! These types represent the JSON output from `cargo build --unit-graph -Z unstable-options`.
 //! Each unit represents a single rustc invocation in the build graph.
 
+/// Number of SHA-256 bytes kept in identity hashes by default (16 hex chars).
+pub const DEFAULT_HASH_LEN_BYTES: usize = 8;
+
+/// Rust editions rustc accepts for `--edition`.
+pub const EDITIONS: &[&str] = &["2015", "2018", "2021", "2024"];
+
 /// The root structure of the unit graph JSON.
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct UnitGraph {
@@ -69,6 +75,8 @@ pub struct Target {
 
     /// Rust edition.
     /// Values: "2015", "2018", "2021", "2024"
+    /// Use [`Target::effective_edition`] for the edition passed to rustc.
+    #[serde(default)]
     pub edition: String,
 
     /// Whether tests are enabled for this target.
@@ -84,6 +92,31 @@ pub struct Target {
     pub doc: bool,
 }
 
+impl Target {
+    /// Returns the edition passed to rustc.
+    ///
+    /// An empty edition falls back to `2015`, the default of both rustc and
+    /// cargo. Unknown values are passed through (and reported by
+    /// [`UnitGraph::validate`]).
+    pub fn effective_edition(&self) -> &str {
+        if self.edition.is_empty() {
+            "2015"
+        } else {
+            &self.edition
+        }
+    }
+
+    /// Returns whether rustc accepts `-C lto` for this target.
+    ///
+    /// LTO only runs for executables, cdylibs, and staticlibs; rustc errors
+    /// if any other crate type is requested alongside.
+    pub fn supports_lto(&self) -> bool {
+        self.crate_types
+            .iter()
+            .all(|t| matches!(t.as_str(), "bin" | "cdylib" | "staticlib"))
+    }
+}
+
 /// Compilation profile settings.
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct Profile {
@@ -92,6 +125,8 @@ pub struct Profile {
 
     /// Optimization level.
     /// Values: "0", "1", "2", "3", "s", "z"
+    /// Use [`Profile::effective_opt_level`] for the level passed to rustc.
+    #[serde(default)]
     pub opt_level: String,
 
     /// LTO (Link-Time Optimization) setting.
@@ -112,9 +147,10 @@ pub struct Profile {
     #[serde(default)]
     pub debug_assertions: bool,
 
-    /// Whether overflow checks are enabled.
-    #[serde(default)]
-    pub overflow_checks: bool,
+    /// Whether overflow checks are enabled, or `None` if the graph omits it.
+    /// Use [`Profile::overflow_checks`] for the effective value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overflow_checks: Option<bool>,
 
     /// Whether to set rpath.
     #[serde(default)]
@@ -138,6 +174,30 @@ pub struct Profile {
     pub split_debuginfo: Option<String>,
 }
 
+impl Profile {
+    /// Returns the optimization level rustc is invoked with.
+    ///
+    /// Cargo records each unit's own profile, so build scripts and proc-macros
+    /// already carry any `build-override` level here. An empty value follows
+    /// cargo's defaults: `3` for `release` and `bench`, `0` otherwise.
+    pub fn effective_opt_level(&self) -> &str {
+        match self.opt_level.as_str() {
+            "" if matches!(self.name.as_str(), "release" | "bench") => "3",
+            "" => "0",
+            level => level,
+        }
+    }
+
+    /// Returns whether overflow checks are enabled.
+    ///
+    /// When the graph omits the field, this follows cargo's defaults: on for
+    /// `dev` and `test` profiles, off otherwise.
+    pub fn overflow_checks(&self) -> bool {
+        self.overflow_checks
+            .unwrap_or(matches!(self.name.as_str(), "dev" | "test"))
+    }
+}
+
 /// LTO setting (can be string "false"/"true"/"thin"/"fat" or boolean).
 #[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
 pub enum LtoSetting {
@@ -363,6 +423,20 @@ pub struct Dependency {
     pub noprelude: bool,
 }
 
+impl Dependency {
+    /// Returns true if this edge points at a build script execution
+    /// (`run-custom-build`) unit in `graph`.
+    ///
+    /// Such edges supply the build script's outputs (`OUT_DIR`, `cargo:`
+    /// directives) rather than a crate to link against.
+    pub fn is_build_dependency(&self, graph: &UnitGraph) -> bool {
+        graph
+            .units
+            .get(self.index)
+            .is_some_and(|unit| unit.mode == "run-custom-build")
+    }
+}
+
 fn default_true() -> bool {
     true
 }
@@ -400,6 +474,35 @@ impl Unit {
         self.target.kind.contains(&"test".to_string()) || self.mode == "test"
     }
 
+    /// Returns the panic strategy rustc compiles this unit with.
+    ///
+    /// Like cargo, test and bench harnesses always unwind, whatever the profile
+    /// says: the harness catches panics to report failures and `#[should_panic]`.
+    pub fn panic_strategy(&self) -> PanicStrategy {
+        if self.is_test() || self.mode == "bench" {
+            PanicStrategy::Unwind
+        } else {
+            self.profile.panic
+        }
+    }
+
+    /// Returns the crate name rustc sees: the target name with `-` replaced by `_`.
+    ///
+    /// This is the name passed as `--crate-name` and used in library file names.
+    pub fn crate_name(&self) -> String {
+        self.target.name.replace('-', "_")
+    }
+
+    /// Returns true if this unit runs doctests (`rustdoc --test`), not rustc.
+    pub fn is_doctest(&self) -> bool {
+        self.mode == "doctest"
+    }
+
+    /// Returns true if this unit produces a linked artifact (binary, test, or cdylib).
+    pub fn is_linked(&self) -> bool {
+        self.is_bin() || self.is_test() || self.target.crate_types.iter().any(|ct| ct == "cdylib")
+    }
+
     /// Extracts the package name from pkg_id.
     ///
     /// Formats supported:
@@ -407,6 +510,11 @@ impl Unit {
     /// - Git: "git+https://github.com/user/repo#version" -> "repo"
     /// - Old format: "name version (source)" -> "name"
     pub fn package_name(&self) -> &str {
+        // Old format first: its source may itself contain `#` or spaces
+        if let Some((name, _, _)) = split_old_pkg_id(&self.pkg_id) {
+            return name;
+        }
+
         // Handle git dependencies: "git+<url>#version"
         // The package name is the last segment of the URL path
         if self.pkg_id.starts_with("git+") {
@@ -446,6 +554,11 @@ impl Unit {
     /// - Git: "git+https://github.com/user/repo#version" -> "version"
     /// - Old format: "name version (source)" -> "version"
     pub fn package_version(&self) -> Option<&str> {
+        // Old format first: its source may itself contain `#` or spaces
+        if let Some((_, version, _)) = split_old_pkg_id(&self.pkg_id) {
+            return Some(version);
+        }
+
         // Handle git dependencies: "git+<url>#version"
         if self.pkg_id.starts_with("git+") {
             if let Some(hash_pos) = self.pkg_id.find('#') {
@@ -470,27 +583,55 @@ impl Unit {
         parts.next() // return version
     }
 
+    /// Returns the unit's package source, parsed from its pkg_id.
+    ///
+    /// Unlike [`crate::source_filter::SourceLocation::from_unit`], this only
+    /// looks at the source part of the pkg_id, not the package name, version,
+    /// or source paths. Returns `None` for unrecognized sources.
+    pub fn source_type(&self) -> Option<crate::source_filter::SourceType> {
+        // Old format: "name version (source)"
+        let source = match split_old_pkg_id(&self.pkg_id) {
+            Some((_, _, source)) => source,
+            // New format: "source#name@version" or "source#version"
+            None => self
+                .pkg_id
+                .split_once('#')
+                .map_or(self.pkg_id.as_str(), |(source, _)| source),
+        };
+        crate::source_filter::parse_source_type(source)
+    }
+
     /// Returns true if this unit is from an external source (registry or git).
     ///
     /// External dependencies get `--cap-lints warn` to prevent lint errors from
     /// breaking builds. Local path dependencies (workspace crates) don't get this
     /// since we want to see lint errors in our own code.
     pub fn is_external_dependency(&self) -> bool {
-        // Check new format first: "registry+..." or "git+..."
-        if self.pkg_id.starts_with("registry+") || self.pkg_id.starts_with("git+") {
-            return true;
-        }
+        matches!(
+            self.source_type(),
+            Some(
+                crate::source_filter::SourceType::Registry { .. }
+                    | crate::source_filter::SourceType::Git { .. }
+            )
+        )
+    }
 
-        // Check old format: "name version (registry+...)" or "name version (git+...)"
-        if let Some(paren_pos) = self.pkg_id.find('(') {
-            let source = &self.pkg_id[paren_pos + 1..];
-            if source.starts_with("registry+") || source.starts_with("git+") {
-                return true;
-            }
+    /// Returns the target triple this unit compiles for.
+    ///
+    /// Resolved from the unit's own `platform` (cargo sets it for target units
+    /// under `--target`), then `target_platform` for non-host units when
+    /// cross-compiling, then `host_platform`, then [`host_triple`].
+    pub fn target_triple(&self, config: &crate::nix_gen::NixGenConfig) -> String {
+        if let Some(ref platform) = self.platform {
+            return platform.clone();
         }
-
-        // path+file:// sources are local workspace crates
-        false
+        if config.cross_compiling
+            && !crate::proc_macro::requires_host_toolchain(self)
+            && let Some(ref target) = config.target_platform
+        {
+            return target.clone();
+        }
+        config.host_platform.clone().unwrap_or_else(host_triple)
     }
 
     /// Computes a unique identity hash for this unit.
@@ -525,6 +666,119 @@ impl Unit {
     /// Returns a 16-character hex string (first 64 bits of SHA-256).
     #[must_use]
     pub fn identity_hash_with_deps(&self, dep_hashes: &[&str]) -> String {
+        self.identity_hash_with_len(dep_hashes, DEFAULT_HASH_LEN_BYTES)
+    }
+
+    /// Computes the identity hash like [`Self::identity_hash_with_deps`], keeping
+    /// `len_bytes` bytes of the SHA-256 digest.
+    ///
+    /// Returns a `2 * len_bytes`-character hex string. `len_bytes` must be at most 32.
+    #[must_use]
+    pub fn identity_hash_with_len(&self, dep_hashes: &[&str], len_bytes: usize) -> String {
+        self.identity_inputs(dep_hashes).hash(len_bytes)
+    }
+
+    /// Collects the fields that make up this unit's identity hash.
+    ///
+    /// `dep_hashes` are the already-computed identity hashes of the unit's
+    /// direct dependencies, as for [`Self::identity_hash_with_deps`].
+    #[must_use]
+    pub fn identity_inputs<'a>(&'a self, dep_hashes: &[&'a str]) -> IdentityInputs<'a> {
+        let mut features: Vec<&str> = self.features.iter().map(String::as_str).collect();
+        features.sort_unstable();
+        let mut dep_hashes = dep_hashes.to_vec();
+        dep_hashes.sort_unstable();
+
+        IdentityInputs {
+            pkg_id: &self.pkg_id,
+            target_name: &self.target.name,
+            crate_types: &self.target.crate_types,
+            features,
+            profile: &self.profile.name,
+            opt_level: self.profile.effective_opt_level(),
+            lto: &self.profile.lto,
+            debuginfo: self.profile.debuginfo,
+            panic: self.panic_strategy(),
+            debug_assertions: self.profile.debug_assertions,
+            overflow_checks: self.profile.overflow_checks(),
+            codegen_units: self.profile.codegen_units,
+            mode: &self.mode,
+            platform: self.platform.as_deref(),
+            dep_hashes,
+        }
+    }
+
+    /// Returns a Nix-safe derivation name for this unit.
+    ///
+    /// Format: `{crate_name}-{version}-{identity_hash}`
+    /// Example: `serde-1.0.219-a1b2c3d4e5f67890`
+    #[must_use]
+    pub fn derivation_name(&self) -> String {
+        let name = &self.target.name;
+        let version = self.package_version().unwrap_or("0.0.0");
+        let hash = self.identity_hash();
+        format!("{name}-{version}-{hash}")
+    }
+}
+
+/// The inputs of a unit's identity hash (see [`Unit::identity_inputs`]).
+///
+/// Serializable so tools can record exactly what went into a hash and diff
+/// why a derivation name changed between runs.
+#[derive(Debug, serde::Serialize)]
+pub struct IdentityInputs<'a> {
+    /// Package ID.
+    pub pkg_id: &'a str,
+
+    /// Target name.
+    pub target_name: &'a str,
+
+    /// Crate types of the target.
+    pub crate_types: &'a [String],
+
+    /// Enabled features, sorted.
+    pub features: Vec<&'a str>,
+
+    /// Profile name.
+    pub profile: &'a str,
+
+    /// Effective optimization level.
+    pub opt_level: &'a str,
+
+    /// LTO setting.
+    pub lto: &'a LtoSetting,
+
+    /// Debug information level.
+    pub debuginfo: DebugInfo,
+
+    /// Panic strategy.
+    pub panic: PanicStrategy,
+
+    /// Whether debug assertions are enabled.
+    pub debug_assertions: bool,
+
+    /// Whether overflow checks are enabled (effective value).
+    pub overflow_checks: bool,
+
+    /// Number of codegen units, if set.
+    pub codegen_units: Option<u32>,
+
+    /// Compile mode.
+    pub mode: &'a str,
+
+    /// Target triple, if cargo recorded one.
+    pub platform: Option<&'a str>,
+
+    /// Identity hashes of direct dependencies, sorted.
+    pub dep_hashes: Vec<&'a str>,
+}
+
+impl IdentityInputs<'_> {
+    /// Hashes the inputs, keeping `len_bytes` bytes of the SHA-256 digest as hex.
+    ///
+    /// `len_bytes` must be at most 32.
+    #[must_use]
+    pub fn hash(&self, len_bytes: usize) -> String {
         use sha2::Digest as _;
 
         let mut hasher = sha2::Sha256::new();
@@ -534,38 +788,36 @@ impl Unit {
         hasher.update(b"\0");
 
         // Target name and crate types (same pkg can have multiple targets)
-        hasher.update(self.target.name.as_bytes());
+        hasher.update(self.target_name.as_bytes());
         hasher.update(b"\0");
-        for ct in &self.target.crate_types {
+        for ct in self.crate_types {
             hasher.update(ct.as_bytes());
             hasher.update(b"\0");
         }
 
-        // Sorted features for determinism - avoid clone by collecting indices and sorting
+        // Sorted features for determinism
         if self.features.is_empty() {
             hasher.update(b"\0");
         } else {
-            let mut indices: Vec<usize> = (0..self.features.len()).collect();
-            indices.sort_by(|&a, &b| self.features[a].cmp(&self.features[b]));
-            for idx in indices {
-                hasher.update(self.features[idx].as_bytes());
+            for feature in &self.features {
+                hasher.update(feature.as_bytes());
                 hasher.update(b"\0");
             }
         }
 
         // Profile fields that affect compilation output - use discriminant bytes instead of Debug format
-        hasher.update(self.profile.name.as_bytes());
+        hasher.update(self.profile.as_bytes());
         hasher.update(b"\0");
-        hasher.update(self.profile.opt_level.as_bytes());
+        hasher.update(self.opt_level.as_bytes());
         hasher.update(b"\0");
         // LTO: encode as single byte
-        hasher.update(match self.profile.lto {
+        hasher.update(match self.lto {
             LtoSetting::Off => b"0",
             LtoSetting::Thin => b"1",
             LtoSetting::Fat => b"2",
         });
         // DebugInfo: encode as single byte
-        hasher.update(match self.profile.debuginfo {
+        hasher.update(match self.debuginfo {
             DebugInfo::None => b"0",
             DebugInfo::LineDirectivesOnly => b"1",
             DebugInfo::LineTablesOnly => b"2",
@@ -573,24 +825,15 @@ impl Unit {
             DebugInfo::Full => b"4",
         });
         // Panic: encode as single byte
-        hasher.update(match self.profile.panic {
+        hasher.update(match self.panic {
             PanicStrategy::Unwind => b"0",
             PanicStrategy::Abort => b"1",
         });
-        hasher.update(if self.profile.debug_assertions {
-            b"1"
-        } else {
-            b"0"
-        });
-        hasher.update(if self.profile.overflow_checks {
-            b"1"
-        } else {
-            b"0"
-        });
+        hasher.update(if self.debug_assertions { b"1" } else { b"0" });
+        hasher.update(if self.overflow_checks { b"1" } else { b"0" });
 
         // Codegen units (affects output) - avoid to_string allocation
-        if let Some(cgu) = self.profile.codegen_units {
-            // Write number directly as bytes
+        if let Some(cgu) = self.codegen_units {
             let mut buf = itoa::Buffer::new();
             hasher.update(buf.format(cgu).as_bytes());
         }
@@ -601,38 +844,23 @@ impl Unit {
         hasher.update(b"\0");
 
         // Platform (proc-macros compile for host)
-        if let Some(ref platform) = self.platform {
+        if let Some(platform) = self.platform {
             hasher.update(platform.as_bytes());
         }
         hasher.update(b"\0");
 
         // CRITICAL: Include dependency hashes for proper rustc unification!
-        // Sort for determinism (order in unit graph might vary)
-        if !dep_hashes.is_empty() {
-            let mut sorted_hashes: Vec<&str> = dep_hashes.to_vec();
-            sorted_hashes.sort();
+        if !self.dep_hashes.is_empty() {
             hasher.update(b"deps:");
-            for h in sorted_hashes {
+            for h in &self.dep_hashes {
                 hasher.update(h.as_bytes());
                 hasher.update(b"\0");
             }
         }
 
-        // Take first 8 bytes (16 hex chars) for a reasonably unique short ID
+        // Take the first len_bytes bytes (16 hex chars by default) for a reasonably unique short ID
         let result = hasher.finalize();
-        hex::encode(&result[..8])
-    }
-
-    /// Returns a Nix-safe derivation name for this unit.
-    ///
-    /// Format: `{crate_name}-{version}-{identity_hash}`
-    /// Example: `serde-1.0.219-a1b2c3d4e5f67890`
-    #[must_use]
-    pub fn derivation_name(&self) -> String {
-        let name = &self.target.name;
-        let version = self.package_version().unwrap_or("0.0.0");
-        let hash = self.identity_hash();
-        format!("{name}-{version}-{hash}")
+        hex::encode(&result[..len_bytes])
     }
 }
 
@@ -641,6 +869,459 @@ impl UnitGraph {
     pub fn root_units(&self) -> impl Iterator<Item = &Unit> {
         self.roots.iter().filter_map(|&i| self.units.get(i))
     }
+
+    /// Builds an index from package name to the indices of its units, in graph order.
+    ///
+    /// Build once and reuse it when querying many packages; for a single lookup
+    /// use [`Self::units_by_package`].
+    pub fn package_index(&self) -> rustc_hash::FxHashMap<String, Vec<usize>> {
+        let mut index: rustc_hash::FxHashMap<String, Vec<usize>> = rustc_hash::FxHashMap::default();
+        for (i, unit) in self.units.iter().enumerate() {
+            index
+                .entry(unit.package_name().to_string())
+                .or_default()
+                .push(i);
+        }
+        index
+    }
+
+    /// Returns every unit of the named package (libs, bins, tests, build scripts).
+    pub fn units_by_package(&self, name: &str) -> Vec<&Unit> {
+        self.units
+            .iter()
+            .filter(|unit| unit.package_name() == name)
+            .collect()
+    }
+
+    /// Checks structural invariants of the graph.
+    ///
+    /// Returns a human-readable description of every problem found: an
+    /// unsupported format version, root or dependency indices that point
+    /// outside `units`, and units that depend on themselves.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.version != 1 {
+            problems.push(format!("unsupported unit graph version {}", self.version));
+        }
+
+        for &root in &self.roots {
+            if root >= self.units.len() {
+                problems.push(format!(
+                    "root index {root} out of range ({} units)",
+                    self.units.len()
+                ));
+            }
+        }
+
+        for (i, unit) in self.units.iter().enumerate() {
+            if !unit.target.edition.is_empty() && !EDITIONS.contains(&unit.target.edition.as_str())
+            {
+                problems.push(format!(
+                    "unit {i} ({}): unknown edition `{}`",
+                    unit.pkg_id, unit.target.edition
+                ));
+            }
+            for dep in &unit.dependencies {
+                if dep.index >= self.units.len() {
+                    problems.push(format!(
+                        "unit {i} ({}): dependency index {} out of range",
+                        unit.pkg_id, dep.index
+                    ));
+                } else if dep.index == i {
+                    problems.push(format!("unit {i} ({}): depends on itself", unit.pkg_id));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Flags `panic=abort` units that may not link against a build-std sysroot.
+    ///
+    /// With `-Zbuild-std`, the standard library is compiled from the graph, so a
+    /// linked unit with `panic=abort` needs the `panic_abort` runtime among its
+    /// (transitive) std dependencies; otherwise rustc falls back to
+    /// `panic_unwind` and the link fails or mixes strategies. These are
+    /// warnings for review rather than hard errors.
+    pub fn panic_strategy_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        for (i, unit) in self.units.iter().enumerate() {
+            if unit.is_std || !unit.is_linked() || unit.profile.panic != PanicStrategy::Abort {
+                continue;
+            }
+
+            // Collect transitive std dependencies
+            let mut visited = vec![false; self.units.len()];
+            let mut stack: Vec<usize> = unit.dependencies.iter().map(|d| d.index).collect();
+            let mut has_std = false;
+            let mut has_panic_abort = false;
+            while let Some(idx) = stack.pop() {
+                let Some(dep) = self.units.get(idx) else {
+                    continue;
+                };
+                if std::mem::replace(&mut visited[idx], true) {
+                    continue;
+                }
+                if dep.is_std {
+                    has_std = true;
+                    has_panic_abort |= dep.target.name == "panic_abort";
+                }
+                stack.extend(dep.dependencies.iter().map(|d| d.index));
+            }
+
+            if has_std && !has_panic_abort {
+                warnings.push(format!(
+                    "unit {i} ({}): panic=abort with build-std, but panic_abort is not \
+                     in its dependencies (use -Zbuild-std=std,panic_abort)",
+                    unit.pkg_id
+                ));
+            }
+        }
+
+        warnings
+    }
+
+    /// Flags roots whose dependency closure mixes runtime-affecting profile settings.
+    ///
+    /// Units linked into one artifact should agree on `debug_assertions`,
+    /// `overflow_checks`, and `panic`; otherwise generic code instantiated in
+    /// different crates behaves differently. Build scripts and proc-macros run
+    /// on the host with their own profile, so the walk does not descend into
+    /// them. Emits at most one warning per root and setting.
+    pub fn profile_consistency_warnings(&self) -> Vec<String> {
+        fn setting(profile: &Profile, name: &str) -> String {
+            match name {
+                "debug_assertions" => profile.debug_assertions.to_string(),
+                "overflow_checks" => profile.overflow_checks().to_string(),
+                _ => match profile.panic {
+                    PanicStrategy::Unwind => "unwind".to_string(),
+                    PanicStrategy::Abort => "abort".to_string(),
+                },
+            }
+        }
+
+        let mut warnings = Vec::new();
+
+        for &root in &self.roots {
+            let Some(root_unit) = self.units.get(root) else {
+                continue;
+            };
+
+            // Collect the target-side closure of the root
+            let mut visited = vec![false; self.units.len()];
+            let mut closure = Vec::new();
+            let mut stack: Vec<usize> = root_unit.dependencies.iter().map(|d| d.index).collect();
+            while let Some(idx) = stack.pop() {
+                let Some(dep) = self.units.get(idx) else {
+                    continue;
+                };
+                if std::mem::replace(&mut visited[idx], true)
+                    || dep.is_build_script()
+                    || dep.is_proc_macro()
+                {
+                    continue;
+                }
+                closure.push(idx);
+                stack.extend(dep.dependencies.iter().map(|d| d.index));
+            }
+            closure.sort_unstable();
+
+            for name in ["debug_assertions", "overflow_checks", "panic"] {
+                let expected = setting(&root_unit.profile, name);
+                let mismatched: Vec<usize> = closure
+                    .iter()
+                    .copied()
+                    .filter(|&idx| setting(&self.units[idx].profile, name) != expected)
+                    .collect();
+                if let Some(&first) = mismatched.first() {
+                    warnings.push(format!(
+                        "unit {root} ({}): {name}={expected}, but {} unit(s) in its \
+                         closure differ (e.g. unit {first} ({}) has {name}={})",
+                        root_unit.pkg_id,
+                        mismatched.len(),
+                        self.units[first].pkg_id,
+                        setting(&self.units[first].profile, name),
+                    ));
+                }
+            }
+        }
+
+        warnings
+    }
+
+    /// Merges several unit graphs into one.
+    ///
+    /// Units are re-indexed into a single `units` array. Units that are identical
+    /// across graphs (same identity hash, including their dependencies) are
+    /// deduplicated so shared dependencies appear once. Roots are unioned in
+    /// order of first appearance.
+    ///
+    /// Out-of-range dependency and root indices (see [`Self::validate`]) have
+    /// nothing to point at in the merged graph and are dropped.
+    #[must_use]
+    pub fn merge(graphs: Vec<UnitGraph>) -> UnitGraph {
+        let mut merged = UnitGraph {
+            version: graphs.iter().map(|g| g.version).max().unwrap_or(1),
+            units: Vec::new(),
+            roots: Vec::new(),
+        };
+        let mut index_by_hash: rustc_hash::FxHashMap<String, usize> =
+            rustc_hash::FxHashMap::default();
+
+        for graph in graphs {
+            let hashes = graph.identity_hashes();
+
+            // First pass: assign each unit its merged index
+            let mut new_index = Vec::with_capacity(graph.units.len());
+            let mut added = Vec::new();
+            for (unit, hash) in graph.units.into_iter().zip(hashes) {
+                let next = merged.units.len() + added.len();
+                let idx = *index_by_hash.entry(hash).or_insert_with(|| {
+                    added.push(unit);
+                    next
+                });
+                new_index.push(idx);
+            }
+
+            // Second pass: rewrite dependency indices of newly added units
+            for mut unit in added {
+                unit.dependencies.retain(|dep| dep.index < new_index.len());
+                for dep in &mut unit.dependencies {
+                    dep.index = new_index[dep.index];
+                }
+                merged.units.push(unit);
+            }
+
+            for root in graph.roots {
+                let Some(&idx) = new_index.get(root) else {
+                    continue;
+                };
+                if !merged.roots.contains(&idx) {
+                    merged.roots.push(idx);
+                }
+            }
+        }
+
+        merged
+    }
+
+    /// Computes each unit's identity hash including its dependencies' hashes.
+    ///
+    /// Out-of-range dependency indices and edges closing a cycle (see
+    /// [`Self::validate`] and [`Self::find_cycle`]) are left out of the hash.
+    pub(crate) fn identity_hashes(&self) -> Vec<String> {
+        fn compute(
+            idx: usize,
+            graph: &UnitGraph,
+            hashes: &mut [Option<String>],
+            visiting: &mut [bool],
+        ) -> String {
+            if let Some(ref h) = hashes[idx] {
+                return h.clone();
+            }
+            visiting[idx] = true;
+            let unit = &graph.units[idx];
+            let mut dep_hashes = Vec::with_capacity(unit.dependencies.len());
+            for dep in &unit.dependencies {
+                if dep.index < graph.units.len() && !visiting[dep.index] {
+                    dep_hashes.push(compute(dep.index, graph, hashes, visiting));
+                }
+            }
+            visiting[idx] = false;
+            let dep_refs: Vec<&str> = dep_hashes.iter().map(String::as_str).collect();
+            let hash = unit.identity_hash_with_deps(&dep_refs);
+            hashes[idx] = Some(hash.clone());
+            hash
+        }
+
+        let mut hashes = vec![None; self.units.len()];
+        let mut visiting = vec![false; self.units.len()];
+        (0..self.units.len())
+            .map(|i| compute(i, self, &mut hashes, &mut visiting))
+            .collect()
+    }
+
+    /// Finds a dependency cycle, if any.
+    ///
+    /// Returns the unit indices forming the cycle, starting and ending with the
+    /// same unit. Out-of-range dependency indices are ignored (see [`Self::validate`]).
+    pub fn find_cycle(&self) -> Option<Vec<usize>> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum State {
+            Unvisited,
+            InProgress,
+            Done,
+        }
+
+        let mut state = vec![State::Unvisited; self.units.len()];
+
+        for start in 0..self.units.len() {
+            if state[start] != State::Unvisited {
+                continue;
+            }
+
+            // Iterative DFS: stack of (unit index, next dependency position)
+            let mut stack = vec![(start, 0)];
+            state[start] = State::InProgress;
+
+            while let Some(&mut (node, ref mut next)) = stack.last_mut() {
+                let deps = &self.units[node].dependencies;
+                if *next < deps.len() {
+                    let dep = deps[*next].index;
+                    *next += 1;
+                    match state.get(dep) {
+                        Some(State::Unvisited) => {
+                            state[dep] = State::InProgress;
+                            stack.push((dep, 0));
+                        }
+                        Some(State::InProgress) => {
+                            let pos = stack.iter().position(|&(n, _)| n == dep)?;
+                            let mut cycle: Vec<usize> =
+                                stack[pos..].iter().map(|&(n, _)| n).collect();
+                            cycle.push(dep);
+                            return Some(cycle);
+                        }
+                        Some(State::Done) | None => {}
+                    }
+                } else {
+                    state[node] = State::Done;
+                    stack.pop();
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Returns the length of the longest dependency chain starting at each
+    /// unit, in edges (a unit without dependencies has depth 0).
+    ///
+    /// Dependencies that close a cycle or are out of range are ignored.
+    pub fn depths(&self) -> Vec<usize> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum State {
+            Unvisited,
+            InProgress,
+            Done,
+        }
+
+        let mut state = vec![State::Unvisited; self.units.len()];
+        let mut depth = vec![0; self.units.len()];
+
+        for start in 0..self.units.len() {
+            if state[start] != State::Unvisited {
+                continue;
+            }
+
+            // Iterative post-order DFS: a unit's depth is final once all its deps are
+            let mut stack = vec![(start, 0)];
+            state[start] = State::InProgress;
+
+            while let Some(&mut (node, ref mut next)) = stack.last_mut() {
+                let deps = &self.units[node].dependencies;
+                if *next < deps.len() {
+                    let dep = deps[*next].index;
+                    *next += 1;
+                    if state.get(dep) == Some(&State::Unvisited) {
+                        state[dep] = State::InProgress;
+                        stack.push((dep, 0));
+                    }
+                } else {
+                    depth[node] = deps
+                        .iter()
+                        .filter(|dep| state.get(dep.index) == Some(&State::Done))
+                        .map(|dep| depth[dep.index] + 1)
+                        .max()
+                        .unwrap_or(0);
+                    state[node] = State::Done;
+                    stack.pop();
+                }
+            }
+        }
+
+        depth
+    }
+
+    /// Counts units by kind and source, for summaries like `--list-units`.
+    pub fn stats(&self) -> GraphStats {
+        let count = |pred: &dyn Fn(&Unit) -> bool| self.units.iter().filter(|u| pred(u)).count();
+        GraphStats {
+            units: self.units.len(),
+            roots: self.roots.len(),
+            proc_macros: count(&Unit::is_proc_macro),
+            build_scripts: count(&|u| u.is_build_script() && u.mode != "run-custom-build"),
+            build_script_runs: count(&|u| u.mode == "run-custom-build"),
+            external: count(&Unit::is_external_dependency),
+            local: count(&|u| !u.is_std && !u.is_external_dependency()),
+            std: count(&|u| u.is_std),
+            packages: self
+                .units
+                .iter()
+                .map(|u| (u.package_name(), u.package_version()))
+                .collect::<rustc_hash::FxHashSet<_>>()
+                .len(),
+            max_depth: self.depths().into_iter().max().unwrap_or(0),
+        }
+    }
+}
+
+/// Unit counts and shape of a [`UnitGraph`], as returned by [`UnitGraph::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct GraphStats {
+    /// Total number of units.
+    pub units: usize,
+    /// Number of root units.
+    pub roots: usize,
+    /// Proc-macro units.
+    pub proc_macros: usize,
+    /// Build script compile units.
+    pub build_scripts: usize,
+    /// Build script run units (`run-custom-build`).
+    pub build_script_runs: usize,
+    /// Units from registry or git sources.
+    pub external: usize,
+    /// Non-std units from path sources.
+    pub local: usize,
+    /// Standard library units (build-std).
+    pub std: usize,
+    /// Distinct packages (name and version) among all units.
+    pub packages: usize,
+    /// Longest dependency chain in the graph, in edges.
+    pub max_depth: usize,
+}
+
+/// Returns the triple of the platform this tool runs on, e.g. `x86_64-unknown-linux-gnu`.
+///
+/// The fallback when neither the unit graph nor the config names a platform.
+pub fn host_triple() -> String {
+    let arch = std::env::consts::ARCH;
+    match std::env::consts::OS {
+        "macos" => format!("{arch}-apple-darwin"),
+        "linux" if cfg!(target_env = "musl") => format!("{arch}-unknown-linux-musl"),
+        "linux" => format!("{arch}-unknown-linux-gnu"),
+        "windows" if cfg!(target_env = "gnu") => format!("{arch}-pc-windows-gnu"),
+        "windows" => format!("{arch}-pc-windows-msvc"),
+        os => format!("{arch}-unknown-{os}"),
+    }
+}
+
+/// Splits an old-format pkg_id, `"name version (source)"`, into its parts.
+///
+/// Only the segment before the first `(` is split on whitespace, so spaces
+/// (or `#`) inside the source, e.g. `/Users/My User/project`, are preserved.
+/// Returns `None` for anything else, including new-format pkg_ids.
+pub(crate) fn split_old_pkg_id(pkg_id: &str) -> Option<(&str, &str, &str)> {
+    let without_close = pkg_id.strip_suffix(')')?;
+    let paren = without_close.find('(')?;
+    let mut head = without_close[..paren].split_whitespace();
+    let name = head.next()?;
+    let version = head.next()?;
+    if head.next().is_some() {
+        return None;
+    }
+    Some((name, version, &without_close[paren + 1..]))
 }
 
 /// Parses a unit graph from JSON. Test helper available to all crate tests.
@@ -649,6 +1330,27 @@ pub(crate) fn parse_test_unit_graph(json: &str) -> UnitGraph {
     serde_json::from_str(json).expect("failed to parse unit graph")
 }
 
+/// Returns the part of generated Nix between `pname = "<pname>"` and the next
+/// `pname`, i.e. that unit's derivation. Test helper.
+#[cfg(test)]
+pub(crate) fn unit_section<'a>(nix: &'a str, pname: &str) -> &'a str {
+    nix.split(&format!("pname = \"{pname}\""))
+        .nth(1)
+        .unwrap_or_else(|| panic!("no derivation with pname {pname}"))
+        .split("pname = ")
+        .next()
+        .unwrap()
+}
+
+/// Returns the first line of generated Nix starting with `prefix` (after
+/// indentation), e.g. a unit's `"name" = mkUnit` binding. Test helper.
+#[cfg(test)]
+pub(crate) fn drv_line<'a>(nix: &'a str, prefix: &str) -> &'a str {
+    nix.lines()
+        .find(|l| l.trim_start().starts_with(prefix))
+        .unwrap_or_else(|| panic!("no line starting with {prefix}"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -741,7 +1443,8 @@ mod tests {
         assert_eq!(unit.profile.codegen_units, Some(16));
         assert_eq!(unit.profile.debuginfo, DebugInfo::None);
         assert!(!unit.profile.debug_assertions);
-        assert!(!unit.profile.overflow_checks);
+        assert_eq!(unit.profile.overflow_checks, Some(false));
+        assert!(!unit.profile.overflow_checks());
         assert_eq!(unit.profile.panic, PanicStrategy::Abort);
         assert_eq!(unit.profile.strip, StripSetting::Symbols);
 
@@ -866,6 +1569,136 @@ mod tests {
         assert!(!unit.is_bin());
     }
 
+    #[test]
+    fn test_source_type_and_external_dependency() {
+        use crate::source_filter::SourceType;
+
+        let unit = |pkg_id: &str| -> Unit {
+            serde_json::from_str(&format!(
+                r#"{{
+                    "pkg_id": "{pkg_id}",
+                    "target": {{
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "x",
+                        "src_path": "/x/src/lib.rs",
+                        "edition": "2021"
+                    }},
+                    "profile": {{"name": "dev", "opt_level": "0"}},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }}"#
+            ))
+            .unwrap()
+        };
+
+        for pkg_id in [
+            "my-crate 0.1.0 (path+file:///Users/My User/project)",
+            "path+file:///Users/My User/project#my-crate@0.1.0",
+            "path+file:///workspace/app#0.1.0",
+        ] {
+            let unit = unit(pkg_id);
+            assert!(
+                matches!(unit.source_type(), Some(SourceType::Path { .. })),
+                "{pkg_id}"
+            );
+            assert!(!unit.is_external_dependency(), "{pkg_id}");
+        }
+
+        for pkg_id in [
+            "serde 1.0.219 (registry+https://github.com/rust-lang/crates.io-index)",
+            "registry+https://github.com/rust-lang/crates.io-index#serde@1.0.219",
+        ] {
+            let unit = unit(pkg_id);
+            assert_eq!(
+                unit.source_type(),
+                Some(SourceType::Registry {
+                    url: "https://github.com/rust-lang/crates.io-index".to_string()
+                }),
+                "{pkg_id}"
+            );
+            assert!(unit.is_external_dependency(), "{pkg_id}");
+        }
+
+        for pkg_id in [
+            "regex 1.4.3 (git+https://github.com/rust-lang/regex?branch=dev#0123abcd)",
+            "git+https://github.com/rust-lang/regex?branch=dev#regex@1.4.3",
+        ] {
+            let unit = unit(pkg_id);
+            let Some(SourceType::Git { url, reference, .. }) = unit.source_type() else {
+                panic!("{pkg_id}: not a git source");
+            };
+            assert_eq!(url, "https://github.com/rust-lang/regex");
+            assert_eq!(reference.as_deref(), Some("dev"));
+            assert!(unit.is_external_dependency(), "{pkg_id}");
+        }
+
+        let unknown = unit("foo 0.1.0 (directory+/vendor/foo)");
+        assert_eq!(unknown.source_type(), None);
+        assert!(!unknown.is_external_dependency());
+    }
+
+    #[test]
+    fn test_dependency_is_build_dependency() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-pkg 0.1.0 (path+file:///test)",
+                    "target": {
+                        "kind": ["custom-build"],
+                        "crate_types": ["bin"],
+                        "name": "build-script-build",
+                        "src_path": "/test/build.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "dep 0.1.0 (path+file:///dep)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "dep",
+                        "src_path": "/dep/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-pkg 0.1.0 (path+file:///test)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_pkg",
+                        "src_path": "/test/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "build_script_build"},
+                        {"index": 1, "extern_crate_name": "dep"}
+                    ]
+                }
+            ],
+            "roots": [2]
+        }"#;
+
+        let graph: UnitGraph = serde_json::from_str(json).expect("failed to parse");
+        let deps = &graph.units[2].dependencies;
+        assert!(deps[0].is_build_dependency(&graph));
+        assert!(!deps[1].is_build_dependency(&graph));
+    }
+
     #[test]
     fn test_identity_hash_deterministic() {
         let json = r#"{
@@ -1122,4 +1955,525 @@ mod tests {
         assert_eq!(unit.package_name(), "my-crate");
         assert_eq!(unit.package_version(), Some("1.2.3"));
     }
+
+    #[test]
+    fn test_old_format_pkg_id_with_spaces_in_path() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///Users/My User/my project)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "my_crate", "src_path": "/Users/My User/my project/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "dep 0.2.0 (git+https://github.com/user/repo?rev=abc#abc123)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "dep", "src_path": "/git/dep/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+
+        assert_eq!(graph.units[0].package_name(), "my-crate");
+        assert_eq!(graph.units[0].package_version(), Some("0.1.0"));
+        // A `#` inside the old-format source is not mistaken for the new format
+        assert_eq!(graph.units[1].package_name(), "dep");
+        assert_eq!(graph.units[1].package_version(), Some("0.2.0"));
+    }
+
+    #[test]
+    fn test_panic_abort_with_build_std_warns_without_panic_abort() {
+        let std_unit = |name: &str, deps: &str| {
+            format!(
+                r#"{{
+                    "pkg_id": "{name} 0.0.0 (path+file:///rust/library/{name})",
+                    "target": {{"kind": ["lib"], "crate_types": ["lib"], "name": "{name}", "src_path": "/rust/library/{name}/src/lib.rs", "edition": "2021"}},
+                    "profile": {{"name": "release", "opt_level": "3", "panic": "abort"}},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [{deps}],
+                    "is_std": true
+                }}"#
+            )
+        };
+        let app = |deps: &str| {
+            format!(
+                r#"{{
+                    "pkg_id": "app 0.1.0 (path+file:///ws)",
+                    "target": {{"kind": ["bin"], "crate_types": ["bin"], "name": "app", "src_path": "/ws/src/main.rs", "edition": "2021"}},
+                    "profile": {{"name": "release", "opt_level": "3", "panic": "abort"}},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [{deps}]
+                }}"#
+            )
+        };
+        let core_dep = r#"{"index": 0, "extern_crate_name": "core", "noprelude": true}"#;
+        let std_dep = r#"{"index": 1, "extern_crate_name": "std", "noprelude": true}"#;
+
+        // core <- std <- app: no panic_abort anywhere
+        let json = format!(
+            r#"{{"version": 1, "units": [{}, {}, {}], "roots": [2]}}"#,
+            std_unit("core", ""),
+            std_unit("std", core_dep),
+            app(std_dep)
+        );
+        let warnings = parse_test_unit_graph(&json).panic_strategy_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("unit 2 (app 0.1.0"));
+        assert!(warnings[0].contains("panic_abort"));
+
+        // panic_abort in the sysroot: fine
+        let panic_abort_dep =
+            r#"{"index": 3, "extern_crate_name": "panic_abort", "noprelude": true}"#;
+        let json = format!(
+            r#"{{"version": 1, "units": [{}, {}, {}, {}], "roots": [2]}}"#,
+            std_unit("core", ""),
+            std_unit("std", &format!("{core_dep}, {panic_abort_dep}")),
+            app(std_dep),
+            std_unit("panic_abort", core_dep)
+        );
+        assert!(
+            parse_test_unit_graph(&json)
+                .panic_strategy_warnings()
+                .is_empty()
+        );
+
+        // No build-std: the prebuilt sysroot provides panic_abort
+        let json = format!(r#"{{"version": 1, "units": [{}], "roots": [0]}}"#, app(""));
+        assert!(
+            parse_test_unit_graph(&json)
+                .panic_strategy_warnings()
+                .is_empty()
+        );
+    }
+
+    fn graph_with_deps(deps: &[&[usize]]) -> UnitGraph {
+        let units: Vec<String> = deps
+            .iter()
+            .enumerate()
+            .map(|(i, deps)| {
+                let deps: Vec<String> = deps
+                    .iter()
+                    .map(|d| format!(r#"{{"index": {d}, "extern_crate_name": "c{d}"}}"#))
+                    .collect();
+                format!(
+                    r#"{{
+                        "pkg_id": "c{i} 0.1.0 (path+file:///ws/c{i})",
+                        "target": {{
+                            "kind": ["lib"],
+                            "crate_types": ["lib"],
+                            "name": "c{i}",
+                            "src_path": "/ws/c{i}/src/lib.rs",
+                            "edition": "2021"
+                        }},
+                        "profile": {{"name": "dev", "opt_level": "0"}},
+                        "features": [],
+                        "mode": "build",
+                        "dependencies": [{}]
+                    }}"#,
+                    deps.join(", ")
+                )
+            })
+            .collect();
+        parse_test_unit_graph(&format!(
+            r#"{{"version": 1, "units": [{}], "roots": [0]}}"#,
+            units.join(", ")
+        ))
+    }
+
+    #[test]
+    fn test_validate_ok() {
+        let graph = graph_with_deps(&[&[1, 2], &[2], &[]]);
+        assert!(graph.validate().is_empty());
+        assert_eq!(graph.find_cycle(), None);
+    }
+
+    #[test]
+    fn test_validate_bad_indices() {
+        let mut graph = graph_with_deps(&[&[5], &[1]]);
+        graph.roots.push(9);
+        let problems = graph.validate();
+        assert_eq!(problems.len(), 3);
+        assert!(problems.iter().any(|p| p.contains("root index 9")));
+        assert!(
+            problems
+                .iter()
+                .any(|p| p.contains("unit 0") && p.contains("index 5"))
+        );
+        assert!(
+            problems
+                .iter()
+                .any(|p| p.contains("unit 1") && p.contains("itself"))
+        );
+    }
+
+    #[test]
+    fn test_validate_unknown_edition() {
+        let mut graph = graph_with_deps(&[&[]]);
+        graph.units[0].target.edition = "2027".to_string();
+        let problems = graph.validate();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("unknown edition `2027`"));
+    }
+
+    #[test]
+    fn test_profile_consistency_warnings() {
+        // c0 -> c1 -> c2, c0 -> c3 (build script, host profile)
+        let mut graph = graph_with_deps(&[&[1, 3], &[2], &[], &[]]);
+        assert!(graph.profile_consistency_warnings().is_empty());
+
+        graph.units[2].profile.debug_assertions = true;
+        graph.units[1].profile.panic = PanicStrategy::Abort;
+        graph.units[3].target.kind = vec!["custom-build".to_string()];
+        graph.units[3].profile.overflow_checks = Some(false);
+
+        let warnings = graph.profile_consistency_warnings();
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(warnings[0].contains("debug_assertions=false"));
+        assert!(warnings[0].contains("unit 2 (c2 0.1.0"));
+        assert!(warnings[1].contains("panic=unwind"));
+        assert!(warnings[1].contains("has panic=abort"));
+    }
+
+    #[test]
+    fn test_merge_shares_common_dependency() {
+        // Graph A: app_a (0) -> shared (1); graph B: shared (0) <- app_b (1)
+        let json_a = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "app-a 0.1.0 (path+file:///ws/app-a)",
+                    "target": {"kind": ["bin"], "crate_types": ["bin"], "name": "app-a", "src_path": "/ws/app-a/src/main.rs", "edition": "2021"},
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [{"index": 1, "extern_crate_name": "shared"}]
+                },
+                {
+                    "pkg_id": "shared 0.1.0 (path+file:///ws/shared)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "shared", "src_path": "/ws/shared/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": ["std"],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [0]
+        }"#;
+        let json_b = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "shared 0.1.0 (path+file:///ws/shared)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "shared", "src_path": "/ws/shared/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": ["std"],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app-b 0.1.0 (path+file:///ws/app-b)",
+                    "target": {"kind": ["bin"], "crate_types": ["bin"], "name": "app-b", "src_path": "/ws/app-b/src/main.rs", "edition": "2021"},
+                    "profile": {"name": "release", "opt_level": "3"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [{"index": 0, "extern_crate_name": "shared"}]
+                }
+            ],
+            "roots": [1]
+        }"#;
+
+        let merged = UnitGraph::merge(vec![
+            parse_test_unit_graph(json_a),
+            parse_test_unit_graph(json_b),
+        ]);
+
+        assert_eq!(merged.units.len(), 3);
+        let shared: Vec<usize> = (0..merged.units.len())
+            .filter(|&i| merged.units[i].target.name == "shared")
+            .collect();
+        assert_eq!(shared.len(), 1);
+
+        // Both roots survive and point at the shared dependency
+        let root_names: Vec<&str> = merged
+            .root_units()
+            .map(|u| u.target.name.as_str())
+            .collect();
+        assert_eq!(root_names, ["app-a", "app-b"]);
+        for root in merged.root_units() {
+            assert_eq!(root.dependencies[0].index, shared[0]);
+        }
+        assert!(merged.validate().is_empty());
+    }
+
+    #[test]
+    fn test_merge_drops_out_of_range_indices() {
+        let mut graph = graph_with_deps(&[&[1, 7], &[]]);
+        graph.roots = vec![0, 9];
+
+        let merged = UnitGraph::merge(vec![graph]);
+
+        assert_eq!(merged.units.len(), 2);
+        assert_eq!(merged.roots, [0]);
+        let deps: Vec<usize> = merged.units[0]
+            .dependencies
+            .iter()
+            .map(|d| d.index)
+            .collect();
+        assert_eq!(deps, [1]);
+        assert!(merged.validate().is_empty());
+    }
+
+    #[test]
+    fn test_find_cycle() {
+        let graph = graph_with_deps(&[&[1], &[2], &[1]]);
+        assert_eq!(graph.find_cycle(), Some(vec![1, 2, 1]));
+    }
+
+    #[test]
+    fn test_identity_hashes_malformed_graph() {
+        // A cycle and an out-of-range index must not recurse forever or panic
+        let graph = graph_with_deps(&[&[1], &[2], &[1, 7]]);
+        let hashes = graph.identity_hashes();
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(hashes, graph.identity_hashes());
+    }
+
+    #[test]
+    fn test_depths() {
+        // 0 -> 1 -> 2, 0 -> 2, 3 alone
+        let graph = graph_with_deps(&[&[1, 2], &[2], &[], &[]]);
+        assert_eq!(graph.depths(), vec![2, 1, 0, 0]);
+
+        // The edge closing a cycle is ignored
+        let graph = graph_with_deps(&[&[1], &[2], &[1]]);
+        assert_eq!(graph.depths(), vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "serde 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "serde", "src_path": "/registry/serde/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-macro 0.1.0 (path+file:///workspace/my-macro)",
+                    "target": {"kind": ["proc-macro"], "crate_types": ["proc-macro"], "name": "my_macro", "src_path": "/workspace/my-macro/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [{"index": 0, "extern_crate_name": "serde"}]
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {"kind": ["custom-build"], "crate_types": ["bin"], "name": "build-script-build", "src_path": "/workspace/app/build.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {"kind": ["custom-build"], "crate_types": ["bin"], "name": "build-script-build", "src_path": "/workspace/app/build.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "run-custom-build",
+                    "dependencies": [{"index": 2, "extern_crate_name": "build_script_build"}]
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {"kind": ["bin"], "crate_types": ["bin"], "name": "app", "src_path": "/workspace/app/src/main.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [
+                        {"index": 0, "extern_crate_name": "serde"},
+                        {"index": 1, "extern_crate_name": "my_macro"},
+                        {"index": 3, "extern_crate_name": "build_script_build"}
+                    ]
+                }
+            ],
+            "roots": [4]
+        }"#;
+
+        assert_eq!(
+            parse_test_unit_graph(json).stats(),
+            GraphStats {
+                units: 5,
+                roots: 1,
+                proc_macros: 1,
+                build_scripts: 1,
+                build_script_runs: 1,
+                external: 1,
+                local: 4,
+                std: 0,
+                packages: 3,
+                max_depth: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_units_by_package() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {"kind": ["custom-build"], "crate_types": ["bin"], "name": "build-script-build", "src_path": "/workspace/build.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "other 0.2.0 (path+file:///workspace/other)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "other", "src_path": "/workspace/other/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "my_crate", "src_path": "/workspace/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {"kind": ["lib"], "crate_types": ["lib"], "name": "my_crate", "src_path": "/workspace/src/lib.rs", "edition": "2021"},
+                    "profile": {"name": "test", "opt_level": "0"},
+                    "features": [],
+                    "mode": "test",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "my-crate 0.1.0 (path+file:///workspace)",
+                    "target": {"kind": ["bin"], "crate_types": ["bin"], "name": "my-tool", "src_path": "/workspace/src/main.rs", "edition": "2021"},
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }
+            ],
+            "roots": [3, 4]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+
+        let units = graph.units_by_package("my-crate");
+        assert_eq!(units.len(), 4);
+        assert!(units.iter().any(|u| u.is_build_script()));
+        assert!(units.iter().any(|u| u.is_lib() && !u.is_test()));
+        assert!(units.iter().any(|u| u.is_test()));
+        assert!(units.iter().any(|u| u.is_bin()));
+        assert!(graph.units_by_package("missing").is_empty());
+
+        let index = graph.package_index();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index["my-crate"], vec![0, 2, 3, 4]);
+        assert_eq!(index["other"], vec![1]);
+    }
+
+    #[test]
+    fn test_target_triple() {
+        let json = r#"{
+            "version": 1,
+            "units": [
+                {
+                    "pkg_id": "my-macro 0.1.0 (path+file:///workspace/my-macro)",
+                    "target": {
+                        "kind": ["proc-macro"],
+                        "crate_types": ["proc-macro"],
+                        "name": "my_macro",
+                        "src_path": "/workspace/my-macro/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                },
+                {
+                    "pkg_id": "app 0.1.0 (path+file:///workspace/app)",
+                    "target": {
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "app",
+                        "src_path": "/workspace/app/src/lib.rs",
+                        "edition": "2021"
+                    },
+                    "profile": {"name": "dev", "opt_level": "0"},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": [],
+                    "platform": "riscv64gc-unknown-linux-gnu"
+                }
+            ],
+            "roots": [1, 2]
+        }"#;
+
+        let graph = parse_test_unit_graph(json);
+        let (proc_macro, lib, lib_with_platform) =
+            (&graph.units[0], &graph.units[1], &graph.units[2]);
+
+        // Native: everything builds for the host
+        let native = crate::nix_gen::NixGenConfig::default();
+        assert_eq!(proc_macro.target_triple(&native), host_triple());
+        assert_eq!(lib.target_triple(&native), host_triple());
+
+        // Cross: proc-macros stay on the host, target crates use the target
+        let cross = crate::nix_gen::NixGenConfig::default()
+            .with_cross_compilation("x86_64-unknown-linux-gnu", "aarch64-unknown-linux-gnu");
+        assert_eq!(proc_macro.target_triple(&cross), "x86_64-unknown-linux-gnu");
+        assert_eq!(lib.target_triple(&cross), "aarch64-unknown-linux-gnu");
+
+        // The unit's own platform wins over the config
+        assert_eq!(
+            lib_with_platform.target_triple(&native),
+            "riscv64gc-unknown-linux-gnu"
+        );
+        assert_eq!(
+            lib_with_platform.target_triple(&cross),
+            "riscv64gc-unknown-linux-gnu"
+        );
+
+        // The fallback is a full triple, not just the architecture
+        assert!(host_triple().starts_with(std::env::consts::ARCH));
+        assert!(host_triple().matches('-').count() >= 2);
+    }
 }
@@ -0,0 +1,346 @@
+//! Tests for the `nix-cargo-unit` command-line interface.
+//!
+//! These run the built binary with a unit graph on stdin and check its
+//! exit status and output.
+
+use std::io::Write as _;
+use std::process::{Command, Output, Stdio};
+
+/// Runs the CLI with the given arguments, feeding `stdin` as input.
+fn run_cli(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nix-cargo-unit"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to spawn nix-cargo-unit");
+
+    child
+        .stdin
+        .take()
+        .expect("stdin not piped")
+        .write_all(stdin.as_bytes())
+        .expect("failed to write stdin");
+
+    child
+        .wait_with_output()
+        .expect("failed to wait for nix-cargo-unit")
+}
+
+/// A single-unit graph with the given pkg_id.
+fn single_unit_graph(pkg_id: &str) -> String {
+    format!(
+        r#"{{
+            "version": 1,
+            "units": [
+                {{
+                    "pkg_id": "{pkg_id}",
+                    "target": {{
+                        "kind": ["lib"],
+                        "crate_types": ["lib"],
+                        "name": "my_crate",
+                        "src_path": "/workspace/src/lib.rs",
+                        "edition": "2021"
+                    }},
+                    "profile": {{"name": "dev", "opt_level": "0"}},
+                    "features": [],
+                    "mode": "build",
+                    "dependencies": []
+                }}
+            ],
+            "roots": [0]
+        }}"#
+    )
+}
+
+#[test]
+fn test_check_good_graph() {
+    let graph = single_unit_graph("my-crate 0.1.0 (path+file:///workspace)");
+    let output = run_cli(&["--check"], &graph);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("ok: 1 units, 1 roots"));
+    // No Nix is emitted in check mode
+    assert!(!stdout.contains("mkDerivation"));
+}
+
+#[test]
+fn test_check_bad_pkg_id() {
+    let graph = single_unit_graph("not a valid pkg id");
+    let output = run_cli(&["--check"], &graph);
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("unit 0 (not a valid pkg id)"));
+}
+
+#[test]
+fn test_config_file_with_cli_override() {
+    let dir = std::env::temp_dir().join(format!("nix-cargo-unit-cli-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config_path = dir.join("nix-cargo-unit.toml");
+    std::fs::write(
+        &config_path,
+        "workspace_root = \"/from-file\"\ncontent_addressed = true\n",
+    )
+    .unwrap();
+    let config_arg = config_path.to_str().unwrap();
+
+    let graph = single_unit_graph("my-crate 0.1.0 (path+file:///workspace)")
+        .replace("/workspace/src/lib.rs", "/from-cli/src/lib.rs");
+
+    // File settings apply
+    let output = run_cli(&["--config", config_arg], &graph);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("__contentAddressed = true"));
+    assert!(stdout.contains("/from-cli/src/lib.rs"));
+    assert!(!stdout.contains("${src}/src/lib.rs"));
+
+    // CLI flags override the file
+    let output = run_cli(
+        &["--config", config_arg, "--workspace-root", "/from-cli"],
+        &graph,
+    );
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("__contentAddressed = true"));
+    assert!(stdout.contains("${src}/src/lib.rs"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_timing_output() {
+    let graph = single_unit_graph("my-crate 0.1.0 (path+file:///workspace)");
+    let output = run_cli(&["--timing", "--workspace-root", "/workspace"], &graph);
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let timings: Vec<&str> = stderr
+        .lines()
+        .filter(|line| line.starts_with("timing: "))
+        .collect();
+    assert_eq!(timings.len(), 2, "{stderr}");
+    for (line, phase) in timings.iter().zip(["parse", "generate"]) {
+        let ms = line
+            .strip_prefix(&format!("timing: {phase} "))
+            .and_then(|rest| rest.strip_suffix("ms"))
+            .unwrap_or_else(|| panic!("unexpected timing line: {line}"));
+        assert!(ms.parse::<f64>().is_ok(), "{line}");
+    }
+
+    // Without the flag nothing is printed
+    let output = run_cli(&["--workspace-root", "/workspace"], &graph);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("timing:"));
+}
+
+#[test]
+fn test_inspect_output() {
+    let graph = single_unit_graph("my-crate 0.1.0 (path+file:///workspace)");
+    let output = run_cli(&["inspect", "--workspace-root", "/workspace"], &graph);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("unit 0: my-crate 0.1.0 (path+file:///workspace)"));
+    assert!(stdout.contains("  name: my-crate\n"), "{stdout}");
+    assert!(stdout.contains("  source: path /workspace\n"), "{stdout}");
+    assert!(stdout.contains("  crate_root: /workspace\n"), "{stdout}");
+    assert!(stdout.contains("  entry_point: src/lib.rs\n"), "{stdout}");
+    assert!(
+        stdout.contains("  src_path: /workspace/src/lib.rs -> ${src}/src/lib.rs\n"),
+        "{stdout}"
+    );
+    // No Nix is emitted
+    assert!(!stdout.contains("mkDerivation"));
+}
+
+#[test]
+fn test_cargo_config_env() {
+    let dir = std::env::temp_dir().join(format!("nix-cargo-unit-env-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join(".cargo")).unwrap();
+    let config_path = dir.join(".cargo/config.toml");
+    std::fs::write(
+        &config_path,
+        "[env]\nMY_VAR = \"x\"\nDATA = { value = \"data\", relative = true, force = true }\n",
+    )
+    .unwrap();
+
+    let root = dir.to_str().unwrap();
+    let graph = single_unit_graph(&format!("my-crate 0.1.0 (path+file://{root})"))
+        .replace("/workspace", root);
+    let output = run_cli(
+        &[
+            "--cargo-config",
+            config_path.to_str().unwrap(),
+            "--workspace-root",
+            root,
+        ],
+        &graph,
+    );
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("|| export MY_VAR=x"));
+    // Relative to the directory containing `.cargo/`, remapped into the source tree
+    assert!(stdout.contains("export DATA=\"${src}/data\""));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_list_units() {
+    let unit = |pkg_id: &str, kind: &str, name: &str, mode: &str, deps: &str, src: &str| {
+        format!(
+            r#"{{
+                "pkg_id": "{pkg_id}",
+                "target": {{"kind": ["{kind}"], "crate_types": ["{kind}"], "name": "{name}", "src_path": "{src}", "edition": "2021"}},
+                "profile": {{"name": "dev", "opt_level": "0"}},
+                "features": [],
+                "mode": "{mode}",
+                "dependencies": [{deps}]
+            }}"#
+        )
+    };
+    let dep = |i: usize| format!(r#"{{"index": {i}, "extern_crate_name": "d{i}"}}"#);
+    let graph = format!(
+        r#"{{"version": 1, "units": [{}], "roots": [4]}}"#,
+        [
+            unit(
+                "serde 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)",
+                "lib",
+                "serde",
+                "build",
+                "",
+                "/home/user/.cargo/registry/src/index.crates.io-6f17d22bba15001f/serde-1.0.0/src/lib.rs"
+            ),
+            unit(
+                "my-macro 0.1.0 (path+file:///workspace/my-macro)",
+                "proc-macro",
+                "my_macro",
+                "build",
+                &dep(0),
+                "/workspace/my-macro/src/lib.rs"
+            ),
+            unit(
+                "app 0.1.0 (path+file:///workspace/app)",
+                "custom-build",
+                "build-script-build",
+                "build",
+                "",
+                "/workspace/app/build.rs"
+            ),
+            unit(
+                "app 0.1.0 (path+file:///workspace/app)",
+                "custom-build",
+                "build-script-build",
+                "run-custom-build",
+                &dep(2),
+                "/workspace/app/build.rs"
+            ),
+            unit(
+                "app 0.1.0 (path+file:///workspace/app)",
+                "bin",
+                "app",
+                "build",
+                &[dep(0), dep(1), dep(3)].join(", "),
+                "/workspace/app/src/main.rs"
+            ),
+        ]
+        .join(", ")
+    );
+
+    let output = run_cli(&["--list-units", "--workspace-root", "/workspace"], &graph);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("mkUnit"));
+
+    let rows: Vec<Vec<&str>> = stdout
+        .lines()
+        .skip(1)
+        .take_while(|l| !l.is_empty())
+        .map(|l| l.split_whitespace().collect())
+        .collect();
+    assert_eq!(rows.len(), 5);
+    assert!(rows[0][0].starts_with("serde-1.0.0-"));
+    assert_eq!(rows[0][1..], ["lib", "registry", "0"]);
+    assert_eq!(rows[1][1..], ["proc-macro", "path", "1"]);
+    assert_eq!(rows[3][1..], ["build-script-run", "path", "1"]);
+    assert_eq!(rows[4][1..], ["bin", "path", "3"]);
+
+    for line in [
+        "units: 5",
+        "roots: 1",
+        "proc-macros: 1",
+        "build scripts: 1",
+        "build script runs: 1",
+        "external: 1",
+        "local: 4",
+        "std: 0",
+        "packages: 3",
+        "max depth: 2",
+    ] {
+        assert!(
+            stdout.lines().any(|l| l == line),
+            "missing `{line}`:\n{stdout}"
+        );
+    }
+}
+
+#[test]
+fn test_lockfile_mismatch() {
+    let dir = std::env::temp_dir().join(format!("nix-cargo-unit-lock-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let lock_path = dir.join("Cargo.lock");
+    std::fs::write(
+        &lock_path,
+        "version = 3\n\n[[package]]\nname = \"other\"\nversion = \"1.0.0\"\n",
+    )
+    .unwrap();
+
+    let graph =
+        single_unit_graph("my-crate 0.1.0 (registry+https://github.com/rust-lang/crates.io-index)");
+    let output = run_cli(&["--lockfile", lock_path.to_str().unwrap()], &graph);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("error: my-crate 0.1.0 is not in Cargo.lock"));
+    assert!(stderr.contains("unit graph does not match"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_diff_against_per_unit_dir() {
+    let dir = std::env::temp_dir().join(format!("nix-cargo-unit-diff-{}", std::process::id()));
+    let graph = single_unit_graph("my-crate 0.1.0 (path+file:///workspace)");
+    let args = ["--workspace-root", "/workspace"];
+
+    let units = dir.join("units");
+    let units_arg = units.to_str().unwrap();
+    let output = run_cli(
+        &[&args[..], &["--emit-per-unit-drv-files", units_arg]].concat(),
+        &graph,
+    );
+    assert!(output.status.success());
+
+    // The directory is compared through its default.nix
+    let output = run_cli(&[&args[..], &["--diff", units_arg]].concat(), &graph);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "0 added, 0 removed, 1 unchanged\n"
+    );
+
+    // A file without derivations is an error, not "everything added"
+    let empty = dir.join("empty.nix");
+    std::fs::write(&empty, "{ }\n").unwrap();
+    let output = run_cli(
+        &[&args[..], &["--diff", empty.to_str().unwrap()]].concat(),
+        &graph,
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no derivations found"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
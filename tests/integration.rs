@@ -439,3 +439,57 @@ fn test_workspace_outputs_map_targets() {
         "example_macros should be in libraries"
     );
 }
+
+/// Returns whether `nix-instantiate` can be run.
+fn nix_instantiate_available() -> bool {
+    Command::new("nix-instantiate")
+        .arg("--version")
+        .output()
+        .is_ok_and(|o| o.status.success())
+}
+
+#[test]
+fn test_generated_nix_parses() {
+    if !nix_instantiate_available() {
+        eprintln!("skipping: nix-instantiate not found on PATH");
+        return;
+    }
+
+    let json = get_unit_graph();
+    let graph = parse_unit_graph(&json);
+
+    let workspace_root =
+        std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(EXAMPLE_WORKSPACE);
+    let dir = std::env::temp_dir().join(format!("nix-cargo-unit-parse-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Cover the main output variants: plain, content-addressed, and cross
+    for (name, content_addressed, cross_compiling) in [
+        ("default", false, false),
+        ("ca", true, false),
+        ("cross", false, true),
+    ] {
+        let config = nix_cargo_unit::nix_gen::NixGenConfig {
+            workspace_root: workspace_root.to_string_lossy().to_string(),
+            content_addressed,
+            cross_compiling,
+            ..Default::default()
+        };
+        let nix = nix_cargo_unit::nix_gen::NixGenerator::new(config).generate(&graph);
+
+        let path = dir.join(format!("{name}.nix"));
+        std::fs::write(&path, &nix).unwrap();
+        let output = Command::new("nix-instantiate")
+            .arg("--parse")
+            .arg(&path)
+            .output()
+            .expect("failed to run nix-instantiate");
+        assert!(
+            output.status.success(),
+            "{name}: generated Nix does not parse:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}